@@ -0,0 +1,375 @@
+use std::collections::BTreeSet;
+
+use crate::{
+    ast::{Action, Condition, Expr, FactSet, Lambda, Rule, FACT_SET_BINDING},
+    json::Json,
+};
+
+pub struct RuleDependencies {
+    pub rule: String,
+    pub reads: BTreeSet<String>,
+    pub writes: BTreeSet<String>,
+}
+
+impl RuleDependencies {
+    pub fn of(rule: &Rule) -> RuleDependencies {
+        let mut reads = BTreeSet::new();
+        let mut writes = BTreeSet::new();
+
+        condition_reads(&rule.condition, &mut reads);
+        for action in &rule.actions {
+            match action {
+                Action::Assign { field, expr } => {
+                    expr_reads(expr, &mut reads);
+                    writes.insert(field.clone());
+                }
+                Action::Insert { fact_type, fields } => {
+                    for (field, expr) in fields {
+                        expr_reads(expr, &mut reads);
+                        writes.insert(format!("{}.{}", fact_type, field));
+                    }
+                    writes.insert(fact_type.clone());
+                }
+                Action::Update(_) | Action::Retract(_) => {}
+            }
+        }
+
+        // a pattern reads the set of its type's instances, which inserts,
+        // updates and retracts change; a pattern variable's fields are
+        // those of its fact type, whichever rule binds it
+        let patterns = rule.patterns();
+        for (_, fact_type) in &patterns {
+            reads.insert(fact_type.to_string());
+        }
+        for action in &rule.actions {
+            if let Some(fact_type) = action.fact_type(&patterns) {
+                writes.insert(fact_type.to_string());
+            }
+        }
+        let by_type = |fields: BTreeSet<String>| -> BTreeSet<String> {
+            fields
+                .into_iter()
+                .map(|name| match name.split_once('.') {
+                    Some((var, field)) => match patterns.iter().find(|(v, _)| *v == var) {
+                        Some((_, fact_type)) => format!("{}.{}", fact_type, field),
+                        None => name,
+                    },
+                    None => name,
+                })
+                .collect()
+        };
+
+        RuleDependencies {
+            rule: rule.name.clone(),
+            reads: by_type(reads),
+            writes: by_type(writes),
+        }
+    }
+
+    /// Fields the rule writes that its own condition or actions also read.
+    pub fn self_dependencies(&self) -> Vec<&String> {
+        self.writes.intersection(&self.reads).collect()
+    }
+}
+
+/// Fields a condition reads, i.e. the facts a rule matches on.
+pub fn condition_fields(cond: &Condition) -> BTreeSet<String> {
+    let mut fields = BTreeSet::new();
+    condition_reads(cond, &mut fields);
+    fields
+}
+
+fn condition_reads(cond: &Condition, out: &mut BTreeSet<String>) {
+    match cond {
+        Condition::Compare { left, right, .. } => {
+            expr_reads(left, out);
+            expr_reads(right, out);
+        }
+        Condition::And(a, b) | Condition::Or(a, b) => {
+            condition_reads(a, out);
+            condition_reads(b, out);
+        }
+        Condition::Not(c) => condition_reads(c, out),
+        Condition::Pattern { constraint, .. } => {
+            if let Some(c) = constraint {
+                condition_reads(c, out);
+            }
+        }
+        Condition::Exists(set) => fact_set_reads(set, out),
+    }
+}
+
+// a fact set reads its type's instances: the type's name, and the
+// fields its constraint names
+fn fact_set_reads(set: &FactSet, out: &mut BTreeSet<String>) {
+    out.insert(set.fact_type.clone());
+    let mut reads = BTreeSet::new();
+    if let Some(c) = &set.constraint {
+        condition_reads(c, &mut reads);
+    }
+    out.extend(reads.into_iter().map(|name| match name.split_once('.') {
+        Some((var, field)) if var == FACT_SET_BINDING => format!("{}.{}", set.fact_type, field),
+        _ => name,
+    }));
+}
+
+fn expr_reads(expr: &Expr, out: &mut BTreeSet<String>) {
+    match expr {
+        Expr::Literal(_) => {}
+        Expr::FieldRef(name) => {
+            out.insert(name.clone());
+        }
+        Expr::BinOp { left, right, .. } => {
+            expr_reads(left, out);
+            expr_reads(right, out);
+        }
+        Expr::Call { args, .. } => {
+            for arg in args {
+                expr_reads(arg, out);
+            }
+        }
+        Expr::Aggregate { list, lambda, .. } => {
+            expr_reads(list, out);
+            let Some(lambda) = lambda else { return };
+            // the items the lambda's parameter names are read through the list
+            let mut reads = BTreeSet::new();
+            match lambda {
+                Lambda::Map(_, body) => expr_reads(body, &mut reads),
+                Lambda::Filter(_, cond) => condition_reads(cond, &mut reads),
+            }
+            out.extend(reads.into_iter().filter(|name| name.split('.').next() != Some(lambda.param())));
+        }
+        Expr::Facts(set) => fact_set_reads(set, out),
+    }
+}
+
+/// `from` writes at least one field that `to` reads.
+pub struct Edge {
+    pub from: usize,
+    pub to: usize,
+    pub fields: Vec<String>,
+}
+
+pub struct DependencyGraph {
+    pub rules: Vec<RuleDependencies>,
+    pub edges: Vec<Edge>,
+}
+
+impl DependencyGraph {
+    pub fn build(rules: &[Rule]) -> DependencyGraph {
+        let rules: Vec<RuleDependencies> = rules.iter().map(RuleDependencies::of).collect();
+        let mut edges = Vec::new();
+
+        for (from, writer) in rules.iter().enumerate() {
+            for (to, reader) in rules.iter().enumerate() {
+                let fields: Vec<String> = writer.writes.intersection(&reader.reads).cloned().collect();
+                if !fields.is_empty() {
+                    edges.push(Edge { from, to, fields });
+                }
+            }
+        }
+
+        DependencyGraph { rules, edges }
+    }
+
+    /// Rules that write a field they themselves read.
+    pub fn self_loops(&self) -> Vec<&str> {
+        self.edges
+            .iter()
+            .filter(|e| e.from == e.to)
+            .map(|e| self.rules[e.from].rule.as_str())
+            .collect()
+    }
+
+    /// Groups of two or more rules that can re-trigger each other
+    /// (strongly connected components, Tarjan's algorithm).
+    pub fn cycles(&self) -> Vec<Vec<&str>> {
+        let n = self.rules.len();
+        let mut succ = vec![Vec::new(); n];
+        for e in &self.edges {
+            if e.from != e.to {
+                succ[e.from].push(e.to);
+            }
+        }
+
+        let mut tarjan = Tarjan {
+            succ: &succ,
+            index: vec![None; n],
+            low: vec![0; n],
+            on_stack: vec![false; n],
+            stack: Vec::new(),
+            next: 0,
+            components: Vec::new(),
+        };
+        for v in 0..n {
+            if tarjan.index[v].is_none() {
+                tarjan.visit(v);
+            }
+        }
+
+        let mut cycles: Vec<Vec<&str>> = tarjan
+            .components
+            .into_iter()
+            .filter(|c| c.len() > 1)
+            .map(|mut c| {
+                c.sort();
+                c.into_iter().map(|i| self.rules[i].rule.as_str()).collect()
+            })
+            .collect();
+        cycles.sort();
+        cycles
+    }
+
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph rules {\n");
+        for (i, r) in self.rules.iter().enumerate() {
+            out.push_str(&format!("    r{} [label=\"{}\"];\n", i, dot_escape(&r.rule)));
+        }
+        for e in &self.edges {
+            out.push_str(&format!(
+                "    r{} -> r{} [label=\"{}\"];\n",
+                e.from,
+                e.to,
+                dot_escape(&e.fields.join(", "))
+            ));
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    pub fn to_json(&self) -> String {
+        let strings = |items: Vec<&str>| Json::Array(items.into_iter().map(|s| Json::Str(s.to_string())).collect());
+
+        let rules = self
+            .rules
+            .iter()
+            .map(|r| {
+                Json::Object(vec![
+                    ("name".into(), Json::Str(r.rule.clone())),
+                    ("reads".into(), strings(r.reads.iter().map(|s| s.as_str()).collect())),
+                    ("writes".into(), strings(r.writes.iter().map(|s| s.as_str()).collect())),
+                ])
+            })
+            .collect();
+        let edges = self
+            .edges
+            .iter()
+            .map(|e| {
+                Json::Object(vec![
+                    ("from".into(), Json::Str(self.rules[e.from].rule.clone())),
+                    ("to".into(), Json::Str(self.rules[e.to].rule.clone())),
+                    ("fields".into(), strings(e.fields.iter().map(|s| s.as_str()).collect())),
+                ])
+            })
+            .collect();
+        let cycles = self.cycles().into_iter().map(strings).collect();
+
+        Json::Object(vec![
+            ("rules".into(), Json::Array(rules)),
+            ("edges".into(), Json::Array(edges)),
+            ("self_loops".into(), strings(self.self_loops())),
+            ("cycles".into(), Json::Array(cycles)),
+        ])
+        .to_string()
+    }
+}
+
+struct Tarjan<'a> {
+    succ: &'a [Vec<usize>],
+    index: Vec<Option<usize>>,
+    low: Vec<usize>,
+    on_stack: Vec<bool>,
+    stack: Vec<usize>,
+    next: usize,
+    components: Vec<Vec<usize>>,
+}
+
+impl Tarjan<'_> {
+    fn visit(&mut self, v: usize) {
+        self.index[v] = Some(self.next);
+        self.low[v] = self.next;
+        self.next += 1;
+        self.stack.push(v);
+        self.on_stack[v] = true;
+
+        for &w in &self.succ[v] {
+            match self.index[w] {
+                None => {
+                    self.visit(w);
+                    self.low[v] = self.low[v].min(self.low[w]);
+                }
+                Some(iw) if self.on_stack[w] => {
+                    self.low[v] = self.low[v].min(iw);
+                }
+                _ => {}
+            }
+        }
+
+        if Some(self.low[v]) == self.index[v] {
+            let mut component = Vec::new();
+            while let Some(w) = self.stack.pop() {
+                self.on_stack[w] = false;
+                component.push(w);
+                if w == v {
+                    break;
+                }
+            }
+            self.components.push(component);
+        }
+    }
+}
+
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_fib_rule_reads_what_it_writes() {
+        let input = r#"
+    rule CalcFib "Calculate Fibonacci" {
+        when
+            Vibo.A == 0 || Vibo.B == 0
+        then
+            Vibo.A = 1;
+            Vibo.B = 1;
+    }
+    "#;
+        let rules = parse(input.to_string()).unwrap();
+        let graph = DependencyGraph::build(&rules);
+
+        let deps = &graph.rules[0];
+        assert_eq!(deps.reads.len(), 2);
+        assert_eq!(deps.writes.len(), 2);
+        assert_eq!(deps.self_dependencies().len(), 2);
+        assert_eq!(graph.self_loops(), vec!["CalcFib"]);
+        assert!(graph.cycles().is_empty());
+    }
+
+    #[test]
+    fn test_chain_and_cycle() {
+        let input = r#"
+    rule A { when X.a == 1 then X.b = X.a + 1; }
+    rule B { when X.b > 1 then X.c = X.b; }
+    rule C { when X.c > 1 then X.a = 1; }
+    rule D { when X.d == 1 then X.e = 1; }
+    "#;
+        let rules = parse(input.to_string()).unwrap();
+        let graph = DependencyGraph::build(&rules);
+
+        assert!(graph.self_loops().is_empty());
+        assert_eq!(graph.cycles(), vec![vec!["A", "B", "C"]]);
+
+        let dot = graph.to_dot();
+        assert!(dot.contains("r0 -> r1 [label=\"X.b\"];"));
+        assert!(!dot.contains("r3 ->"));
+
+        let json = graph.to_json();
+        assert!(json.contains("{\"from\":\"C\",\"to\":\"A\",\"fields\":[\"X.a\"]}"));
+        assert!(json.contains("\"cycles\":[[\"A\",\"B\",\"C\"]]"));
+    }
+}
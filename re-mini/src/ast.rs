@@ -1,5 +1,17 @@
-use crate::{context::DataContext, value::Value};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fmt,
+};
 
+use crate::{
+    analysis::condition_fields,
+    context::{DataContext, FactId},
+    functions,
+    lexer::{quote_ident, quote_path, quote_str},
+    value::Value,
+};
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum Expr {
     Literal(Value),
     FieldRef(String),
@@ -8,63 +20,479 @@ pub enum Expr {
         op: Op,
         right: Box<Expr>,
     },
+    /// A built-in function, see [`crate::functions`].
+    Call { name: String, args: Vec<Expr> },
+    /// An aggregate over a list, written as a method on it:
+    /// `Order.Items.sum(i => i.Price)`.
+    Aggregate {
+        func: Aggregate,
+        list: Box<Expr>,
+        lambda: Option<Lambda>,
+    },
+    /// The instances of a fact set, as a list of records of their fields.
+    Facts(FactSet),
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Aggregate {
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+    Any,
+    All,
+}
+
+impl Aggregate {
+    pub const ALL: [Aggregate; 7] =
+        [Aggregate::Count, Aggregate::Sum, Aggregate::Avg, Aggregate::Min, Aggregate::Max, Aggregate::Any, Aggregate::All];
+
+    pub fn from_name(name: &str) -> Option<Aggregate> {
+        Aggregate::ALL.into_iter().find(|a| a.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aggregate::Count => "count",
+            Aggregate::Sum => "sum",
+            Aggregate::Avg => "avg",
+            Aggregate::Min => "min",
+            Aggregate::Max => "max",
+            Aggregate::Any => "any",
+            Aggregate::All => "all",
+        }
+    }
+
+    /// `count`, `any` and `all` take a predicate; the others map each
+    /// item to a value.
+    pub fn takes_predicate(&self) -> bool {
+        matches!(self, Aggregate::Count | Aggregate::Any | Aggregate::All)
+    }
+
+    /// Checks that `lambda` is of the kind the aggregate takes.
+    pub fn check(&self, lambda: Option<&Lambda>) -> Result<(), String> {
+        match (self, lambda) {
+            (Aggregate::Any | Aggregate::All, None) => {
+                Err(format!("{}() needs a predicate, like {}(i => i.Price > 100)", self, self))
+            }
+            (_, Some(Lambda::Map(..))) if self.takes_predicate() => {
+                Err(format!("{}() takes a predicate, like {}(i => i.Price > 100)", self, self))
+            }
+            (_, Some(Lambda::Filter(..))) if !self.takes_predicate() => {
+                Err(format!("{}() takes a value, like {}(i => i.Price)", self, self))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A function of each item of a list, which the body names by `param`:
+/// `i => i.Price`, or `p => p > 100` for a list of plain values.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Lambda {
+    /// For `sum`, `avg`, `min` and `max`.
+    Map(String, Box<Expr>),
+    /// For `count`, `any` and `all`.
+    Filter(String, Box<Condition>),
+}
+
+impl Lambda {
+    pub fn param(&self) -> &str {
+        match self {
+            Lambda::Map(param, _) | Lambda::Filter(param, _) => param,
+        }
+    }
+}
+
+/// The instances of `fact_type` that meet `constraint`, as `exists` and
+/// aggregates read them: `Transaction(Amount > 100)`. Inside the
+/// constraint, the instance's fields are written `$.Field`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FactSet {
+    pub fact_type: String,
+    pub constraint: Option<Box<Condition>>,
+}
+
+/// The variable a fact set's constraint names its instance by.
+pub const FACT_SET_BINDING: &str = "$";
+
+impl FactSet {
+    pub(crate) fn matching(&self, ctx: &DataContext, locals: &[(&str, &Value)]) -> Result<Vec<FactId>, String> {
+        let mut ids = Vec::new();
+        for (id, _) in ctx.facts_of(&self.fact_type) {
+            ctx.bind(FACT_SET_BINDING, id);
+            let result = self.constraint.as_ref().map_or(Ok(true), |c| c.evaluate_in(ctx, locals));
+            ctx.unbind(1);
+            if result? {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum Op {
     Add,
+    Sub,
 }
 
 impl Expr {
     pub fn evaluate(&self, ctx: &DataContext) -> Result<Value, String> {
+        self.evaluate_in(ctx, &[])
+    }
+
+    // `locals` holds the items bound to the lambda parameters in scope,
+    // innermost last
+    fn evaluate_in(&self, ctx: &DataContext, locals: &[(&str, &Value)]) -> Result<Value, String> {
+        let Some(budget) = ctx.budget() else {
+            return self.eval(ctx, locals);
+        };
+        budget.charge()?;
+        let value = self.eval(ctx, locals)?;
+        budget.check_value(&value)?;
+        Ok(value)
+    }
+
+    fn eval(&self, ctx: &DataContext, locals: &[(&str, &Value)]) -> Result<Value, String> {
         match self {
             Expr::Literal(v) => Ok(v.clone()),
-            Expr::FieldRef(name) => ctx
-                .get(String::from(name))
-                .cloned()
-                .ok_or_else(|| format!("field {} not found", name)),
+            Expr::FieldRef(name) => {
+                let (var, field) = match name.split_once('.') {
+                    Some((var, field)) => (var, Some(field)),
+                    None => (name.as_str(), None),
+                };
+                let item = match locals.iter().rev().find(|(param, _)| *param == var) {
+                    Some((_, item)) => match (field, item) {
+                        (None, item) => Some(*item),
+                        (Some(field), Value::Record(fields)) => fields.get(field),
+                        _ => None,
+                    },
+                    None => ctx.get(String::from(name)),
+                };
+                item.cloned().ok_or_else(|| format!("field {} not found", name))
+            }
             Expr::BinOp { left, op, right } => {
-                let l = left.evaluate(ctx)?;
-                let r = right.evaluate(ctx)?;
+                let l = left.evaluate_in(ctx, locals)?;
+                let r = right.evaluate_in(ctx, locals)?;
                 match op {
                     Op::Add => l.add(&r),
+                    Op::Sub => l.sub(&r),
                 }
             }
+            Expr::Call { name, args } => {
+                let args = args.iter().map(|a| a.evaluate_in(ctx, locals)).collect::<Result<Vec<_>, _>>()?;
+                functions::call(name, &args, ctx)
+            }
+            Expr::Aggregate { func, list, lambda } => match list.evaluate_in(ctx, locals)? {
+                Value::List(items) => aggregate(*func, &items, lambda.as_ref(), ctx, locals),
+                other => Err(format!("{}() needs a list, got {:?}", func, other)),
+            },
+            Expr::Facts(set) => Ok(Value::List(
+                set.matching(ctx, locals)?
+                    .into_iter()
+                    .filter_map(|id| ctx.fact(id))
+                    .map(|fact| Value::Record(fact.fields.clone()))
+                    .collect(),
+            )),
+        }
+    }
+}
+
+fn aggregate(
+    func: Aggregate,
+    items: &[Value],
+    lambda: Option<&Lambda>,
+    ctx: &DataContext,
+    locals: &[(&str, &Value)],
+) -> Result<Value, String> {
+    func.check(lambda)?;
+    fn with<'a>(locals: &[(&'a str, &'a Value)], param: &'a str, item: &'a Value) -> Vec<(&'a str, &'a Value)> {
+        let mut locals = locals.to_vec();
+        locals.push((param, item));
+        locals
+    }
+
+    if func.takes_predicate() {
+        let mut held = 0;
+        for item in items {
+            let holds = match lambda {
+                Some(Lambda::Filter(param, cond)) => cond.evaluate_in(ctx, &with(locals, param, item))?,
+                _ => true,
+            };
+            match (func, holds) {
+                (Aggregate::Any, true) => return Ok(Value::Bool(true)),
+                (Aggregate::All, false) => return Ok(Value::Bool(false)),
+                _ => held += holds as i64,
+            }
+        }
+        return Ok(match func {
+            Aggregate::Any => Value::Bool(false),
+            Aggregate::All => Value::Bool(true),
+            _ => Value::Int(held),
+        });
+    }
+
+    let values = items
+        .iter()
+        .map(|item| match lambda {
+            Some(Lambda::Map(param, body)) => body.evaluate_in(ctx, &with(locals, param, item)),
+            _ => Ok(item.clone()),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut values = values.into_iter();
+    let Some(first) = values.next() else {
+        return match func {
+            Aggregate::Sum => Ok(Value::Int(0)),
+            _ => Err(format!("{}() of an empty list", func)),
+        };
+    };
+    match (func, &first) {
+        (Aggregate::Sum, Value::Int(_) | Value::Duration(_)) => values.try_fold(first, |sum, v| sum.add(&v)),
+        (Aggregate::Avg, Value::Int(_)) => {
+            let mut n = 1;
+            let sum = values.try_fold(first, |sum, v| {
+                n += 1;
+                sum.add(&v)
+            })?;
+            match sum {
+                Value::Int(sum) => Ok(Value::Int(sum / n)),
+                other => Err(format!("cannot average {:?}", other)),
+            }
         }
+        // a lone list or record has no order either
+        (Aggregate::Min | Aggregate::Max, Value::List(_) | Value::Record(_)) => Err(format!("cannot compare {:?}", first)),
+        (Aggregate::Min | Aggregate::Max, _) => values.try_fold(first, |best, v| {
+            let order = v.compare(&best)?;
+            let better = if func == Aggregate::Min { order.is_lt() } else { order.is_gt() };
+            Ok(if better { v } else { best })
+        }),
+        _ => Err(format!("cannot {} {:?}", if func == Aggregate::Sum { "sum" } else { "average" }, first)),
     }
 }
 
+#[derive(Clone, Debug, PartialEq)]
 pub enum CmpOp {
     Eq,
+    NotEq,
     Lt,
     Gt,
+    LtEq,
+    GtEq,
 }
 
+#[derive(Clone, Debug, PartialEq)]
 pub enum Condition {
     Compare { left: Expr, op: CmpOp, right: Expr },
     Or(Box<Condition>, Box<Condition>),
     And(Box<Condition>, Box<Condition>),
+    Not(Box<Condition>),
+    /// Matches an instance of `fact_type` in working memory, binding it to
+    /// `binding`. Inside `constraint`, the instance's fields are written
+    /// `binding.Field`. A pattern written without a variable gets a
+    /// `$`-prefixed one, which is not printed.
+    Pattern {
+        binding: String,
+        fact_type: String,
+        constraint: Option<Box<Condition>>,
+    },
+    /// Holds when the fact set has an instance: `exists Order(Total > 100)`.
+    /// `not exists` is its negation.
+    Exists(FactSet),
 }
 
 impl Condition {
     pub fn evaluate(&self, ctx: &DataContext) -> Result<bool, String> {
+        self.evaluate_in(ctx, &[])
+    }
+
+    fn evaluate_in(&self, ctx: &DataContext, locals: &[(&str, &Value)]) -> Result<bool, String> {
         match self {
             Condition::Compare { left, op, right } => {
-                let l = left.evaluate(ctx)?;
-                let r = right.evaluate(ctx)?;
+                let l = left.evaluate_in(ctx, locals)?;
+                let r = right.evaluate_in(ctx, locals)?;
                 match op {
-                    CmpOp::Eq => Ok(l.eq(&r)),
-                    CmpOp::Gt => Ok(l.gt(&r)),
-                    CmpOp::Lt => Ok(l.lt(&r)),
+                    CmpOp::Eq => Ok(l.equals(&r)),
+                    CmpOp::NotEq => Ok(!l.equals(&r)),
+                    CmpOp::Gt => Ok(l.compare(&r)?.is_gt()),
+                    CmpOp::Lt => Ok(l.compare(&r)?.is_lt()),
+                    CmpOp::GtEq => Ok(l.compare(&r)?.is_ge()),
+                    CmpOp::LtEq => Ok(l.compare(&r)?.is_le()),
+                }
+            }
+            Condition::And(a, b) => Ok(a.evaluate_in(ctx, locals)? && b.evaluate_in(ctx, locals)?),
+            Condition::Or(a, b) => Ok(a.evaluate_in(ctx, locals)? || b.evaluate_in(ctx, locals)?),
+            Condition::Not(c) => Ok(!c.evaluate_in(ctx, locals)?),
+            Condition::Pattern { binding, fact_type, constraint } => {
+                let holds = |c: &Option<Box<Condition>>| c.as_ref().map_or(Ok(true), |c| c.evaluate_in(ctx, locals));
+                if ctx.bound(binding).is_some() {
+                    return holds(constraint);
+                }
+                // unbound, the pattern asks whether any instance matches
+                for (id, _) in ctx.facts_of(fact_type) {
+                    ctx.bind(binding, id);
+                    let result = holds(constraint);
+                    ctx.unbind(1);
+                    if result? {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            Condition::Exists(set) => {
+                for (id, _) in ctx.facts_of(&set.fact_type) {
+                    ctx.bind(FACT_SET_BINDING, id);
+                    let result = set.constraint.as_ref().map_or(Ok(true), |c| c.evaluate_in(ctx, locals));
+                    ctx.unbind(1);
+                    if result? {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+        }
+    }
+
+    /// The operands of the `&&`s at the top of the condition, in order.
+    pub fn conjuncts(&self) -> Vec<&Condition> {
+        match self {
+            Condition::And(a, b) => {
+                let mut items = a.conjuncts();
+                items.extend(b.conjuncts());
+                items
+            }
+            _ => vec![self],
+        }
+    }
+}
+
+// Patterns only make sense as `&&` operands at the top of a condition,
+// each binding a new variable that only later patterns refer to.
+pub(crate) fn check_patterns(cond: &Condition) -> Result<(), String> {
+    let conjuncts = cond.conjuncts();
+    let variables: Vec<&str> = conjuncts
+        .iter()
+        .filter_map(|c| match c {
+            Condition::Pattern { binding, .. } => Some(binding.as_str()),
+            _ => None,
+        })
+        .collect();
+    let mut bound = HashSet::new();
+    for c in conjuncts {
+        let Condition::Pattern { binding, constraint, .. } = c else {
+            nested_pattern(c)?;
+            continue;
+        };
+        if !bound.insert(binding.as_str()) {
+            return Err(format!("variable {} is bound twice", binding));
+        }
+        if let Some(constraint) = constraint {
+            nested_pattern(constraint)?;
+            for name in condition_fields(constraint) {
+                let var = name.split('.').next().unwrap_or_default();
+                if variables.contains(&var) && !bound.contains(var) {
+                    return Err(format!("variable {} is used before the pattern that binds it", var));
                 }
             }
-            Condition::And(a, b) => Ok(a.evaluate(ctx)? && b.evaluate(ctx)?),
-            Condition::Or(a, b) => Ok(a.evaluate(ctx)? || b.evaluate(ctx)?),
         }
     }
+    Ok(())
+}
+
+fn nested_pattern(cond: &Condition) -> Result<(), String> {
+    match cond {
+        Condition::Compare { left, right, .. } => {
+            expr_patterns(left)?;
+            expr_patterns(right)
+        }
+        Condition::Exists(set) => set.constraint.as_deref().map_or(Ok(()), nested_pattern),
+        Condition::And(a, b) | Condition::Or(a, b) => {
+            nested_pattern(a)?;
+            nested_pattern(b)
+        }
+        Condition::Not(c) => nested_pattern(c),
+        Condition::Pattern { .. } => Err(format!("pattern {} can only be joined to the condition with a top-level &&", cond)),
+    }
+}
+
+fn condition_fact_sets<'a>(cond: &'a Condition, out: &mut Vec<&'a FactSet>) {
+    match cond {
+        Condition::Compare { left, right, .. } => {
+            expr_fact_sets(left, out);
+            expr_fact_sets(right, out);
+        }
+        Condition::And(a, b) | Condition::Or(a, b) => {
+            condition_fact_sets(a, out);
+            condition_fact_sets(b, out);
+        }
+        Condition::Not(c) => condition_fact_sets(c, out),
+        Condition::Pattern { constraint, .. } => {
+            if let Some(c) = constraint {
+                condition_fact_sets(c, out);
+            }
+        }
+        Condition::Exists(set) => fact_set_and_nested(set, out),
+    }
+}
+
+fn fact_set_and_nested<'a>(set: &'a FactSet, out: &mut Vec<&'a FactSet>) {
+    out.push(set);
+    if let Some(c) = &set.constraint {
+        condition_fact_sets(c, out);
+    }
+}
+
+fn expr_fact_sets<'a>(expr: &'a Expr, out: &mut Vec<&'a FactSet>) {
+    match expr {
+        Expr::Literal(_) | Expr::FieldRef(_) => {}
+        Expr::BinOp { left, right, .. } => {
+            expr_fact_sets(left, out);
+            expr_fact_sets(right, out);
+        }
+        Expr::Call { args, .. } => args.iter().for_each(|a| expr_fact_sets(a, out)),
+        Expr::Aggregate { list, lambda, .. } => {
+            expr_fact_sets(list, out);
+            match lambda {
+                Some(Lambda::Map(_, body)) => expr_fact_sets(body, out),
+                Some(Lambda::Filter(_, cond)) => condition_fact_sets(cond, out),
+                None => {}
+            }
+        }
+        Expr::Facts(set) => fact_set_and_nested(set, out),
+    }
 }
 
+// lambdas and fact sets may hold conditions, which can't bind either
+fn expr_patterns(expr: &Expr) -> Result<(), String> {
+    match expr {
+        Expr::Literal(_) | Expr::FieldRef(_) => Ok(()),
+        Expr::BinOp { left, right, .. } => {
+            expr_patterns(left)?;
+            expr_patterns(right)
+        }
+        Expr::Call { args, .. } => args.iter().try_for_each(expr_patterns),
+        Expr::Aggregate { list, lambda, .. } => {
+            expr_patterns(list)?;
+            match lambda {
+                Some(Lambda::Map(_, body)) => expr_patterns(body),
+                Some(Lambda::Filter(_, cond)) => nested_pattern(cond),
+                None => Ok(()),
+            }
+        }
+        Expr::Facts(set) => set.constraint.as_deref().map_or(Ok(()), nested_pattern),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum Action {
     Assign { field: String, expr: Expr },
+    /// Adds an instance to working memory.
+    Insert { fact_type: String, fields: Vec<(String, Expr)> },
+    /// Tells the engine the instance bound to the variable has changed.
+    Update(String),
+    /// Removes the instance bound to the variable from working memory.
+    Retract(String),
 }
 
 impl Action {
@@ -76,30 +504,493 @@ impl Action {
 
                 Ok(())
             }
+            Action::Insert { fact_type, fields } => {
+                let mut values = BTreeMap::new();
+                for (field, expr) in fields {
+                    values.insert(field.clone(), expr.evaluate(ctx)?);
+                }
+                ctx.insert_fact(fact_type, values);
+                Ok(())
+            }
+            Action::Update(binding) => {
+                let id = ctx.bound(binding).ok_or_else(|| format!("variable {} is not bound", binding))?;
+                ctx.update_fact(id);
+                Ok(())
+            }
+            Action::Retract(binding) => {
+                let id = ctx.bound(binding).ok_or_else(|| format!("variable {} is not bound", binding))?;
+                ctx.retract_fact(id);
+                Ok(())
+            }
+        }
+    }
+
+    /// The fact type the action adds, changes or removes instances of,
+    /// given the rule's pattern variables and their types.
+    pub fn fact_type<'a>(&'a self, vars: &[(&'a str, &'a str)]) -> Option<&'a str> {
+        match self {
+            Action::Assign { .. } => None,
+            Action::Insert { fact_type, .. } => Some(fact_type),
+            Action::Update(binding) | Action::Retract(binding) => {
+                vars.iter().find(|(name, _)| name == binding).map(|(_, fact_type)| *fact_type)
+            }
+        }
+    }
+}
+
+/// Checks that an action only refers to variables the rule's patterns
+/// bind, and that an insert sets each field once.
+pub(crate) fn check_action(action: &Action, variables: &[&str]) -> Result<(), String> {
+    match action {
+        Action::Assign { .. } => Ok(()),
+        Action::Insert { fields, .. } => {
+            for (i, (field, _)) in fields.iter().enumerate() {
+                if fields[..i].iter().any(|(f, _)| f == field) {
+                    return Err(format!("field {} is set twice", field));
+                }
+            }
+            Ok(())
+        }
+        Action::Update(binding) | Action::Retract(binding) => {
+            if binding.starts_with('$') || !variables.contains(&binding.as_str()) {
+                return Err(format!("variable {} is not bound by a pattern", binding));
+            }
+            Ok(())
         }
     }
 }
 
+/// Where a comment inside a rule, or after it, is printed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CommentPlace {
+    /// Before the condition.
+    When,
+    /// Before the action at this index, or after the last action when it
+    /// is the number of actions.
+    Action(usize),
+    /// After the rule's closing brace, for comments that end the file.
+    After,
+}
+
+/// Rules of which one fires per run: once a rule of the group has fired,
+/// the group's other rules are held back until the run ends.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ActivationGroup {
+    pub name: String,
+    /// Another rule of the group matching while one is on the agenda or
+    /// has fired is an error, rather than held back.
+    pub unique: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct Rule {
     pub name: String,
+    pub description: Option<String>,
+    /// Comments written before the rule, with their delimiters.
+    pub comments: Vec<String>,
+    /// Comments written inside the rule, or after the last rule of a file,
+    /// each with where it goes.
+    pub placed_comments: Vec<(CommentPlace, String)>,
     pub condition: Condition,
     pub actions: Vec<Action>,
+    /// Rules with higher salience fire first.
+    pub salience: i64,
+    /// The rule is not re-activated by changes made by its own actions.
+    pub no_loop: bool,
+    /// The rule fires at most once per engine run.
+    pub lock_on_active: bool,
+    pub activation_group: Option<ActivationGroup>,
 }
 
 impl Rule {
+    pub fn new(name: String, condition: Condition, actions: Vec<Action>) -> Rule {
+        Rule {
+            name,
+            description: None,
+            comments: Vec::new(),
+            placed_comments: Vec::new(),
+            condition,
+            actions,
+            salience: 0,
+            no_loop: false,
+            lock_on_active: false,
+            activation_group: None,
+        }
+    }
+
     pub fn evaluate(&self, ctx: &DataContext) -> Result<bool, String> {
-        self.condition.evaluate(ctx)
+        Ok(!self.matches(ctx)?.is_empty())
+    }
+
+    /// The variables and fact types of the condition's patterns, in order.
+    pub fn patterns(&self) -> Vec<(&str, &str)> {
+        self.condition
+            .conjuncts()
+            .into_iter()
+            .filter_map(|c| match c {
+                Condition::Pattern { binding, fact_type, .. } => Some((binding.as_str(), fact_type.as_str())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Every tuple of instances the condition matches, one instance per
+    /// pattern in order, found by joining the patterns left to right. A
+    /// rule without patterns matches once, with no instances, when its
+    /// condition holds.
+    pub fn matches(&self, ctx: &DataContext) -> Result<Vec<Vec<FactId>>, String> {
+        let (patterns, rest): (Vec<&Condition>, Vec<&Condition>) = self
+            .condition
+            .conjuncts()
+            .into_iter()
+            .partition(|c| matches!(c, Condition::Pattern { .. }));
+        if patterns.is_empty() {
+            return Ok(if self.condition.evaluate(ctx)? { vec![Vec::new()] } else { Vec::new() });
+        }
+        let mut tuples = Vec::new();
+        join(&patterns, &rest, ctx, &mut Vec::new(), &mut tuples)?;
+        Ok(tuples)
+    }
+
+    /// The types of the instances the condition's fact sets range over.
+    pub(crate) fn fact_set_types(&self) -> BTreeSet<&str> {
+        let mut sets = Vec::new();
+        condition_fact_sets(&self.condition, &mut sets);
+        sets.into_iter().map(|set| set.fact_type.as_str()).collect()
     }
 
+    /// The instances the condition's fact sets hold with the pattern
+    /// variables bound to `facts`. A fact set that can't be evaluated on
+    /// its own, inside a lambda, holds every instance of its type.
+    pub(crate) fn fact_set_instances(&self, ctx: &DataContext, facts: &[FactId]) -> BTreeSet<FactId> {
+        let mut sets = Vec::new();
+        condition_fact_sets(&self.condition, &mut sets);
+        let mut ids = BTreeSet::new();
+        if sets.is_empty() {
+            return ids;
+        }
+        for ((binding, _), id) in self.patterns().into_iter().zip(facts) {
+            ctx.bind(binding, *id);
+        }
+        for set in sets {
+            match set.matching(ctx, &[]) {
+                Ok(matching) => ids.extend(matching),
+                Err(_) => ids.extend(ctx.facts_of(&set.fact_type).map(|(id, _)| id)),
+            }
+        }
+        ctx.unbind(facts.len());
+        ids
+    }
+
+    /// Runs the actions with the pattern variables bound to `facts`, as
+    /// returned by [`Rule::matches`].
+    pub fn fire(&self, ctx: &mut DataContext, facts: &[FactId]) -> Result<(), String> {
+        for ((binding, _), id) in self.patterns().into_iter().zip(facts) {
+            ctx.bind(binding, *id);
+        }
+        let result = self.execute(ctx);
+        ctx.unbind(facts.len());
+        result
+    }
+
+    /// Runs all actions, or none of them: if an action fails, the writes
+    /// made by the earlier ones are undone.
     pub fn execute(&self, ctx: &mut DataContext) -> Result<(), String> {
-        for action in &self.actions {
-            action.execute(ctx)?;
+        let snapshot = ctx.snapshot();
+        ctx.set_writer(Some(self.name.clone()));
+        let result = self.actions.iter().try_for_each(|action| action.execute(ctx));
+        ctx.set_writer(None);
+
+        if let Err(e) = result {
+            ctx.restore(snapshot);
+            return Err(format!("rule {}: {}", self.name, e));
         }
 
         Ok(())
     }
 }
 
+// binds each instance that satisfies the first pattern, then joins the
+// rest; with every pattern bound, the other conditions decide
+fn join(
+    patterns: &[&Condition],
+    rest: &[&Condition],
+    ctx: &DataContext,
+    tuple: &mut Vec<FactId>,
+    out: &mut Vec<Vec<FactId>>,
+) -> Result<(), String> {
+    let Some((Condition::Pattern { binding, fact_type, constraint }, later)) = patterns.split_first() else {
+        for cond in rest {
+            if !cond.evaluate(ctx)? {
+                return Ok(());
+            }
+        }
+        out.push(tuple.clone());
+        return Ok(());
+    };
+    for (id, _) in ctx.facts_of(fact_type) {
+        ctx.bind(binding, id);
+        let result = match constraint.as_ref().map_or(Ok(true), |c| c.evaluate(ctx)) {
+            Ok(true) => {
+                tuple.push(id);
+                let result = join(later, rest, ctx, tuple, out);
+                tuple.pop();
+                result
+            }
+            other => other.map(|_| ()),
+        };
+        ctx.unbind(1);
+        result?;
+    }
+    Ok(())
+}
+
+// the constraint as written inside a pattern, with the fields of its own
+// instance named without the variable
+pub(crate) fn unbind_fields(cond: &Condition, binding: &str) -> Condition {
+    map_fields(cond, &|name| match name.split_once('.') {
+        Some((var, field)) if var == binding => field.to_string(),
+        _ => name.to_string(),
+    })
+}
+
+// the inverse of `unbind_fields`: bare names become fields of the
+// instance, except the parameters of the lambdas around the condition
+pub(crate) fn bind_fields(cond: &Condition, binding: &str, params: &[String]) -> Condition {
+    map_fields(cond, &|name| {
+        if name.contains('.') || params.iter().any(|p| p == name) {
+            name.to_string()
+        } else {
+            format!("{}.{}", binding, name)
+        }
+    })
+}
+
+fn map_fields(cond: &Condition, f: &dyn Fn(&str) -> String) -> Condition {
+    match cond {
+        Condition::Compare { left, op, right } => Condition::Compare {
+            left: map_expr_fields(left, f),
+            op: op.clone(),
+            right: map_expr_fields(right, f),
+        },
+        Condition::And(a, b) => Condition::And(Box::new(map_fields(a, f)), Box::new(map_fields(b, f))),
+        Condition::Or(a, b) => Condition::Or(Box::new(map_fields(a, f)), Box::new(map_fields(b, f))),
+        Condition::Not(c) => Condition::Not(Box::new(map_fields(c, f))),
+        // a fact set's constraint names fields its own way
+        Condition::Pattern { .. } | Condition::Exists(_) => cond.clone(),
+    }
+}
+
+fn map_expr_fields(expr: &Expr, f: &dyn Fn(&str) -> String) -> Expr {
+    match expr {
+        Expr::FieldRef(name) => Expr::FieldRef(f(name)),
+        Expr::Literal(_) => expr.clone(),
+        Expr::BinOp { left, op, right } => Expr::BinOp {
+            left: Box::new(map_expr_fields(left, f)),
+            op: op.clone(),
+            right: Box::new(map_expr_fields(right, f)),
+        },
+        Expr::Call { name, args } => Expr::Call {
+            name: name.clone(),
+            args: args.iter().map(|a| map_expr_fields(a, f)).collect(),
+        },
+        Expr::Aggregate { func, list, lambda } => {
+            // the lambda's parameter hides any field of the same name
+            let lambda = lambda.as_ref().map(|lambda| {
+                let param = lambda.param();
+                let f = |name: &str| {
+                    if name.split('.').next() == Some(param) {
+                        name.to_string()
+                    } else {
+                        f(name)
+                    }
+                };
+                match lambda {
+                    Lambda::Map(param, body) => Lambda::Map(param.clone(), Box::new(map_expr_fields(body, &f))),
+                    Lambda::Filter(param, cond) => Lambda::Filter(param.clone(), Box::new(map_fields(cond, &f))),
+                }
+            });
+            Expr::Aggregate {
+                func: *func,
+                list: Box::new(map_expr_fields(list, f)),
+                lambda,
+            }
+        }
+        Expr::Facts(_) => expr.clone(),
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Op::Add => write!(f, "+"),
+            Op::Sub => write!(f, "-"),
+        }
+    }
+}
+
+// operators are left-associative, so only a compound right operand needs parentheses
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Literal(v) => write!(f, "{}", v),
+            Expr::FieldRef(name) => write!(f, "{}", quote_path(name)),
+            Expr::BinOp { left, op, right } => match right.as_ref() {
+                Expr::BinOp { .. } => write!(f, "{} {} ({})", left, op, right),
+                _ => write!(f, "{} {} {}", left, op, right),
+            },
+            Expr::Call { name, args } => {
+                let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+                write!(f, "{}({})", name, args.join(", "))
+            }
+            Expr::Aggregate { func, list, lambda } => {
+                match list.as_ref() {
+                    Expr::BinOp { .. } => write!(f, "({}).{}(", list, func)?,
+                    _ => write!(f, "{}.{}(", list, func)?,
+                }
+                match lambda {
+                    Some(Lambda::Map(param, body)) => write!(f, "{} => {}", quote_ident(param), body)?,
+                    Some(Lambda::Filter(param, cond)) => write!(f, "{} => {}", quote_ident(param), cond)?,
+                    None => {}
+                }
+                write!(f, ")")
+            }
+            Expr::Facts(set) => write!(f, "{}", set),
+        }
+    }
+}
+
+impl fmt::Display for Aggregate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl fmt::Display for FactSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.constraint {
+            Some(c) => write!(f, "{}({})", quote_ident(&self.fact_type), unbind_fields(c, FACT_SET_BINDING)),
+            None => write!(f, "{}()", quote_ident(&self.fact_type)),
+        }
+    }
+}
+
+impl fmt::Display for CmpOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            CmpOp::Eq => "==",
+            CmpOp::NotEq => "!=",
+            CmpOp::Lt => "<",
+            CmpOp::Gt => ">",
+            CmpOp::LtEq => "<=",
+            CmpOp::GtEq => ">=",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+// `&&` and `||` share one precedence level, left to right
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            // `any` and `all` need no `== true`
+            Condition::Compare {
+                left: left @ Expr::Aggregate { func: Aggregate::Any | Aggregate::All, .. },
+                op: CmpOp::Eq,
+                right: Expr::Literal(Value::Bool(true)),
+            } => write!(f, "{}", left),
+            Condition::Compare { left, op, right } => write!(f, "{} {} {}", left, op, right),
+            Condition::And(a, b) | Condition::Or(a, b) => {
+                let op = if matches!(self, Condition::And(..)) { "&&" } else { "||" };
+                match b.as_ref() {
+                    Condition::And(..) | Condition::Or(..) => write!(f, "{} {} ({})", a, op, b),
+                    _ => write!(f, "{} {} {}", a, op, b),
+                }
+            }
+            Condition::Not(c) => match c.as_ref() {
+                Condition::Not(_) => write!(f, "!{}", c),
+                Condition::Exists(_) => write!(f, "not {}", c),
+                _ => write!(f, "!({})", c),
+            },
+            Condition::Pattern { binding, fact_type, constraint } => {
+                if !binding.starts_with('$') {
+                    write!(f, "{}: ", quote_ident(binding))?;
+                }
+                match constraint {
+                    Some(c) => write!(f, "{}({})", quote_ident(fact_type), unbind_fields(c, binding)),
+                    None => write!(f, "{}()", quote_ident(fact_type)),
+                }
+            }
+            Condition::Exists(set) => write!(f, "exists {}", set),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Assign { field, expr } => write!(f, "{} = {};", quote_path(field), expr),
+            Action::Insert { fact_type, fields } => {
+                write!(f, "insert({} {{", quote_ident(fact_type))?;
+                for (i, (field, expr)) in fields.iter().enumerate() {
+                    write!(f, "{} {}: {}", if i == 0 { "" } else { "," }, quote_ident(field), expr)?;
+                }
+                write!(f, "{}}});", if fields.is_empty() { "" } else { " " })
+            }
+            Action::Update(binding) => write!(f, "update({});", quote_ident(binding)),
+            Action::Retract(binding) => write!(f, "retract({});", quote_ident(binding)),
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for comment in &self.comments {
+            writeln!(f, "{}", comment)?;
+        }
+        write!(f, "rule {}", quote_ident(&self.name))?;
+        if let Some(description) = &self.description {
+            write!(f, " {}", quote_str(description))?;
+        }
+        if self.salience != 0 {
+            write!(f, " salience {}", self.salience)?;
+        }
+        if self.no_loop {
+            write!(f, " no-loop")?;
+        }
+        if self.lock_on_active {
+            write!(f, " lock-on-active")?;
+        }
+        if let Some(group) = &self.activation_group {
+            write!(f, " activation-group {}", quote_str(&group.name))?;
+            if group.unique {
+                write!(f, " unique")?;
+            }
+        }
+        let comments = |f: &mut fmt::Formatter<'_>, place: CommentPlace| -> fmt::Result {
+            for (_, comment) in self.placed_comments.iter().filter(|(p, _)| *p == place) {
+                writeln!(f, "        {}", comment)?;
+            }
+            Ok(())
+        };
+        writeln!(f, " {{")?;
+        writeln!(f, "    when")?;
+        comments(f, CommentPlace::When)?;
+        writeln!(f, "        {}", self.condition)?;
+        writeln!(f, "    then")?;
+        for (i, action) in self.actions.iter().enumerate() {
+            comments(f, CommentPlace::Action(i))?;
+            writeln!(f, "        {}", action)?;
+        }
+        comments(f, CommentPlace::Action(self.actions.len()))?;
+        write!(f, "}}")?;
+        for (_, comment) in self.placed_comments.iter().filter(|(p, _)| *p == CommentPlace::After) {
+            write!(f, "\n{}", comment)?;
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*; // means: import all from parent module (which is `ast`)
@@ -122,6 +1013,7 @@ mod tests {
     // Example
     // rule: "when A == 3, set C = A + B"
     #[test]
+    #[allow(clippy::vec_init_then_push, clippy::bool_assert_comparison)]
     fn test_rule_evaluate_and_execute() {
         let mut ctx = DataContext::new();
         ctx.set("A".into(), Value::Int(3));
@@ -137,19 +1029,105 @@ mod tests {
             },
         });
 
-        let rule = Rule {
-            name: String::from("add_rule"),
-            condition: Condition::Compare {
+        let rule = Rule::new(
+            String::from("add_rule"),
+            Condition::Compare {
                 left: Expr::FieldRef("A".into()),
                 op: CmpOp::Eq,
                 right: Expr::Literal(Value::Int(3)),
             },
-            actions: actions,
-        };
+            actions,
+        );
 
         assert_eq!(rule.evaluate(&ctx).unwrap(), true);
         rule.execute(&mut ctx).unwrap();
 
         assert_eq!(ctx.get("C".into()), Some(&Value::Int(8)));
     }
+
+    #[test]
+    fn test_rule_execute_is_atomic() {
+        let mut ctx = DataContext::new();
+        ctx.set("A".into(), Value::Int(3));
+
+        let actions = vec![
+            Action::Assign {
+                field: "A".into(),
+                expr: Expr::Literal(Value::Int(4)),
+            },
+            Action::Assign {
+                field: "C".into(),
+                expr: Expr::FieldRef("Missing".into()),
+            },
+        ];
+        let rule = Rule::new(
+            String::from("partial_rule"),
+            Condition::Compare {
+                left: Expr::FieldRef("A".into()),
+                op: CmpOp::Eq,
+                right: Expr::Literal(Value::Int(3)),
+            },
+            actions,
+        );
+
+        let err = rule.execute(&mut ctx).unwrap_err();
+        assert_eq!(err, "rule partial_rule: field Missing not found");
+        assert_eq!(ctx.get("A".into()), Some(&Value::Int(3)));
+        assert_eq!(ctx.get("C".into()), None);
+    }
+
+    #[test]
+    fn test_date_arithmetic() {
+        use crate::parser::parse_expr;
+
+        let mut ctx = DataContext::new();
+        ctx.set("Order.Placed".into(), Value::Str("2024-03-30".into()));
+        ctx.set_now(Some(crate::time::DateTime::parse("2024-04-10T12:00:00+02:00").unwrap()));
+
+        let eval = |src: &str| parse_expr(src.to_string()).unwrap().evaluate(&ctx).map(|v| v.to_string());
+        assert_eq!(eval("date(Order.Placed) + 30d").unwrap(), "d\"2024-04-29\"");
+        assert_eq!(eval("weekday(date(Order.Placed))").unwrap(), "6");
+        assert_eq!(eval("today() - date(Order.Placed)").unwrap(), "11d");
+        assert_eq!(eval("now() - dt\"2024-04-09T09:30:00Z\"").unwrap(), "1d30m");
+        assert_eq!(eval("dt\"2024-01-31T23:00:00Z\" + 2h - -1d").unwrap(), "dt\"2024-02-02T01:00:00Z\"");
+        assert_eq!(eval("year(now()) - 1").unwrap(), "2023");
+        assert_eq!(
+            eval("d\"2024-01-01\" + 12h").unwrap_err(),
+            "cannot add 12h to date 2024-01-01: not a whole number of days"
+        );
+        assert!(eval("d\"2024-01-01\" - 1").is_err());
+    }
+
+    #[test]
+    fn test_dates_compare_with_datetimes_at_midnight_utc() {
+        use crate::parser::parse_condition;
+
+        let ctx = DataContext::new();
+        let eval = |src: &str| parse_condition(src.to_string()).unwrap().evaluate(&ctx);
+        assert_eq!(eval("d\"2030-01-01\" < dt\"2020-01-01T00:00:00Z\""), Ok(false));
+        assert_eq!(eval("d\"2030-01-01\" > dt\"2020-01-01T00:00:00Z\""), Ok(true));
+        assert_eq!(eval("dt\"2020-01-01T00:00:00Z\" < d\"2030-01-01\""), Ok(true));
+        assert_eq!(eval("d\"2024-01-01\" == dt\"2024-01-01T00:00:00Z\""), Ok(true));
+        assert_eq!(eval("d\"2024-01-01\" <= dt\"2024-01-01T00:00:00Z\""), Ok(true));
+        // 2023-12-31T23:00:00Z is before midnight UTC
+        assert_eq!(eval("dt\"2024-01-01T01:00:00+02:00\" < d\"2024-01-01\""), Ok(true));
+        assert_eq!(eval("d\"2024-01-01\" != dt\"2024-01-01T01:00:00+02:00\""), Ok(true));
+        assert!(eval("d\"2024-01-01\" < 1d").is_err());
+    }
+
+    #[test]
+    fn test_compare_needs_matching_types() {
+        use crate::parser::parse_condition;
+
+        let mut ctx = DataContext::new();
+        ctx.set("Order.Items".into(), Value::List(vec![Value::Int(1)]));
+        let eval = |src: &str| parse_condition(src.to_string()).unwrap().evaluate(&ctx);
+        assert_eq!(eval("\"a\" < \"b\" && false < true && 1h <= 60m"), Ok(true));
+        assert_eq!(eval("1 < \"a\""), Err("cannot compare Int(1) and Str(\"a\")".into()));
+        assert_eq!(eval("true > 5"), Err("cannot compare Bool(true) and Int(5)".into()));
+        assert_eq!(eval("Order.Items >= Order.Items"), Err("cannot compare List([Int(1)]) and List([Int(1)])".into()));
+        assert_eq!(eval("Order.Items.max(i => i) > 0"), Ok(true));
+        // equality still holds or fails across types
+        assert_eq!(eval("1 != \"1\""), Ok(true));
+    }
 }
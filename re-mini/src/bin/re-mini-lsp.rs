@@ -0,0 +1,26 @@
+use std::io::{self, BufReader};
+
+use re_mini::lsp::{read_message, write_message, Server};
+
+fn main() {
+    let mut server = Server::new();
+    let mut input = BufReader::new(io::stdin());
+    let mut output = io::stdout();
+
+    while !server.exit {
+        let msg = match read_message(&mut input) {
+            Ok(Some(msg)) => msg,
+            Ok(None) => break,
+            Err(e) => {
+                eprintln!("re-mini-lsp: {}", e);
+                continue;
+            }
+        };
+        for reply in server.handle(&msg) {
+            if let Err(e) = write_message(&mut output, &reply) {
+                eprintln!("re-mini-lsp: {}", e);
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,28 @@
+use std::io::{self, BufRead, Write};
+
+use re_mini::repl::Repl;
+
+fn main() {
+    let mut repl = Repl::new();
+    for path in std::env::args().skip(1) {
+        match repl.eval_line(&format!("load {}", path)) {
+            Ok(out) => println!("{}", out),
+            Err(e) => eprintln!("error: {}", e),
+        }
+    }
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    while !repl.done {
+        print!("> ");
+        io::stdout().flush().ok();
+        let Some(Ok(line)) = lines.next() else {
+            break;
+        };
+        match repl.eval_line(&line) {
+            Ok(out) if out.is_empty() => {}
+            Ok(out) => println!("{}", out),
+            Err(e) => eprintln!("error: {}", e),
+        }
+    }
+}
@@ -0,0 +1,468 @@
+//! Subcommands of the `re-mini` binary. Each takes file contents and
+//! returns the text to print, so they can be tested without touching disk.
+
+use std::{collections::HashSet, fs, path::Path};
+
+use crate::{
+    analysis::RuleDependencies,
+    ast::Rule,
+    context::{ChangeKind, DataContext},
+    coverage::Coverage,
+    engine::RuleEngine,
+    json::{context_from_json, context_to_json, Json},
+    knowledge_base::KnowledgeBase,
+    lexer::{lex, line_col, Token},
+    parser::{parse, parse_recovering},
+    rule_json::{parse_json_rules, rules_to_json},
+    testing::{report, TestSuite},
+};
+
+pub const USAGE: &str = "\
+usage:
+    re-mini run <rules.grl> --facts <input.json>
+    re-mini check <rules.grl>
+    re-mini fmt <rules.grl>
+    re-mini explain <rules.grl> --facts <input.json>
+    re-mini test <rules.grl> --tests <tests.json>
+    re-mini coverage <rules.grl> --facts <input.json>... [--format text|lcov]
+    re-mini profile <rules.grl> --facts <input.json>... [--format table|folded]
+    re-mini compile <rules.grl> [--out <rules.kb>]
+    re-mini export <rules.grl> [--format json|yaml]
+    re-mini import <rules.json|rules.yaml>";
+
+pub fn main(args: &[String]) -> Result<String, String> {
+    let Some((command, args)) = args.split_first() else {
+        return Err(USAGE.into());
+    };
+
+    match command.as_str() {
+        "run" => run(&read_rules(args)?, &read_facts(args)?),
+        "check" => check(&read_rules(args)?),
+        "fmt" => format(&read_rules(args)?),
+        "explain" => explain(&read_rules(args)?, &read_facts(args)?),
+        "test" => test(&read_rules(args)?, &read_tests(args)?),
+        "coverage" => {
+            let facts = read_all_facts(args)?;
+            match option(args, "--format")? {
+                None | Some("text") => coverage(&read_rules(args)?, &facts, None),
+                Some("lcov") => coverage(&read_rules(args)?, &facts, Some(rules_path(args)?)),
+                Some(other) => Err(format!("unknown format '{}'", other)),
+            }
+        }
+        "profile" => {
+            let facts = read_all_facts(args)?;
+            match option(args, "--format")? {
+                None | Some("table") => profile(&read_rules(args)?, &facts, false),
+                Some("folded") => profile(&read_rules(args)?, &facts, true),
+                Some(other) => Err(format!("unknown format '{}'", other)),
+            }
+        }
+        "compile" => {
+            let path = rules_path(args)?;
+            let out = match option(args, "--out")? {
+                Some(out) => out.to_string(),
+                None => Path::new(path).with_extension("kb").display().to_string(),
+            };
+            let name = Path::new(path).file_stem().map(|s| s.to_string_lossy()).unwrap_or_default();
+            let bytes = compile(&name, &read_rules(args)?)?;
+            fs::write(&out, bytes).map_err(|e| format!("{}: {}", out, e))?;
+            Ok(format!("compiled {} to {}\n", path, out))
+        }
+        "export" => export(&read_rules(args)?, option(args, "--format")?.unwrap_or("json")),
+        "import" => {
+            let path = rules_path(args)?;
+            let yaml = path.ends_with(".yaml") || path.ends_with(".yml");
+            import(&read_rules(args)?, if yaml { "yaml" } else { "json" })
+        }
+        "help" | "--help" | "-h" => Ok(format!("{}\n", USAGE)),
+        other => Err(format!("unknown command '{}'\n{}", other, USAGE)),
+    }
+}
+
+fn read_file(path: &str) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))
+}
+
+// the first argument that is neither an option nor an option's value
+fn rules_path(args: &[String]) -> Result<&str, String> {
+    let mut i = 0;
+    while i < args.len() {
+        if args[i].starts_with("--") {
+            i += 2;
+            continue;
+        }
+        return Ok(&args[i]);
+    }
+    Err(format!("missing rules file\n{}", USAGE))
+}
+
+fn read_rules(args: &[String]) -> Result<String, String> {
+    read_file(rules_path(args)?)
+}
+
+fn option<'a>(args: &'a [String], name: &str) -> Result<Option<&'a str>, String> {
+    match args.iter().position(|a| a == name) {
+        Some(i) => match args.get(i + 1) {
+            Some(value) => Ok(Some(value)),
+            None => Err(format!("{} needs a value", name)),
+        },
+        None => Ok(None),
+    }
+}
+
+// every `--facts` file, in order
+fn read_all_facts(args: &[String]) -> Result<Vec<String>, String> {
+    let mut facts = Vec::new();
+    for (i, arg) in args.iter().enumerate() {
+        if arg == "--facts" {
+            facts.push(read_file(args.get(i + 1).ok_or("--facts needs a file")?)?);
+        }
+    }
+    if facts.is_empty() {
+        return Err(format!("missing --facts\n{}", USAGE));
+    }
+    Ok(facts)
+}
+
+fn read_facts(args: &[String]) -> Result<String, String> {
+    match args.iter().position(|a| a == "--facts") {
+        Some(i) => read_file(args.get(i + 1).ok_or("--facts needs a file")?),
+        None => Err(format!("missing --facts\n{}", USAGE)),
+    }
+}
+
+fn read_tests(args: &[String]) -> Result<String, String> {
+    match args.iter().position(|a| a == "--tests") {
+        Some(i) => read_file(args.get(i + 1).ok_or("--tests needs a file")?),
+        None => Err(format!("missing --tests\n{}", USAGE)),
+    }
+}
+
+fn load(source: &str, facts: &str) -> Result<(Vec<Rule>, DataContext), String> {
+    let rules = parse(source.to_string())?;
+    let facts = Json::parse(facts).map_err(|e| format!("facts: {}", e))?;
+    let ctx = context_from_json(&facts)?;
+    Ok((rules, ctx))
+}
+
+/// Runs the rules and prints the resulting facts as JSON.
+pub fn run(source: &str, facts: &str) -> Result<String, String> {
+    let (rules, mut ctx) = load(source, facts)?;
+    RuleEngine::new().execute(&rules, &mut ctx)?;
+    Ok(format!("{}\n", context_to_json(&ctx)?.pretty()))
+}
+
+/// Runs the scenarios in a test file against the rules. Any failure makes
+/// this an error, so the exit status reflects the outcome.
+pub fn test(source: &str, tests: &str) -> Result<String, String> {
+    let rules = parse(source.to_string())?;
+    let suite = TestSuite::from_json(tests).map_err(|e| format!("tests: {}", e))?;
+    let results = suite.run(&rules);
+    let out = report(&results);
+    if results.iter().all(|r| r.passed()) {
+        Ok(out)
+    } else {
+        Err(out.trim_end().to_string())
+    }
+}
+
+/// Runs the rules on each facts document and reports rule and branch
+/// coverage: as text, or as LCOV for the rules file at `lcov_path`.
+pub fn coverage(source: &str, facts: &[String], lcov_path: Option<&str>) -> Result<String, String> {
+    let rules = parse(source.to_string())?;
+    let mut coverage = Coverage::new(&rules);
+    let mut engine = RuleEngine::new();
+    for (i, facts) in facts.iter().enumerate() {
+        let facts = Json::parse(facts).map_err(|e| format!("facts {}: {}", i + 1, e))?;
+        let ctx = context_from_json(&facts)?;
+        coverage.record(&rules, ctx, &mut engine).map_err(|e| format!("facts {}: {}", i + 1, e))?;
+    }
+    match lcov_path {
+        Some(path) => coverage.lcov(path, source),
+        None => Ok(coverage.text_report()),
+    }
+}
+
+/// Runs the rules on each facts document with profiling on, and prints
+/// the timings as a table or as folded stacks.
+pub fn profile(source: &str, facts: &[String], folded: bool) -> Result<String, String> {
+    let rules = parse(source.to_string())?;
+    let mut engine = RuleEngine::new();
+    engine.set_profiling(true);
+    for (i, facts) in facts.iter().enumerate() {
+        let facts = Json::parse(facts).map_err(|e| format!("facts {}: {}", i + 1, e))?;
+        let mut ctx = context_from_json(&facts)?;
+        engine.execute(&rules, &mut ctx).map_err(|e| format!("facts {}: {}", i + 1, e))?;
+    }
+    let profile = engine.profile().unwrap_or_default();
+    Ok(if folded { profile.folded() } else { profile.table() })
+}
+
+/// Parses the rules into a knowledge base cache, which services load
+/// with [`KnowledgeBase::load`] instead of reparsing.
+pub fn compile(name: &str, source: &str) -> Result<Vec<u8>, String> {
+    Ok(KnowledgeBase::parse(name, source)?.to_bytes())
+}
+
+/// Prints GRL rules as a JSON (or, with the `yaml` feature, YAML) rules
+/// document.
+pub fn export(source: &str, format: &str) -> Result<String, String> {
+    let rules = parse(source.to_string())?;
+    match format {
+        "json" => Ok(format!("{}\n", rules_to_json(&rules).pretty())),
+        #[cfg(feature = "yaml")]
+        "yaml" => Ok(crate::rule_json::rules_to_yaml(&rules)),
+        other => Err(format!("unknown format '{}'", other)),
+    }
+}
+
+/// Prints the rules of a JSON or YAML rules document as GRL.
+pub fn import(document: &str, format: &str) -> Result<String, String> {
+    let rules = match format {
+        "json" => parse_json_rules(document)?,
+        #[cfg(feature = "yaml")]
+        "yaml" => crate::rule_json::parse_yaml_rules(document)?,
+        other => return Err(format!("unknown format '{}'", other)),
+    };
+    let rules: Vec<String> = rules.iter().map(|r| r.to_string()).collect();
+    Ok(format!("{}\n", rules.join("\n\n")))
+}
+
+/// Parses and validates the rules: duplicate names are errors, rules that
+/// may retrigger themselves are warnings.
+pub fn check(source: &str) -> Result<String, String> {
+    let (rules, diagnostics) = parse_recovering(source.to_string());
+
+    let mut seen = HashSet::new();
+    let mut errors = Vec::new();
+    for d in diagnostics {
+        let (line, col) = line_col(source, d.span.start);
+        errors.push(format!("error: {}:{}: {}", line + 1, col + 1, d.message));
+    }
+    for rule in &rules {
+        if !seen.insert(rule.name.as_str()) {
+            errors.push(format!("error: duplicate rule name {}", rule.name));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors.join("\n"));
+    }
+
+    let mut out = String::new();
+    for rule in &rules {
+        let deps = RuleDependencies::of(rule);
+        let fields = deps.self_dependencies();
+        if !rule.no_loop && !rule.lock_on_active && !fields.is_empty() {
+            let fields: Vec<&str> = fields.iter().map(|f| f.as_str()).collect();
+            out.push_str(&format!(
+                "warning: rule {} writes fields it reads ({}); consider no-loop\n",
+                rule.name,
+                fields.join(", ")
+            ));
+        }
+    }
+    out.push_str(&format!("ok: {} rules\n", rules.len()));
+    Ok(out)
+}
+
+/// Prints the rules in canonical form.
+pub fn format(source: &str) -> Result<String, String> {
+    let rules = parse(source.to_string())?;
+    if rules.is_empty() {
+        // with no rule to carry them, the comments are all there is
+        let tokens = lex(source).map_err(|e| e.message)?;
+        let comments: Vec<String> = tokens
+            .into_iter()
+            .filter_map(|(token, _)| match token {
+                Token::Comment(text) => Some(text),
+                _ => None,
+            })
+            .collect();
+        return Ok(format!("{}\n", comments.join("\n")));
+    }
+    let rules: Vec<String> = rules.iter().map(|r| r.to_string()).collect();
+    Ok(format!("{}\n", rules.join("\n\n")))
+}
+
+/// Runs the rules, printing each rule as it fires and the facts it changed.
+pub fn explain(source: &str, facts: &str) -> Result<String, String> {
+    let (rules, mut ctx) = load(source, facts)?;
+    let mut engine = RuleEngine::new();
+    let mut out = String::new();
+
+    loop {
+        ctx.checkpoint();
+        let fired = match engine.step(&rules, &mut ctx) {
+            Ok(Some(i)) => i,
+            Ok(None) => break,
+            Err(e) => {
+                out.push_str(&format!("error: {}", e));
+                return Err(out);
+            }
+        };
+
+        out.push_str(&format!("cycle {}: {}\n", engine.cycles(), fired.describe(&rules, &ctx)));
+        for change in ctx.diff() {
+            let show = |v: Option<crate::value::Value>| match v {
+                Some(v) => v.to_string(),
+                None => "(unset)".to_string(),
+            };
+            let kind = match change.kind {
+                ChangeKind::Inserted => "inserted",
+                ChangeKind::Modified => "modified",
+                ChangeKind::Removed => "removed",
+            };
+            out.push_str(&format!(
+                "    {} {}: {} -> {}\n",
+                kind,
+                change.path,
+                show(change.old),
+                show(change.new)
+            ));
+        }
+    }
+
+    out.push_str(&format!("{} rule(s) fired\n", engine.cycles()));
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIB: &str = r#"
+    rule CalcFib "Calculate Fibonacci" {
+        when
+            Vibo.A == 0 || Vibo.B == 0
+        then
+            Vibo.A = 1;
+            Vibo.B = 1;
+    }
+    "#;
+
+    #[test]
+    fn test_run() {
+        let out = run(FIB, r#"{"Vibo": {"A": 0, "B": 1}}"#).unwrap();
+        assert_eq!(Json::parse(&out).unwrap().to_string(), r#"{"Vibo":{"A":1,"B":1}}"#);
+    }
+
+    #[test]
+    fn test_check() {
+        let out = check(FIB).unwrap();
+        assert_eq!(
+            out,
+            "warning: rule CalcFib writes fields it reads (Vibo.A, Vibo.B); consider no-loop\nok: 1 rules\n"
+        );
+
+        let dup = "rule A { when X.a == 1 then X.b = 1; } rule A { when X.a == 2 then X.b = 2; }";
+        assert_eq!(check(dup).unwrap_err(), "error: duplicate rule name A");
+        assert!(check("rule { }").is_err());
+
+        let typos = "rule A { when X.a == then X.b = 1; }\nrule B { when X.a == 1 then X.b = 1 }\nrule C { when X.a == 1 then X.b = 1; }\nrule D { when X.a ~ 1 then X.b = 1; }";
+        assert_eq!(
+            check(typos).unwrap_err(),
+            "error: 1:22: unexpected token in atom Some(Then)\nerror: 2:37: expected ';'\nerror: 4:19: unexpected char '~' at pos 132"
+        );
+    }
+
+    #[test]
+    fn test_fmt() {
+        let out = format("rule R  salience 3 {when X.a==1&&(X.b==2||X.c==3) then X.d=X.a+(X.b+1);}").unwrap();
+        assert_eq!(
+            out,
+            "rule R salience 3 {\n    when\n        X.a == 1 && (X.b == 2 || X.c == 3)\n    then\n        X.d = X.a + (X.b + 1);\n}\n"
+        );
+        assert_eq!(format(&out).unwrap(), out);
+
+        let out = format("// keep me\nrule R { when X.a == 1 then X.b = 2; }").unwrap();
+        assert!(out.starts_with("// keep me\nrule R {\n"));
+
+        let out = format("rule R { when X.a == 1 /* one */ then X.b = 2; }\nrule S { when X.a == 2 then X.b = 3; }\n// trailing note\n").unwrap();
+        assert_eq!(
+            out,
+            "rule R {\n    when\n        /* one */\n        X.a == 1\n    then\n        X.b = 2;\n}\n\nrule S {\n    when\n        X.a == 2\n    then\n        X.b = 3;\n}\n// trailing note\n"
+        );
+        assert_eq!(format(&out).unwrap(), out);
+
+        // a file of only comments keeps them
+        let out = format("// nothing yet\n\n/* rules go\n   here */\n").unwrap();
+        assert_eq!(out, "// nothing yet\n/* rules go\n   here */\n");
+        assert_eq!(format(&out).unwrap(), out);
+    }
+
+    #[test]
+    fn test_explain() {
+        let out = explain(FIB, r#"{"Vibo": {"A": 0, "B": 1}}"#).unwrap();
+        assert_eq!(out, "cycle 1: CalcFib\n    modified Vibo.A: 0 -> 1\n1 rule(s) fired\n");
+    }
+
+    #[test]
+    fn test_test() {
+        let tests = r#"{"scenarios": [
+            {"name": "start", "facts": {"Vibo": {"A": 0, "B": 0}}, "expect": {"Vibo": {"A": 1, "B": 1}}}
+        ]}"#;
+        assert_eq!(test(FIB, tests).unwrap(), "PASS start\n1 passed, 0 failed\n");
+
+        let tests = tests.replace("\"A\": 1", "\"A\": 2");
+        assert_eq!(
+            test(FIB, &tests).unwrap_err(),
+            "FAIL start\n    Vibo.A: expected 2, got 1\n0 passed, 1 failed"
+        );
+    }
+
+    #[test]
+    fn test_coverage() {
+        let facts = [r#"{"Vibo": {"A": 0, "B": 1}}"#.to_string(), r#"{"Vibo": {"A": 1, "B": 1}}"#.to_string()];
+        let out = coverage(FIB, &facts, None).unwrap();
+        assert_eq!(
+            out,
+            "rules fired: 1/1 (100.0%)\nbranch outcomes: 5/6 (83.3%)\n\nrule CalcFib: fired 1\n\
+             \x20   true   1  false   2  Vibo.A == 0\n\
+             \x20   true   1  false   2  Vibo.A == 0 || Vibo.B == 0\n\
+             \x20 ! true   0  false   2  Vibo.B == 0\n"
+        );
+
+        let lcov = coverage(FIB, &facts, Some("fib.grl")).unwrap();
+        assert!(lcov.starts_with("TN:\nSF:fib.grl\nFN:2,CalcFib\nFNDA:1,CalcFib\n"));
+        assert!(lcov.contains("BRDA:4,2,0,0\nBRDA:4,2,1,2\n"));
+    }
+
+    #[test]
+    fn test_profile() {
+        let facts = [r#"{"Vibo": {"A": 0, "B": 1}}"#.to_string()];
+        let out = profile(FIB, &facts, false).unwrap();
+        assert!(out.starts_with("rule      evals   fires"));
+        assert!(out.contains("\nCalcFib       2       1  "));
+
+        let out = profile(FIB, &facts, true).unwrap();
+        let stacks: Vec<&str> = out.lines().map(|l| l.rsplit_once(' ').unwrap().0).collect();
+        assert_eq!(
+            stacks,
+            vec!["CalcFib;evaluate", "CalcFib;evaluate;Vibo.A == 0", "CalcFib;evaluate;Vibo.B == 0", "CalcFib;execute"]
+        );
+    }
+
+    #[test]
+    fn test_compile() {
+        let bytes = compile("fib", FIB).unwrap();
+        let kb = KnowledgeBase::from_bytes(&bytes).unwrap();
+        assert_eq!(kb.name, "fib");
+        assert_eq!(kb.rules, parse(FIB.to_string()).unwrap());
+        assert!(compile("bad", "rule {").is_err());
+    }
+
+    #[test]
+    fn test_export_import() {
+        let json = export(FIB, "json").unwrap();
+        assert!(json.starts_with("{\n  \"rules\": [\n    {\n      \"name\": \"CalcFib\",\n"));
+        assert_eq!(import(&json, "json").unwrap(), format(FIB).unwrap());
+        assert!(export(FIB, "xml").is_err());
+    }
+
+    #[test]
+    fn test_main_usage_errors() {
+        assert!(main(&[]).is_err());
+        assert!(main(&["frobnicate".to_string()]).is_err());
+        assert!(main(&["run".to_string()]).is_err());
+    }
+}
@@ -1,27 +1,515 @@
-use std::collections::HashMap;
+use std::{
+    cell::RefCell,
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    hash::{Hash, Hasher},
+    sync::atomic::{AtomicU64, Ordering},
+};
 
-use crate::value::Value;
+use crate::{
+    limits::Budget,
+    time::{Clock, DateTime},
+    value::Value,
+};
+
+/// Identifies a fact instance in working memory.
+pub type FactId = u64;
+
+/// One instance of a fact type in working memory, e.g. one of several
+/// orders. Rules match instances with patterns like `o: Order(Total > 100)`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fact {
+    pub fact_type: String,
+    pub fields: BTreeMap<String, Value>,
+    revision: u64,
+}
+
+impl Fact {
+    /// How many times the instance was passed to `update`.
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+}
+
+/// A fact by name, or the instances of a fact type: what a rule's matches
+/// depend on.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum FactKey {
+    Named(String),
+    Type(String),
+}
+
+/// The path an instance's field has among the facts, as listed by
+/// [`DataContext::sorted_facts`] and [`DataContext::diff`]: `Order#3.Total`.
+pub fn instance_path(fact_type: &str, id: FactId, field: &str) -> String {
+    format!("{}#{}.{}", fact_type, id, field)
+}
+
+// moves `fingerprint` from a fact's old value to its new one
+fn rehash(fingerprint: &mut u64, path: &str, old: Option<&Value>, new: Option<&Value>) {
+    let hash = |value: &Value| {
+        let mut hasher = DefaultHasher::new();
+        (path, value).hash(&mut hasher);
+        hasher.finish()
+    };
+    if let Some(old) = old {
+        *fingerprint = fingerprint.wrapping_sub(hash(old));
+    }
+    if let Some(new) = new {
+        *fingerprint = fingerprint.wrapping_add(hash(new));
+    }
+}
+
+// numbers contexts as they are created
+static NEXT_CONTEXT: AtomicU64 = AtomicU64::new(1);
 
 pub struct DataContext {
+    // tells contexts apart, so an engine's cached matches aren't used on
+    // another one
+    id: u64,
+    facts: HashMap<String, Value>,
+    // working memory, by id
+    memory: BTreeMap<FactId, Fact>,
+    next_id: FactId,
+    // pattern variables bound while a rule is matched or fired, latest last
+    bindings: RefCell<Vec<(String, FactId)>>,
+    // counts changes; the count at each key's last change, and at the last
+    // change to everything at once
+    version: u64,
+    versions: HashMap<FactKey, u64>,
+    changed_all: u64,
+    // the sum of the hashes of every fact and instance field, kept up to
+    // date as they are written
+    fingerprint: u64,
+    // facts and instance fields by path, as they were at the last checkpoint
+    baseline: HashMap<String, Value>,
+    // rule that last wrote each fact since the checkpoint
+    writers: HashMap<String, String>,
+    writer: Option<String>,
+    // what `now()` returns; the system time when unset
+    now: Option<DateTime>,
+    // what the current run may still spend
+    budget: Option<Budget>,
+}
+
+/// A copy of the facts taken by [`DataContext::snapshot`].
+#[derive(Clone)]
+pub struct Snapshot {
     facts: HashMap<String, Value>,
+    memory: BTreeMap<FactId, Fact>,
+    writers: HashMap<String, String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChangeKind {
+    Inserted,
+    Modified,
+    Removed,
+}
+
+/// One fact that differs from the last checkpoint.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FactChange {
+    pub path: String,
+    pub kind: ChangeKind,
+    pub old: Option<Value>,
+    pub new: Option<Value>,
+    /// Rule whose action made the last write, `None` for writes from outside the engine.
+    pub rule: Option<String>,
+}
+
+impl Default for DataContext {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl DataContext {
     pub fn new() -> DataContext {
         Self {
-            facts: HashMap::new()
+            id: NEXT_CONTEXT.fetch_add(1, Ordering::Relaxed),
+            facts: HashMap::new(),
+            memory: BTreeMap::new(),
+            next_id: 1,
+            bindings: RefCell::new(Vec::new()),
+            version: 0,
+            versions: HashMap::new(),
+            changed_all: 0,
+            fingerprint: 0,
+            baseline: HashMap::new(),
+            writers: HashMap::new(),
+            writer: None,
+            now: None,
+            budget: None,
         }
     }
 
     pub fn add(&mut self, name: String, value: Value){
-        self.facts.insert(name, value);
+        self.set(name, value);
     }
 
+    /// The fact `name`. A name whose first part is a bound pattern
+    /// variable, like `o.Total`, is that field of the bound instance.
     pub fn get(&self, name: String) -> Option<&Value> {
+        if let Some((id, field)) = self.resolve(&name) {
+            return self.memory.get(&id)?.fields.get(field);
+        }
         self.facts.get(&name) // we borrow here
     }
 
     pub fn set(&mut self, name: String, value: Value) {
-        self.facts.insert(name, value);
+        if let Some((id, field)) = self.resolve(&name) {
+            if let Some(fact) = self.memory.get_mut(&id) {
+                let path = instance_path(&fact.fact_type, id, field);
+                let old = fact.fields.insert(field.to_string(), value);
+                rehash(&mut self.fingerprint, &path, old.as_ref(), fact.fields.get(field));
+                let key = FactKey::Type(fact.fact_type.clone());
+                self.record_write(&path);
+                self.touch(key);
+            }
+            return;
+        }
+        self.record_write(&name);
+        self.touch(FactKey::Named(name.clone()));
+        let old = self.facts.insert(name.clone(), value);
+        rehash(&mut self.fingerprint, &name, old.as_ref(), self.facts.get(&name));
     }
-}
\ No newline at end of file
+
+    pub fn remove(&mut self, name: String) -> Option<Value> {
+        if let Some((id, field)) = self.resolve(&name) {
+            let fact = self.memory.get_mut(&id)?;
+            let path = instance_path(&fact.fact_type, id, field);
+            let old = fact.fields.remove(field);
+            rehash(&mut self.fingerprint, &path, old.as_ref(), None);
+            let key = FactKey::Type(fact.fact_type.clone());
+            self.record_write(&path);
+            self.touch(key);
+            return old;
+        }
+        self.record_write(&name);
+        self.touch(FactKey::Named(name.clone()));
+        let old = self.facts.remove(&name);
+        rehash(&mut self.fingerprint, &name, old.as_ref(), None);
+        old
+    }
+
+    // the instance and field a `variable.Field` name refers to
+    fn resolve<'a>(&self, name: &'a str) -> Option<(FactId, &'a str)> {
+        let (var, field) = name.split_once('.')?;
+        Some((self.bound(var)?, field))
+    }
+
+    /// Adds an instance of `fact_type` to working memory, returning its id.
+    pub fn insert_fact(&mut self, fact_type: &str, fields: BTreeMap<String, Value>) -> FactId {
+        let id = self.next_id;
+        self.next_id += 1;
+        for (field, value) in &fields {
+            let path = instance_path(fact_type, id, field);
+            self.record_write(&path);
+            rehash(&mut self.fingerprint, &path, None, Some(value));
+        }
+        self.touch(FactKey::Type(fact_type.to_string()));
+        self.memory.insert(
+            id,
+            Fact {
+                fact_type: fact_type.to_string(),
+                fields,
+                revision: 0,
+            },
+        );
+        id
+    }
+
+    /// Removes an instance from working memory.
+    pub fn retract_fact(&mut self, id: FactId) -> Option<Fact> {
+        let fact = self.memory.remove(&id)?;
+        for (field, value) in &fact.fields {
+            let path = instance_path(&fact.fact_type, id, field);
+            self.record_write(&path);
+            rehash(&mut self.fingerprint, &path, Some(value), None);
+        }
+        self.touch(FactKey::Type(fact.fact_type.clone()));
+        Some(fact)
+    }
+
+    /// Marks an instance as changed, so the rules that matched it may
+    /// fire on it again even if the fields they read are as they were.
+    pub fn update_fact(&mut self, id: FactId) -> bool {
+        let Some(fact) = self.memory.get_mut(&id) else {
+            return false;
+        };
+        fact.revision += 1;
+        let key = FactKey::Type(fact.fact_type.clone());
+        self.touch(key);
+        true
+    }
+
+    fn touch(&mut self, key: FactKey) {
+        self.version += 1;
+        self.versions.insert(key, self.version);
+    }
+
+    fn touch_all(&mut self) {
+        self.version += 1;
+        self.changed_all = self.version;
+    }
+
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Counts the changes made to the facts so far.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// A hash of all the facts and instance fields, the same whenever they
+    /// hold the same values, in whatever order those were written.
+    pub(crate) fn fingerprint(&self) -> u64 {
+        self.fingerprint
+    }
+
+    /// Whether `key` may have changed since the facts were at `version`.
+    pub fn changed_since(&self, key: &FactKey, version: u64) -> bool {
+        self.changed_all > version || self.versions.get(key).is_some_and(|v| *v > version)
+    }
+
+    pub fn fact(&self, id: FactId) -> Option<&Fact> {
+        self.memory.get(&id)
+    }
+
+    /// Every instance in working memory, by id.
+    pub fn instances(&self) -> impl Iterator<Item = (FactId, &Fact)> {
+        self.memory.iter().map(|(id, fact)| (*id, fact))
+    }
+
+    /// The instances of `fact_type`, by id.
+    pub fn facts_of<'a>(&'a self, fact_type: &'a str) -> impl Iterator<Item = (FactId, &'a Fact)> {
+        self.instances().filter(move |(_, fact)| fact.fact_type == fact_type)
+    }
+
+    /// Binds pattern variable `name` to instance `id`, hiding any earlier
+    /// binding of the same name until `unbind` drops this one.
+    pub fn bind(&self, name: &str, id: FactId) {
+        self.bindings.borrow_mut().push((name.to_string(), id));
+    }
+
+    /// Drops the last `n` bindings.
+    pub fn unbind(&self, n: usize) {
+        let mut bindings = self.bindings.borrow_mut();
+        let len = bindings.len().saturating_sub(n);
+        bindings.truncate(len);
+    }
+
+    /// The instance pattern variable `name` is bound to.
+    pub fn bound(&self, name: &str) -> Option<FactId> {
+        self.bindings.borrow().iter().rev().find(|(n, _)| n == name).map(|(_, id)| *id)
+    }
+
+    /// Fixes the time `now()` returns, or with `None` goes back to the
+    /// system clock.
+    pub fn set_now(&mut self, now: Option<DateTime>) {
+        if now != self.now {
+            self.touch_all();
+        }
+        self.now = now;
+    }
+
+    pub fn now(&self) -> DateTime {
+        self.now.unwrap_or_else(|| Clock::System.now())
+    }
+
+    /// Sets the runtime limits that evaluating expressions is charged
+    /// against, or with `None` lifts them.
+    pub fn set_budget(&mut self, budget: Option<Budget>) {
+        self.budget = budget;
+    }
+
+    pub fn budget(&self) -> Option<&Budget> {
+        self.budget.as_ref()
+    }
+
+    /// Attributes the following writes to `rule`, until cleared with `None`.
+    pub fn set_writer(&mut self, rule: Option<String>) {
+        self.writer = rule;
+    }
+
+    fn record_write(&mut self, name: &str) {
+        match &self.writer {
+            Some(rule) => {
+                self.writers.insert(name.to_string(), rule.clone());
+            }
+            None => {
+                self.writers.remove(name);
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            facts: self.facts.clone(),
+            memory: self.memory.clone(),
+            writers: self.writers.clone(),
+        }
+    }
+
+    /// Puts the facts back the way they were when `snapshot` was taken.
+    pub fn restore(&mut self, snapshot: Snapshot) {
+        self.facts = snapshot.facts;
+        self.memory = snapshot.memory;
+        self.fingerprint = 0;
+        for (name, value) in &self.facts {
+            rehash(&mut self.fingerprint, name, None, Some(value));
+        }
+        for (id, fact) in &self.memory {
+            for (field, value) in &fact.fields {
+                rehash(&mut self.fingerprint, &instance_path(&fact.fact_type, *id, field), None, Some(value));
+            }
+        }
+        self.touch_all();
+        self.writers = snapshot.writers;
+    }
+
+    /// Marks the current facts as the baseline that `diff` compares against.
+    pub fn checkpoint(&mut self) {
+        self.baseline = self.sorted_facts().into_iter().collect();
+        self.writers.clear();
+    }
+
+    /// Facts inserted, modified or removed since the last checkpoint (or
+    /// since the context was created), ordered by path.
+    pub fn diff(&self) -> Vec<FactChange> {
+        let mut changes = Vec::new();
+        let facts: HashMap<String, Value> = self.sorted_facts().into_iter().collect();
+
+        for (path, new) in &facts {
+            let kind = match self.baseline.get(path) {
+                None => ChangeKind::Inserted,
+                Some(old) if old != new => ChangeKind::Modified,
+                Some(_) => continue,
+            };
+            changes.push(FactChange {
+                path: path.clone(),
+                kind,
+                old: self.baseline.get(path).cloned(),
+                new: Some(new.clone()),
+                rule: self.writers.get(path).cloned(),
+            });
+        }
+        for (path, old) in &self.baseline {
+            if !facts.contains_key(path) {
+                changes.push(FactChange {
+                    path: path.clone(),
+                    kind: ChangeKind::Removed,
+                    old: Some(old.clone()),
+                    new: None,
+                    rule: self.writers.get(path).cloned(),
+                });
+            }
+        }
+
+        changes.sort_by(|a, b| a.path.cmp(&b.path));
+        changes
+    }
+
+    /// The facts set by name, ordered by name.
+    pub fn named_facts(&self) -> Vec<(String, Value)> {
+        let mut facts: Vec<(String, Value)> = self
+            .facts
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        facts.sort_by(|a, b| a.0.cmp(&b.0));
+        facts
+    }
+
+    /// All facts ordered by path, instance fields included under their
+    /// [`instance_path`].
+    pub fn sorted_facts(&self) -> Vec<(String, Value)> {
+        let mut facts = self.named_facts();
+        for (id, fact) in &self.memory {
+            for (field, value) in &fact.fields {
+                facts.push((instance_path(&fact.fact_type, *id, field), value.clone()));
+            }
+        }
+        facts.sort_by(|a, b| a.0.cmp(&b.0));
+        facts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{engine::RuleEngine, parser::parse};
+
+    #[test]
+    fn test_diff_since_checkpoint() {
+        let mut ctx = DataContext::new();
+        ctx.set("Order.Total".into(), Value::Int(150));
+        ctx.set("Order.Discount".into(), Value::Int(0));
+        ctx.set("Order.Coupon".into(), Value::Int(7));
+        ctx.checkpoint();
+        assert!(ctx.diff().is_empty());
+
+        let rules = parse(
+            r#"
+    rule Discount { when Order.Total > 100 && Order.Discount == 0 then Order.Discount = 10; Order.Final = Order.Total + 0; }
+    "#
+            .to_string(),
+        )
+        .unwrap();
+        RuleEngine::new().execute(&rules, &mut ctx).unwrap();
+        ctx.remove("Order.Coupon".into());
+        // rewriting a fact with its old value is not a change
+        ctx.set("Order.Total".into(), Value::Int(150));
+
+        let changes = ctx.diff();
+        assert_eq!(
+            changes,
+            vec![
+                FactChange {
+                    path: "Order.Coupon".into(),
+                    kind: ChangeKind::Removed,
+                    old: Some(Value::Int(7)),
+                    new: None,
+                    rule: None,
+                },
+                FactChange {
+                    path: "Order.Discount".into(),
+                    kind: ChangeKind::Modified,
+                    old: Some(Value::Int(0)),
+                    new: Some(Value::Int(10)),
+                    rule: Some("Discount".into()),
+                },
+                FactChange {
+                    path: "Order.Final".into(),
+                    kind: ChangeKind::Inserted,
+                    old: None,
+                    new: Some(Value::Int(150)),
+                    rule: Some("Discount".into()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fingerprint_follows_values() {
+        let mut ctx = DataContext::new();
+        ctx.set("A".into(), Value::Int(1));
+        let start = ctx.fingerprint();
+        let snapshot = ctx.snapshot();
+
+        ctx.set("A".into(), Value::Int(2));
+        let id = ctx.insert_fact("Order", BTreeMap::from([("Total".to_string(), Value::Int(5))]));
+        assert_ne!(ctx.fingerprint(), start);
+        // the same values, written in another order
+        ctx.retract_fact(id);
+        ctx.set("A".into(), Value::Int(1));
+        assert_eq!(ctx.fingerprint(), start);
+
+        ctx.set("B".into(), Value::Int(3));
+        ctx.restore(snapshot);
+        assert_eq!(ctx.fingerprint(), start);
+        ctx.remove("A".into());
+        assert_eq!(ctx.fingerprint(), 0);
+    }
+}
@@ -0,0 +1,466 @@
+//! Rule coverage over a corpus of fact documents: how often each rule
+//! fired, and for every node of its condition whether it was ever true and
+//! ever false.
+//!
+//! Condition nodes are numbered in source order (an in-order walk of the
+//! tree), which is also the order of the operators that write them, so
+//! the LCOV output can put each branch on its GRL source line. A pattern
+//! counts as a node, written by its fact type, and a rule with patterns is
+//! walked once per combination of instances they could bind. An `exists`
+//! test is a node too, its constraint walked for every instance; an
+//! aggregate is part of the comparison it appears in.
+
+use crate::{
+    ast::{Aggregate, Condition, FactSet, Rule, FACT_SET_BINDING},
+    context::{DataContext, FactId},
+    engine::RuleEngine,
+    functions,
+    lexer::line_col,
+    syntax::{SyntaxKind, SyntaxTree},
+};
+
+/// How often one condition node evaluated each way.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BranchCoverage {
+    pub condition: String,
+    pub when_true: usize,
+    pub when_false: usize,
+}
+
+impl BranchCoverage {
+    pub fn covered(&self) -> bool {
+        self.when_true > 0 && self.when_false > 0
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RuleCoverage {
+    pub name: String,
+    pub fired: usize,
+    pub branches: Vec<BranchCoverage>,
+}
+
+pub struct Coverage {
+    pub rules: Vec<RuleCoverage>,
+}
+
+// the condition's nodes in source order
+fn nodes<'a>(cond: &'a Condition, out: &mut Vec<&'a Condition>) {
+    match cond {
+        Condition::Compare { .. } => out.push(cond),
+        Condition::And(a, b) | Condition::Or(a, b) => {
+            nodes(a, out);
+            out.push(cond);
+            nodes(b, out);
+        }
+        Condition::Not(c) => {
+            out.push(cond);
+            nodes(c, out);
+        }
+        Condition::Pattern { constraint, .. } | Condition::Exists(FactSet { constraint, .. }) => {
+            out.push(cond);
+            if let Some(c) = constraint {
+                nodes(c, out);
+            }
+        }
+    }
+}
+
+fn count(branches: &mut [BranchCoverage], id: usize, skip: bool, value: bool) -> bool {
+    if !skip {
+        let branch = &mut branches[id];
+        if value {
+            branch.when_true += 1;
+        } else {
+            branch.when_false += 1;
+        }
+    }
+    value
+}
+
+// Evaluates like `Condition::evaluate`, counting each node's outcome.
+// Nodes that short-circuiting skips are walked with `skip` set, so they
+// keep their numbers but aren't counted.
+fn walk(
+    cond: &Condition,
+    ctx: &DataContext,
+    skip: bool,
+    next: &mut usize,
+    branches: &mut [BranchCoverage],
+) -> Result<bool, String> {
+    match cond {
+        Condition::Compare { .. } => {
+            let id = *next;
+            *next += 1;
+            let value = !skip && cond.evaluate(ctx)?;
+            Ok(count(branches, id, skip, value))
+        }
+        Condition::And(a, b) => {
+            let a = walk(a, ctx, skip, next, branches)?;
+            let id = *next;
+            *next += 1;
+            let b = walk(b, ctx, skip || !a, next, branches)?;
+            Ok(count(branches, id, skip, a && b))
+        }
+        Condition::Or(a, b) => {
+            let a = walk(a, ctx, skip, next, branches)?;
+            let id = *next;
+            *next += 1;
+            let b = walk(b, ctx, skip || a, next, branches)?;
+            Ok(count(branches, id, skip, a || b))
+        }
+        Condition::Not(c) => {
+            let id = *next;
+            *next += 1;
+            let c = walk(c, ctx, skip, next, branches)?;
+            Ok(count(branches, id, skip, !c))
+        }
+        // walked with its variable bound, so it holds when its constraint does
+        Condition::Pattern { constraint, .. } => {
+            let id = *next;
+            *next += 1;
+            let value = match constraint {
+                Some(c) => walk(c, ctx, skip, next, branches)?,
+                None => !skip,
+            };
+            Ok(count(branches, id, skip, value))
+        }
+        Condition::Exists(set) => {
+            let id = *next;
+            *next += 1;
+            let Some(c) = &set.constraint else {
+                let value = !skip && cond.evaluate(ctx)?;
+                return Ok(count(branches, id, skip, value));
+            };
+            let ids: Vec<FactId> = if skip { Vec::new() } else { ctx.facts_of(&set.fact_type).map(|(id, _)| id).collect() };
+            let start = *next;
+            let mut value = false;
+            for fact in &ids {
+                *next = start;
+                ctx.bind(FACT_SET_BINDING, *fact);
+                let result = walk(c, ctx, false, next, branches);
+                ctx.unbind(1);
+                value |= result?;
+            }
+            // numbers the constraint's nodes when no instance walked them
+            *next = start;
+            walk(c, ctx, true, next, branches)?;
+            Ok(count(branches, id, skip, value))
+        }
+    }
+}
+
+// every combination of instances the rule's patterns could bind; a single
+// empty one for a rule without patterns
+fn candidates(rule: &Rule, ctx: &DataContext) -> Vec<Vec<FactId>> {
+    let mut tuples = vec![Vec::new()];
+    for (_, fact_type) in rule.patterns() {
+        let ids: Vec<FactId> = ctx.facts_of(fact_type).map(|(id, _)| id).collect();
+        tuples = tuples
+            .into_iter()
+            .flat_map(|tuple: Vec<FactId>| {
+                ids.iter().map(move |&id| {
+                    let mut tuple = tuple.clone();
+                    tuple.push(id);
+                    tuple
+                })
+            })
+            .collect();
+    }
+    tuples
+}
+
+fn percent(n: usize, total: usize) -> String {
+    if total == 0 {
+        return "100.0%".into();
+    }
+    format!("{:.1}%", n as f64 * 100.0 / total as f64)
+}
+
+impl Coverage {
+    pub fn new(rules: &[Rule]) -> Coverage {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                let mut conds = Vec::new();
+                nodes(&rule.condition, &mut conds);
+                RuleCoverage {
+                    name: rule.name.clone(),
+                    fired: 0,
+                    branches: conds
+                        .into_iter()
+                        .map(|c| BranchCoverage {
+                            condition: c.to_string(),
+                            when_true: 0,
+                            when_false: 0,
+                        })
+                        .collect(),
+                }
+            })
+            .collect();
+        Coverage { rules }
+    }
+
+    /// Runs the rules on one fact document, recording what fired and how
+    /// every rule's condition evaluated on each fact state along the way.
+    pub fn record(&mut self, rules: &[Rule], mut ctx: DataContext, engine: &mut RuleEngine) -> Result<(), String> {
+        engine.reset();
+        loop {
+            for (rule, coverage) in rules.iter().zip(&mut self.rules) {
+                let patterns = rule.patterns();
+                for tuple in candidates(rule, &ctx) {
+                    for ((binding, _), id) in patterns.iter().zip(&tuple) {
+                        ctx.bind(binding, *id);
+                    }
+                    // a condition that fails to evaluate still counts as far as it got
+                    let _ = walk(&rule.condition, &ctx, false, &mut 0, &mut coverage.branches);
+                    ctx.unbind(tuple.len());
+                }
+            }
+            match engine.step(rules, &mut ctx)? {
+                Some(activation) => self.rules[activation.rule].fired += 1,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    pub fn never_fired(&self) -> Vec<&str> {
+        self.rules.iter().filter(|r| r.fired == 0).map(|r| r.name.as_str()).collect()
+    }
+
+    pub fn text_report(&self) -> String {
+        let fired = self.rules.iter().filter(|r| r.fired > 0).count();
+        let branches: Vec<&BranchCoverage> = self.rules.iter().flat_map(|r| &r.branches).collect();
+        let outcomes = branches.len() * 2;
+        let hit: usize = branches
+            .iter()
+            .map(|b| (b.when_true > 0) as usize + (b.when_false > 0) as usize)
+            .sum();
+
+        let mut out = format!("rules fired: {}/{} ({})\n", fired, self.rules.len(), percent(fired, self.rules.len()));
+        out.push_str(&format!("branch outcomes: {}/{} ({})\n", hit, outcomes, percent(hit, outcomes)));
+        let never = self.never_fired();
+        if !never.is_empty() {
+            out.push_str(&format!("never fired: {}\n", never.join(", ")));
+        }
+
+        for rule in &self.rules {
+            out.push_str(&format!("\nrule {}: fired {}\n", rule.name, rule.fired));
+            for b in &rule.branches {
+                let mark = if b.covered() { " " } else { "!" };
+                out.push_str(&format!(
+                    "  {} true {:>3}  false {:>3}  {}\n",
+                    mark, b.when_true, b.when_false, b.condition
+                ));
+            }
+        }
+        out
+    }
+
+    /// LCOV tracefile for `source`, the rules' GRL text: each rule is a
+    /// function, and each condition node a pair of branches (true, false)
+    /// on the line of its operator.
+    pub fn lcov(&self, path: &str, source: &str) -> Result<String, String> {
+        let tree = SyntaxTree::parse(source).map_err(|d| d.message)?;
+        let line = |offset: usize| line_col(source, offset).0 + 1;
+
+        let mut out = format!("TN:\nSF:{}\n", path);
+        let mut lines = Vec::new();
+        let mut branch_lines = Vec::new();
+
+        for rule in &self.rules {
+            let node = tree.rule_named(&rule.name).ok_or_else(|| format!("rule {} not in source", rule.name))?;
+            let header = line(tree.tokens()[node.tokens.start + 1].span.start);
+            out.push_str(&format!("FN:{},{}\n", header, rule.name));
+            lines.push((header, rule.fired));
+
+            // the operators and pattern fact types between `when` and
+            // `then`, in source order
+            let tokens: Vec<_> = tree.tokens()[node.tokens.clone()]
+                .iter()
+                .skip_while(|t| t.text != "when")
+                .take_while(|t| t.text != "then")
+                .collect();
+            let text = |i: usize| tokens.get(i).map_or("", |t| t.text.as_str());
+            // the `)` closing the `(` at `open`
+            let close = |open: usize| {
+                let mut depth = 0;
+                (open..tokens.len()).find(|&i| {
+                    depth += (text(i) == "(") as i32 - (text(i) == ")") as i32;
+                    depth == 0
+                })
+            };
+            let compare = |s: &str| ["==", "!=", "<", ">", "<=", ">="].contains(&s);
+            let mut ops = Vec::new();
+            // whether the comparison being read already has its operator
+            let mut compared = false;
+            let mut i = 0;
+            while i < tokens.len() {
+                let t = tokens[i];
+                let call = t.kind == SyntaxKind::Ident && text(i + 1) == "(";
+                let method = call && i > 0 && text(i - 1) == "." && Aggregate::from_name(&t.text).is_some();
+                let end = if call { close(i + 1).unwrap_or(tokens.len()) } else { i };
+                // a fact set an aggregate is taken over, and the aggregate
+                // itself, are inside the comparison; only a bare `any` or
+                // `all` is a comparison of its own
+                if method || (call && text(end + 1) == "." && functions::signature(t.text.trim_matches('`')).is_none()) {
+                    if method && matches!(t.text.as_str(), "any" | "all") && !compared && !compare(text(end + 1)) {
+                        ops.push(line(t.span.start));
+                    }
+                    i = end + 1;
+                    continue;
+                }
+                let pattern = call && functions::signature(t.text.trim_matches('`')).is_none();
+                let not = t.text == "not" && text(i + 1) == "exists";
+                if pattern || not || compare(&t.text) || ["&&", "||", "!"].contains(&t.text.as_str()) {
+                    ops.push(line(t.span.start));
+                    compared = compare(&t.text);
+                }
+                i += 1;
+            }
+            for (b, branch) in rule.branches.iter().enumerate() {
+                branch_lines.push((ops.get(b).copied().unwrap_or(header), branch));
+            }
+        }
+        for rule in &self.rules {
+            out.push_str(&format!("FNDA:{},{}\n", rule.fired, rule.name));
+        }
+        let hit = self.rules.iter().filter(|r| r.fired > 0).count();
+        out.push_str(&format!("FNF:{}\nFNH:{}\n", self.rules.len(), hit));
+
+        let mut found = 0;
+        let mut taken = 0;
+        for (block, (line, branch)) in branch_lines.iter().enumerate() {
+            let evaluated = branch.when_true + branch.when_false > 0;
+            for (n, count) in [branch.when_true, branch.when_false].into_iter().enumerate() {
+                let count = if evaluated { count.to_string() } else { "-".into() };
+                out.push_str(&format!("BRDA:{},{},{},{}\n", line, block, n, count));
+            }
+            found += 2;
+            taken += (branch.when_true > 0) as usize + (branch.when_false > 0) as usize;
+        }
+        out.push_str(&format!("BRF:{}\nBRH:{}\n", found, taken));
+
+        for (line, count) in &lines {
+            out.push_str(&format!("DA:{},{}\n", line, count));
+        }
+        let hit = lines.iter().filter(|(_, c)| *c > 0).count();
+        out.push_str(&format!("LF:{}\nLH:{}\nend_of_record\n", lines.len(), hit));
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parser::parse, value::Value};
+
+    const RULES: &str = "rule Discount {
+    when
+        Order.Total > 100 &&
+        !(Order.Vip == true)
+    then
+        Order.Discount = 10;
+}
+rule Vip { when Order.Vip == true || Order.Total > 1000 then Order.Discount = 20; }
+rule Unused { when Order.Total < 0 then Order.Discount = 0; }
+";
+
+    fn coverage() -> Coverage {
+        let rules = parse(RULES.to_string()).unwrap();
+        let mut coverage = Coverage::new(&rules);
+        let mut engine = RuleEngine::new();
+        for (total, vip) in [(150, false), (50, true)] {
+            let mut ctx = DataContext::new();
+            ctx.set("Order.Total".into(), Value::Int(total));
+            ctx.set("Order.Vip".into(), Value::Bool(vip));
+            coverage.record(&rules, ctx, &mut engine).unwrap();
+        }
+        coverage
+    }
+
+    #[test]
+    fn test_branch_counts() {
+        let coverage = coverage();
+        assert_eq!(coverage.never_fired(), vec!["Unused"]);
+
+        let discount = &coverage.rules[0];
+        assert_eq!(discount.fired, 1);
+        let counts: Vec<(&str, usize, usize)> = discount
+            .branches
+            .iter()
+            .map(|b| (b.condition.as_str(), b.when_true, b.when_false))
+            .collect();
+        // the first document is evaluated twice, before and after Discount fires;
+        // in the second, `Order.Total > 100` short-circuits the rest
+        assert_eq!(
+            counts,
+            vec![
+                ("Order.Total > 100", 2, 2),
+                ("Order.Total > 100 && !(Order.Vip == true)", 2, 2),
+                ("!(Order.Vip == true)", 2, 0),
+                ("Order.Vip == true", 0, 2),
+            ]
+        );
+
+        let report = coverage.text_report();
+        assert!(report.starts_with("rules fired: 2/3 (66.7%)\nbranch outcomes: 12/16 (75.0%)\nnever fired: Unused\n"));
+    }
+
+    #[test]
+    fn test_lcov() {
+        let lcov = coverage().lcov("rules.grl", RULES).unwrap();
+        let lines: Vec<&str> = lcov.lines().collect();
+        assert_eq!(&lines[..5], &["TN:", "SF:rules.grl", "FN:1,Discount", "FN:8,Vip", "FN:9,Unused"]);
+        assert!(lines.contains(&"BRDA:3,0,0,2"));
+        assert!(lines.contains(&"BRDA:4,2,1,0"));
+        assert!(lines.contains(&"BRDA:9,7,0,0"));
+        assert!(lines.contains(&"BRDA:9,7,1,4"));
+        assert!(lines.contains(&"DA:9,0"));
+        assert_eq!(lines.last(), Some(&"end_of_record"));
+    }
+
+    #[test]
+    fn test_patterns_are_walked_per_instance() {
+        let source = "rule Big {\n    when\n        o: Order(Total > 100)\n    then\n        o.Big = true;\n}\n";
+        let rules = parse(source.to_string()).unwrap();
+        let mut coverage = Coverage::new(&rules);
+        let mut ctx = DataContext::new();
+        for total in [150, 50] {
+            ctx.insert_fact("Order", [("Total".to_string(), Value::Int(total))].into());
+        }
+        coverage.record(&rules, ctx, &mut RuleEngine::new()).unwrap();
+
+        let big = &coverage.rules[0];
+        assert_eq!(big.fired, 1);
+        let counts: Vec<(&str, usize, usize)> = big.branches.iter().map(|b| (b.condition.as_str(), b.when_true, b.when_false)).collect();
+        assert_eq!(counts, vec![("o: Order(Total > 100)", 2, 2), ("o.Total > 100", 2, 2)]);
+
+        let lcov = coverage.lcov("big.grl", source).unwrap();
+        assert!(lcov.contains("BRDA:3,0,0,2\nBRDA:3,0,1,2\nBRDA:3,1,0,2\n"));
+    }
+
+    #[test]
+    fn test_exists_and_aggregates() {
+        let source = "rule Quiet {\n    when\n        not exists Order(Total > 100) &&\n        Order.Totals.any(t => t > 100)\n    then\n        Stats.Quiet = true;\n}\n";
+        let rules = parse(source.to_string()).unwrap();
+        let mut coverage = Coverage::new(&rules);
+        let mut ctx = DataContext::new();
+        ctx.insert_fact("Order", [("Total".to_string(), Value::Int(50))].into());
+        ctx.set("Order.Totals".into(), Value::List(vec![Value::Int(150)]));
+        coverage.record(&rules, ctx, &mut RuleEngine::new()).unwrap();
+
+        let counts: Vec<(&str, usize, usize)> =
+            coverage.rules[0].branches.iter().map(|b| (b.condition.as_str(), b.when_true, b.when_false)).collect();
+        assert_eq!(
+            counts[..3],
+            [("not exists Order(Total > 100)", 2, 0), ("exists Order(Total > 100)", 0, 2), ("`$`.Total > 100", 0, 2)]
+        );
+        assert_eq!(counts[4], ("Order.Totals.any(t => t > 100)", 2, 0));
+
+        // `not`, the fact type, `>` and `&&` on line 3, and `any` on line 4
+        let lcov = coverage.lcov("quiet.grl", source).unwrap();
+        assert!(lcov.contains("BRDA:3,0,0,2\nBRDA:3,0,1,0\nBRDA:3,1,0,0\nBRDA:3,1,1,2\n"));
+        assert!(lcov.contains("BRDA:3,3,0,2\nBRDA:3,3,1,0\nBRDA:4,4,0,2\nBRDA:4,4,1,0\n"));
+    }
+}
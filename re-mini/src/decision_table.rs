@@ -0,0 +1,407 @@
+//! Decision tables loaded from CSV and compiled to ordinary rules.
+//!
+//! The first CSV row is the header. Each column is one of:
+//!
+//! - `when <field> [<op>]`: a condition on `field`, compared with the
+//!   cell using `op` (`==` when omitted).
+//! - `then <field>`: an action assigning the cell to `field`.
+//! - `priority`: the row's priority, used by [`HitPolicy::Priority`].
+//!
+//! Cells hold expressions; an empty or `-` cell is "don't care" for
+//! conditions and "no assignment" for actions.
+//!
+//! ```text
+//! when Order.Total >=,then Order.Discount
+//! 1000,20
+//! 100,10
+//! ```
+
+use crate::{
+    ast::{Action, ActivationGroup, CmpOp, Condition, Expr, Rule},
+    parser::parse_expr,
+    value::Value,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HitPolicy {
+    /// Only the first matching row fires.
+    First,
+    /// At most one row may match. Rows with identical inputs are caught
+    /// when the table is compiled; rows whose inputs overlap without being
+    /// identical, like `> 10` and `> 5`, make the run fail when more than
+    /// one of them matches.
+    Unique,
+    /// Every matching row fires, in table order.
+    Collect,
+    /// Only the matching row with the highest priority fires.
+    Priority,
+}
+
+impl HitPolicy {
+    pub fn from_name(name: &str) -> Result<HitPolicy, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "first" => Ok(HitPolicy::First),
+            "unique" => Ok(HitPolicy::Unique),
+            "collect" => Ok(HitPolicy::Collect),
+            "priority" => Ok(HitPolicy::Priority),
+            other => Err(format!("unknown hit policy '{}'", other)),
+        }
+    }
+}
+
+enum Column {
+    Condition { field: String, op: CmpOp },
+    Action { field: String },
+    Priority,
+}
+
+pub struct DecisionTable {
+    pub name: String,
+    pub hit_policy: HitPolicy,
+    columns: Vec<Column>,
+    rows: Vec<Vec<String>>,
+}
+
+impl DecisionTable {
+    pub fn from_csv(name: &str, hit_policy: HitPolicy, csv: &str) -> Result<DecisionTable, String> {
+        let mut records = parse_csv(csv)?.into_iter();
+        let header = records.next().ok_or("decision table has no header row")?;
+        let columns = header
+            .iter()
+            .map(|cell| parse_column(cell))
+            .collect::<Result<Vec<Column>, String>>()?;
+
+        if hit_policy == HitPolicy::Priority
+            && !columns.iter().any(|c| matches!(c, Column::Priority))
+        {
+            return Err("priority hit policy needs a 'priority' column".into());
+        }
+
+        let mut rows = Vec::new();
+        for (i, record) in records.enumerate() {
+            if record.len() != columns.len() {
+                return Err(format!(
+                    "row {} has {} cells, expected {}",
+                    i + 1,
+                    record.len(),
+                    columns.len()
+                ));
+            }
+            rows.push(record);
+        }
+
+        Ok(DecisionTable {
+            name: name.to_string(),
+            hit_policy,
+            columns,
+            rows,
+        })
+    }
+
+    /// Compiles each row to a rule named `<table>_row<n>`.
+    ///
+    /// Every row rule is `lock-on-active`, so a row fires at most once per
+    /// run. For `First`, `Priority` and `Unique`, the rows share an
+    /// activation group named after the table, so once one row has fired
+    /// the others don't; for `Unique` the group is unique.
+    pub fn to_rules(&self) -> Result<Vec<Rule>, String> {
+        let mut order: Vec<usize> = (0..self.rows.len()).collect();
+        let mut priorities = vec![0; self.rows.len()];
+
+        match self.hit_policy {
+            HitPolicy::Priority => {
+                for (i, p) in priorities.iter_mut().enumerate() {
+                    *p = self.priority(i)?;
+                }
+                order.sort_by_key(|&i| std::cmp::Reverse(priorities[i]));
+            }
+            HitPolicy::Unique => self.check_unique()?,
+            HitPolicy::First | HitPolicy::Collect => {}
+        }
+
+        let group = match self.hit_policy {
+            HitPolicy::Collect => None,
+            policy => Some(ActivationGroup {
+                name: self.name.clone(),
+                unique: policy == HitPolicy::Unique,
+            }),
+        };
+
+        let mut rules = Vec::new();
+        for (rank, &i) in order.iter().enumerate() {
+            let mut rule = Rule::new(
+                format!("{}_row{}", self.name, i + 1),
+                self.row_condition(i)?,
+                self.row_actions(i)?,
+            );
+            rule.salience = match self.hit_policy {
+                HitPolicy::Priority => priorities[i],
+                _ => (order.len() - rank) as i64,
+            };
+            rule.lock_on_active = true;
+            rule.activation_group = group.clone();
+            rules.push(rule);
+        }
+
+        Ok(rules)
+    }
+
+    fn row_condition(&self, row: usize) -> Result<Condition, String> {
+        let mut condition: Option<Condition> = None;
+        for (column, cell) in self.columns.iter().zip(&self.rows[row]) {
+            let Column::Condition { field, op } = column else {
+                continue;
+            };
+            if is_blank(cell) {
+                continue;
+            }
+
+            let compare = Condition::Compare {
+                left: Expr::FieldRef(field.clone()),
+                op: op.clone(),
+                right: self.cell_expr(row, cell)?,
+            };
+            condition = Some(match condition {
+                Some(c) => Condition::And(Box::new(c), Box::new(compare)),
+                None => compare,
+            });
+        }
+
+        // a row without conditions always matches
+        Ok(condition.unwrap_or(Condition::Compare {
+            left: Expr::Literal(Value::Int(1)),
+            op: CmpOp::Eq,
+            right: Expr::Literal(Value::Int(1)),
+        }))
+    }
+
+    fn row_actions(&self, row: usize) -> Result<Vec<Action>, String> {
+        let mut actions = Vec::new();
+        for (column, cell) in self.columns.iter().zip(&self.rows[row]) {
+            if let Column::Action { field } = column {
+                if !is_blank(cell) {
+                    actions.push(Action::Assign {
+                        field: field.clone(),
+                        expr: self.cell_expr(row, cell)?,
+                    });
+                }
+            }
+        }
+        Ok(actions)
+    }
+
+    fn priority(&self, row: usize) -> Result<i64, String> {
+        for (column, cell) in self.columns.iter().zip(&self.rows[row]) {
+            if let Column::Priority = column {
+                return cell.trim().parse().map_err(|_| {
+                    format!("table {} row {}: invalid priority '{}'", self.name, row + 1, cell)
+                });
+            }
+        }
+        Ok(0)
+    }
+
+    fn check_unique(&self) -> Result<(), String> {
+        let inputs: Vec<Vec<&str>> = self
+            .rows
+            .iter()
+            .map(|row| {
+                self.columns
+                    .iter()
+                    .zip(row)
+                    .filter(|(c, _)| matches!(c, Column::Condition { .. }))
+                    .map(|(_, cell)| if is_blank(cell) { "-" } else { cell.trim() })
+                    .collect()
+            })
+            .collect();
+
+        for i in 0..inputs.len() {
+            for j in i + 1..inputs.len() {
+                if inputs[i] == inputs[j] {
+                    return Err(format!(
+                        "table {}: rows {} and {} overlap under unique hit policy",
+                        self.name,
+                        i + 1,
+                        j + 1
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn cell_expr(&self, row: usize, cell: &str) -> Result<Expr, String> {
+        parse_expr(cell.trim().to_string())
+            .map_err(|e| format!("table {} row {}: {}", self.name, row + 1, e))
+    }
+}
+
+fn is_blank(cell: &str) -> bool {
+    let cell = cell.trim();
+    cell.is_empty() || cell == "-"
+}
+
+fn parse_column(header: &str) -> Result<Column, String> {
+    let words: Vec<&str> = header.split_whitespace().collect();
+    match words.as_slice() {
+        [p] if p.eq_ignore_ascii_case("priority") => Ok(Column::Priority),
+        ["when", field] => Ok(Column::Condition {
+            field: field.to_string(),
+            op: CmpOp::Eq,
+        }),
+        ["when", field, op] => {
+            let op = match *op {
+                "==" => CmpOp::Eq,
+                "!=" => CmpOp::NotEq,
+                "<" => CmpOp::Lt,
+                ">" => CmpOp::Gt,
+                "<=" => CmpOp::LtEq,
+                ">=" => CmpOp::GtEq,
+                other => return Err(format!("unknown operator '{}' in column '{}'", other, header)),
+            };
+            Ok(Column::Condition {
+                field: field.to_string(),
+                op,
+            })
+        }
+        ["then", field] => Ok(Column::Action {
+            field: field.to_string(),
+        }),
+        _ => Err(format!("invalid column header '{}'", header)),
+    }
+}
+
+// RFC 4180 style: fields may be quoted, with `""` for a literal quote
+fn parse_csv(input: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = input.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => in_quotes = false,
+                c => field.push(c),
+            }
+            continue;
+        }
+
+        match c {
+            '"' => in_quotes = true,
+            ',' => record.push(std::mem::take(&mut field)),
+            '\r' => {}
+            '\n' => {
+                record.push(std::mem::take(&mut field));
+                if record.iter().any(|f| !f.trim().is_empty()) {
+                    records.push(std::mem::take(&mut record));
+                } else {
+                    record.clear();
+                }
+            }
+            c => field.push(c),
+        }
+    }
+
+    if in_quotes {
+        return Err("unterminated quoted field in CSV".into());
+    }
+    record.push(field);
+    if record.iter().any(|f| !f.trim().is_empty()) {
+        records.push(record);
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{context::DataContext, engine::RuleEngine};
+
+    const DISCOUNTS: &str = "\
+when Order.Total >=,when Customer.Tier,then Order.Discount
+1000,-,20
+100,-,10
+-,2,5
+";
+
+    fn run(rules: &[Rule], total: i64, tier: i64) -> (Vec<String>, Option<Value>) {
+        let mut ctx = DataContext::new();
+        ctx.set("Order.Total".into(), Value::Int(total));
+        ctx.set("Customer.Tier".into(), Value::Int(tier));
+        let fired = RuleEngine::new().execute(rules, &mut ctx).unwrap();
+        (fired, ctx.get("Order.Discount".into()).cloned())
+    }
+
+    #[test]
+    fn test_first_hit_policy() {
+        let table = DecisionTable::from_csv("Discount", HitPolicy::First, DISCOUNTS).unwrap();
+        let rules = table.to_rules().unwrap();
+        assert_eq!(rules.len(), 3);
+
+        assert_eq!(run(&rules, 150, 2), (vec!["Discount_row2".to_string()], Some(Value::Int(10))));
+        assert_eq!(run(&rules, 50, 2), (vec!["Discount_row3".to_string()], Some(Value::Int(5))));
+        assert_eq!(run(&rules, 50, 1), (vec![], None));
+    }
+
+    #[test]
+    fn test_collect_hit_policy() {
+        let table = DecisionTable::from_csv("Discount", HitPolicy::Collect, DISCOUNTS).unwrap();
+        let rules = table.to_rules().unwrap();
+
+        let (fired, discount) = run(&rules, 1500, 2);
+        assert_eq!(fired, vec!["Discount_row1", "Discount_row2", "Discount_row3"]);
+        assert_eq!(discount, Some(Value::Int(5)));
+    }
+
+    #[test]
+    fn test_priority_hit_policy() {
+        let csv = "\
+when Order.Total >=,then Order.Discount,priority
+100,10,1
+1000,20,5
+\"0\",1,0
+";
+        let table = DecisionTable::from_csv("Discount", HitPolicy::Priority, csv).unwrap();
+        let rules = table.to_rules().unwrap();
+        assert_eq!(rules[0].name, "Discount_row2");
+        assert_eq!(rules[0].salience, 5);
+
+        assert_eq!(run(&rules, 2000, 0), (vec!["Discount_row2".to_string()], Some(Value::Int(20))));
+        assert_eq!(run(&rules, 20, 0), (vec!["Discount_row3".to_string()], Some(Value::Int(1))));
+    }
+
+    #[test]
+    fn test_unique_hit_policy_rejects_overlap() {
+        let csv = "when Customer.Tier,then Order.Discount\n1,5\n2,10\n 1 ,15\n";
+        let table = DecisionTable::from_csv("Tier", HitPolicy::Unique, csv).unwrap();
+        let err = table.to_rules().err().unwrap();
+        assert_eq!(err, "table Tier: rows 1 and 3 overlap under unique hit policy");
+    }
+
+    #[test]
+    fn test_unique_hit_policy_fails_on_overlapping_match() {
+        let csv = "when Order.Total >,then Order.Discount\n10,5\n5,1\n";
+        let table = DecisionTable::from_csv("Total", HitPolicy::Unique, csv).unwrap();
+        let rules = table.to_rules().unwrap();
+
+        let mut ctx = DataContext::new();
+        ctx.set("Order.Total".into(), Value::Int(20));
+        let err = RuleEngine::new().execute(&rules, &mut ctx).unwrap_err();
+        assert_eq!(err, "rules Total_row1 and Total_row2 of unique activation group Total both match");
+        assert_eq!(run(&rules, 7, 0), (vec!["Total_row2".to_string()], Some(Value::Int(1))));
+    }
+
+    #[test]
+    fn test_invalid_tables() {
+        assert!(DecisionTable::from_csv("T", HitPolicy::First, "when A.b =~,then A.c\n1,2\n").is_err());
+        assert!(DecisionTable::from_csv("T", HitPolicy::First, "when A.b,then A.c\n1\n").is_err());
+        assert!(DecisionTable::from_csv("T", HitPolicy::Priority, "when A.b,then A.c\n1,2\n").is_err());
+        assert!(DecisionTable::from_csv("T", HitPolicy::First, "when A.b,then A.c\n\"1,2\n").is_err());
+    }
+}
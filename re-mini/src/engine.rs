@@ -1,11 +1,729 @@
-use crate::context::DataContext;
+use std::{
+    cell::RefCell,
+    collections::{BTreeSet, HashMap},
+};
 
-struct RuleEngine {
-    
+use crate::{
+    analysis::condition_fields,
+    ast::Rule,
+    context::{instance_path, DataContext, Fact, FactId, FactKey},
+    limits::Limits,
+    profile::Profile,
+    time::Clock,
+    value::Value,
+};
+
+type FactState = Vec<(String, Value)>;
+
+// one firing of an activation: the values of the facts its condition
+// read, the revision of each matched instance and the instances its fact
+// sets held when it fired
+struct Fired {
+    state: FactState,
+    revisions: Vec<u64>,
+    sets: BTreeSet<FactId>,
+}
+
+// a rule's matches, and the activations among them that refraction
+// didn't skip, as of a version of one context's facts and the number of
+// times the rule had fired; `tuples` is unset for a rule without patterns
+// skipped before its condition was evaluated
+struct Matches {
+    context: u64,
+    version: u64,
+    fired: usize,
+    tuples: Option<Vec<Vec<FactId>>>,
+    pending: Vec<Activation>,
+}
+
+/// A rule ready to fire on one tuple of instances.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Activation {
+    pub rule: usize,
+    /// The instance bound to each of the rule's patterns, in order; empty
+    /// for a rule without patterns.
+    pub facts: Vec<FactId>,
+}
+
+impl Activation {
+    /// The rule's name, followed by the instances it matched:
+    /// `Discount (o: Order#1, Customer#2)`.
+    pub fn describe(&self, rules: &[Rule], ctx: &DataContext) -> String {
+        let rule = &rules[self.rule];
+        if self.facts.is_empty() {
+            return rule.name.clone();
+        }
+        let facts: Vec<String> = rule
+            .patterns()
+            .into_iter()
+            .zip(&self.facts)
+            .map(|((binding, fact_type), id)| {
+                let fact_type = ctx.fact(*id).map_or(fact_type, |f| f.fact_type.as_str());
+                if binding.starts_with('$') {
+                    format!("{}#{}", fact_type, id)
+                } else {
+                    format!("{}: {}#{}", binding, fact_type, id)
+                }
+            })
+            .collect();
+        format!("{} ({})", rule.name, facts.join(", "))
+    }
+}
+
+fn revisions(ctx: &DataContext, facts: &[FactId]) -> Vec<u64> {
+    facts.iter().map(|id| ctx.fact(*id).map_or(0, Fact::revision)).collect()
+}
+
+// the instance and field an instance path like `Order#3.Total` names
+fn instance_field(path: &str) -> Option<(FactId, &str)> {
+    let (_, rest) = path.split_once('#')?;
+    let (id, field) = rest.split_once('.')?;
+    Some((id.parse().ok()?, field))
+}
+
+// the facts the rule's condition reads for `activation`: pattern
+// variables read the instances bound to them
+fn activation_reads(rule: &Rule, activation: &Activation, reads: &BTreeSet<String>) -> BTreeSet<String> {
+    let patterns = rule.patterns();
+    reads
+        .iter()
+        .map(|name| {
+            let bound = name.split_once('.').and_then(|(var, field)| {
+                let at = patterns.iter().position(|(v, _)| *v == var)?;
+                Some(instance_path(patterns[at].1, activation.facts[at], field))
+            });
+            bound.unwrap_or_else(|| name.clone())
+        })
+        .collect()
+}
+
+// the values of the facts named by `reads` and of the fields of every
+// instance of `types`, ordered by path
+fn read_state(ctx: &DataContext, reads: &BTreeSet<String>, types: &BTreeSet<&str>) -> FactState {
+    let mut state: FactState = reads
+        .iter()
+        .filter_map(|path| {
+            let value = instance_field(path)
+                .and_then(|(id, field)| Some(ctx.fact(id)?.fields.get(field)))
+                .unwrap_or_else(|| ctx.get(path.clone()));
+            Some((path.clone(), value?.clone()))
+        })
+        .collect();
+    for fact_type in types {
+        for (id, fact) in ctx.facts_of(fact_type) {
+            for (field, value) in &fact.fields {
+                state.push((instance_path(fact_type, id, field), value.clone()));
+            }
+        }
+    }
+    state.sort_by(|a, b| a.0.cmp(&b.0));
+    state.dedup_by(|a, b| a.0 == b.0);
+    state
+}
+
+pub struct RuleEngine {
+    /// The runtime limits; the parse-time ones are for
+    /// [`crate::parser::parse_with_limits`].
+    pub limits: Limits,
+    /// Undo every change made during `execute` when it fails.
+    pub rollback_on_error: bool,
+    /// Source of `now()`, read once at the start of each run so every rule
+    /// in the run sees the same time.
+    pub clock: Clock,
+    // the rule of each activation that fired, in firing order
+    history: Vec<usize>,
+    // where in `history` the facts first had each fingerprint, just
+    // before a rule fired
+    states: HashMap<u64, usize>,
+    // what each activation fired on
+    fired: HashMap<Activation, Vec<Fired>>,
+    // how many times each rule has fired
+    fires: Vec<usize>,
+    // the rule that fired in each activation group
+    groups: HashMap<String, usize>,
+    last_fired: Option<Activation>,
+    // each rule's matches when the agenda was last built
+    matches: RefCell<Vec<Option<Matches>>>,
+    // a RefCell since `agenda` evaluates conditions through `&self`
+    profile: Option<RefCell<Profile>>,
+}
+
+impl Default for RuleEngine {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl RuleEngine {
-    fn execute(ctx: &DataContext) -> Result<(), ()> {
-        Ok(())
+    pub fn new() -> RuleEngine {
+        RuleEngine {
+            limits: Limits::default(),
+            rollback_on_error: false,
+            clock: Clock::System,
+            history: Vec::new(),
+            states: HashMap::new(),
+            fired: HashMap::new(),
+            fires: Vec::new(),
+            groups: HashMap::new(),
+            last_fired: None,
+            matches: RefCell::new(Vec::new()),
+            profile: None,
+        }
+    }
+
+    /// Turns timing of every evaluation and firing on or off. Turning it on
+    /// starts an empty profile, which accumulates across runs.
+    pub fn set_profiling(&mut self, on: bool) {
+        self.profile = if on { Some(RefCell::new(Profile::default())) } else { None };
+    }
+
+    /// What profiling has recorded so far, if it is on.
+    pub fn profile(&self) -> Option<Profile> {
+        self.profile.as_ref().map(|p| p.borrow().clone())
+    }
+
+    /// Forgets which rules fired, starting a new run.
+    pub fn reset(&mut self) {
+        self.history.clear();
+        self.states.clear();
+        self.fired.clear();
+        self.fires.clear();
+        self.groups.clear();
+        self.last_fired = None;
+        self.matches.borrow_mut().clear();
+    }
+
+    pub fn cycles(&self) -> usize {
+        self.history.len()
+    }
+
+    /// The activations that may fire on the current facts, in firing
+    /// order: highest salience first, then declaration order, then the
+    /// order the patterns matched in.
+    ///
+    /// An activation is skipped when it already fired while the facts its
+    /// condition reads had the same values (refraction), when its rule is
+    /// `no-loop` and it fired last, or when its rule is `lock-on-active` and
+    /// fired at any point in this run. The facts a condition reads include
+    /// the instances its fact sets hold. An activation whose instances were
+    /// passed to `update` since it fired may fire again.
+    ///
+    /// Once a rule of an activation group has fired, the group's other
+    /// rules are skipped for the rest of the run. In a unique group they
+    /// are an error instead when they match, as is two of its rules
+    /// matching at once.
+    ///
+    /// Refraction looks only at the facts a condition reads, not at all the
+    /// facts, so a rule's write to a fact it doesn't test, or another
+    /// rule's, doesn't fire it again on a match it already acted on. A rule
+    /// that writes a fact it tests still fires again, unless it is `no-loop`.
+    ///
+    /// A rule's matches are kept between calls, and the rule is matched
+    /// again only once the facts it reads or the instances of a type its
+    /// patterns, `exists` tests or aggregates match have changed. The
+    /// activations refraction lets through are kept with them until the
+    /// rule fires again.
+    pub fn agenda(&self, rules: &[Rule], ctx: &DataContext) -> Result<Vec<Activation>, String> {
+        let mut agenda = Vec::new();
+        // the first rule of each unique group with activations
+        let mut matched: HashMap<&str, usize> = HashMap::new();
+        if self.matches.borrow().len() != rules.len() {
+            let mut matches = self.matches.borrow_mut();
+            matches.clear();
+            matches.resize_with(rules.len(), || None);
+        }
+
+        for (i, rule) in rules.iter().enumerate() {
+            let fired = self.fires.get(i).copied().unwrap_or(0);
+            if rule.lock_on_active && fired > 0 {
+                continue;
+            }
+            let group = rule.activation_group.as_ref();
+            let held = group.and_then(|g| self.groups.get(&g.name)).copied().filter(|j| *j != i);
+            if held.is_some() && !group.is_some_and(|g| g.unique) {
+                continue;
+            }
+            // a rule without patterns has one activation at most, which is
+            // checked before its condition is evaluated
+            let only = Activation { rule: i, facts: Vec::new() };
+            if rule.patterns().is_empty() && rule.no_loop && self.last_fired.as_ref() == Some(&only) {
+                continue;
+            }
+            let activations: Vec<Activation> = self
+                .pending(i, rule, ctx, fired)?
+                .into_iter()
+                .filter(|a| !(rule.no_loop && self.last_fired.as_ref() == Some(a)))
+                .collect();
+            if let Some(group) = group.filter(|g| g.unique && !activations.is_empty()) {
+                if let Some(j) = held.or_else(|| matched.get(group.name.as_str()).copied()) {
+                    return Err(format!(
+                        "rules {} and {} of unique activation group {} both match",
+                        rules[j].name, rule.name, group.name
+                    ));
+                }
+                matched.insert(&group.name, i);
+            }
+            agenda.extend(activations);
+        }
+
+        agenda.sort_by_key(|a| std::cmp::Reverse(rules[a.rule].salience));
+        Ok(agenda)
+    }
+
+    // the rule's activations that refraction doesn't skip, kept from an
+    // earlier agenda unless something the rule depends on has changed or
+    // the rule has fired since
+    fn pending(&self, i: usize, rule: &Rule, ctx: &DataContext, fired: usize) -> Result<Vec<Activation>, String> {
+        let patterns = rule.patterns();
+        let reads = condition_fields(&rule.condition);
+        let types = rule.fact_set_types();
+        let mut depends: Vec<FactKey> = patterns.iter().map(|(_, t)| FactKey::Type(t.to_string())).collect();
+        depends.extend(types.iter().map(|t| FactKey::Type(t.to_string())));
+        for name in &reads {
+            let var = name.split_once('.').map(|(var, _)| var);
+            if !patterns.iter().any(|(v, _)| Some(*v) == var) {
+                depends.push(FactKey::Named(name.clone()));
+            }
+            // the type an `exists` or aggregate reads the instances of
+            if var.is_none() {
+                depends.push(FactKey::Type(name.clone()));
+            }
+        }
+
+        let mut tuples = None;
+        if let Some(cached) = &self.matches.borrow()[i] {
+            if cached.context == ctx.id() && !depends.iter().any(|key| ctx.changed_since(key, cached.version)) {
+                if cached.fired == fired {
+                    return Ok(cached.pending.clone());
+                }
+                tuples = cached.tuples.clone();
+            }
+        }
+
+        let refracted = |activation: &Activation| {
+            let Some(fired) = self.fired.get(activation) else {
+                return false;
+            };
+            let reads = activation_reads(rule, activation, &reads);
+            let revisions = revisions(ctx, &activation.facts);
+            let sets = rule.fact_set_instances(ctx, &activation.facts);
+            let state = read_state(ctx, &reads, &types);
+            // fact sets read the instances they held when the activation
+            // fired or hold now
+            let matched = |s: &FactState, sets: &BTreeSet<FactId>| -> FactState {
+                s.iter()
+                    .filter(|(k, _)| reads.contains(k) || instance_field(k).is_some_and(|(id, _)| sets.contains(&id)))
+                    .cloned()
+                    .collect()
+            };
+            fired.iter().any(|f| {
+                let sets = f.sets.union(&sets).copied().collect();
+                f.revisions == revisions && matched(&f.state, &sets) == matched(&state, &sets)
+            })
+        };
+
+        let mut pending = Vec::new();
+        if !(patterns.is_empty() && refracted(&Activation { rule: i, facts: Vec::new() })) {
+            let evaluated = match tuples {
+                Some(tuples) => tuples,
+                None => match &self.profile {
+                    Some(profile) => profile.borrow_mut().matches(i, rule, ctx)?,
+                    None => rule.matches(ctx)?,
+                },
+            };
+            for facts in &evaluated {
+                let activation = Activation { rule: i, facts: facts.clone() };
+                if patterns.is_empty() || !refracted(&activation) {
+                    pending.push(activation);
+                }
+            }
+            tuples = Some(evaluated);
+        }
+        self.matches.borrow_mut()[i] = Some(Matches {
+            context: ctx.id(),
+            version: ctx.version(),
+            fired,
+            tuples,
+            pending: pending.clone(),
+        });
+        Ok(pending)
+    }
+
+    /// Fires the first activation on the agenda, returning it, or `None`
+    /// when nothing is left to fire.
+    ///
+    /// Returning to a fact state seen earlier in the run means the rules
+    /// fired since then undo each other's changes; this is reported as an
+    /// oscillation error.
+    pub fn step(&mut self, rules: &[Rule], ctx: &mut DataContext) -> Result<Option<Activation>, String> {
+        if self.history.is_empty() {
+            ctx.set_now(Some(self.clock.now()));
+            ctx.set_budget(self.limits.budget());
+        }
+        if let Some(budget) = ctx.budget() {
+            budget.check_deadline()?;
+        }
+        let Some(activation) = self.agenda(rules, ctx)?.into_iter().next() else {
+            return Ok(None);
+        };
+        if self.history.len() >= self.limits.max_cycles {
+            return Err(format!("max cycles ({}) reached", self.limits.max_cycles));
+        }
+
+        let i = activation.rule;
+        let rule = &rules[i];
+        // fact states are compared by their fingerprints
+        let before = ctx.fingerprint();
+        let fired = Fired {
+            state: read_state(ctx, &activation_reads(rule, &activation, &condition_fields(&rule.condition)), &rule.fact_set_types()),
+            revisions: revisions(ctx, &activation.facts),
+            sets: rule.fact_set_instances(ctx, &activation.facts),
+        };
+        match &self.profile {
+            Some(profile) => profile.borrow_mut().execute(i, rule, ctx, &activation.facts)?,
+            None => rule.fire(ctx, &activation.facts)?,
+        }
+        let after = ctx.fingerprint();
+
+        if after != before {
+            if let Some(start) = self.states.get(&after) {
+                let mut names: Vec<&str> = Vec::new();
+                for fired in &self.history[*start..] {
+                    let name = rules[*fired].name.as_str();
+                    if !names.contains(&name) {
+                        names.push(name);
+                    }
+                }
+                if !names.contains(&rule.name.as_str()) {
+                    names.push(&rule.name);
+                }
+                return Err(format!("oscillation detected between rules {}", names.join(", ")));
+            }
+        }
+
+        self.states.entry(before).or_insert(self.history.len());
+        self.history.push(i);
+        self.fired.entry(activation.clone()).or_default().push(fired);
+        if self.fires.len() <= i {
+            self.fires.resize(i + 1, 0);
+        }
+        self.fires[i] += 1;
+        if let Some(group) = &rule.activation_group {
+            self.groups.entry(group.name.clone()).or_insert(i);
+        }
+        self.last_fired = Some(activation.clone());
+        Ok(Some(activation))
+    }
+
+    /// Fires rules until the agenda is empty, returning the names of the
+    /// fired rules in order.
+    ///
+    /// Each rule's actions are applied atomically. With `rollback_on_error`
+    /// set, a failure also undoes the rules that fired before it.
+    pub fn execute(&mut self, rules: &[Rule], ctx: &mut DataContext) -> Result<Vec<String>, String> {
+        self.reset();
+        let snapshot = if self.rollback_on_error {
+            Some(ctx.snapshot())
+        } else {
+            None
+        };
+
+        let mut fired = Vec::new();
+        let result = loop {
+            match self.step(rules, ctx) {
+                Ok(Some(activation)) => fired.push(rules[activation.rule].name.clone()),
+                Ok(None) => break Ok(fired),
+                Err(e) => {
+                    if let Some(snapshot) = snapshot {
+                        ctx.restore(snapshot);
+                    }
+                    break Err(e);
+                }
+            }
+        };
+        // the limits and the time are the run's, not the facts'
+        ctx.set_budget(None);
+        ctx.set_now(None);
+        result
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse, parse_condition};
+
+    fn run(input: &str, facts: &[(&str, i64)]) -> (Result<Vec<String>, String>, DataContext) {
+        let rules = parse(input.to_string()).unwrap();
+        let mut ctx = DataContext::new();
+        for (name, v) in facts {
+            ctx.set(name.to_string(), Value::Int(*v));
+        }
+        let mut engine = RuleEngine::new();
+        engine.limits.max_cycles = 50;
+        (engine.execute(&rules, &mut ctx), ctx)
+    }
+
+    #[test]
+    fn test_refraction_stops_rule_on_unchanged_facts() {
+        let (fired, ctx) = run("rule R { when X.a > 0 then X.b = 1; }", &[("X.a", 1), ("X.b", 0)]);
+        // X.b changed, but R doesn't read it, so it doesn't fire again
+        assert_eq!(fired.unwrap(), vec!["R"]);
+        assert_eq!(ctx.get("X.b".into()), Some(&Value::Int(1)));
+
+        let (fired, ctx) = run("rule R { when X.a > 0 then X.b = X.b + 1; }", &[("X.a", 1), ("X.b", 0)]);
+        assert_eq!(fired.unwrap(), vec!["R"]);
+        assert_eq!(ctx.get("X.b".into()), Some(&Value::Int(1)));
+    }
+
+    #[test]
+    fn test_refraction_releases_rule_when_read_facts_change() {
+        let (fired, ctx) = run("rule Down { when X.a > 0 then X.a = X.a - 1; }", &[("X.a", 3)]);
+        assert_eq!(fired.unwrap(), vec!["Down", "Down", "Down"]);
+        assert_eq!(ctx.get("X.a".into()), Some(&Value::Int(0)));
+
+        // a write by another rule to a fact R reads fires R again
+        let input = "rule R { when X.a > 0 then X.b = X.b + 1; } rule S { when X.b == 1 then X.a = 2; }";
+        let (fired, ctx) = run(input, &[("X.a", 1), ("X.b", 0)]);
+        assert_eq!(fired.unwrap(), vec!["R", "S", "R"]);
+        assert_eq!(ctx.get("X.b".into()), Some(&Value::Int(2)));
+    }
+
+    #[test]
+    fn test_no_loop() {
+        let input = "rule Count { when X.n < 10 then X.n = X.n + 1; }";
+        let (fired, ctx) = run(input, &[("X.n", 0)]);
+        assert_eq!(fired.unwrap().len(), 10);
+        assert_eq!(ctx.get("X.n".into()), Some(&Value::Int(10)));
+
+        let input = "rule Count no-loop { when X.n < 10 then X.n = X.n + 1; }";
+        let (fired, ctx) = run(input, &[("X.n", 0)]);
+        assert_eq!(fired.unwrap(), vec!["Count"]);
+        assert_eq!(ctx.get("X.n".into()), Some(&Value::Int(1)));
+    }
+
+    #[test]
+    fn test_lock_on_active() {
+        let input = r#"
+    rule Count lock-on-active { when X.n < 10 then X.n = X.n + 1; }
+    rule Other no-loop { when X.n < 10 then X.m = X.n; }
+    "#;
+        let (fired, ctx) = run(input, &[("X.n", 0), ("X.m", 0)]);
+        assert_eq!(fired.unwrap(), vec!["Count", "Other"]);
+        assert_eq!(ctx.get("X.m".into()), Some(&Value::Int(1)));
+    }
+
+    #[test]
+    fn test_activation_group() {
+        let input = r#"
+    rule A activation-group "G" { when X.n < 2 then X.n = X.n + 1; }
+    rule B activation-group "G" { when X.n == 1 then X.m = 1; }
+    "#;
+        let (fired, ctx) = run(input, &[("X.n", 0), ("X.m", 0)]);
+        assert_eq!(fired.unwrap(), vec!["A", "A"]);
+        assert_eq!(ctx.get("X.m".into()), Some(&Value::Int(0)));
+
+        // in a unique group, B matching after A fired is an error
+        let (fired, _) = run(&input.replace("\"G\"", "\"G\" unique"), &[("X.n", 0), ("X.m", 0)]);
+        assert_eq!(fired.unwrap_err(), "rules A and B of unique activation group G both match");
+    }
+
+    #[test]
+    fn test_salience_orders_agenda() {
+        let input = r#"
+    rule Low { when X.n == 0 then X.low = 1; }
+    rule High salience 10 { when X.n == 0 then X.n = 1; }
+    "#;
+        let (fired, ctx) = run(input, &[("X.n", 0)]);
+        assert_eq!(fired.unwrap(), vec!["High"]);
+        assert_eq!(ctx.get("X.low".into()), None);
+    }
+
+    #[test]
+    fn test_oscillation_is_reported() {
+        let input = r#"
+    rule On { when X.s == 0 then X.s = 1; }
+    rule Off { when X.s == 1 then X.s = 0; }
+    "#;
+        let (fired, _) = run(input, &[("X.s", 0)]);
+        assert_eq!(fired.unwrap_err(), "oscillation detected between rules On, Off");
+    }
+
+    #[test]
+    fn test_rollback_on_error() {
+        let input = r#"
+    rule First { when X.a == 0 then X.a = 1; }
+    rule Second { when X.a == 1 then X.b = X.missing; }
+    "#;
+        let rules = parse(input.to_string()).unwrap();
+        let mut ctx = DataContext::new();
+        ctx.set("X.a".into(), Value::Int(0));
+
+        let mut engine = RuleEngine::new();
+        assert!(engine.execute(&rules, &mut ctx).is_err());
+        assert_eq!(ctx.get("X.a".into()), Some(&Value::Int(1)));
+
+        ctx.set("X.a".into(), Value::Int(0));
+        engine.rollback_on_error = true;
+        let err = engine.execute(&rules, &mut ctx).unwrap_err();
+        assert_eq!(err, "rule Second: field X.missing not found");
+        assert_eq!(ctx.get("X.a".into()), Some(&Value::Int(0)));
+    }
+
+    #[test]
+    fn test_agenda_keeps_unchanged_rules() {
+        let rules = parse(
+            r#"
+    rule Count salience 10 { when C.n < 20 then C.n = C.n + 1; }
+    rule Flag { when o: Order(Total > 100) then Flagged.n = 1; }
+    "#
+            .to_string(),
+        )
+        .unwrap();
+        let facts = crate::json::Json::parse(r#"{"C": {"n": 0}, "Order": [{"Total": 150}, {"Total": 50}]}"#).unwrap();
+        let mut ctx = crate::json::context_from_json(&facts).unwrap();
+
+        let mut engine = RuleEngine::new();
+        engine.set_profiling(true);
+        let fired = engine.execute(&rules, &mut ctx).unwrap();
+        assert_eq!(fired.len(), 21);
+        assert_eq!(fired.last().map(String::as_str), Some("Flag"));
+        // Count's writes leave Flag's matches as they were
+        let profile = engine.profile().unwrap();
+        assert_eq!(profile.rules[0].evaluate.count, 21);
+        assert_eq!(profile.rules[1].evaluate.count, 1);
+    }
+
+    #[test]
+    fn test_max_cycles() {
+        let (fired, _) = run("rule Grow { when X.n > 0 then X.n = X.n + 1; }", &[("X.n", 1)]);
+        assert_eq!(fired.unwrap_err(), "max cycles (50) reached");
+    }
+
+    #[test]
+    fn test_fixed_clock() {
+        let rules = parse(
+            r#"
+    rule Weekend { when weekday(today()) >= 6 then Shop.Open = false; }
+    rule Old { when now() - datetime(Account.Opened) > 90d then Account.Old = true; }
+    "#
+            .to_string(),
+        )
+        .unwrap();
+        let mut ctx = DataContext::new();
+        ctx.set("Account.Opened".into(), Value::Str("2024-01-01T00:00:00Z".into()));
+
+        let fixed = crate::time::DateTime::parse("2024-06-01T10:00:00Z").unwrap();
+        let mut engine = RuleEngine::new();
+        engine.clock = Clock::Fixed(fixed);
+        assert_eq!(engine.execute(&rules, &mut ctx).unwrap(), vec!["Weekend", "Old"]);
+        assert_eq!(ctx.get("Account.Old".into()), Some(&Value::Bool(true)));
+        // outside a run, `now()` is the system clock again
+        assert_ne!(ctx.now(), fixed);
+    }
+
+    #[test]
+    fn test_pattern_activations_per_tuple() {
+        let rules = parse(
+            r#"
+    rule Match { when o: Order(Total > 100) && c: Customer(Id == o.CustomerId) then o.Owner = c.Name; }
+    rule Bump no-loop { when o: Order(Total < 200) && Shop.Open == true then o.Total = o.Total + 100; }
+    "#
+            .to_string(),
+        )
+        .unwrap();
+        let facts = crate::json::Json::parse(
+            r#"{
+                "Order": [{"Total": 150, "CustomerId": 7}, {"Total": 50, "CustomerId": 7}, {"Total": 300, "CustomerId": 8}],
+                "Customer": [{"Id": 7, "Name": "ann"}, {"Id": 8, "Name": "bob"}],
+                "Shop": {"Open": false}
+            }"#,
+        )
+        .unwrap();
+        let mut ctx = crate::json::context_from_json(&facts).unwrap();
+
+        let mut engine = RuleEngine::new();
+        let agenda: Vec<String> = engine.agenda(&rules, &ctx).unwrap().iter().map(|a| a.describe(&rules, &ctx)).collect();
+        assert_eq!(agenda, vec!["Match (o: Order#1, c: Customer#4)", "Match (o: Order#3, c: Customer#5)"]);
+        assert_eq!(engine.execute(&rules, &mut ctx).unwrap(), vec!["Match", "Match"]);
+        let owner = |id| ctx.fact(id).unwrap().fields.get("Owner").cloned();
+        assert_eq!(owner(1), Some(Value::Str("ann".into())));
+        assert_eq!(owner(2), None);
+        assert_eq!(owner(3), Some(Value::Str("bob".into())));
+
+        // no-loop holds an activation back after it fired, not its whole rule
+        ctx.set("Shop.Open".into(), Value::Bool(true));
+        assert_eq!(engine.execute(&rules[1..], &mut ctx).unwrap(), vec!["Bump", "Bump"]);
+        let total = |id| ctx.fact(id).unwrap().fields["Total"].clone();
+        assert_eq!((total(1), total(2), total(3)), (Value::Int(250), Value::Int(150), Value::Int(300)));
+        assert_eq!(ctx.get("Shop.Open".into()), Some(&Value::Bool(true)));
+    }
+
+    #[test]
+    fn test_insert_update_retract() {
+        let rules = parse(
+            r#"
+    rule Archive salience 10 { when o: Order(Closed == true) then insert(Archived { Id: o.Id }); retract(o); }
+    rule Tally { when o: Order() then Stats.Seen = Stats.Seen + 1; }
+    rule Touch { when o: Order(Id == 1) && Stats.Touched == 0 then Stats.Touched = 1; update(o); }
+    "#
+            .to_string(),
+        )
+        .unwrap();
+        let facts = crate::json::Json::parse(
+            r#"{"Order": [{"Id": 1, "Closed": false}, {"Id": 2, "Closed": true}], "Stats": {"Seen": 0, "Touched": 0}}"#,
+        )
+        .unwrap();
+        let mut ctx = crate::json::context_from_json(&facts).unwrap();
+
+        let mut engine = RuleEngine::new();
+        engine.set_profiling(true);
+        // the update lets Tally fire on Order#1 again
+        assert_eq!(engine.execute(&rules, &mut ctx).unwrap(), vec!["Archive", "Tally", "Touch", "Tally"]);
+        assert_eq!(ctx.get("Stats.Seen".into()), Some(&Value::Int(2)));
+        assert!(ctx.fact(2).is_none());
+        let archived: Vec<&Value> = ctx.facts_of("Archived").map(|(_, f)| &f.fields["Id"]).collect();
+        assert_eq!(archived, vec![&Value::Int(2)]);
+
+        // matched at the start, after the retract and after the update;
+        // Tally's writes to Stats change nothing the rules read
+        let evaluated: Vec<usize> = engine.profile().unwrap().rules.iter().map(|r| r.evaluate.count).collect();
+        assert_eq!(evaluated, vec![3, 3, 3]);
+    }
+
+    #[test]
+    fn test_aggregates_and_exists() {
+        let rules = parse(
+            r#"
+    rule Totals { when Order.Items.count() > 0 then Order.Sum = Order.Items.sum(i => i.Price); Order.Max = Order.Items.max(i => i.Price); Order.Cheap = Order.Items.count(i => i.Price < 50); }
+    rule Big { when Order.Items.any(i => i.Price > 100) && Order.Items.avg(i => i.Price) < 100 then Order.Big = true; }
+    rule Fraud { when a: Account(Transaction(Owner == a.Id && Amount > 1000).count() >= 2) then a.Flagged = true; }
+    rule Quiet { when a: Account() && not exists Transaction(Owner == a.Id) then insert(Transaction { Owner: a.Id, Amount: 0 }); }
+    "#
+            .to_string(),
+        )
+        .unwrap();
+        let facts = crate::json::Json::parse(
+            r#"{"Order": {"Items": [{"Price": 20}, {"Price": 150}, {"Price": 40}]},
+                "Account": [{"Id": 1}, {"Id": 2}],
+                "Transaction": [{"Owner": 1, "Amount": 2000}, {"Owner": 1, "Amount": 6000}, {"Owner": 1, "Amount": 10}]}"#,
+        )
+        .unwrap();
+        let mut ctx = crate::json::context_from_json(&facts).unwrap();
+
+        // the transaction Quiet inserts for account 2 is not one account 1's
+        // fact set holds, so Fraud does not fire on it again; the one it
+        // inserts keeps Quiet from firing again
+        assert_eq!(RuleEngine::new().execute(&rules, &mut ctx).unwrap(), vec!["Totals", "Big", "Fraud", "Quiet"]);
+        assert_eq!(ctx.get("Order.Sum".into()), Some(&Value::Int(210)));
+        assert_eq!(ctx.get("Order.Max".into()), Some(&Value::Int(150)));
+        assert_eq!(ctx.get("Order.Cheap".into()), Some(&Value::Int(2)));
+        assert_eq!(ctx.fact(1).unwrap().fields.get("Flagged"), Some(&Value::Bool(true)));
+        assert_eq!(ctx.facts_of("Transaction").count(), 4);
+
+        let mut ctx = DataContext::new();
+        ctx.set("Order.Items".into(), Value::List(Vec::new()));
+        let err = parse_condition("Order.Items.max(i => i) > 1".to_string()).unwrap().evaluate(&ctx).unwrap_err();
+        assert_eq!(err, "max() of an empty list");
+    }
+}
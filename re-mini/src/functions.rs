@@ -0,0 +1,82 @@
+//! Built-in functions callable from rule expressions, e.g. `year(d)`.
+
+use crate::{
+    context::DataContext,
+    schema::ValueType,
+    time::{Date, DateTime},
+    value::Value,
+};
+
+// name, number of arguments, result type
+const FUNCTIONS: [(&str, usize, ValueType); 9] = [
+    ("now", 0, ValueType::DateTime),
+    ("today", 0, ValueType::Date),
+    ("date", 1, ValueType::Date),
+    ("datetime", 1, ValueType::DateTime),
+    ("year", 1, ValueType::Int),
+    ("month", 1, ValueType::Int),
+    ("day", 1, ValueType::Int),
+    ("weekday", 1, ValueType::Int),
+    ("hour", 1, ValueType::Int),
+];
+
+/// Arity and result type of a built-in function.
+pub fn signature(name: &str) -> Option<(usize, ValueType)> {
+    FUNCTIONS.iter().find(|(n, _, _)| *n == name).map(|(_, arity, ty)| (*arity, *ty))
+}
+
+/// Checks a call's name and argument count, as the parser does.
+pub fn check_call(name: &str, args: usize) -> Result<(), String> {
+    match signature(name) {
+        None => Err(format!("unknown function '{}'", name)),
+        Some((arity, _)) if arity != args => Err(format!(
+            "function {} takes {} argument{}, got {}",
+            name,
+            arity,
+            if arity == 1 { "" } else { "s" },
+            args
+        )),
+        Some(_) => Ok(()),
+    }
+}
+
+pub fn call(name: &str, args: &[Value], ctx: &DataContext) -> Result<Value, String> {
+    check_call(name, args.len())?;
+    let bad_arg = || format!("{}() can't take {:?}", name, args[0]);
+
+    match name {
+        "now" => Ok(Value::DateTime(ctx.now())),
+        "today" => Ok(Value::Date(ctx.now().date())),
+        // dates in JSON facts arrive as strings
+        "date" => match &args[0] {
+            Value::Date(d) => Ok(Value::Date(*d)),
+            Value::DateTime(t) => Ok(Value::Date(t.date())),
+            Value::Str(s) => Date::parse(s).map(Value::Date),
+            _ => Err(bad_arg()),
+        },
+        "datetime" => match &args[0] {
+            Value::Date(d) => d.at_midnight(0).map(Value::DateTime),
+            Value::DateTime(t) => Ok(Value::DateTime(*t)),
+            Value::Str(s) => DateTime::parse(s).map(Value::DateTime),
+            _ => Err(bad_arg()),
+        },
+        "hour" => match &args[0] {
+            Value::DateTime(t) => Ok(Value::Int(t.hour() as i64)),
+            _ => Err(bad_arg()),
+        },
+        _ => {
+            let date = match &args[0] {
+                Value::Date(d) => *d,
+                Value::DateTime(t) => t.date(),
+                _ => return Err(bad_arg()),
+            };
+            let n = match name {
+                "year" => date.year(),
+                "month" => date.month() as i64,
+                "day" => date.day() as i64,
+                _ => date.weekday() as i64,
+            };
+            Ok(Value::Int(n))
+        }
+    }
+}
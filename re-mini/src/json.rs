@@ -0,0 +1,537 @@
+use std::{collections::BTreeMap, fmt};
+
+use crate::{context::DataContext, limits::Limits, value::Value};
+
+/// A parsed JSON document. Object members keep their source order.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn parse(input: &str) -> Result<Json, String> {
+        Json::parse_with_limits(input, &Limits::default())
+    }
+
+    /// Parses with arrays and objects nested at most `limits.max_depth`
+    /// levels deep.
+    pub fn parse_with_limits(input: &str, limits: &Limits) -> Result<Json, String> {
+        let mut p = JsonParser {
+            chars: input.chars().collect(),
+            pos: 0,
+            depth: 0,
+            max_depth: limits.max_depth,
+        };
+        let value = p.value()?;
+        p.skip_ws();
+        if p.pos < p.chars.len() {
+            return Err(format!("unexpected trailing characters at pos {}", p.pos));
+        }
+        Ok(value)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Json::Int(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    /// Indented rendering; `Display` gives the compact form.
+    pub fn pretty(&self) -> String {
+        let mut out = String::new();
+        self.write_pretty(&mut out, 0);
+        out
+    }
+
+    fn write_pretty(&self, out: &mut String, indent: usize) {
+        let pad = "  ".repeat(indent + 1);
+        match self {
+            Json::Array(items) if !items.is_empty() => {
+                out.push_str("[\n");
+                for (i, item) in items.iter().enumerate() {
+                    out.push_str(&pad);
+                    item.write_pretty(out, indent + 1);
+                    out.push_str(if i + 1 < items.len() { ",\n" } else { "\n" });
+                }
+                out.push_str(&"  ".repeat(indent));
+                out.push(']');
+            }
+            Json::Object(members) if !members.is_empty() => {
+                out.push_str("{\n");
+                for (i, (k, v)) in members.iter().enumerate() {
+                    out.push_str(&pad);
+                    out.push_str(&quote(k));
+                    out.push_str(": ");
+                    v.write_pretty(out, indent + 1);
+                    out.push_str(if i + 1 < members.len() { ",\n" } else { "\n" });
+                }
+                out.push_str(&"  ".repeat(indent));
+                out.push('}');
+            }
+            other => out.push_str(&other.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Int(n) => write!(f, "{}", n),
+            Json::Str(s) => write!(f, "{}", quote(s)),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(members) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in members.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}:{}", quote(k), v)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+pub fn quote(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+struct JsonParser {
+    chars: Vec<char>,
+    pos: usize,
+    depth: usize,
+    max_depth: usize,
+}
+
+impl JsonParser {
+    fn skip_ws(&mut self) {
+        while self.pos < self.chars.len() && self.chars[self.pos].is_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        self.skip_ws();
+        if self.chars.get(self.pos) == Some(&c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(format!("expected '{}' at pos {}", c, self.pos))
+        }
+    }
+
+    fn keyword(&mut self, word: &str, value: Json) -> Result<Json, String> {
+        let end = self.pos + word.len();
+        if end <= self.chars.len() && self.chars[self.pos..end].iter().copied().eq(word.chars()) {
+            self.pos = end;
+            Ok(value)
+        } else {
+            Err(format!("unexpected token at pos {}", self.pos))
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_ws();
+        let nested = matches!(self.chars.get(self.pos), Some('[' | '{'));
+        if nested {
+            if self.depth == self.max_depth {
+                return Err(format!("JSON nested deeper than {} levels at pos {}", self.max_depth, self.pos));
+            }
+            self.depth += 1;
+        }
+        let value = self.item();
+        if nested {
+            self.depth -= 1;
+        }
+        value
+    }
+
+    fn item(&mut self) -> Result<Json, String> {
+        match self.chars.get(self.pos) {
+            None => Err("unexpected end of JSON input".into()),
+            Some('n') => self.keyword("null", Json::Null),
+            Some('t') => self.keyword("true", Json::Bool(true)),
+            Some('f') => self.keyword("false", Json::Bool(false)),
+            Some('"') => Ok(Json::Str(self.string()?)),
+            Some('[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                self.skip_ws();
+                if self.chars.get(self.pos) == Some(&']') {
+                    self.pos += 1;
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    self.skip_ws();
+                    match self.chars.get(self.pos) {
+                        Some(',') => self.pos += 1,
+                        Some(']') => {
+                            self.pos += 1;
+                            return Ok(Json::Array(items));
+                        }
+                        _ => return Err(format!("expected ',' or ']' at pos {}", self.pos)),
+                    }
+                }
+            }
+            Some('{') => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                self.skip_ws();
+                if self.chars.get(self.pos) == Some(&'}') {
+                    self.pos += 1;
+                    return Ok(Json::Object(members));
+                }
+                loop {
+                    self.skip_ws();
+                    if self.chars.get(self.pos) != Some(&'"') {
+                        return Err(format!("expected object key at pos {}", self.pos));
+                    }
+                    let key = self.string()?;
+                    self.expect(':')?;
+                    let value = self.value()?;
+                    members.push((key, value));
+                    self.skip_ws();
+                    match self.chars.get(self.pos) {
+                        Some(',') => self.pos += 1,
+                        Some('}') => {
+                            self.pos += 1;
+                            return Ok(Json::Object(members));
+                        }
+                        _ => return Err(format!("expected ',' or '}}' at pos {}", self.pos)),
+                    }
+                }
+            }
+            Some(c) if *c == '-' || c.is_ascii_digit() => self.number(),
+            Some(c) => Err(format!("unexpected character '{}' at pos {}", c, self.pos)),
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let start = self.pos;
+        if self.chars[self.pos] == '-' {
+            self.pos += 1;
+        }
+        while self.pos < self.chars.len() && self.chars[self.pos].is_ascii_digit() {
+            self.pos += 1;
+        }
+        if matches!(self.chars.get(self.pos), Some('.' | 'e' | 'E')) {
+            return Err(format!("only integer numbers are supported, at pos {}", start));
+        }
+        let text: String = self.chars[start..self.pos].iter().collect();
+        text.parse()
+            .map(Json::Int)
+            .map_err(|_| format!("invalid number '{}' at pos {}", text, start))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let start = self.pos;
+        self.pos += 1;
+        let mut out = String::new();
+        loop {
+            let Some(&c) = self.chars.get(self.pos) else {
+                return Err(format!("unterminated string starting at pos {}", start));
+            };
+            self.pos += 1;
+            match c {
+                '"' => return Ok(out),
+                '\\' => {
+                    let Some(&e) = self.chars.get(self.pos) else {
+                        return Err(format!("unterminated string starting at pos {}", start));
+                    };
+                    self.pos += 1;
+                    match e {
+                        '"' => out.push('"'),
+                        '\\' => out.push('\\'),
+                        '/' => out.push('/'),
+                        'b' => out.push('\u{8}'),
+                        'f' => out.push('\u{c}'),
+                        'n' => out.push('\n'),
+                        'r' => out.push('\r'),
+                        't' => out.push('\t'),
+                        'u' => {
+                            let at = self.pos;
+                            let mut code = self.hex4()?;
+                            // a character outside the BMP is a surrogate pair
+                            if (0xd800..0xdc00).contains(&code) {
+                                if self.chars.get(self.pos) != Some(&'\\') || self.chars.get(self.pos + 1) != Some(&'u') {
+                                    return Err(format!("unpaired surrogate in \\u escape at pos {}", at));
+                                }
+                                self.pos += 2;
+                                let low = self.hex4()?;
+                                if !(0xdc00..0xe000).contains(&low) {
+                                    return Err(format!("unpaired surrogate in \\u escape at pos {}", at));
+                                }
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                            }
+                            let c = char::from_u32(code)
+                                .ok_or_else(|| format!("unpaired surrogate in \\u escape at pos {}", at))?;
+                            out.push(c);
+                        }
+                        other => return Err(format!("invalid escape '\\{}' at pos {}", other, self.pos)),
+                    }
+                }
+                c => out.push(c),
+            }
+        }
+    }
+
+    // the four hex digits of a `\u` escape
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = self.chars.get(self.pos..self.pos + 4).filter(|d| d.iter().all(char::is_ascii_hexdigit));
+        let Some(digits) = digits else {
+            return Err(format!("invalid \\u escape at pos {}", self.pos));
+        };
+        let code = digits.iter().fold(0, |code, d| code * 16 + d.to_digit(16).unwrap_or(0));
+        self.pos += 4;
+        Ok(code)
+    }
+}
+
+/// Dates and times become ISO 8601 strings, which rules can read back
+/// with `date()` and `datetime()`.
+pub fn value_to_json(value: &Value) -> Json {
+    match value {
+        Value::Int(n) => Json::Int(*n),
+        Value::Bool(b) => Json::Bool(*b),
+        Value::Str(s) => Json::Str(s.clone()),
+        Value::Date(d) => Json::Str(d.to_string()),
+        Value::DateTime(t) => Json::Str(t.to_string()),
+        Value::Duration(d) => Json::Str(d.to_string()),
+        Value::List(items) => Json::Array(items.iter().map(value_to_json).collect()),
+        Value::Record(fields) => Json::Object(fields.iter().map(|(k, v)| (k.clone(), value_to_json(v))).collect()),
+    }
+}
+
+/// Arrays become lists, and objects in them records; null fields of a
+/// record are left out.
+pub fn value_from_json(json: &Json) -> Result<Value, String> {
+    match json {
+        Json::Int(n) => Ok(Value::Int(*n)),
+        Json::Bool(b) => Ok(Value::Bool(*b)),
+        Json::Str(s) => Ok(Value::Str(s.clone())),
+        Json::Array(items) => items
+            .iter()
+            .enumerate()
+            .map(|(i, item)| value_from_json(item).map_err(|e| format!("[{}]: {}", i, e)))
+            .collect::<Result<_, _>>()
+            .map(Value::List),
+        Json::Object(fields) => fields
+            .iter()
+            .filter(|(_, v)| *v != Json::Null)
+            .map(|(k, v)| Ok((k.clone(), value_from_json(v).map_err(|e| format!("{}: {}", k, e))?)))
+            .collect::<Result<_, String>>()
+            .map(Value::Record),
+        other => Err(format!("unsupported fact value {}", other)),
+    }
+}
+
+/// Loads facts from a JSON object, flattening nested objects into dotted
+/// paths: `{"Order": {"Total": 5}}` sets `Order.Total`. A top-level array
+/// of objects is a list of instances for working memory:
+/// `{"Order": [{"Total": 5}, {"Total": 7}]}` inserts two orders. Any
+/// other array is a list value, like `{"Order": {"Items": [...]}}`.
+pub fn context_from_json(json: &Json) -> Result<DataContext, String> {
+    let mut ctx = DataContext::new();
+    let Json::Object(members) = json else {
+        return Err("facts must be a JSON object".into());
+    };
+    for member in members {
+        let (fact_type, Json::Array(items)) = member else {
+            flatten_into(&mut ctx, "", std::slice::from_ref(member))?;
+            continue;
+        };
+        for (i, item) in items.iter().enumerate() {
+            let at = format!("{}[{}]", fact_type, i);
+            let Json::Object(fields) = item else {
+                return Err(format!("{}: an instance must be a JSON object", at));
+            };
+            let mut values = BTreeMap::new();
+            for (k, v) in fields {
+                if *v != Json::Null {
+                    values.insert(k.clone(), value_from_json(v).map_err(|e| format!("{}.{}: {}", at, k, e))?);
+                }
+            }
+            ctx.insert_fact(fact_type, values);
+        }
+    }
+    Ok(ctx)
+}
+
+fn flatten_into(ctx: &mut DataContext, prefix: &str, members: &[(String, Json)]) -> Result<(), String> {
+    for (k, v) in members {
+        let path = if prefix.is_empty() {
+            k.clone()
+        } else {
+            format!("{}.{}", prefix, k)
+        };
+        match v {
+            Json::Object(inner) => flatten_into(ctx, &path, inner)?,
+            Json::Null => {}
+            v => ctx.set(path.clone(), value_from_json(v).map_err(|e| format!("{}: {}", path, e))?),
+        }
+    }
+    Ok(())
+}
+
+/// The inverse of [`context_from_json`]: dotted paths become nested
+/// objects, and instances are listed in arrays under their fact type.
+pub fn context_to_json(ctx: &DataContext) -> Result<Json, String> {
+    let mut root = Vec::new();
+    for (path, value) in ctx.named_facts() {
+        let mut members = &mut root;
+        let mut parts = path.split('.').peekable();
+        while let Some(part) = parts.next() {
+            if parts.peek().is_none() {
+                members.push((part.to_string(), value_to_json(&value)));
+                break;
+            }
+            let i = match members.iter().position(|(k, _)| k == part) {
+                Some(i) => i,
+                None => {
+                    members.push((part.to_string(), Json::Object(Vec::new())));
+                    members.len() - 1
+                }
+            };
+            members = match &mut members[i].1 {
+                Json::Object(inner) => inner,
+                _ => return Err(format!("fact {} conflicts with a value at '{}'", path, part)),
+            };
+        }
+    }
+
+    let mut instances: BTreeMap<&str, Vec<Json>> = BTreeMap::new();
+    for (_, fact) in ctx.instances() {
+        let fields = fact.fields.iter().map(|(k, v)| (k.clone(), value_to_json(v))).collect();
+        instances.entry(&fact.fact_type).or_default().push(Json::Object(fields));
+    }
+    for (fact_type, items) in instances {
+        if root.iter().any(|(k, _)| k == fact_type) {
+            return Err(format!("instances of {} conflict with a value at '{}'", fact_type, fact_type));
+        }
+        root.push((fact_type.to_string(), Json::Array(items)));
+    }
+    Ok(Json::Object(root))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_print() {
+        let input = r#" { "a": [1, -2, true, null], "b": {"c": "x\"é\n"}, "d": {} } "#;
+        let json = Json::parse(input).unwrap();
+        assert_eq!(json.to_string(), r#"{"a":[1,-2,true,null],"b":{"c":"x\"é\n"},"d":{}}"#);
+        assert_eq!(Json::parse(&json.pretty()).unwrap(), json);
+
+        assert!(Json::parse("{\"a\": 1.5}").is_err());
+        assert!(Json::parse("[1, 2").is_err());
+        assert!(Json::parse("\"abc").is_err());
+        assert!(Json::parse("{} x").is_err());
+
+        // `\u` takes exactly four hex digits, and pairs surrogates
+        assert_eq!(Json::parse(r#""\u00e9\ud83d\ude00""#).unwrap(), Json::Str("é😀".into()));
+        assert_eq!(Json::parse(r#""\ud83d""#).unwrap_err(), "unpaired surrogate in \\u escape at pos 3");
+        assert_eq!(Json::parse(r#""\ude00x""#).unwrap_err(), "unpaired surrogate in \\u escape at pos 3");
+        assert_eq!(Json::parse(r#""\ud83d\u0041""#).unwrap_err(), "unpaired surrogate in \\u escape at pos 3");
+        assert_eq!(Json::parse(r#""\u+0e9""#).unwrap_err(), "invalid \\u escape at pos 3");
+        assert!(Json::parse(r#""\u12""#).is_err());
+    }
+
+    #[test]
+    fn test_parse_depth_limit() {
+        assert_eq!(Json::parse(&"[".repeat(200_000)).unwrap_err(), "JSON nested deeper than 128 levels at pos 128");
+        assert_eq!(Json::parse(&"{\"a\":".repeat(200_000)).unwrap_err(), "JSON nested deeper than 128 levels at pos 640");
+
+        let limits = Limits {
+            max_depth: 2,
+            ..Limits::default()
+        };
+        assert!(Json::parse_with_limits("[[1], {\"a\": 2}]", &limits).is_ok());
+        assert!(Json::parse_with_limits("[[[1]]]", &limits).is_err());
+    }
+
+    #[test]
+    fn test_context_round_trip() {
+        let json = Json::parse(r#"{"Order": {"Total": 150, "Vip": true}, "Name": "x"}"#).unwrap();
+        let ctx = context_from_json(&json).unwrap();
+        assert_eq!(ctx.get("Order.Total".into()), Some(&Value::Int(150)));
+        assert_eq!(ctx.get("Order.Vip".into()), Some(&Value::Bool(true)));
+
+        let out = context_to_json(&ctx).unwrap();
+        assert_eq!(out.to_string(), r#"{"Name":"x","Order":{"Total":150,"Vip":true}}"#);
+
+        let json = Json::parse(r#"{"Order": [{"Total": 150, "Note": null}, {"Total": 5}], "Shop": {"Open": true}}"#).unwrap();
+        let ctx = context_from_json(&json).unwrap();
+        assert_eq!(ctx.facts_of("Order").map(|(id, _)| id).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(ctx.fact(2).unwrap().fields.get("Total"), Some(&Value::Int(5)));
+        assert_eq!(
+            context_to_json(&ctx).unwrap().to_string(),
+            r#"{"Shop":{"Open":true},"Order":[{"Total":150},{"Total":5}]}"#
+        );
+        assert_eq!(
+            context_from_json(&Json::parse(r#"{"Order": [1]}"#).unwrap()).err().unwrap(),
+            "Order[0]: an instance must be a JSON object"
+        );
+
+        // arrays below the top level are list values
+        let json = Json::parse(r#"{"Order":{"Items":[{"Price":5},[1,"a"]]}}"#).unwrap();
+        let ctx = context_from_json(&json).unwrap();
+        let Some(Value::List(items)) = ctx.get("Order.Items".into()) else { panic!() };
+        assert_eq!(items[1], Value::List(vec![Value::Int(1), Value::Str("a".into())]));
+        assert_eq!(context_to_json(&ctx).unwrap(), json);
+    }
+}
@@ -0,0 +1,772 @@
+//! A named set of parsed rules, and a compact binary cache of it that
+//! loads without reparsing.
+//!
+//! The cache starts with a header: the magic bytes `REMINIKB`, the format
+//! version and an FNV-1a checksum of the rest, so a cache written by an
+//! older build or damaged on disk is rejected rather than misread. Integers
+//! are LEB128 varints, zigzag-encoded when signed; strings and lists are
+//! prefixed with their length.
+
+use std::{collections::BTreeMap, fs};
+
+use crate::{
+    ast::{Action, ActivationGroup, Aggregate, CmpOp, CommentPlace, Condition, Expr, FactSet, Lambda, Op, Rule},
+    limits::Limits,
+    parser::parse,
+    time::{Date, DateTime, Duration},
+    value::Value,
+};
+
+const MAGIC: &[u8; 8] = b"REMINIKB";
+/// Bumped whenever the encoding of the AST changes.
+pub const FORMAT_VERSION: u32 = 6;
+const HEADER_LEN: usize = MAGIC.len() + 4 + 8;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct KnowledgeBase {
+    pub name: String,
+    pub rules: Vec<Rule>,
+}
+
+impl KnowledgeBase {
+    pub fn new(name: &str, rules: Vec<Rule>) -> KnowledgeBase {
+        KnowledgeBase {
+            name: name.to_string(),
+            rules,
+        }
+    }
+
+    pub fn parse(name: &str, source: &str) -> Result<KnowledgeBase, String> {
+        Ok(KnowledgeBase::new(name, parse(source.to_string())?))
+    }
+
+    pub fn rule(&self, name: &str) -> Option<&Rule> {
+        self.rules.iter().find(|r| r.name == name)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut payload = Writer::default();
+        payload.str(&self.name);
+        payload.len(self.rules.len());
+        for rule in &self.rules {
+            payload.rule(rule);
+        }
+
+        let mut out = Vec::with_capacity(HEADER_LEN + payload.0.len());
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        out.extend_from_slice(&fnv1a(&payload.0).to_le_bytes());
+        out.extend_from_slice(&payload.0);
+        out
+    }
+
+    /// Whether `bytes` start like a compiled knowledge base, whether or not
+    /// the rest of them decode.
+    pub fn is_compiled(bytes: &[u8]) -> bool {
+        bytes.starts_with(MAGIC)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<KnowledgeBase, String> {
+        KnowledgeBase::from_bytes_with_limits(bytes, &Limits::default())
+    }
+
+    /// Decodes a cache, rejecting any rule nested deeper or with more nodes
+    /// than `limits` allows. The checksum only catches damage, so a cache
+    /// from elsewhere is held to the limits its source would be.
+    pub fn from_bytes_with_limits(bytes: &[u8], limits: &Limits) -> Result<KnowledgeBase, String> {
+        if bytes.len() < HEADER_LEN || &bytes[..MAGIC.len()] != MAGIC {
+            return Err("not a compiled knowledge base".into());
+        }
+        let version = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+        if version != FORMAT_VERSION {
+            return Err(format!(
+                "knowledge base format version {} is not supported (expected {}); recompile it",
+                version, FORMAT_VERSION
+            ));
+        }
+        let checksum = u64::from_le_bytes(bytes[12..HEADER_LEN].try_into().unwrap());
+        let payload = &bytes[HEADER_LEN..];
+        if fnv1a(payload) != checksum {
+            return Err("knowledge base checksum mismatch".into());
+        }
+
+        let mut r = Reader {
+            bytes: payload,
+            pos: 0,
+            depth: 0,
+            nodes: 0,
+            max_depth: limits.max_depth,
+            max_nodes: limits.max_nodes,
+        };
+        let name = r.str()?;
+        let mut rules = Vec::new();
+        for _ in 0..r.len()? {
+            rules.push(r.rule()?);
+        }
+        if r.pos != payload.len() {
+            return Err("trailing bytes after knowledge base".into());
+        }
+        Ok(KnowledgeBase { name, rules })
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        fs::write(path, self.to_bytes()).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn load(path: &str) -> Result<KnowledgeBase, String> {
+        KnowledgeBase::load_with_limits(path, &Limits::default())
+    }
+
+    pub fn load_with_limits(path: &str, limits: &Limits) -> Result<KnowledgeBase, String> {
+        let bytes = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        KnowledgeBase::from_bytes_with_limits(&bytes, limits).map_err(|e| format!("{}: {}", path, e))
+    }
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| (hash ^ *b as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u64(&mut self, mut n: u64) {
+        while n >= 0x80 {
+            self.0.push(n as u8 | 0x80);
+            n >>= 7;
+        }
+        self.0.push(n as u8);
+    }
+
+    fn i64(&mut self, n: i64) {
+        self.u64(((n << 1) ^ (n >> 63)) as u64);
+    }
+
+    fn len(&mut self, n: usize) {
+        self.u64(n as u64);
+    }
+
+    fn tag(&mut self, tag: u8) {
+        self.0.push(tag);
+    }
+
+    fn str(&mut self, s: &str) {
+        self.len(s.len());
+        self.0.extend_from_slice(s.as_bytes());
+    }
+
+    fn rule(&mut self, rule: &Rule) {
+        self.str(&rule.name);
+        match &rule.description {
+            Some(d) => {
+                self.tag(1);
+                self.str(d);
+            }
+            None => self.tag(0),
+        }
+        self.len(rule.comments.len());
+        for comment in &rule.comments {
+            self.str(comment);
+        }
+        self.len(rule.placed_comments.len());
+        for (place, comment) in &rule.placed_comments {
+            match place {
+                CommentPlace::When => self.tag(0),
+                CommentPlace::Action(i) => {
+                    self.tag(1);
+                    self.u64(*i as u64);
+                }
+                CommentPlace::After => self.tag(2),
+            }
+            self.str(comment);
+        }
+        self.i64(rule.salience);
+        self.tag(rule.no_loop as u8 | (rule.lock_on_active as u8) << 1);
+        match &rule.activation_group {
+            Some(group) => {
+                self.tag(1 + group.unique as u8);
+                self.str(&group.name);
+            }
+            None => self.tag(0),
+        }
+        self.condition(&rule.condition);
+        self.len(rule.actions.len());
+        for action in &rule.actions {
+            match action {
+                Action::Assign { field, expr } => {
+                    self.tag(0);
+                    self.str(field);
+                    self.expr(expr);
+                }
+                Action::Insert { fact_type, fields } => {
+                    self.tag(1);
+                    self.str(fact_type);
+                    self.len(fields.len());
+                    for (field, expr) in fields {
+                        self.str(field);
+                        self.expr(expr);
+                    }
+                }
+                Action::Update(binding) => {
+                    self.tag(2);
+                    self.str(binding);
+                }
+                Action::Retract(binding) => {
+                    self.tag(3);
+                    self.str(binding);
+                }
+            }
+        }
+    }
+
+    fn condition(&mut self, cond: &Condition) {
+        match cond {
+            Condition::Compare { left, op, right } => {
+                self.tag(0);
+                self.expr(left);
+                self.tag(match op {
+                    CmpOp::Eq => 0,
+                    CmpOp::NotEq => 1,
+                    CmpOp::Lt => 2,
+                    CmpOp::Gt => 3,
+                    CmpOp::LtEq => 4,
+                    CmpOp::GtEq => 5,
+                });
+                self.expr(right);
+            }
+            Condition::Or(a, b) => {
+                self.tag(1);
+                self.condition(a);
+                self.condition(b);
+            }
+            Condition::And(a, b) => {
+                self.tag(2);
+                self.condition(a);
+                self.condition(b);
+            }
+            Condition::Not(c) => {
+                self.tag(3);
+                self.condition(c);
+            }
+            Condition::Pattern { binding, fact_type, constraint } => {
+                self.tag(4);
+                self.str(binding);
+                self.str(fact_type);
+                match constraint {
+                    Some(c) => {
+                        self.tag(1);
+                        self.condition(c);
+                    }
+                    None => self.tag(0),
+                }
+            }
+            Condition::Exists(set) => {
+                self.tag(5);
+                self.fact_set(set);
+            }
+        }
+    }
+
+    fn fact_set(&mut self, set: &FactSet) {
+        self.str(&set.fact_type);
+        match &set.constraint {
+            Some(c) => {
+                self.tag(1);
+                self.condition(c);
+            }
+            None => self.tag(0),
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Literal(v) => {
+                self.tag(0);
+                self.value(v);
+            }
+            Expr::FieldRef(name) => {
+                self.tag(1);
+                self.str(name);
+            }
+            Expr::BinOp { left, op, right } => {
+                self.tag(2);
+                self.expr(left);
+                self.tag(match op {
+                    Op::Add => 0,
+                    Op::Sub => 1,
+                });
+                self.expr(right);
+            }
+            Expr::Call { name, args } => {
+                self.tag(3);
+                self.str(name);
+                self.len(args.len());
+                for arg in args {
+                    self.expr(arg);
+                }
+            }
+            Expr::Aggregate { func, list, lambda } => {
+                self.tag(4);
+                self.tag(Aggregate::ALL.iter().position(|a| a == func).unwrap() as u8);
+                self.expr(list);
+                match lambda {
+                    None => self.tag(0),
+                    Some(Lambda::Map(param, body)) => {
+                        self.tag(1);
+                        self.str(param);
+                        self.expr(body);
+                    }
+                    Some(Lambda::Filter(param, body)) => {
+                        self.tag(2);
+                        self.str(param);
+                        self.condition(body);
+                    }
+                }
+            }
+            Expr::Facts(set) => {
+                self.tag(5);
+                self.fact_set(set);
+            }
+        }
+    }
+
+    fn value(&mut self, value: &Value) {
+        match value {
+            Value::Int(n) => {
+                self.tag(0);
+                self.i64(*n);
+            }
+            Value::Bool(b) => self.tag(if *b { 2 } else { 1 }),
+            Value::Str(s) => {
+                self.tag(3);
+                self.str(s);
+            }
+            Value::Date(d) => {
+                self.tag(4);
+                self.i64(d.epoch_days());
+            }
+            Value::DateTime(t) => {
+                self.tag(5);
+                self.i64(t.unix_secs());
+                self.i64(t.offset() as i64);
+            }
+            Value::Duration(d) => {
+                self.tag(6);
+                self.i64(d.secs());
+            }
+            Value::List(items) => {
+                self.tag(7);
+                self.len(items.len());
+                for item in items {
+                    self.value(item);
+                }
+            }
+            Value::Record(fields) => {
+                self.tag(8);
+                self.len(fields.len());
+                for (name, value) in fields {
+                    self.str(name);
+                    self.value(value);
+                }
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    // nesting of the condition, expression or value being read, and the
+    // number of them read so far in the current rule
+    depth: usize,
+    nodes: usize,
+    max_depth: usize,
+    max_nodes: usize,
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> Result<u8, String> {
+        let b = *self.bytes.get(self.pos).ok_or("truncated knowledge base")?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        let mut n = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = self.byte()?;
+            n |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err("invalid varint in knowledge base".into())
+    }
+
+    fn i64(&mut self) -> Result<i64, String> {
+        let n = self.u64()?;
+        Ok((n >> 1) as i64 ^ -((n & 1) as i64))
+    }
+
+    // a length can't exceed the bytes left, which keeps a corrupt one
+    // from allocating or looping for long
+    fn len(&mut self) -> Result<usize, String> {
+        let n = self.u64()?;
+        if n > (self.bytes.len() - self.pos) as u64 {
+            return Err("truncated knowledge base".into());
+        }
+        Ok(n as usize)
+    }
+
+    fn str(&mut self) -> Result<String, String> {
+        let len = self.len()?;
+        let bytes = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        String::from_utf8(bytes.to_vec()).map_err(|_| "invalid string in knowledge base".to_string())
+    }
+
+    fn bad_tag<T>(&self, what: &str, tag: u8) -> Result<T, String> {
+        Err(format!("invalid {} tag {} in knowledge base", what, tag))
+    }
+
+    // reads with `read` one level deeper, counting one more node
+    fn nested<T>(&mut self, read: fn(&mut Self) -> Result<T, String>) -> Result<T, String> {
+        if self.depth >= self.max_depth {
+            return Err(format!("expression nested deeper than {} levels", self.max_depth));
+        }
+        self.nodes += 1;
+        if self.nodes > self.max_nodes {
+            return Err(format!("rule has more than {} expression nodes", self.max_nodes));
+        }
+        self.depth += 1;
+        let result = read(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn rule(&mut self) -> Result<Rule, String> {
+        self.nodes = 0;
+        let name = self.str()?;
+        let description = match self.byte()? {
+            0 => None,
+            1 => Some(self.str()?),
+            tag => return self.bad_tag("description", tag),
+        };
+        let mut comments = Vec::new();
+        for _ in 0..self.len()? {
+            comments.push(self.str()?);
+        }
+        let mut placed_comments = Vec::new();
+        for _ in 0..self.len()? {
+            let place = match self.byte()? {
+                0 => CommentPlace::When,
+                1 => CommentPlace::Action(self.u64()? as usize),
+                2 => CommentPlace::After,
+                tag => return self.bad_tag("comment", tag),
+            };
+            placed_comments.push((place, self.str()?));
+        }
+        let salience = self.i64()?;
+        let flags = self.byte()?;
+        let activation_group = match self.byte()? {
+            0 => None,
+            tag @ (1 | 2) => Some(ActivationGroup { name: self.str()?, unique: tag == 2 }),
+            tag => return self.bad_tag("activation group", tag),
+        };
+        let condition = self.condition()?;
+        let mut actions = Vec::new();
+        for _ in 0..self.len()? {
+            actions.push(match self.byte()? {
+                0 => Action::Assign {
+                    field: self.str()?,
+                    expr: self.expr()?,
+                },
+                1 => {
+                    let fact_type = self.str()?;
+                    let mut fields = Vec::new();
+                    for _ in 0..self.len()? {
+                        fields.push((self.str()?, self.expr()?));
+                    }
+                    Action::Insert { fact_type, fields }
+                }
+                2 => Action::Update(self.str()?),
+                3 => Action::Retract(self.str()?),
+                tag => return self.bad_tag("action", tag),
+            });
+        }
+
+        let mut rule = Rule::new(name, condition, actions);
+        rule.description = description;
+        rule.comments = comments;
+        rule.placed_comments = placed_comments;
+        rule.salience = salience;
+        rule.no_loop = flags & 1 != 0;
+        rule.lock_on_active = flags & 2 != 0;
+        rule.activation_group = activation_group;
+        Ok(rule)
+    }
+
+    fn condition(&mut self) -> Result<Condition, String> {
+        self.nested(Reader::condition_chain)
+    }
+
+    // a chain of `&&` and `||`, which the parser builds in a loop, is read
+    // in one too, so a long chain doesn't nest
+    fn condition_chain(&mut self) -> Result<Condition, String> {
+        let mut chain = Vec::new();
+        let mut tag = self.byte()?;
+        while tag == 1 || tag == 2 {
+            chain.push(tag);
+            tag = self.byte()?;
+        }
+        let mut cond = self.condition_node(tag)?;
+        while let Some(tag) = chain.pop() {
+            let (left, right) = (Box::new(cond), Box::new(self.condition()?));
+            cond = if tag == 1 { Condition::Or(left, right) } else { Condition::And(left, right) };
+        }
+        Ok(cond)
+    }
+
+    fn condition_node(&mut self, tag: u8) -> Result<Condition, String> {
+        match tag {
+            0 => {
+                let left = self.expr()?;
+                let op = match self.byte()? {
+                    0 => CmpOp::Eq,
+                    1 => CmpOp::NotEq,
+                    2 => CmpOp::Lt,
+                    3 => CmpOp::Gt,
+                    4 => CmpOp::LtEq,
+                    5 => CmpOp::GtEq,
+                    tag => return self.bad_tag("operator", tag),
+                };
+                let right = self.expr()?;
+                Ok(Condition::Compare { left, op, right })
+            }
+            3 => Ok(Condition::Not(Box::new(self.condition()?))),
+            4 => {
+                let binding = self.str()?;
+                let fact_type = self.str()?;
+                let constraint = match self.byte()? {
+                    0 => None,
+                    1 => Some(Box::new(self.condition()?)),
+                    tag => return self.bad_tag("constraint", tag),
+                };
+                Ok(Condition::Pattern { binding, fact_type, constraint })
+            }
+            5 => Ok(Condition::Exists(self.fact_set()?)),
+            tag => self.bad_tag("condition", tag),
+        }
+    }
+
+    fn fact_set(&mut self) -> Result<FactSet, String> {
+        let fact_type = self.str()?;
+        let constraint = match self.byte()? {
+            0 => None,
+            1 => Some(Box::new(self.condition()?)),
+            tag => return self.bad_tag("constraint", tag),
+        };
+        Ok(FactSet { fact_type, constraint })
+    }
+
+    fn expr(&mut self) -> Result<Expr, String> {
+        self.nested(Reader::expr_chain)
+    }
+
+    // a chain of `+` and `-`, read in one as a condition's `&&` chain is
+    fn expr_chain(&mut self) -> Result<Expr, String> {
+        let mut chain = 0;
+        let mut tag = self.byte()?;
+        while tag == 2 {
+            chain += 1;
+            tag = self.byte()?;
+        }
+        let mut expr = self.expr_node(tag)?;
+        for _ in 0..chain {
+            let op = match self.byte()? {
+                0 => Op::Add,
+                1 => Op::Sub,
+                tag => return self.bad_tag("operator", tag),
+            };
+            let right = Box::new(self.expr()?);
+            expr = Expr::BinOp { left: Box::new(expr), op, right };
+        }
+        Ok(expr)
+    }
+
+    fn expr_node(&mut self, tag: u8) -> Result<Expr, String> {
+        match tag {
+            0 => Ok(Expr::Literal(self.value_node()?)),
+            1 => Ok(Expr::FieldRef(self.str()?)),
+            3 => {
+                let name = self.str()?;
+                let mut args = Vec::new();
+                for _ in 0..self.len()? {
+                    args.push(self.expr()?);
+                }
+                Ok(Expr::Call { name, args })
+            }
+            4 => {
+                let func = match self.byte()? {
+                    tag if (tag as usize) < Aggregate::ALL.len() => Aggregate::ALL[tag as usize],
+                    tag => return self.bad_tag("aggregate", tag),
+                };
+                let list = Box::new(self.expr()?);
+                let lambda = match self.byte()? {
+                    0 => None,
+                    1 => Some(Lambda::Map(self.str()?, Box::new(self.expr()?))),
+                    2 => Some(Lambda::Filter(self.str()?, Box::new(self.condition()?))),
+                    tag => return self.bad_tag("lambda", tag),
+                };
+                Ok(Expr::Aggregate { func, list, lambda })
+            }
+            5 => Ok(Expr::Facts(self.fact_set()?)),
+            tag => self.bad_tag("expression", tag),
+        }
+    }
+
+    // an item of a list or record; a literal is counted as the expression
+    // it is, as the parser counts it
+    fn value(&mut self) -> Result<Value, String> {
+        self.nested(Reader::value_node)
+    }
+
+    fn value_node(&mut self) -> Result<Value, String> {
+        match self.byte()? {
+            0 => Ok(Value::Int(self.i64()?)),
+            1 => Ok(Value::Bool(false)),
+            2 => Ok(Value::Bool(true)),
+            3 => Ok(Value::Str(self.str()?)),
+            4 => Ok(Value::Date(Date::from_epoch_days(self.i64()?))),
+            5 => {
+                let secs = self.i64()?;
+                let offset = i32::try_from(self.i64()?).map_err(|_| "invalid UTC offset in knowledge base")?;
+                Ok(Value::DateTime(DateTime::from_unix(secs, offset)))
+            }
+            6 => Ok(Value::Duration(Duration::from_secs(self.i64()?))),
+            7 => {
+                let mut items = Vec::new();
+                for _ in 0..self.len()? {
+                    items.push(self.value()?);
+                }
+                Ok(Value::List(items))
+            }
+            8 => {
+                let mut fields = BTreeMap::new();
+                for _ in 0..self.len()? {
+                    fields.insert(self.str()?, self.value()?);
+                }
+                Ok(Value::Record(fields))
+            }
+            tag => self.bad_tag("value", tag),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULES: &str = r#"
+    // discounts
+    rule Discount "Big orders" salience 10 no-loop {
+        when
+            // big or not VIP
+            Order.Total > 100 && !(Order.Placed < d"2024-01-01") || Order.Code == "VIP\n"
+        then
+            Order.Discount = Order.Total - 100 + -5;
+            Order.Due = datetime("2024-06-01T10:00:00+02:00") + 1d12h;
+            /* due later */
+    }
+    rule Negative lock-on-active activation-group "Orders" unique { when Order.Total <= -9223372036854775808 then Order.Flag = false; }
+    rule Match { when o: Order(Total > 100 || !(Vip == true)) && Customer() && c: Customer(Id == o.CustomerId) then o.Owner = c.Name; update(o); }
+    rule Close { when o: Order(Closed == true) then insert(Archive { Id: o.Id, At: now() }); retract(o); }
+    rule Fraud { when a: Account(Transaction(Owner == a.Id).sum(t => t.Amount) > 1000) && not exists Alert() && Order.Items.any(i => i == "x") then a.Flagged = true; }
+    // end
+    "#;
+
+    #[test]
+    fn test_round_trip() {
+        let kb = KnowledgeBase::parse("orders", RULES).unwrap();
+        let bytes = kb.to_bytes();
+        assert_eq!(&bytes[..8], b"REMINIKB");
+        assert_eq!(KnowledgeBase::from_bytes(&bytes).unwrap(), kb);
+        assert!(kb.rule("Negative").unwrap().lock_on_active);
+
+        // lists and records have no GRL literal
+        let mut record = std::collections::BTreeMap::new();
+        record.insert("Price".to_string(), Value::List(vec![Value::Int(5), Value::Str("x".into())]));
+        let rule = Rule::new(
+            "Lists".into(),
+            Condition::Compare {
+                left: Expr::Literal(Value::Record(record)),
+                op: CmpOp::Eq,
+                right: Expr::Literal(Value::List(Vec::new())),
+            },
+            Vec::new(),
+        );
+        let kb = KnowledgeBase::new("lists", vec![rule]);
+        assert_eq!(KnowledgeBase::from_bytes(&kb.to_bytes()).unwrap(), kb);
+    }
+
+    #[test]
+    fn test_rejects_bad_caches() {
+        let bytes = KnowledgeBase::parse("orders", RULES).unwrap().to_bytes();
+
+        assert_eq!(KnowledgeBase::from_bytes(b"rule R {").unwrap_err(), "not a compiled knowledge base");
+
+        let mut stale = bytes.clone();
+        stale[8] = 0;
+        assert_eq!(
+            KnowledgeBase::from_bytes(&stale).unwrap_err(),
+            "knowledge base format version 0 is not supported (expected 6); recompile it"
+        );
+
+        let mut corrupt = bytes.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert_eq!(KnowledgeBase::from_bytes(&corrupt).unwrap_err(), "knowledge base checksum mismatch");
+
+        // a consistent header over a cut-off payload
+        let mut short = bytes[..bytes.len() - 4].to_vec();
+        let checksum = fnv1a(&short[HEADER_LEN..]).to_le_bytes();
+        short[12..HEADER_LEN].copy_from_slice(&checksum);
+        assert_eq!(KnowledgeBase::from_bytes(&short).unwrap_err(), "truncated knowledge base");
+    }
+
+    #[test]
+    fn test_limits() {
+        // a rule of a million nested `!`s, under a valid checksum
+        let mut payload = Writer::default();
+        payload.str("deep");
+        payload.len(1);
+        payload.str("R");
+        payload.tag(0);
+        payload.len(0);
+        payload.len(0);
+        payload.i64(0);
+        payload.tag(0);
+        payload.tag(0);
+        payload.0.extend(std::iter::repeat_n(3, 1_000_000));
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&fnv1a(&payload.0).to_le_bytes());
+        bytes.extend_from_slice(&payload.0);
+        assert_eq!(KnowledgeBase::from_bytes(&bytes).unwrap_err(), "expression nested deeper than 128 levels");
+
+        // long `&&` and `+` chains don't nest
+        let terms: Vec<String> = (0..1000).map(|i| format!("X.a{} == 1 + {}", i, i)).collect();
+        let source = format!("rule Long {{ when {} then X.b = {}; }}", terms.join(" && "), vec!["1"; 1000].join(" + "));
+        let kb = KnowledgeBase::parse("long", &source).unwrap();
+        assert_eq!(KnowledgeBase::from_bytes(&kb.to_bytes()).unwrap(), kb);
+
+        let limits = Limits {
+            max_nodes: 100,
+            ..Limits::default()
+        };
+        assert_eq!(
+            KnowledgeBase::from_bytes_with_limits(&kb.to_bytes(), &limits).unwrap_err(),
+            "rule has more than 100 expression nodes"
+        );
+    }
+}
@@ -0,0 +1,526 @@
+use std::fmt;
+
+use crate::time::{Date, DateTime, Duration};
+
+/// Byte range of a token in the source text.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+
+    pub fn contains(&self, offset: usize) -> bool {
+        self.start <= offset && offset < self.end
+    }
+}
+
+/// An error, or other message, about a span of the source.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Span,
+}
+
+impl Diagnostic {
+    pub fn new(message: impl Into<String>, span: Span) -> Diagnostic {
+        Diagnostic {
+            message: message.into(),
+            span,
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Zero-based line and column (in chars) of a byte offset.
+pub fn line_col(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset.min(source.len())];
+    let line = before.matches('\n').count();
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    (line, before[line_start..].chars().count())
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Token {
+    // the magnitude only: `-` is a separate token, so the parser can tell
+    // `-9223372036854775808` from an overflow
+    Int(u64),
+    Bool(bool),
+
+    Ident(String),
+
+    Rule,
+    When,
+    Then,
+
+    LBrace,
+    RBrace,
+    LParen,
+    RParen,
+    Semicolon,
+    Comma,
+    Dot,
+    Colon,
+
+    Eq,
+    NotEq,
+    Lt,
+    Gt,
+    LtEq,
+    GtEq,
+    Assign,
+    Arrow,
+    And,
+    Or,
+    Not,
+    Plus,
+    Minus,
+    Mul,
+    Div,
+
+    StringLit(String),
+    Date(Date),
+    DateTime(DateTime),
+    // the magnitude, as with `Int`
+    Duration(Duration),
+
+    // trivia: kept so the source can be rebuilt byte for byte
+    Whitespace,
+    Comment(String),
+}
+
+/// Splits the whole input into tokens, trivia included; the spans cover
+/// the input without gaps.
+pub(crate) fn lex(input: &str) -> Result<Vec<(Token, Span)>, Diagnostic> {
+    let (tokens, mut errors) = lex_recovering(input);
+    if errors.is_empty() {
+        Ok(tokens)
+    } else {
+        Err(errors.remove(0))
+    }
+}
+
+/// Like [`lex`], but skips over text it can't tokenize, reporting each
+/// such place instead of stopping at the first.
+pub(crate) fn lex_recovering(input: &str) -> (Vec<(Token, Span)>, Vec<Diagnostic>) {
+    let mut cursor = Cursor { input, pos: 0 };
+    let mut tokens = Vec::new();
+    let mut errors = Vec::new();
+
+    while let Some(c) = cursor.peek() {
+        let start = cursor.pos;
+        let next = cursor.peek_nth(1);
+        cursor.bump();
+
+        let token = match c {
+            c if c.is_whitespace() => {
+                cursor.eat_while(char::is_whitespace);
+                Token::Whitespace
+            }
+            '{' => Token::LBrace,
+            '}' => Token::RBrace,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            ';' => Token::Semicolon,
+            ',' => Token::Comma,
+            '.' => Token::Dot,
+            ':' => Token::Colon,
+            '+' => Token::Plus,
+            '-' => Token::Minus,
+            '*' => Token::Mul,
+            '=' if next == Some('=') => cursor.then(Token::Eq),
+            '=' if next == Some('>') => cursor.then(Token::Arrow),
+            '=' => Token::Assign,
+            '!' if next == Some('=') => cursor.then(Token::NotEq),
+            '!' => Token::Not,
+            '<' if next == Some('=') => cursor.then(Token::LtEq),
+            '<' => Token::Lt,
+            '>' if next == Some('=') => cursor.then(Token::GtEq),
+            '>' => Token::Gt,
+            '&' if next == Some('&') => cursor.then(Token::And),
+            '|' if next == Some('|') => cursor.then(Token::Or),
+            '/' if next == Some('/') => {
+                cursor.eat_while(|c| c != '\n');
+                // a `\r` before the newline is whitespace, not comment text
+                if input[..cursor.pos].ends_with('\r') {
+                    cursor.pos -= 1;
+                }
+                Token::Comment(input[start..cursor.pos].to_string())
+            }
+            '/' if next == Some('*') => {
+                cursor.bump();
+                match input[cursor.pos..].find("*/") {
+                    Some(end) => cursor.pos += end + 2,
+                    None => {
+                        cursor.pos = input.len();
+                        errors.push(Diagnostic::new("unterminated block comment", Span::new(start, start + 2)));
+                        continue;
+                    }
+                }
+                Token::Comment(input[start..cursor.pos].to_string())
+            }
+            '/' => Token::Div,
+            '"' => match cursor.string(start, &mut errors) {
+                Some(s) => Token::StringLit(s),
+                None => continue,
+            },
+            'r' if next == Some('"') || (next == Some('#') && cursor.raw_string_ahead()) => {
+                match cursor.raw_string(start, &mut errors) {
+                    Some(s) => Token::StringLit(s),
+                    None => continue,
+                }
+            }
+            // `d"2024-01-31"` and `dt"2024-01-31T09:00:00Z"`
+            'd' if next == Some('"') || (next == Some('t') && cursor.peek_nth(1) == Some('"')) => {
+                let datetime = next == Some('t');
+                if datetime {
+                    cursor.bump();
+                }
+                cursor.bump();
+                let Some(text) = cursor.string(start, &mut errors) else {
+                    continue;
+                };
+                let parsed = if datetime {
+                    DateTime::parse(&text).map(Token::DateTime)
+                } else {
+                    Date::parse(&text).map(Token::Date)
+                };
+                match parsed {
+                    Ok(token) => token,
+                    Err(message) => {
+                        errors.push(Diagnostic::new(message, Span::new(start, cursor.pos)));
+                        continue;
+                    }
+                }
+            }
+            '`' => {
+                cursor.eat_while(|c| c != '`' && c != '\n');
+                if cursor.peek() != Some('`') {
+                    errors.push(Diagnostic::new("unterminated quoted identifier", Span::new(start, cursor.pos)));
+                    continue;
+                }
+                cursor.bump();
+                let name = &input[start + 1..cursor.pos - 1];
+                if name.is_empty() || name.contains('.') {
+                    let message = format!("invalid quoted identifier {}", &input[start..cursor.pos]);
+                    errors.push(Diagnostic::new(message, Span::new(start, cursor.pos)));
+                    continue;
+                }
+                Token::Ident(name.to_string())
+            }
+            c if c.is_ascii_digit() => {
+                cursor.eat_while(|c| c.is_alphanumeric() || c == '_');
+                let text = &input[start..cursor.pos];
+                // a decimal number with a unit letter, e.g. `30d` or `1h30m`
+                let radix = text.starts_with('0') && text[1..].starts_with(['x', 'X', 'b', 'B']);
+                let duration = !radix && text.ends_with(['w', 'd', 'h', 'm', 's']);
+                let parsed = if duration {
+                    Duration::parse(text).map(Token::Duration)
+                } else {
+                    int_literal(text).map(Token::Int)
+                };
+                match parsed {
+                    Ok(token) => token,
+                    Err(message) => {
+                        errors.push(Diagnostic::new(message, Span::new(start, cursor.pos)));
+                        continue;
+                    }
+                }
+            }
+            c if is_ident_start(c) => {
+                cursor.eat_while(is_ident_continue);
+                match &input[start..cursor.pos] {
+                    "rule" => Token::Rule,
+                    "when" => Token::When,
+                    "then" => Token::Then,
+                    "true" => Token::Bool(true),
+                    "false" => Token::Bool(false),
+                    word => Token::Ident(word.to_string()),
+                }
+            }
+            _ => {
+                let span = Span::new(start, cursor.pos);
+                errors.push(Diagnostic::new(format!("unexpected char '{}' at pos {}", c, start), span));
+                continue;
+            }
+        };
+
+        tokens.push((token, Span::new(start, cursor.pos)));
+    }
+    (tokens, errors)
+}
+
+/// Value of a decimal, `0x` hex or `0b` binary literal; underscores may
+/// separate digits.
+fn int_literal(text: &str) -> Result<u64, String> {
+    let (digits, radix, kind) = if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        (hex, 16, "hex")
+    } else if let Some(bin) = text.strip_prefix("0b").or_else(|| text.strip_prefix("0B")) {
+        (bin, 2, "binary")
+    } else {
+        (text, 10, "decimal")
+    };
+
+    let mut n: u64 = 0;
+    let mut any = false;
+    for c in digits.chars() {
+        if c == '_' {
+            continue;
+        }
+        let d = c
+            .to_digit(radix)
+            .ok_or_else(|| format!("invalid digit '{}' in {} literal {}", c, kind, text))?;
+        n = n
+            .checked_mul(radix as u64)
+            .and_then(|n| n.checked_add(d as u64))
+            .ok_or_else(|| format!("integer literal {} out of range", text))?;
+        any = true;
+    }
+    if !any {
+        return Err(format!("{} literal {} has no digits", kind, text));
+    }
+    Ok(n)
+}
+
+// identifiers follow Unicode's XID classes, as Rust's do
+fn is_ident_start(c: char) -> bool {
+    unicode_ident::is_xid_start(c) || c == '_'
+}
+
+pub(crate) fn is_ident_continue(c: char) -> bool {
+    unicode_ident::is_xid_continue(c)
+}
+
+const KEYWORDS: [&str; 5] = ["rule", "when", "then", "true", "false"];
+
+/// Writes an identifier so that it lexes back to itself, quoting it in
+/// backticks when it isn't a plain word.
+pub fn quote_ident(name: &str) -> String {
+    let mut chars = name.chars();
+    let plain = chars.next().is_some_and(is_ident_start) && chars.all(is_ident_continue);
+    if plain && !KEYWORDS.contains(&name) {
+        name.to_string()
+    } else {
+        format!("`{}`", name)
+    }
+}
+
+/// Whether [`quote_ident`] can write the name so that it lexes back: a
+/// quoted identifier can't be empty or hold a backtick, a line break or a
+/// `.`.
+pub fn is_quotable(name: &str) -> bool {
+    !name.is_empty() && !name.contains(['`', '\n', '.'])
+}
+
+/// Writes a dotted field path, quoting each part as needed.
+pub fn quote_path(path: &str) -> String {
+    path.split('.').map(quote_ident).collect::<Vec<_>>().join(".")
+}
+
+/// Writes a string literal, escaping what the lexer would otherwise
+/// misread.
+pub fn quote_str(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+struct Cursor<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl Cursor<'_> {
+    fn peek(&self) -> Option<char> {
+        self.input[self.pos..].chars().next()
+    }
+
+    fn peek_nth(&self, n: usize) -> Option<char> {
+        self.input[self.pos..].chars().nth(n)
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    // consumes the second char of a two-char token
+    fn then(&mut self, token: Token) -> Token {
+        self.bump();
+        token
+    }
+
+    fn eat_while(&mut self, f: impl Fn(char) -> bool) {
+        while self.peek().is_some_and(&f) {
+            self.bump();
+        }
+    }
+
+    // after the `r`: any number of `#` followed by `"`
+    fn raw_string_ahead(&self) -> bool {
+        self.input[self.pos..].trim_start_matches('#').starts_with('"')
+    }
+
+    // the body of a `"..."` literal, after the opening quote
+    fn string(&mut self, start: usize, errors: &mut Vec<Diagnostic>) -> Option<String> {
+        let mut s = String::new();
+        loop {
+            let at = self.pos;
+            match self.bump() {
+                None => {
+                    errors.push(Diagnostic::new("unterminated string literal", Span::new(start, start + 1)));
+                    return None;
+                }
+                Some('"') => return Some(s),
+                Some('\\') => match self.escape() {
+                    Ok(c) => s.push(c),
+                    Err(message) => errors.push(Diagnostic::new(message, Span::new(at, self.pos))),
+                },
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    // the char an escape sequence stands for, after the backslash
+    fn escape(&mut self) -> Result<char, String> {
+        match self.bump() {
+            Some('n') => Ok('\n'),
+            Some('r') => Ok('\r'),
+            Some('t') => Ok('\t'),
+            Some('0') => Ok('\0'),
+            Some('"') => Ok('"'),
+            Some('\\') => Ok('\\'),
+            Some('u') => {
+                if self.bump() != Some('{') {
+                    return Err("expected '{' in unicode escape".into());
+                }
+                let digits_start = self.pos;
+                self.eat_while(|c| c.is_ascii_hexdigit());
+                let digits = &self.input[digits_start..self.pos];
+                if self.bump() != Some('}') || digits.is_empty() || digits.len() > 6 {
+                    return Err("malformed unicode escape".into());
+                }
+                let code = u32::from_str_radix(digits, 16).map_err(|e| e.to_string())?;
+                char::from_u32(code).ok_or_else(|| format!("invalid unicode escape {:x}", code))
+            }
+            Some(c) => Err(format!("unknown escape '\\{}'", c)),
+            None => Err("unterminated string literal".into()),
+        }
+    }
+
+    // `r"..."` or `r#"..."#`, after the `r`; no escapes are processed
+    fn raw_string(&mut self, start: usize, errors: &mut Vec<Diagnostic>) -> Option<String> {
+        let hashes = self.input[self.pos..].len() - self.input[self.pos..].trim_start_matches('#').len();
+        self.pos += hashes + 1;
+        let close = format!("\"{}", "#".repeat(hashes));
+        match self.input[self.pos..].find(&close) {
+            Some(end) => {
+                let s = self.input[self.pos..self.pos + end].to_string();
+                self.pos += end + close.len();
+                Some(s)
+            }
+            None => {
+                self.pos = self.input.len();
+                errors.push(Diagnostic::new("unterminated raw string literal", Span::new(start, start + 1)));
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(input: &str) -> Vec<Token> {
+        lex(input).unwrap().into_iter().map(|(t, _)| t).filter(|t| *t != Token::Whitespace).collect()
+    }
+
+    fn error(input: &str) -> (String, &str) {
+        let d = lex(input).unwrap_err();
+        (d.message, &input[d.span.start..d.span.end])
+    }
+
+    #[test]
+    fn test_strings() {
+        assert_eq!(
+            tokens(r#""a\n\"b\" \\ \u{e9}\u{1F600}""#),
+            vec![Token::StringLit("a\n\"b\" \\ \u{e9}\u{1F600}".into())]
+        );
+        assert_eq!(tokens(r##"r"C:\dir" r#"say "hi""#"##), vec![Token::StringLit("C:\\dir".into()), Token::StringLit("say \"hi\"".into())]);
+        assert_eq!(tokens("r + 1"), vec![Token::Ident("r".into()), Token::Plus, Token::Int(1)]);
+
+        for s in ["plain", "quote \" and \\ slash", "tab\tnew\nline", "bell \u{7}", "ünïcödé"] {
+            assert_eq!(tokens(&quote_str(s)), vec![Token::StringLit(s.into())]);
+        }
+    }
+
+    #[test]
+    fn test_identifiers() {
+        assert_eq!(
+            tokens("Kunde.Größe `Order Total` `when` ñ_1"),
+            vec![
+                Token::Ident("Kunde".into()),
+                Token::Dot,
+                Token::Ident("Größe".into()),
+                Token::Ident("Order Total".into()),
+                Token::Ident("when".into()),
+                Token::Ident("ñ_1".into()),
+            ]
+        );
+        // a combining accent continues an identifier, a superscript doesn't
+        assert_eq!(tokens("Cafe\u{301}"), vec![Token::Ident("Cafe\u{301}".into())]);
+        assert_eq!(quote_ident("x²"), "`x²`");
+        assert_eq!(quote_path("Kunde.Größe"), "Kunde.Größe");
+        assert_eq!(quote_path("Order.net total"), "Order.`net total`");
+        assert_eq!(quote_ident("then"), "`then`");
+    }
+
+    #[test]
+    fn test_int_literals() {
+        assert_eq!(
+            tokens("0xFF 0b1010 1_000_000 -5 18446744073709551615"),
+            vec![Token::Int(255), Token::Int(10), Token::Int(1_000_000), Token::Minus, Token::Int(5), Token::Int(u64::MAX)]
+        );
+        assert_eq!(
+            error("x = 18446744073709551616;"),
+            ("integer literal 18446744073709551616 out of range".into(), "18446744073709551616")
+        );
+        assert_eq!(error("0xFG"), ("invalid digit 'G' in hex literal 0xFG".into(), "0xFG"));
+        assert_eq!(error("0b_"), ("binary literal 0b_ has no digits".into(), "0b_"));
+        assert_eq!(error("12abc"), ("invalid digit 'a' in decimal literal 12abc".into(), "12abc"));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(error("x == \"abc"), ("unterminated string literal".into(), "\""));
+        assert_eq!(error("r#\"abc\""), ("unterminated raw string literal".into(), "r"));
+        assert_eq!(error("a /* b"), ("unterminated block comment".into(), "/*"));
+        assert_eq!(error("`Order"), ("unterminated quoted identifier".into(), "`Order"));
+        assert_eq!(error("\"\\q\""), ("unknown escape '\\q'".into(), "\\q"));
+        assert_eq!(error("\"\\u{110000}\""), ("invalid unicode escape 110000".into(), "\\u{110000}"));
+        assert_eq!(error("Größe € 1"), ("unexpected char '€' at pos 8".into(), "€"));
+        assert_eq!(error("x² == 1"), ("unexpected char '²' at pos 1".into(), "²"));
+    }
+}
@@ -2,4 +2,25 @@ pub mod value;
 pub mod context;
 pub mod ast;
 pub mod engine;
-pub mod parser;
\ No newline at end of file
+pub mod lexer;
+pub mod parser;
+pub mod analysis;
+pub mod decision_table;
+pub mod json;
+pub mod cli;
+pub mod repl;
+pub mod syntax;
+pub mod schema;
+pub mod lsp;
+pub mod time;
+pub mod functions;
+pub mod testing;
+pub mod coverage;
+pub mod profile;
+pub mod limits;
+pub mod knowledge_base;
+pub mod rule_json;
+#[cfg(feature = "yaml")]
+pub mod yaml;
+pub mod registry;
+pub mod rule_set;
\ No newline at end of file
@@ -0,0 +1,214 @@
+//! Limits on what rules may cost, for running rules from untrusted authors.
+//!
+//! The parser, and the decoder of compiled knowledge bases, enforce the
+//! nesting depth and node count of each rule; the engine enforces the rest
+//! during a run. Each limit fails with its own
+//! error:
+//!
+//! - `max cycles (N) reached`
+//! - `expression nested deeper than N levels`
+//! - `JSON nested deeper than N levels at pos P`
+//! - `rule has more than N expression nodes`
+//! - `fuel exhausted after N steps`
+//! - `string of N bytes is longer than the limit of M`
+//! - `list of N items is longer than the limit of M`
+//! - `record of N fields is longer than the limit of M`
+//! - `deadline of D exceeded`
+
+use std::{
+    cell::Cell,
+    time::{Duration, Instant},
+};
+
+use crate::value::Value;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Limits {
+    /// Rules fired per run.
+    pub max_cycles: usize,
+    /// Nesting of parentheses, `!` and function calls in a rule, and of
+    /// arrays and objects in JSON input.
+    pub max_depth: usize,
+    /// Comparisons and operands in a rule.
+    pub max_nodes: usize,
+    /// Expression nodes evaluated per run.
+    pub fuel: Option<u64>,
+    /// Bytes in a string an expression produces.
+    pub max_string_len: Option<usize>,
+    /// Items in a list, or fields in a record, an expression produces.
+    pub max_list_len: Option<usize>,
+    /// Wall-clock time per run.
+    pub deadline: Option<Duration>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_cycles: 1000,
+            max_depth: 128,
+            max_nodes: 10_000,
+            fuel: None,
+            max_string_len: None,
+            max_list_len: None,
+            deadline: None,
+        }
+    }
+}
+
+impl Limits {
+    /// What a run may still spend, or `None` when nothing is limited.
+    pub fn budget(&self) -> Option<Budget> {
+        if self.fuel.is_none() && self.max_string_len.is_none() && self.max_list_len.is_none() && self.deadline.is_none()
+        {
+            return None;
+        }
+        Some(Budget {
+            fuel: self.fuel,
+            used: Cell::new(0),
+            max_string_len: self.max_string_len,
+            max_list_len: self.max_list_len,
+            deadline: self.deadline.map(|d| (Instant::now() + d, d)),
+        })
+    }
+}
+
+/// The runtime limits of one run, started when the run starts. Kept in
+/// the [`crate::context::DataContext`] so expressions can charge it.
+#[derive(Clone, Debug)]
+pub struct Budget {
+    fuel: Option<u64>,
+    used: Cell<u64>,
+    max_string_len: Option<usize>,
+    max_list_len: Option<usize>,
+    // when the run must end, and the limit it was computed from
+    deadline: Option<(Instant, Duration)>,
+}
+
+impl Budget {
+    /// Pays for evaluating one expression node.
+    pub fn charge(&self) -> Result<(), String> {
+        let used = self.used.get() + 1;
+        self.used.set(used);
+        if let Some(fuel) = self.fuel {
+            if used > fuel {
+                return Err(format!("fuel exhausted after {} steps", fuel));
+            }
+        }
+        self.check_deadline()
+    }
+
+    pub fn check_deadline(&self) -> Result<(), String> {
+        match self.deadline {
+            Some((at, limit)) if Instant::now() >= at => Err(format!("deadline of {:?} exceeded", limit)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the size of a value an expression produced.
+    pub fn check_value(&self, value: &Value) -> Result<(), String> {
+        match (value, self.max_string_len, self.max_list_len) {
+            (Value::Str(s), Some(max), _) if s.len() > max => {
+                Err(format!("string of {} bytes is longer than the limit of {}", s.len(), max))
+            }
+            (Value::List(items), _, Some(max)) if items.len() > max => {
+                Err(format!("list of {} items is longer than the limit of {}", items.len(), max))
+            }
+            (Value::Record(fields), _, Some(max)) if fields.len() > max => {
+                Err(format!("record of {} fields is longer than the limit of {}", fields.len(), max))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        context::DataContext,
+        engine::RuleEngine,
+        parser::{parse, parse_with_limits},
+    };
+
+    fn run(input: &str, limits: Limits) -> Result<Vec<String>, String> {
+        let rules = parse(input.to_string()).unwrap();
+        let mut ctx = DataContext::new();
+        ctx.set("X.n".into(), Value::Int(0));
+        ctx.set("X.s".into(), Value::Str("ab".into()));
+        ctx.set("X.items".into(), Value::List(vec![Value::Int(1); 5]));
+        ctx.set(
+            "X.r".into(),
+            Value::Record(["a", "b", "c"].iter().map(|k| (k.to_string(), Value::Int(1))).collect()),
+        );
+        let mut engine = RuleEngine::new();
+        engine.limits = limits;
+        engine.execute(&rules, &mut ctx)
+    }
+
+    #[test]
+    fn test_parse_limits() {
+        let deep = format!("rule R {{ when {}X.n{} == 1 then X.n = 1; }}", "(".repeat(100_000), ")".repeat(100_000));
+        assert_eq!(parse(deep).unwrap_err(), "expression nested deeper than 128 levels");
+
+        let deep = format!("rule R {{ when {}X.n == 1 then X.n = 1; }}", "!".repeat(100_000));
+        assert_eq!(parse(deep).unwrap_err(), "expression nested deeper than 128 levels");
+
+        let limits = Limits {
+            max_nodes: 10,
+            ..Limits::default()
+        };
+        let wide = format!("rule R {{ when X.n == 1 then X.n = {}; }}", ["1"; 10].join(" + "));
+        assert_eq!(parse_with_limits(wide, &limits).unwrap_err(), "rule has more than 10 expression nodes");
+        let small = "rule R { when X.n == 1 then X.n = 1 + 2; }".to_string();
+        assert!(parse_with_limits(small, &limits).is_ok());
+    }
+
+    #[test]
+    fn test_runtime_limits() {
+        let rules = "rule Grow { when X.n < 100 then X.n = X.n + 1; X.s = X.s + X.s; }";
+        let fuel = Limits {
+            fuel: Some(20),
+            ..Limits::default()
+        };
+        assert_eq!(run(rules, fuel).unwrap_err(), "rule Grow: fuel exhausted after 20 steps");
+
+        let strings = Limits {
+            max_string_len: Some(64),
+            ..Limits::default()
+        };
+        assert_eq!(
+            run(rules, strings).unwrap_err(),
+            "rule Grow: string of 128 bytes is longer than the limit of 64"
+        );
+
+        let lists = Limits {
+            max_list_len: Some(4),
+            ..Limits::default()
+        };
+        assert_eq!(
+            run("rule Items { when X.items.count() > 0 then X.n = 1; }", lists.clone()).unwrap_err(),
+            "list of 5 items is longer than the limit of 4"
+        );
+        let records = Limits {
+            max_list_len: Some(2),
+            ..Limits::default()
+        };
+        assert_eq!(
+            run("rule Fields { when X.n == 0 then X.r = X.r; }", records).unwrap_err(),
+            "rule Fields: record of 3 fields is longer than the limit of 2"
+        );
+        assert_eq!(run("rule Small { when X.r == X.r then X.n = 1; }", lists), Ok(vec!["Small".to_string()]));
+
+        let deadline = Limits {
+            deadline: Some(Duration::ZERO),
+            ..Limits::default()
+        };
+        assert_eq!(run(rules, deadline).unwrap_err(), "deadline of 0ns exceeded");
+
+        let cycles = Limits {
+            max_cycles: 3,
+            ..Limits::default()
+        };
+        assert_eq!(run("rule Count { when X.n < 100 then X.n = X.n + 1; }", cycles).unwrap_err(), "max cycles (3) reached");
+    }
+}
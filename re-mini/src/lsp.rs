@@ -0,0 +1,727 @@
+//! Language server for GRL files, speaking LSP over JSON-RPC.
+//!
+//! [`Server::handle`] takes one incoming message and returns the messages
+//! to send back, so the server can be driven in-process; the
+//! `re-mini-lsp` binary wraps it with stdio framing.
+
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Write},
+};
+
+use crate::{
+    json::Json,
+    lexer::{is_ident_continue, Span},
+    parser::parse_recovering,
+    schema::Schema,
+    syntax::{FieldRef, SyntaxTree, TriviaKind},
+};
+
+const ERROR: i64 = 1;
+const WARNING: i64 = 2;
+
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+pub struct Server {
+    schema: Schema,
+    documents: HashMap<String, String>,
+    pub exit: bool,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn obj(members: Vec<(&str, Json)>) -> Json {
+    Json::Object(
+        members
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect(),
+    )
+}
+
+fn response(id: &Json, result: Json) -> Json {
+    obj(vec![
+        ("jsonrpc", Json::Str("2.0".into())),
+        ("id", id.clone()),
+        ("result", result),
+    ])
+}
+
+fn error_response(id: &Json, code: i64, message: String) -> Json {
+    obj(vec![
+        ("jsonrpc", Json::Str("2.0".into())),
+        ("id", id.clone()),
+        (
+            "error",
+            obj(vec![
+                ("code", Json::Int(code)),
+                ("message", Json::Str(message)),
+            ]),
+        ),
+    ])
+}
+
+fn notification(method: &str, params: Json) -> Json {
+    obj(vec![
+        ("jsonrpc", Json::Str("2.0".into())),
+        ("method", Json::Str(method.into())),
+        ("params", params),
+    ])
+}
+
+/// LSP position (line, UTF-16 column) of a byte offset.
+fn position(text: &str, offset: usize) -> Json {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count();
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let character = before[line_start..].encode_utf16().count();
+    obj(vec![
+        ("line", Json::Int(line as i64)),
+        ("character", Json::Int(character as i64)),
+    ])
+}
+
+fn range(text: &str, span: Span) -> Json {
+    obj(vec![
+        ("start", position(text, span.start)),
+        ("end", position(text, span.end)),
+    ])
+}
+
+/// Byte offset of an LSP position, clamped to the text.
+fn offset(text: &str, pos: &Json) -> Option<usize> {
+    let line = pos.get("line")?.as_i64()? as usize;
+    let character = pos.get("character")?.as_i64()? as usize;
+
+    let mut line_start = 0;
+    for _ in 0..line {
+        line_start += text[line_start..].find('\n')? + 1;
+    }
+    let line_text = text[line_start..].split('\n').next().unwrap_or("");
+    let mut units = 0;
+    for (i, c) in line_text.char_indices() {
+        if units >= character {
+            return Some(line_start + i);
+        }
+        units += c.len_utf16();
+    }
+    Some(line_start + line_text.len())
+}
+
+impl Server {
+    pub fn new() -> Server {
+        Server {
+            schema: Schema::new(),
+            documents: HashMap::new(),
+            exit: false,
+        }
+    }
+
+    pub fn handle(&mut self, msg: &Json) -> Vec<Json> {
+        let method = msg.get("method").and_then(|m| m.as_str()).unwrap_or("");
+        let params = msg.get("params").cloned().unwrap_or(Json::Null);
+
+        let Some(id) = msg.get("id") else {
+            return self.handle_notification(method, &params);
+        };
+
+        let result = match method {
+            "initialize" => self.initialize(&params),
+            "shutdown" => Ok(Json::Null),
+            "textDocument/definition" => self.definition(&params),
+            "textDocument/references" => self.references(&params),
+            "textDocument/hover" => self.hover(&params),
+            "textDocument/completion" => self.completion(&params),
+            "textDocument/formatting" => self.formatting(&params),
+            _ => {
+                return vec![error_response(
+                    id,
+                    METHOD_NOT_FOUND,
+                    format!("unknown method {}", method),
+                )]
+            }
+        };
+
+        match result {
+            Ok(result) => vec![response(id, result)],
+            Err(e) => vec![error_response(id, INVALID_PARAMS, e)],
+        }
+    }
+
+    fn handle_notification(&mut self, method: &str, params: &Json) -> Vec<Json> {
+        let uri = params
+            .get("textDocument")
+            .and_then(|d| d.get("uri"))
+            .and_then(|u| u.as_str())
+            .map(|u| u.to_string());
+
+        match (method, uri) {
+            ("textDocument/didOpen", Some(uri)) => {
+                let text = params
+                    .get("textDocument")
+                    .and_then(|d| d.get("text"))
+                    .and_then(|t| t.as_str())
+                    .unwrap_or("");
+                self.documents.insert(uri.clone(), text.to_string());
+                vec![self.publish_diagnostics(&uri)]
+            }
+            ("textDocument/didChange", Some(uri)) => {
+                // full sync: the last change holds the whole text
+                let text = params
+                    .get("contentChanges")
+                    .and_then(|c| c.as_array())
+                    .and_then(|c| c.last())
+                    .and_then(|c| c.get("text"))
+                    .and_then(|t| t.as_str());
+                if let Some(text) = text {
+                    self.documents.insert(uri.clone(), text.to_string());
+                }
+                vec![self.publish_diagnostics(&uri)]
+            }
+            ("textDocument/didClose", Some(uri)) => {
+                self.documents.remove(&uri);
+                vec![notification(
+                    "textDocument/publishDiagnostics",
+                    obj(vec![
+                        ("uri", Json::Str(uri)),
+                        ("diagnostics", Json::Array(vec![])),
+                    ]),
+                )]
+            }
+            ("exit", _) => {
+                self.exit = true;
+                vec![]
+            }
+            _ => vec![],
+        }
+    }
+
+    fn initialize(&mut self, params: &Json) -> Result<Json, String> {
+        if let Some(schema) = params
+            .get("initializationOptions")
+            .and_then(|o| o.get("schema"))
+        {
+            self.schema = Schema::from_json(schema)?;
+        }
+
+        Ok(obj(vec![
+            (
+                "capabilities",
+                obj(vec![
+                    ("textDocumentSync", Json::Int(1)),
+                    ("definitionProvider", Json::Bool(true)),
+                    ("referencesProvider", Json::Bool(true)),
+                    ("hoverProvider", Json::Bool(true)),
+                    (
+                        "completionProvider",
+                        obj(vec![(
+                            "triggerCharacters",
+                            Json::Array(vec![Json::Str(".".into())]),
+                        )]),
+                    ),
+                    ("documentFormattingProvider", Json::Bool(true)),
+                ]),
+            ),
+            (
+                "serverInfo",
+                obj(vec![("name", Json::Str("re-mini-lsp".into()))]),
+            ),
+        ]))
+    }
+
+    fn publish_diagnostics(&self, uri: &str) -> Json {
+        let text = self.documents.get(uri).map(|t| t.as_str()).unwrap_or("");
+        let mut diagnostics = Vec::new();
+        let mut push = |span: Span, severity: i64, message: String| {
+            diagnostics.push(obj(vec![
+                ("range", range(text, span)),
+                ("severity", Json::Int(severity)),
+                ("source", Json::Str("re-mini".into())),
+                ("message", Json::Str(message)),
+            ]));
+        };
+
+        // the syntax tree needs a clean parse; report every error otherwise
+        let (_, errors) = parse_recovering(text.to_string());
+        for d in errors {
+            push(d.span, ERROR, d.message);
+        }
+
+        match SyntaxTree::parse(text) {
+            Err(_) => {}
+            Ok(tree) => {
+                if !self.schema.is_empty() {
+                    for field in tree.field_refs() {
+                        if self.schema.get(&field.name).is_none() {
+                            push(field.span, WARNING, format!("unknown field {}", field.name));
+                        }
+                    }
+                }
+                for (i, node) in tree.rules().iter().enumerate() {
+                    for e in self.schema.check_rule(&node.rule) {
+                        push(tree.rule_name_span(i), ERROR, e);
+                    }
+                }
+            }
+        }
+
+        notification(
+            "textDocument/publishDiagnostics",
+            obj(vec![
+                ("uri", Json::Str(uri.into())),
+                ("diagnostics", Json::Array(diagnostics)),
+            ]),
+        )
+    }
+
+    // the document text and cursor offset of a textDocument/position request
+    fn document_at(&self, params: &Json) -> Result<(String, &str, usize), String> {
+        let uri = params
+            .get("textDocument")
+            .and_then(|d| d.get("uri"))
+            .and_then(|u| u.as_str())
+            .ok_or("missing textDocument.uri")?;
+        let text = self
+            .documents
+            .get(uri)
+            .ok_or_else(|| format!("document {} is not open", uri))?;
+        let at = params
+            .get("position")
+            .and_then(|p| offset(text, p))
+            .ok_or("missing or invalid position")?;
+        Ok((uri.to_string(), text, at))
+    }
+
+    fn location(uri: &str, text: &str, span: Span) -> Json {
+        obj(vec![
+            ("uri", Json::Str(uri.into())),
+            ("range", range(text, span)),
+        ])
+    }
+
+    // the field reference, or rule name, under the cursor
+    fn symbol_at(tree: &SyntaxTree, at: usize) -> Option<Symbol> {
+        if let Some(field) = tree
+            .field_refs()
+            .into_iter()
+            .find(|f| f.span.contains(at) || f.span.end == at)
+        {
+            return Some(Symbol::Field(field));
+        }
+        (0..tree.rules().len())
+            .find(|&i| {
+                let span = tree.rule_name_span(i);
+                span.contains(at) || span.end == at
+            })
+            .map(Symbol::Rule)
+    }
+
+    fn definition(&self, params: &Json) -> Result<Json, String> {
+        let (uri, text, at) = self.document_at(params)?;
+        let Ok(tree) = SyntaxTree::parse(text) else {
+            return Ok(Json::Null);
+        };
+
+        let span = match Self::symbol_at(&tree, at) {
+            // a field is defined where it is first assigned, or else first used
+            Some(Symbol::Field(field)) => {
+                let refs: Vec<FieldRef> = tree
+                    .field_refs()
+                    .into_iter()
+                    .filter(|f| f.name == field.name)
+                    .collect();
+                refs.iter().find(|f| f.write).unwrap_or(&refs[0]).span
+            }
+            Some(Symbol::Rule(i)) => tree.rule_name_span(i),
+            None => return Ok(Json::Null),
+        };
+        Ok(Self::location(&uri, text, span))
+    }
+
+    fn references(&self, params: &Json) -> Result<Json, String> {
+        let (uri, text, at) = self.document_at(params)?;
+        let Ok(tree) = SyntaxTree::parse(text) else {
+            return Ok(Json::Array(vec![]));
+        };
+
+        let spans: Vec<Span> = match Self::symbol_at(&tree, at) {
+            Some(Symbol::Field(field)) => tree
+                .field_refs()
+                .into_iter()
+                .filter(|f| f.name == field.name)
+                .map(|f| f.span)
+                .collect(),
+            Some(Symbol::Rule(i)) => vec![tree.rule_name_span(i)],
+            None => vec![],
+        };
+        Ok(Json::Array(
+            spans
+                .into_iter()
+                .map(|s| Self::location(&uri, text, s))
+                .collect(),
+        ))
+    }
+
+    fn hover(&self, params: &Json) -> Result<Json, String> {
+        let (_, text, at) = self.document_at(params)?;
+        let Ok(tree) = SyntaxTree::parse(text) else {
+            return Ok(Json::Null);
+        };
+
+        let (span, contents) = match Self::symbol_at(&tree, at) {
+            Some(Symbol::Field(field)) => {
+                let ty = match self.schema.get(&field.name) {
+                    Some(ty) => ty.to_string(),
+                    None => "unknown type".to_string(),
+                };
+                (field.span, format!("`{}`: {}", field.name, ty))
+            }
+            Some(Symbol::Rule(i)) => {
+                let rule = &tree.rules()[i].rule;
+                let mut contents = format!("rule **{}**", rule.name);
+                if let Some(description) = &rule.description {
+                    contents.push_str(&format!("\n\n{}", description));
+                }
+                contents.push_str(&format!("\n\nsalience {}", rule.salience));
+                (tree.rule_name_span(i), contents)
+            }
+            None => return Ok(Json::Null),
+        };
+
+        Ok(obj(vec![
+            (
+                "contents",
+                obj(vec![
+                    ("kind", Json::Str("markdown".into())),
+                    ("value", Json::Str(contents)),
+                ]),
+            ),
+            ("range", range(text, span)),
+        ]))
+    }
+
+    fn completion(&self, params: &Json) -> Result<Json, String> {
+        let (_, text, at) = self.document_at(params)?;
+        let prefix_start = text[..at]
+            .char_indices()
+            .rev()
+            .find(|(_, c)| !(is_ident_continue(*c) || *c == '.'))
+            .map_or(0, |(i, c)| i + c.len_utf8());
+        let prefix = &text[prefix_start..at];
+
+        let mut names: Vec<String> = self.schema.fields().map(|(f, _)| f.clone()).collect();
+        if let Ok(tree) = SyntaxTree::parse(text) {
+            names.extend(tree.field_refs().into_iter().map(|f| f.name));
+        }
+        names.sort();
+        names.dedup();
+
+        let items = names
+            .into_iter()
+            .filter(|n| n.starts_with(prefix) && n != prefix)
+            .map(|n| {
+                let mut item = vec![("label", Json::Str(n.clone())), ("kind", Json::Int(5))];
+                if let Some(ty) = self.schema.get(&n) {
+                    item.push(("detail", Json::Str(ty.to_string())));
+                }
+                obj(item)
+            })
+            .collect();
+        Ok(Json::Array(items))
+    }
+
+    fn formatting(&self, params: &Json) -> Result<Json, String> {
+        let uri = params
+            .get("textDocument")
+            .and_then(|d| d.get("uri"))
+            .and_then(|u| u.as_str())
+            .ok_or("missing textDocument.uri")?;
+        let text = self
+            .documents
+            .get(uri)
+            .ok_or_else(|| format!("document {} is not open", uri))?;
+        let Ok(tree) = SyntaxTree::parse(text) else {
+            return Ok(Json::Null);
+        };
+
+        let rules: Vec<String> = tree.rules().iter().map(|r| r.rule.to_string()).collect();
+        let formatted = format!("{}\n", rules.join("\n\n"));
+        // the printer may move a comment but must not drop one, as it
+        // would one in a file without rules; such a file is left as it is
+        let kept = SyntaxTree::parse(&formatted).is_ok_and(|printed| comments(&printed) == comments(&tree));
+        if formatted == *text || !kept {
+            return Ok(Json::Array(Vec::new()));
+        }
+        Ok(Json::Array(vec![obj(vec![
+            ("range", range(text, Span::new(0, text.len()))),
+            ("newText", Json::Str(formatted)),
+        ])]))
+    }
+}
+
+// the text of every comment in the tree, in sorted order
+fn comments(tree: &SyntaxTree) -> Vec<&str> {
+    let mut comments: Vec<&str> = tree
+        .tokens()
+        .iter()
+        .flat_map(|t| &t.leading)
+        .filter(|t| t.kind == TriviaKind::Comment)
+        .map(|t| t.text.as_str())
+        .collect();
+    comments.sort_unstable();
+    comments
+}
+
+enum Symbol {
+    Field(FieldRef),
+    Rule(usize),
+}
+
+/// Reads one `Content-Length` framed message; `None` at end of input.
+pub fn read_message(reader: &mut impl BufRead) -> Result<Option<Json>, String> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).map_err(|e| e.to_string())? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = Some(value.trim().parse::<usize>().map_err(|e| e.to_string())?);
+        }
+    }
+
+    let length = length.ok_or("missing Content-Length header")?;
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).map_err(|e| e.to_string())?;
+    let body = String::from_utf8(body).map_err(|e| e.to_string())?;
+    Json::parse(&body).map(Some)
+}
+
+pub fn write_message(writer: &mut impl Write, msg: &Json) -> io::Result<()> {
+    let body = msg.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    const URI: &str = "file:///rules.grl";
+
+    // drives the server through the same framing the binary uses
+    struct Client {
+        server: Server,
+        next_id: i64,
+    }
+
+    impl Client {
+        fn new(schema: &str) -> Client {
+            let mut client = Client {
+                server: Server::new(),
+                next_id: 1,
+            };
+            let params = format!(r#"{{"initializationOptions": {{"schema": {}}}}}"#, schema);
+            let result = client.request("initialize", &params);
+            assert!(result.get("capabilities").is_some());
+            client
+        }
+
+        fn send(&mut self, msg: Json) -> Vec<Json> {
+            let mut wire = Vec::new();
+            write_message(&mut wire, &msg).unwrap();
+            let msg = read_message(&mut Cursor::new(wire)).unwrap().unwrap();
+            self.server.handle(&msg)
+        }
+
+        fn request(&mut self, method: &str, params: &str) -> Json {
+            let id = self.next_id;
+            self.next_id += 1;
+            let msg = Json::parse(&format!(
+                r#"{{"jsonrpc": "2.0", "id": {}, "method": "{}", "params": {}}}"#,
+                id, method, params
+            ))
+            .unwrap();
+            let replies = self.send(msg);
+            assert_eq!(replies.len(), 1);
+            assert_eq!(replies[0].get("id"), Some(&Json::Int(id)));
+            replies[0]
+                .get("result")
+                .cloned()
+                .unwrap_or_else(|| panic!("{}", replies[0]))
+        }
+
+        fn open(&mut self, text: &str) -> Vec<Json> {
+            let msg = notification(
+                "textDocument/didOpen",
+                obj(vec![(
+                    "textDocument",
+                    obj(vec![
+                        ("uri", Json::Str(URI.into())),
+                        ("text", Json::Str(text.into())),
+                    ]),
+                )]),
+            );
+            self.send(msg)
+        }
+
+        fn at(&mut self, method: &str, line: i64, character: i64) -> Json {
+            let params = format!(
+                r#"{{"textDocument": {{"uri": "{}"}}, "position": {{"line": {}, "character": {}}}}}"#,
+                URI, line, character
+            );
+            self.request(method, &params)
+        }
+    }
+
+    const SCHEMA: &str = r#"{"Order": {"Total": "int", "Discount": "int", "Code": "str"}}"#;
+
+    const SOURCE: &str = "rule Discount \"big orders\" {\n    when Order.Total > 100\n    then Order.Discount = 10;\n}\nrule Again { when Order.Discount == 10 then Order.Code = \"x\"; }\n";
+
+    fn diagnostics(published: &[Json]) -> Vec<Json> {
+        published[0]
+            .get("params")
+            .and_then(|p| p.get("diagnostics"))
+            .and_then(|d| d.as_array())
+            .unwrap()
+            .to_vec()
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let mut client = Client::new(SCHEMA);
+        assert!(diagnostics(&client.open(SOURCE)).is_empty());
+
+        let published = client.open("rule R {\n  when Order.Total > 1\n  Order.Discount = 1;\n}");
+        let diags = diagnostics(&published);
+        assert_eq!(diags.len(), 1);
+        assert_eq!(
+            diags[0].get("message"),
+            Some(&Json::Str("expected 'then'".into()))
+        );
+        assert_eq!(
+            diags[0].get("range").unwrap().to_string(),
+            r#"{"start":{"line":2,"character":2},"end":{"line":2,"character":7}}"#
+        );
+
+        let published = client.open("rule A { when Order.Total > then Order.Discount = 1; }\nrule B { when Order.Total > 1 then Order.Discount = ; }");
+        let lines: Vec<String> = diagnostics(&published)
+            .iter()
+            .map(|d| d.get("range").unwrap().get("start").unwrap().get("line").unwrap().to_string())
+            .collect();
+        assert_eq!(lines, vec!["0", "1"]);
+
+        let published = client.open("rule R { when Order.Total == \"x\" then Order.Nope = 1; }");
+        let messages: Vec<String> = diagnostics(&published)
+            .iter()
+            .map(|d| d.get("message").unwrap().as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "unknown field Order.Nope",
+                "cannot compare int with str in `Order.Total == \"x\"`"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_navigation() {
+        let mut client = Client::new(SCHEMA);
+        client.open(SOURCE);
+
+        // `Order.Discount` in the second rule's condition is defined by the
+        // first rule's action
+        let def = client.at("textDocument/definition", 4, 20);
+        assert_eq!(
+            def.get("range").unwrap().to_string(),
+            r#"{"start":{"line":2,"character":9},"end":{"line":2,"character":23}}"#
+        );
+
+        let refs = client.at("textDocument/references", 2, 12);
+        assert_eq!(refs.as_array().unwrap().len(), 2);
+
+        let hover = client.at("textDocument/hover", 1, 12);
+        assert_eq!(
+            hover.get("contents").unwrap().get("value"),
+            Some(&Json::Str("`Order.Total`: int".into()))
+        );
+        let hover = client.at("textDocument/hover", 0, 6);
+        assert_eq!(
+            hover.get("contents").unwrap().get("value"),
+            Some(&Json::Str(
+                "rule **Discount**\n\nbig orders\n\nsalience 0".into()
+            ))
+        );
+        assert_eq!(client.at("textDocument/hover", 1, 2), Json::Null);
+    }
+
+    #[test]
+    fn test_completion_and_formatting() {
+        let mut client = Client::new(SCHEMA);
+        client.open("rule R { when Order.T");
+        let items = client.at("textDocument/completion", 0, 21);
+        assert_eq!(
+            items.to_string(),
+            r#"[{"label":"Order.Total","kind":5,"detail":"int"}]"#
+        );
+        // after a character of more than one byte
+        let line = "rule R { when Order.Total > 1 then Order.Discount = ×Order.T";
+        client.open(line);
+        let items = client.at("textDocument/completion", 0, line.chars().count() as i64);
+        assert_eq!(
+            items.to_string(),
+            r#"[{"label":"Order.Total","kind":5,"detail":"int"}]"#
+        );
+
+        client.open("rule R {when Order.Total>1 then Order.Discount=2;}");
+        let edits = client.request(
+            "textDocument/formatting",
+            &format!(r#"{{"textDocument": {{"uri": "{}"}}}}"#, URI),
+        );
+        assert_eq!(
+            edits.as_array().unwrap()[0].get("newText"),
+            Some(&Json::Str("rule R {\n    when\n        Order.Total > 1\n    then\n        Order.Discount = 2;\n}\n".into()))
+        );
+
+        let format = |client: &mut Client, text: &str| {
+            client.open(text);
+            client.request("textDocument/formatting", &format!(r#"{{"textDocument": {{"uri": "{}"}}}}"#, URI))
+        };
+        let edits = format(&mut client, "rule R {when Order.Total>1 // big\nthen Order.Discount=2;}\n// trailing note");
+        assert_eq!(
+            edits.as_array().unwrap()[0].get("newText"),
+            Some(&Json::Str(
+                "rule R {\n    when\n        // big\n        Order.Total > 1\n    then\n        Order.Discount = 2;\n}\n// trailing note\n".into()
+            ))
+        );
+        // already formatted, or nothing to keep the comments with
+        let formatted = edits.as_array().unwrap()[0].get("newText").unwrap().as_str().unwrap().to_string();
+        assert_eq!(format(&mut client, &formatted), Json::Array(Vec::new()));
+        assert_eq!(format(&mut client, "// rules go here\n"), Json::Array(Vec::new()));
+    }
+
+    #[test]
+    fn test_lifecycle() {
+        let mut client = Client::new("{}");
+        assert_eq!(client.request("shutdown", "null"), Json::Null);
+
+        let replies =
+            client.send(Json::parse(r#"{"jsonrpc": "2.0", "id": 9, "method": "bogus"}"#).unwrap());
+        assert!(replies[0].get("error").is_some());
+
+        client.send(Json::parse(r#"{"jsonrpc": "2.0", "method": "exit"}"#).unwrap());
+        assert!(client.server.exit);
+    }
+}
@@ -1,256 +1,158 @@
-use crate::{ast::{Action, CmpOp, Condition, Expr, Op, Rule}, value::Value};
-
-#[derive(Debug)]
-enum Token {
-    Int(i64),
-    Bool(bool),
-
-    Ident(String),
-
-    Rule,
-    When,
-    Then,
-
-    LBrace,
-    RBrace,
-    LParen,
-    RParen,
-    Semicolon,
-    Dot,
-
-    Eq,
-    NotEq,
-    Lt,
-    Gt,
-    LtEq,
-    GtEq,
-    Assign,
-    And,
-    Or,
-    Plus,
-    Minus,
-    Mul,
-    Div,
-
-    StringLit(String),
-}
+use std::{cell::Cell, ops::Range};
+
+use crate::{ast::{check_action, check_patterns, Action, ActivationGroup, Aggregate, CmpOp, CommentPlace, Condition, Expr, FactSet, Lambda, Op, Rule, FACT_SET_BINDING}, functions, lexer::{lex_recovering, Diagnostic, Span, Token}, limits::Limits, value::Value};
 
+/// The non-trivia tokens of `input`.
+#[cfg(test)]
 fn tokenize(input: String) -> Result<Vec<Token>, String> {
-    let bytes = input.as_bytes();
-    let mut pos = 0;
-    let mut tokens = Vec::new();
+    Ok(crate::lexer::lex(&input)
+        .map_err(|d| d.message)?
+        .into_iter()
+        .map(|(token, _)| token)
+        .filter(|token| !matches!(token, Token::Whitespace | Token::Comment(_)))
+        .collect())
+}
 
-    while pos < bytes.len() {
-        if bytes[pos].is_ascii_whitespace() {
-            pos += 1;
-            continue;
+struct Parser {
+    tokens: Vec<Token>,
+    spans: Vec<Span>,
+    // the index of the ')' closing each '(', by the index of the '('
+    closing: Vec<Option<usize>>,
+    pos: usize,
+    // furthest token looked at, where errors are reported
+    furthest: Cell<usize>,
+    input_len: usize,
+    // comments, each with the index of the token that follows it
+    comments: Vec<(usize, String)>,
+    // errors recovered from so far, the lexer's first
+    errors: Vec<Diagnostic>,
+    lex_errors: usize,
+    // byte offset where the rule being parsed starts
+    rule_start: usize,
+    // nesting of the comparison or atom being parsed, and the number of
+    // them parsed so far in the current rule
+    depth: usize,
+    nodes: usize,
+    max_depth: usize,
+    max_nodes: usize,
+    // variable of the pattern whose constraint is being parsed, and the
+    // number of patterns without one so far in the current rule
+    pattern: Option<String>,
+    anonymous: usize,
+    // variables the current rule's patterns bind, for its actions
+    variables: Vec<String>,
+    // parameters of the lambdas being parsed, innermost last
+    lambdas: Vec<String>,
+}
+
+impl Parser {
+    fn new(input: String) -> Result<Parser, Diagnostic> {
+        let mut parser = Parser::recovering(input);
+        if parser.errors.is_empty() {
+            Ok(parser)
+        } else {
+            Err(parser.errors.remove(0))
         }
+    }
 
-        match bytes[pos] {
-            b'{' => {
-                tokens.push(Token::LBrace);
-                pos += 1;
-                continue;
-            }
-            b'}' => {
-                tokens.push(Token::RBrace);
-                pos += 1;
-                continue;
-            }
-            b'(' => {
-                tokens.push(Token::LParen);
-                pos += 1;
-                continue;
-            }
-            b')' => {
-                tokens.push(Token::RParen);
-                pos += 1;
-                continue;
-            }
-            b';' => {
-                tokens.push(Token::Semicolon);
-                pos += 1;
-                continue;
-            }
-            b'.' => {
-                tokens.push(Token::Dot);
-                pos += 1;
-                continue;
-            }
-            b'+' => {
-                tokens.push(Token::Plus);
-                pos += 1;
-                continue;
-            }
-            b'-' => {
-                tokens.push(Token::Minus);
-                pos += 1;
-                continue;
-            }
-            b'*' => {
-                tokens.push(Token::Mul);
-                pos += 1;
-                continue;
+    // a parser over whatever could be lexed, keeping the lexer's errors
+    fn recovering(input: String) -> Parser {
+        let (lexed, errors) = lex_recovering(&input);
+        let mut tokens = Vec::new();
+        let mut spans = Vec::new();
+        let mut comments = Vec::new();
+        for (token, span) in lexed {
+            match token {
+                Token::Whitespace => {}
+                Token::Comment(text) => comments.push((tokens.len(), text)),
+                token => {
+                    tokens.push(token);
+                    spans.push(span);
+                }
             }
-            _ => {
-                let next = peek(bytes, pos + 1);
-                match bytes[pos] {
-                    b'=' => {
-                        if next == Some(b'=') {
-                            tokens.push(Token::Eq);
-                            pos += 2;
-                        } else {
-                            tokens.push(Token::Assign);
-                            pos += 1;
-                        }
-                        continue;
-                    }
-                    b'!' => {
-                        if next == Some(b'=') {
-                            tokens.push(Token::NotEq);
-                            pos += 2;
-                        } else {
-                            return Err(format!("unexpected char {} at pos {}", bytes[pos], pos));
-                        }
-                        continue;
-                    }
-                    b'<' => {
-                        if next == Some(b'=') {
-                            tokens.push(Token::LtEq);
-                            pos += 2;
-                        } else {
-                            tokens.push(Token::Lt);
-                            pos += 1;
-                        }
-                        continue;
-                    }
-                    b'>' => {
-                        if next == Some(b'=') {
-                            tokens.push(Token::GtEq);
-                            pos += 2;
-                        } else {
-                            tokens.push(Token::Gt);
-                            pos += 1;
-                        }
-                        continue;
-                    }
-                    b'&' => {
-                        if next == Some(b'&') {
-                            tokens.push(Token::And);
-                            pos += 2;
-                        } else {
-                            return Err(format!("unexpected char {} at pos {}", bytes[pos], pos));
-                        }
-                        continue;
-                    }
-                    b'|' => {
-                        if next == Some(b'|') {
-                            tokens.push(Token::Or);
-                            pos += 2;
-                        } else {
-                            return Err(format!("unexpected char {} at pos {}", bytes[pos], pos));
-                        }
-                        continue;
-                    }
-                    _ => {
-                        if bytes[pos] == b'/' {
-                            let next = peek(bytes, pos + 1);
-                            if next == Some(b'/') {
-                                pos += 2;
-                                while pos < bytes.len() && bytes[pos] != b'\n' {
-                                    pos += 1;
-                                }
-                                continue;
-                            } else if next == Some(b'*') {
-                                pos += 2;
-                                while pos + 1 < bytes.len() {
-                                    if bytes[pos] == b'*' && bytes[pos + 1] == b'/' {
-                                        pos += 2;
-                                        break;
-                                    }
-                                    pos += 1;
-                                }
-                                continue;
-                            } else {
-                                tokens.push(Token::Div);
-                                pos += 1;
-                                continue;
-                            }
-                        }
-
-                        if bytes[pos] == b'"' {
-                            pos += 1;
-                            let start = pos;
-                            while pos < bytes.len() && bytes[pos] != b'"' {
-                                pos += 1;
-                            }
-                            let s = input[start..pos].to_string();
-                            tokens.push(Token::StringLit(s));
-                            pos += 1;
-                            continue;
-                        }
-
-                        if bytes[pos].is_ascii_digit() {
-                            let start = pos;
-                            while pos < bytes.len() && bytes[pos].is_ascii_digit() {
-                                pos += 1;
-                            }
-                            let n: i64 = input[start..pos].parse().map_err(|e| format!("{}", e))?;
-                            tokens.push(Token::Int(n));
-                            continue;
-                        }
-
-                        if bytes[pos].is_ascii_alphabetic() || bytes[pos] == b'_' {
-                            let start = pos;
-                            while pos < bytes.len()
-                                && (bytes[pos].is_ascii_alphanumeric() || bytes[pos] == b'_')
-                            {
-                                pos += 1;
-                            }
-
-                            let word = &input[start..pos];
-                            match word {
-                                "rule" => tokens.push(Token::Rule),
-                                "when" => tokens.push(Token::When),
-                                "then" => tokens.push(Token::Then),
-                                "true" => tokens.push(Token::Bool(true)),
-                                "false" => tokens.push(Token::Bool(false)),
-                                _ => tokens.push(Token::Ident(word.to_string())),
-                            }
-                            continue;
-                        }
-
-                        return Err(format!("unexpected char {} at pos {}", bytes[pos], pos));
+        }
+        let mut closing = vec![None; tokens.len()];
+        let mut open = Vec::new();
+        for (i, token) in tokens.iter().enumerate() {
+            match token {
+                // a '(' left open in one rule doesn't reach into the next
+                Token::Rule => open.clear(),
+                Token::LParen => open.push(i),
+                Token::RParen => {
+                    if let Some(at) = open.pop() {
+                        closing[at] = Some(i);
                     }
                 }
+                _ => {}
             }
         }
+        let lex_errors = errors.len();
+        let limits = Limits::default();
+        Parser {
+            tokens, spans, closing, pos: 0, furthest: Cell::new(0), input_len: input.len(), comments, errors, lex_errors, rule_start: 0,
+            depth: 0, nodes: 0, max_depth: limits.max_depth, max_nodes: limits.max_nodes, pattern: None, anonymous: 0, variables: Vec::new(),
+            lambdas: Vec::new(),
+        }
     }
-    Ok(tokens)
-}
 
-fn peek(bytes: &[u8], pos: usize) -> Option<u8> {
-    bytes.get(pos).copied()
-}
+    fn diagnostic(&self, message: String) -> Diagnostic {
+        let at = self.furthest.get();
+        let span = match self.spans.get(at) {
+            Some(span) => *span,
+            None => Span::new(self.input_len, self.input_len),
+        };
+        Diagnostic::new(message, span)
+    }
 
-struct Parser {
-    tokens: Vec<Token>,
-    pos: usize,
-}
+    // records an error to carry on past; one following a bad character in
+    // the same rule is most likely caused by it, and is left out
+    fn report(&mut self, message: String) {
+        let diagnostic = self.diagnostic(message);
+        let caused = self.errors[..self.lex_errors]
+            .iter()
+            .any(|e| e.span.start >= self.rule_start && e.span.start <= diagnostic.span.start);
+        if !caused {
+            self.errors.push(diagnostic);
+        }
+    }
 
-impl Parser {
     fn peek(&self) -> Option<&Token> {
+        self.furthest.set(self.furthest.get().max(self.pos));
         let token = self.tokens.get(self.pos);
         token
     }
 
     fn advance(&mut self) -> Option<&Token> {
+        self.furthest.set(self.furthest.get().max(self.pos));
         let token = self.tokens.get(self.pos);
         self.pos+=1;
         token
     }
 
+    // runs `parse` one level deeper, counting one more node
+    fn nested<T>(&mut self, parse: fn(&mut Parser) -> Result<T, String>) -> Result<T, String> {
+        if self.depth >= self.max_depth {
+            return Err(format!("expression nested deeper than {} levels", self.max_depth));
+        }
+        self.nodes += 1;
+        if self.nodes > self.max_nodes {
+            return Err(format!("rule has more than {} expression nodes", self.max_nodes));
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn expect_end(&self) -> Result<(), String> {
+        match self.peek() {
+            Some(token) => Err(format!("unexpected token {:?}", token)),
+            None => Ok(()),
+        }
+    }
+
     fn parse_rule(&mut self) -> Result<Rule, String> {
+        let start = self.pos;
         if !matches!(self.peek(), Some(Token::Rule)) {
             return Err("expected 'rule'".into());
         }
@@ -260,8 +162,27 @@ impl Parser {
             s.clone()
         } else {return Err("expected rule name".into());};
 
-        if matches!(self.peek(), Some(Token::StringLit(_))) {
+        let description = if let Some(Token::StringLit(s)) = self.peek() {
+            let s = s.clone();
             self.advance();
+            Some(s)
+        } else {
+            None
+        };
+
+        let mut salience = 0;
+        let mut no_loop = false;
+        let mut lock_on_active = false;
+        let mut activation_group = None;
+        while let Some(Token::Ident(_)) = self.peek() {
+            let attr = self.parse_attribute_name()?;
+            match attr.as_str() {
+                "salience" => salience = self.parse_salience()?,
+                "no-loop" => no_loop = self.parse_flag(),
+                "lock-on-active" => lock_on_active = self.parse_flag(),
+                "activation-group" => activation_group = Some(self.parse_activation_group()?),
+                other => return Err(format!("unknown rule attribute '{}'", other)),
+            }
         }
 
         if !matches!(self.peek(), Some(Token::LBrace)) {
@@ -272,23 +193,124 @@ impl Parser {
         if !matches!(self.peek(), Some(Token::When)) {
             return Err("expected 'when'".into());
         }
+        let when = self.pos;
         self.advance();
         
         let condition = self.parse_condition()?;
+        check_patterns(&condition)?;
+        self.variables = condition
+            .conjuncts()
+            .into_iter()
+            .filter_map(|c| match c {
+                Condition::Pattern { binding, .. } => Some(binding.clone()),
+                _ => None,
+            })
+            .collect();
 
         if !matches!(self.peek(), Some(Token::Then)) {
             return Err("expected 'then'".into());
         }
+        let then = self.pos;
         self.advance();
 
-        let actions = self.parse_actions()?;
+        let (starts, actions): (Vec<usize>, Vec<Action>) = self.parse_actions().into_iter().unzip();
 
         if !matches!(self.peek(), Some(Token::RBrace)) {
             return Err("expected '}'".into());
         }
         self.advance();
 
-        Ok(Rule {name, condition, actions})
+        // comments up to `when` go before the rule, the rest where they
+        // were written: those in the condition before it, those in the
+        // actions before the next one
+        let mut rule = Rule::new(name, condition, actions);
+        for (at, text) in self.comments.iter().filter(|(at, _)| (start..self.pos).contains(at)) {
+            if *at <= when {
+                rule.comments.push(text.clone());
+            } else if *at <= then {
+                rule.placed_comments.push((CommentPlace::When, text.clone()));
+            } else {
+                let next = starts.iter().position(|s| s >= at).unwrap_or(starts.len());
+                rule.placed_comments.push((CommentPlace::Action(next), text.clone()));
+            }
+        }
+        rule.description = description;
+        rule.salience = salience;
+        rule.no_loop = no_loop;
+        rule.lock_on_active = lock_on_active;
+        rule.activation_group = activation_group;
+        Ok(rule)
+    }
+
+    // a group name, then `unique` when a second match is an error
+    fn parse_activation_group(&mut self) -> Result<ActivationGroup, String> {
+        let name = match self.advance() {
+            Some(Token::StringLit(s)) => s.clone(),
+            other => return Err(format!("expected activation group name, got {:?}", other)),
+        };
+        let unique = matches!(self.peek(), Some(Token::Ident(s)) if s == "unique");
+        if unique {
+            self.advance();
+        }
+        Ok(ActivationGroup { name, unique })
+    }
+
+    // a flag attribute may be followed by `true` or `false`, and is on when it isn't
+    fn parse_flag(&mut self) -> bool {
+        if let Some(Token::Bool(b)) = self.peek() {
+            let b = *b;
+            self.advance();
+            b
+        } else {
+            true
+        }
+    }
+
+    fn parse_salience(&mut self) -> Result<i64, String> {
+        let at = if matches!(self.peek(), Some(Token::Minus)) { 1 } else { 0 };
+        match self.tokens.get(self.pos + at) {
+            Some(Token::Int(_)) => self.parse_int(),
+            other => Err(format!("expected salience value, got {:?}", other)),
+        }
+    }
+
+    // an integer literal with an optional sign
+    fn parse_int(&mut self) -> Result<i64, String> {
+        let negative = matches!(self.peek(), Some(Token::Minus));
+        if negative {
+            self.advance();
+        }
+        let n = match self.advance() {
+            Some(Token::Int(n)) => *n,
+            other => return Err(format!("expected number, got {:?}", other)),
+        };
+        if negative {
+            // i64::MIN has no positive counterpart, so negate in u64
+            0i64.checked_sub_unsigned(n).ok_or_else(|| format!("integer literal -{} out of range", n))
+        } else {
+            i64::try_from(n).map_err(|_| format!("integer literal {} out of range", n))
+        }
+    }
+
+    // attribute names are dash-separated words, e.g. `no-loop`
+    fn parse_attribute_name(&mut self) -> Result<String, String> {
+        let mut name = if let Some(Token::Ident(s)) = self.advance() {
+            s.clone()
+        } else {
+            return Err("expected attribute name".into());
+        };
+
+        // `salience -5` is a negative value, not part of the name
+        while matches!(self.peek(), Some(Token::Minus))
+            && matches!(self.tokens.get(self.pos + 1), Some(Token::Ident(_)))
+        {
+            self.advance();
+            if let Some(Token::Ident(s)) = self.advance() {
+                name.push('-');
+                name.push_str(s);
+            }
+        }
+        Ok(name)
     }
 
     fn parse_condition(&mut self) -> Result<Condition, String> {
@@ -314,66 +336,255 @@ impl Parser {
     fn parse_expr(&mut self) -> Result<Expr, String> {
         let mut left = self.parse_atom()?;
         loop {
-            match self.peek() {
-                Some(Token::Plus) => {
+            let op = match self.peek() {
+                Some(Token::Plus) => Op::Add,
+                Some(Token::Minus) => Op::Sub,
+                _ => break,
+            };
+            self.advance();
+            let right = self.parse_atom()?;
+            left = Expr::BinOp {
+                left: Box::new(left),
+                op,
+                right: Box::new(right),
+            };
+        }
+        Ok(left)
+    }
+
+    /// Parses rules to the end of input. A rule with an error is skipped up
+    /// to the next rule boundary, and parsing carries on from there.
+    fn parse_rules(&mut self) -> Vec<(Rule, Range<usize>)> {
+        let mut rules = vec![];
+        while self.pos < self.tokens.len() {
+            let start = self.pos;
+            self.rule_start = self.spans[start].start;
+            self.nodes = 0;
+            self.anonymous = 0;
+            match self.parse_rule() {
+                Ok(rule) => rules.push((rule, start..self.pos)),
+                Err(e) => {
+                    self.report(e);
+                    self.sync_rule(start);
+                }
+            }
+        }
+        // comments after the last token end the file, after the last rule
+        if let Some((rule, _)) = rules.last_mut() {
+            for (_, text) in self.comments.iter().filter(|(at, _)| *at >= self.tokens.len()) {
+                rule.placed_comments.push((CommentPlace::After, text.clone()));
+            }
+        }
+        rules
+    }
+
+    // skips to the next `rule` keyword, or past the next `}`
+    fn sync_rule(&mut self, start: usize) {
+        while let Some(token) = self.peek() {
+            match token {
+                Token::Rule if self.pos > start => break,
+                Token::RBrace => {
+                    self.advance();
+                    break;
+                }
+                _ => {
                     self.advance();
-                    let right = self.parse_atom()?;
-                    left = Expr::BinOp {
-                        left: Box::new(left),
-                        op: Op::Add,
-                        right: Box::new(right),
-                    };
                 }
-                _ => break,
             }
         }
-        Ok(left)
+        self.furthest.set(self.pos);
+    }
+
+    // skips past the next `;`, stopping early at the end of the rule
+    fn sync_statement(&mut self) {
+        while let Some(token) = self.peek() {
+            match token {
+                Token::RBrace | Token::Rule => break,
+                Token::Semicolon => {
+                    self.advance();
+                    break;
+                }
+                _ => {
+                    self.advance();
+                }
+            }
+        }
+        self.furthest.set(self.pos);
     }
 
-    fn parse_actions(&mut self) -> Result<Vec<Action>, String> {
+    // a bad action is reported and dropped, keeping the rest of the rule
+    // the actions, each with the index of its first token
+    fn parse_actions(&mut self) -> Vec<(usize, Action)> {
         let mut actions = Vec::new();
-        while !matches!(self.peek(), Some(Token::RBrace) | None) {
-            let name = if let Some(Token::Ident(s)) = self.advance() {
-                s.clone()
-            } else {
-                return Err("expected identifier".into());
-            };
+        // a `rule` here means the `}` is missing, which the caller reports
+        while !matches!(self.peek(), Some(Token::RBrace | Token::Rule) | None) {
+            let start = self.pos;
+            match self.parse_action() {
+                Ok(action) => {
+                    let variables: Vec<&str> = self.variables.iter().map(String::as_str).collect();
+                    if let Err(e) = check_action(&action, &variables) {
+                        self.report(e);
+                    }
+                    actions.push((start, action));
+                }
+                Err(e) => {
+                    self.report(e);
+                    self.sync_statement();
+                }
+            }
+        }
+        actions
+    }
 
-            if !matches!(self.peek(), Some(Token::Dot)) {
-                return  Err("expected '.'".into());
+    fn parse_action(&mut self) -> Result<Action, String> {
+        if let (Some(Token::Ident(name)), Some(Token::LParen)) = (self.peek(), self.tokens.get(self.pos + 1)) {
+            if matches!(name.as_str(), "insert" | "update" | "retract") {
+                return self.parse_memory_action();
             }
-            self.advance();
+        }
 
-            let field_name = if let Some(Token::Ident(s)) = self.advance() {
-                format!("{}.{}", name, s)
-            } else {
-                return Err("expected field name".into());
-            };
+        let name = if let Some(Token::Ident(s)) = self.advance() {
+            s.clone()
+        } else {
+            return Err("expected identifier".into());
+        };
 
-            if !matches!(self.peek(), Some(Token::Assign)) {
-                return Err("expected '='".into());
-            }
-            self.advance();
+        if !matches!(self.peek(), Some(Token::Dot)) {
+            return  Err("expected '.'".into());
+        }
+        self.advance();
+
+        let field_name = if let Some(Token::Ident(s)) = self.advance() {
+            format!("{}.{}", name, s)
+        } else {
+            return Err("expected field name".into());
+        };
+
+        if !matches!(self.peek(), Some(Token::Assign)) {
+            return Err("expected '='".into());
+        }
+        self.advance();
+
+        let expr = self.parse_expr()?;
+        
+
+        if !matches!(self.peek(), Some(Token::Semicolon)) {
+            return Err("expected ';'".into());
+        }
+        self.advance();
+
+        Ok(Action::Assign { field: field_name, expr })
+    }
 
-            let expr = self.parse_expr()?;
-            
+    // `insert(Order { Total: 5 });`, `update(o);` or `retract(o);`
+    fn parse_memory_action(&mut self) -> Result<Action, String> {
+        let Some(Token::Ident(verb)) = self.advance() else { unreachable!() };
+        let verb = verb.clone();
+        self.advance();
+        let Some(Token::Ident(name)) = self.advance() else {
+            return Err(if verb == "insert" { "expected fact type".into() } else { "expected variable".into() });
+        };
+        let name = name.clone();
 
-            if !matches!(self.peek(), Some(Token::Semicolon)) {
-                return Err("expected ';'".into());
+        let action = if verb == "insert" {
+            if !matches!(self.advance(), Some(Token::LBrace)) {
+                return Err("expected '{' after fact type".into());
+            }
+            let mut fields = Vec::new();
+            while !matches!(self.peek(), Some(Token::RBrace)) {
+                let Some(Token::Ident(field)) = self.advance() else {
+                    return Err("expected field name".into());
+                };
+                let field = field.clone();
+                if !matches!(self.advance(), Some(Token::Colon)) {
+                    return Err("expected ':' after field name".into());
+                }
+                fields.push((field, self.parse_expr()?));
+                match self.peek() {
+                    Some(Token::Comma) => {
+                        self.advance();
+                    }
+                    Some(Token::RBrace) => {}
+                    _ => return Err("expected ',' or '}'".into()),
+                }
             }
             self.advance();
+            Action::Insert { fact_type: name, fields }
+        } else if verb == "update" {
+            Action::Update(name)
+        } else {
+            Action::Retract(name)
+        };
 
-            actions.push(Action::Assign { field: field_name, expr: expr });
+        if !matches!(self.advance(), Some(Token::RParen)) {
+            return Err(format!("expected ')' after {}", verb));
+        }
+        if !matches!(self.peek(), Some(Token::Semicolon)) {
+            return Err("expected ';'".into());
         }
-        Ok(actions)
+        self.advance();
+        Ok(action)
     }
 
     fn parse_comparison(&mut self) -> Result<Condition, String> {
+        self.nested(Parser::comparison)
+    }
+
+    fn comparison(&mut self) -> Result<Condition, String> {
+        if matches!(self.peek(), Some(Token::Not)) {
+            self.advance();
+            let inner = self.parse_comparison()?;
+            return Ok(Condition::Not(Box::new(inner)));
+        }
+
+        // `exists Order(...)` and `not exists Order(...)`
+        let keyword = |parser: &Parser, offset: usize, word: &str| {
+            matches!(parser.tokens.get(parser.pos + offset), Some(Token::Ident(w)) if w == word)
+        };
+        if keyword(self, 0, "not") && keyword(self, 1, "exists") {
+            self.advance();
+            let inner = self.parse_comparison()?;
+            return Ok(Condition::Not(Box::new(inner)));
+        }
+        if keyword(self, 0, "exists") && matches!(self.tokens.get(self.pos + 1), Some(Token::Ident(_))) {
+            self.advance();
+            return Ok(Condition::Exists(self.parse_fact_set()?));
+        }
+
+        // what follows the parentheses tells `Order(Total > 100)` from a
+        // fact set an aggregate is taken over, as in
+        // `Order(Total > 100).count() > 2`, or a misspelt function on the
+        // left of a comparison
+        let named = matches!(self.tokens.get(self.pos + 1), Some(Token::Colon));
+        if named || !self.group_continues(self.pos + 1) {
+            if let Some(pattern) = self.parse_pattern()? {
+                return Ok(pattern);
+            }
+        }
+
+        // likewise a grouped condition, or a parenthesised expression on
+        // the left of a comparison
+        if matches!(self.peek(), Some(Token::LParen)) && !self.group_continues(self.pos) {
+            self.advance();
+            let cond = self.parse_condition()?;
+            if !matches!(self.advance(), Some(Token::RParen)) {
+                return Err("expected ')'".into());
+            }
+            return Ok(cond);
+        }
+
         let left = self.parse_expr()?;
         let cmp_op = match self.peek() {
             Some(Token::Eq) => CmpOp::Eq,
+            Some(Token::NotEq) => CmpOp::NotEq,
             Some(Token::Lt) => CmpOp::Lt,
             Some(Token::Gt) => CmpOp::Gt,
+            Some(Token::LtEq) => CmpOp::LtEq,
+            Some(Token::GtEq) => CmpOp::GtEq,
+            // `any` and `all` are conditions of their own
+            _ if matches!(left, Expr::Aggregate { func: Aggregate::Any | Aggregate::All, .. }) => {
+                return Ok(Condition::Compare { left, op: CmpOp::Eq, right: Expr::Literal(Value::Bool(true)) });
+            }
             other => return Err(format!("unexpected operator {:?}", other)),
         };
         self.advance();
@@ -382,15 +593,173 @@ impl Parser {
         Ok(Condition::Compare {left, op:cmp_op, right})
     }
 
+    // `o: Order(Total > 100)`, or `Order(Total > 100)` without a variable,
+    // which is told apart from a function call by its name
+    fn parse_pattern(&mut self) -> Result<Option<Condition>, String> {
+        let binding = match (self.peek(), self.tokens.get(self.pos + 1), self.tokens.get(self.pos + 2)) {
+            (Some(Token::Ident(var)), Some(Token::Colon), Some(Token::Ident(_))) => {
+                let var = var.clone();
+                self.pos += 2;
+                var
+            }
+            (Some(Token::Ident(name)), Some(Token::LParen), _) if functions::signature(name).is_none() => {
+                self.anonymous += 1;
+                format!("${}", self.anonymous)
+            }
+            _ => return Ok(None),
+        };
+        let Some(Token::Ident(fact_type)) = self.advance() else {
+            return Err("expected fact type".into());
+        };
+        let fact_type = fact_type.clone();
+        if !matches!(self.advance(), Some(Token::LParen)) {
+            return Err("expected '(' after fact type".into());
+        }
+
+        let constraint = if matches!(self.peek(), Some(Token::RParen)) {
+            None
+        } else {
+            let outer = self.pattern.replace(binding.clone());
+            let constraint = self.parse_condition();
+            self.pattern = outer;
+            Some(Box::new(constraint?))
+        };
+        if !matches!(self.advance(), Some(Token::RParen)) {
+            return Err("expected ')' after pattern".into());
+        }
+        Ok(Some(Condition::Pattern { binding, fact_type, constraint }))
+    }
+
+    // `Transaction(Amount > 100)`, whose bare names are fields of each
+    // instance in turn
+    fn parse_fact_set(&mut self) -> Result<FactSet, String> {
+        let Some(Token::Ident(fact_type)) = self.advance() else {
+            return Err("expected fact type".into());
+        };
+        let fact_type = fact_type.clone();
+        if !matches!(self.advance(), Some(Token::LParen)) {
+            return Err("expected '(' after fact type".into());
+        }
+        let constraint = if matches!(self.peek(), Some(Token::RParen)) {
+            None
+        } else {
+            let outer = self.pattern.replace(FACT_SET_BINDING.to_string());
+            let constraint = self.parse_condition();
+            self.pattern = outer;
+            Some(Box::new(constraint?))
+        };
+        if !matches!(self.advance(), Some(Token::RParen)) {
+            return Err("expected ')' after fact set".into());
+        }
+        Ok(FactSet { fact_type, constraint })
+    }
+
+    // whether `.count(`, `.sum(` and so on come next
+    fn aggregate_follows(&self) -> bool {
+        self.aggregate_at(self.pos)
+    }
+
+    fn aggregate_at(&self, at: usize) -> bool {
+        matches!(
+            (self.tokens.get(at), self.tokens.get(at + 1), self.tokens.get(at + 2)),
+            (Some(Token::Dot), Some(Token::Ident(name)), Some(Token::LParen)) if Aggregate::from_name(name).is_some()
+        )
+    }
+
+    // whether an expression goes on after the parentheses opened at
+    // `open`, so they don't hold a condition of their own; looked up rather
+    // than found by parsing and backtracking, which takes exponential time
+    // on nested calls
+    fn group_continues(&self, open: usize) -> bool {
+        let Some(close) = self.closing.get(open).copied().flatten() else {
+            return false;
+        };
+        matches!(
+            self.tokens.get(close + 1),
+            Some(
+                Token::Dot | Token::Eq | Token::NotEq | Token::Lt | Token::Gt | Token::LtEq | Token::GtEq | Token::Plus | Token::Minus | Token::Mul
+                    | Token::Div
+            )
+        )
+    }
+
+    // `.sum(i => i.Price)` after `list`, with the `.` next
+    fn parse_aggregate(&mut self, list: Expr) -> Result<Expr, String> {
+        self.advance();
+        let Some(Token::Ident(name)) = self.advance() else { unreachable!() };
+        let func = Aggregate::from_name(name).unwrap();
+        self.advance();
+        let lambda = if matches!(self.peek(), Some(Token::RParen)) {
+            None
+        } else {
+            let Some(Token::Ident(param)) = self.advance() else {
+                return Err(format!("expected a lambda, like {}(i => i.Price)", func));
+            };
+            let param = param.clone();
+            if !matches!(self.advance(), Some(Token::Arrow)) {
+                return Err("expected '=>' after lambda parameter".into());
+            }
+            self.lambdas.push(param.clone());
+            let lambda = if func.takes_predicate() {
+                self.parse_condition().map(|body| Lambda::Filter(param, Box::new(body)))
+            } else {
+                self.parse_expr().map(|body| Lambda::Map(param, Box::new(body)))
+            };
+            self.lambdas.pop();
+            Some(lambda?)
+        };
+        if !matches!(self.advance(), Some(Token::RParen)) {
+            return Err(format!("expected ')' after {}", func));
+        }
+        func.check(lambda.as_ref())?;
+        Ok(Expr::Aggregate { func, list: Box::new(list), lambda })
+    }
+
     fn parse_atom(&mut self) -> Result<Expr, String> {
+        self.nested(Parser::atom)
+    }
+
+    fn atom(&mut self) -> Result<Expr, String> {
+        let mut expr = self.primary()?;
+        while self.aggregate_follows() {
+            expr = self.parse_aggregate(expr)?;
+        }
+        Ok(expr)
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
         match self.peek() {
-            Some(Token::Int(_)) => {
-                if let Some(Token::Int(n)) = self.advance() {
-                    let n = *n;
-                    Ok(Expr::Literal(Value::Int(n)))
-                } else {
-                    unreachable!()
-                }
+            Some(Token::Minus) if matches!(self.tokens.get(self.pos + 1), Some(Token::Duration(_))) => {
+                self.advance();
+                let Some(Token::Duration(d)) = self.advance() else { unreachable!() };
+                let d = d.checked_neg().ok_or_else(|| format!("duration -{} out of range", d))?;
+                Ok(Expr::Literal(Value::Duration(d)))
+            }
+            Some(Token::Int(_) | Token::Minus) => Ok(Expr::Literal(Value::Int(self.parse_int()?))),
+            Some(Token::Date(d)) => {
+                let d = *d;
+                self.advance();
+                Ok(Expr::Literal(Value::Date(d)))
+            }
+            Some(Token::DateTime(t)) => {
+                let t = *t;
+                self.advance();
+                Ok(Expr::Literal(Value::DateTime(t)))
+            }
+            Some(Token::Duration(d)) => {
+                let d = *d;
+                self.advance();
+                Ok(Expr::Literal(Value::Duration(d)))
+            }
+            Some(Token::Bool(b)) => {
+                let b = *b;
+                self.advance();
+                Ok(Expr::Literal(Value::Bool(b)))
+            },
+            Some(Token::StringLit(s)) => {
+                let s = s.clone();
+                self.advance();
+                Ok(Expr::Literal(Value::Str(s)))
             },
             Some(Token::Ident(_)) => {
                 let name = if let Some(Token::Ident(s)) = self.advance() {
@@ -399,13 +768,44 @@ impl Parser {
                     unreachable!()
                 };
 
-                if matches!(self.peek(), Some(Token::Dot)) {
+                // a fact set, when an aggregate is taken over it
+                if matches!(self.peek(), Some(Token::LParen))
+                    && functions::signature(&name).is_none()
+                    && self.closing[self.pos].is_some_and(|close| self.aggregate_at(close + 1))
+                {
+                    self.pos -= 1;
+                    return Ok(Expr::Facts(self.parse_fact_set()?));
+                }
+                if matches!(self.peek(), Some(Token::LParen)) {
+                    if functions::signature(&name).is_none() {
+                        return Err(format!("unknown function '{}'", name));
+                    }
+                    self.advance();
+                    let mut args = Vec::new();
+                    while !matches!(self.peek(), Some(Token::RParen)) {
+                        if !args.is_empty() {
+                            if !matches!(self.peek(), Some(Token::Comma)) {
+                                return Err("expected ',' or ')'".into());
+                            }
+                            self.advance();
+                        }
+                        args.push(self.parse_expr()?);
+                    }
+                    self.advance();
+                    functions::check_call(&name, args.len())?;
+                    Ok(Expr::Call { name, args })
+                } else if matches!(self.peek(), Some(Token::Dot)) && !self.aggregate_follows() {
                     self.advance();
                     if let Some(Token::Ident(field)) = self.advance() {
                         Ok(Expr::FieldRef(format!("{}.{}", name, field)))
                     } else {
                         Err("expected field name after '.'".into())
                     }
+                } else if self.lambdas.contains(&name) {
+                    Ok(Expr::FieldRef(name))
+                } else if let Some(binding) = &self.pattern {
+                    // inside a pattern, a bare name is a field of its instance
+                    Ok(Expr::FieldRef(format!("{}.{}", binding, name)))
                 } else {
                     Ok(Expr::FieldRef(name))
                 }
@@ -424,21 +824,91 @@ impl Parser {
     } 
 }
 
-fn parse(input: String) -> Result<Vec<Rule>, String> {
-    let tokens = tokenize(input.to_string())?;
-    let mut parser = Parser{tokens, pos: 0};
-    let mut rules = vec![];
-    while parser.pos < parser.tokens.len() {
-        rules.push(parser.parse_rule()?);
+/// Parses a single expression, e.g. a decision table cell.
+pub fn parse_expr(input: String) -> Result<Expr, String> {
+    let mut parser = Parser::new(input).map_err(|d| d.message)?;
+    let expr = parser.parse_expr()?;
+    parser.expect_end()?;
+    Ok(expr)
+}
+
+/// Parses a single condition, as written after `when`.
+pub fn parse_condition(input: String) -> Result<Condition, String> {
+    let mut parser = Parser::new(input).map_err(|d| d.message)?;
+    let condition = parser.parse_condition()?;
+    parser.expect_end()?;
+    check_patterns(&condition)?;
+    Ok(condition)
+}
+
+pub fn parse(input: String) -> Result<Vec<Rule>, String> {
+    parse_with_limits(input, &Limits::default())
+}
+
+/// Parses rules, rejecting any nested deeper or with more nodes than
+/// `limits` allows.
+pub fn parse_with_limits(input: String, limits: &Limits) -> Result<Vec<Rule>, String> {
+    let (rules, mut errors) = parse_ranges_recovering(input, limits);
+    if !errors.is_empty() {
+        return Err(errors.remove(0).message);
     }
+    Ok(rules.into_iter().map(|(rule, _)| rule).collect())
+}
+
+/// Parses as much of the input as it can, returning the rules that parsed
+/// along with every error found, in source order. A rule with a bad action
+/// keeps its other actions; any other error drops the rule.
+pub fn parse_recovering(input: String) -> (Vec<Rule>, Vec<Diagnostic>) {
+    let (rules, errors) = parse_ranges_recovering(input, &Limits::default());
+    (rules.into_iter().map(|(rule, _)| rule).collect(), errors)
+}
 
-    Ok(rules)
+/// [`parse_recovering`] with `limits`, returning each rule along with the
+/// span of its name.
+pub fn parse_recovering_with_limits(input: String, limits: &Limits) -> (Vec<(Rule, Span)>, Vec<Diagnostic>) {
+    let mut parser = Parser::recovering(input);
+    parser.max_depth = limits.max_depth;
+    parser.max_nodes = limits.max_nodes;
+    let rules = parser
+        .parse_rules()
+        .into_iter()
+        .map(|(rule, range)| {
+            let name = parser.spans[range.start + 1];
+            (rule, name)
+        })
+        .collect();
+    let mut errors = parser.errors;
+    errors.sort_by_key(|d| d.span.start);
+    (rules, errors)
+}
+
+/// Parses rules along with the range of non-trivia token indices each one covers.
+pub(crate) fn parse_with_ranges(input: String) -> Result<Vec<(Rule, Range<usize>)>, Diagnostic> {
+    let (rules, mut errors) = parse_ranges_recovering(input, &Limits::default());
+    if errors.is_empty() {
+        Ok(rules)
+    } else {
+        Err(errors.remove(0))
+    }
+}
+
+fn parse_ranges_recovering(input: String, limits: &Limits) -> (Vec<(Rule, Range<usize>)>, Vec<Diagnostic>) {
+    let mut parser = Parser::recovering(input);
+    parser.max_depth = limits.max_depth;
+    parser.max_nodes = limits.max_nodes;
+    let rules = parser.parse_rules();
+    let mut errors = parser.errors;
+    errors.sort_by_key(|d| d.span.start);
+    (rules, errors)
 }
 
 
 #[cfg(test)]
 mod tests {
-    use crate::context::DataContext;
+    use crate::{
+        context::DataContext,
+        time::{Date, DateTime, Duration},
+    };
 
     use super::*;
 
@@ -449,6 +919,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_parse_fib_rule() {
         let input = r#"
     rule CalcFib "Calculate Fibonacci" {
@@ -478,4 +949,309 @@ mod tests {
             assert_eq!(ctx.get("Vibo.B".into()).unwrap().clone(), Value::Int(1));
         }
     }
+
+    #[test]
+    fn test_parse_rule_attributes() {
+        let input = r#"
+    rule A "desc" no-loop lock-on-active false { when X.a == 0 then X.a = 1; }
+    rule B lock-on-active { when X.a == 0 then X.a = 1; }
+    rule C activation-group "Tier" unique no-loop { when X.a == 0 then X.a = 1; }
+    "#;
+
+        let rules = parse(input.to_string()).unwrap();
+        assert!(rules[0].no_loop);
+        assert!(!rules[0].lock_on_active);
+        assert!(!rules[1].no_loop);
+        assert!(rules[1].lock_on_active);
+        assert_eq!(rules[1].activation_group, None);
+        assert_eq!(rules[2].activation_group, Some(ActivationGroup { name: "Tier".into(), unique: true }));
+        assert!(rules[2].no_loop);
+
+        let err = parse("rule C no-such { when X.a == 0 then X.a = 1; }".to_string());
+        assert!(err.is_err());
+
+        let rules = parse("rule D salience -5 { when X.a == 0 then X.a = 1; }".to_string()).unwrap();
+        assert_eq!(rules[0].salience, -5);
+    }
+
+    #[test]
+    fn test_parse_not_and_grouped_conditions() {
+        let input = r#"
+    rule R { when !(X.a >= 2 || X.b != 0) && (X.a + 1) <= 2 then X.c = 1; }
+    "#;
+        let rules = parse(input.to_string()).unwrap();
+
+        let mut ctx = DataContext::new();
+        ctx.set("X.a".into(), Value::Int(1));
+        ctx.set("X.b".into(), Value::Int(0));
+        assert!(rules[0].evaluate(&ctx).unwrap());
+
+        ctx.set("X.b".into(), Value::Int(3));
+        assert!(!rules[0].evaluate(&ctx).unwrap());
+    }
+
+    #[test]
+    fn test_parse_int_literals() {
+        let condition = parse_condition("X.a > -5 && X.b == -9223372036854775808 && X.c == 0x7fff_ffff_ffff_ffff".to_string()).unwrap();
+        assert_eq!(condition.to_string(), "X.a > -5 && X.b == -9223372036854775808 && X.c == 9223372036854775807");
+        assert_eq!(parse_expr("1_000 + -0b11".to_string()).unwrap().to_string(), "1000 + -3");
+
+        let input = "rule R { when X.a == 9223372036854775808 then X.b = 1; }";
+        let d = parse_with_ranges(input.to_string()).unwrap_err();
+        assert_eq!(d.message, "integer literal 9223372036854775808 out of range");
+        assert_eq!(&input[d.span.start..d.span.end], "9223372036854775808");
+        assert_eq!(parse_expr("-9223372036854775809".to_string()).unwrap_err(), "integer literal -9223372036854775809 out of range");
+    }
+
+    #[test]
+    fn test_parse_recovering() {
+        let input = r#"
+    rule A { when X.a == 1 then X.b = ; X.c = 3; }
+    rule B { when X.a == then X.b = 1; }
+    rule C { when X.a == 1 then X.b = 2;
+    rule D salience 2 { when X.a == 1 then X.d = 4; }
+    "#;
+
+        let (rules, errors) = parse_recovering(input.to_string());
+        let names: Vec<&str> = rules.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["A", "D"]);
+        assert_eq!(rules[0].actions, vec![Action::Assign { field: "X.c".into(), expr: Expr::Literal(Value::Int(3)) }]);
+
+        let messages: Vec<&str> = errors.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(messages, vec!["unexpected token in atom Some(Semicolon)", "unexpected token in atom Some(Then)", "expected '}'"]);
+        assert_eq!(&input[errors[2].span.start..errors[2].span.end], "rule");
+
+        assert_eq!(parse(input.to_string()).unwrap_err(), errors[0].message);
+    }
+
+    #[test]
+    fn test_comments_stay_with_rules() {
+        let input = r#"
+    // discount for big orders
+    rule A { when X.a > 1 /* big */ then X.b = 1; }
+    /* second */
+    rule B { when X.a > 2 then X.c = 1; }
+    "#;
+        let rules = parse(input.to_string()).unwrap();
+        assert_eq!(rules[0].comments, vec!["// discount for big orders"]);
+        assert_eq!(rules[0].placed_comments, vec![(CommentPlace::When, "/* big */".to_string())]);
+        assert_eq!(rules[1].comments, vec!["/* second */"]);
+    }
+
+    #[test]
+    fn test_comments_keep_their_place() {
+        let input = r#"
+    rule A /* header */ {
+        when
+            X.a > 1 && // big
+            X.b < 2
+        then
+            // first
+            X.c = 1;
+            X.d = /* inline */ 2;
+            // done
+    }
+    // trailing note
+    "#;
+        let rules = parse(input.to_string()).unwrap();
+        assert_eq!(rules[0].comments, vec!["/* header */"]);
+        let placed = |place, text: &str| (place, text.to_string());
+        assert_eq!(
+            rules[0].placed_comments,
+            vec![
+                placed(CommentPlace::When, "// big"),
+                placed(CommentPlace::Action(0), "// first"),
+                placed(CommentPlace::Action(2), "/* inline */"),
+                placed(CommentPlace::Action(2), "// done"),
+                placed(CommentPlace::After, "// trailing note"),
+            ]
+        );
+        assert_eq!(
+            rules[0].to_string(),
+            "/* header */\nrule A {\n    when\n        // big\n        X.a > 1 && X.b < 2\n    then\n        // first\n        X.c = 1;\n        X.d = 2;\n        /* inline */\n        // done\n}\n// trailing note"
+        );
+        assert_eq!(parse(rules[0].to_string()).unwrap(), rules);
+    }
+
+    #[test]
+    fn test_parse_patterns() {
+        let input = "rule R { when o: Order(Total > 100 && year(Placed) == 2024) && Customer() && c: Customer(Id == o.CustomerId) && X.a == 1 then o.Owner = c.Name; }";
+        let rules = parse(input.to_string()).unwrap();
+        assert_eq!(rules[0].patterns(), vec![("o", "Order"), ("$1", "Customer"), ("c", "Customer")]);
+        let Condition::Pattern { constraint: Some(c), .. } = rules[0].condition.conjuncts()[0] else { panic!() };
+        // bare names in a constraint are fields of its instance
+        assert_eq!(crate::analysis::condition_fields(c).into_iter().collect::<Vec<_>>(), vec!["o.Placed", "o.Total"]);
+
+        let printed = rules[0].condition.to_string();
+        assert_eq!(printed, "o: Order(Total > 100 && year(Placed) == 2024) && Customer() && c: Customer(Id == o.CustomerId) && X.a == 1");
+        assert_eq!(parse_condition(printed).unwrap(), rules[0].condition);
+
+        let err = |cond: &str| parse_condition(cond.to_string()).unwrap_err();
+        assert_eq!(err("X.a == 1 || o: Order()"), "pattern o: Order() can only be joined to the condition with a top-level &&");
+        assert_eq!(err("o: Order(c: Customer())"), "pattern c: Customer() can only be joined to the condition with a top-level &&");
+        assert_eq!(err("o: Order() && o: Order()"), "variable o is bound twice");
+        assert_eq!(err("c: Customer(Id == o.CustomerId) && o: Order()"), "variable o is used before the pattern that binds it");
+        assert_eq!(err("o: Order(Total >)"), "unexpected token in atom Some(RParen)");
+        // a misspelt function is not taken for a pattern
+        assert_eq!(err("yeer(X.a) > 1"), "unknown function 'yeer'");
+    }
+
+    #[test]
+    fn test_parse_memory_actions() {
+        let input = "rule R { when o: Order(Closed == true) then insert(Archive { Id: o.Id, At: now() }); update(o); retract(o); insert(Empty {}); }";
+        let rules = parse(input.to_string()).unwrap();
+        assert_eq!(rules[0].actions[1], Action::Update("o".into()));
+        let printed: Vec<String> = rules[0].actions.iter().map(|a| a.to_string()).collect();
+        assert_eq!(printed, vec!["insert(Archive { Id: o.Id, At: now() });", "update(o);", "retract(o);", "insert(Empty {});"]);
+        assert_eq!(parse(rules[0].to_string()).unwrap(), rules);
+
+        // the verbs are only actions when called
+        let rules = parse("rule R { when X.a == 1 then insert.Count = 1; }".to_string()).unwrap();
+        assert_eq!(rules[0].actions[0].to_string(), "insert.Count = 1;");
+
+        let err = |input: &str| parse(input.to_string()).unwrap_err();
+        assert_eq!(err("rule R { when X.a == 1 then retract(X); }"), "variable X is not bound by a pattern");
+        assert_eq!(err("rule R { when X.a == 1 then insert(A { b: 1, b: 2 }); }"), "field b is set twice");
+        assert_eq!(err("rule R { when X.a == 1 then insert(A { b 1 }); }"), "expected ':' after field name");
+    }
+
+    #[test]
+    fn test_parse_aggregates() {
+        let input = "a: Account(Items.sum(i => i.Price) > 100 && Transaction(Owner == a.Id && Amount > 1000).count() >= 2) \
+            && not exists Alert(Account == a.Id) && Order.Tags.any(t => t == \"vip\") && !Order.Items.all(i => i.Ok == true)";
+        let cond = parse_condition(input.to_string()).unwrap();
+        let Condition::Pattern { constraint: Some(c), .. } = cond.conjuncts()[0] else { panic!() };
+        // the lambda parameter is not a field of the instance, and a fact
+        // set's bare names are fields of its own instances
+        let fields = crate::analysis::condition_fields(c).into_iter().collect::<Vec<_>>();
+        assert_eq!(fields, vec!["Transaction", "Transaction.Amount", "Transaction.Owner", "a.Id", "a.Items"]);
+
+        let printed = cond.to_string();
+        assert_eq!(printed, input.replace("!Order.Items.all(i => i.Ok == true)", "!(Order.Items.all(i => i.Ok == true))"));
+        assert_eq!(parse_condition(printed).unwrap(), cond);
+
+        let err = |cond: &str| parse_condition(cond.to_string()).unwrap_err();
+        assert_eq!(err("Order.Items.any() == true"), "any() needs a predicate, like any(i => i.Price > 100)");
+        assert_eq!(err("Order.Items.sum(i => i.Price > 1) > 1"), "expected ')' after sum");
+        assert_eq!(err("Order.Items.sum(i.Price) > 1"), "expected '=>' after lambda parameter");
+        assert_eq!(err("exists Order(o: Customer())"), "pattern o: Customer() can only be joined to the condition with a top-level &&");
+        // compared without an aggregate, a fact set is taken for a call
+        assert_eq!(err("Order(Total > 1) > 1"), "unknown function 'Order'");
+    }
+
+    #[test]
+    fn test_nested_calls_parse_without_backtracking() {
+        let nested = |open: &str, inner: &str, close: &str| format!("{}{}{}", open.repeat(30), inner, close.repeat(30));
+        let start = std::time::Instant::now();
+        assert_eq!(parse_condition(nested("A(", "x", ")")).unwrap_err(), "unexpected operator Some(RParen)");
+        assert_eq!(parse_condition(nested("A(", "X.a", ") > 1")).unwrap_err(), "unknown function 'A'");
+        assert_eq!(parse_condition(nested("(", "X.a == 1", ")")).unwrap().to_string(), "X.a == 1");
+        let calls = nested("year(", "X.a", ")") + " > 1";
+        assert_eq!(parse_condition(calls.clone()).unwrap().to_string(), calls);
+        let sets = (0..30).fold("X.a == 1".to_string(), |inner, _| format!("A({}).count() > 0", inner));
+        assert!(parse_condition(sets).is_ok());
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+    }
+
+    // a tiny xorshift generator, so the round-trip test needs no dependencies
+    struct Gen(u64);
+
+    impl Gen {
+        fn next(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+
+        fn field(&mut self) -> String {
+            ["X.a", "X.b", "Order.Total", "Flag", "Kunde.Größe", "X.then", "Order.net total"][self.next(7) as usize].to_string()
+        }
+
+        fn expr(&mut self, depth: u32) -> Expr {
+            match self.next(if depth == 0 { 4 } else { 6 }) {
+                0 => Expr::Literal(Value::Int([self.next(1000) as i64, -(self.next(1000) as i64), i64::MIN, i64::MAX][self.next(4) as usize])),
+                1 if self.next(2) == 0 => Expr::Literal(
+                    [
+                        Value::Date(Date::from_ymd(2024, 2, 29).unwrap()),
+                        Value::DateTime(DateTime::parse("2024-01-01T10:00:00-05:30").unwrap()),
+                        Value::Duration(Duration::from_secs(self.next(200_000) as i64 - 100_000)),
+                    ][self.next(3) as usize]
+                        .clone(),
+                ),
+                1 => Expr::Literal(Value::Bool(self.next(2) == 0)),
+                2 => Expr::Literal(Value::Str(
+                    ["s", "say \"hi\"", "back\\slash", "line\nbreak\t\u{7}", "ünïcödé"][self.next(5) as usize].to_string(),
+                )),
+                3 => Expr::FieldRef(self.field()),
+                4 => Expr::Call { name: "year".into(), args: vec![self.expr(depth - 1)] },
+                _ => Expr::BinOp {
+                    left: Box::new(self.expr(depth - 1)),
+                    op: if self.next(2) == 0 { Op::Add } else { Op::Sub },
+                    right: Box::new(self.expr(depth - 1)),
+                },
+            }
+        }
+
+        fn condition(&mut self, depth: u32) -> Condition {
+            match self.next(if depth == 0 { 1 } else { 4 }) {
+                0 => Condition::Compare {
+                    left: self.expr(2),
+                    op: [CmpOp::Eq, CmpOp::NotEq, CmpOp::Lt, CmpOp::Gt, CmpOp::LtEq, CmpOp::GtEq]
+                        [self.next(6) as usize]
+                        .clone(),
+                    right: self.expr(2),
+                },
+                1 => Condition::And(Box::new(self.condition(depth - 1)), Box::new(self.condition(depth - 1))),
+                2 => Condition::Or(Box::new(self.condition(depth - 1)), Box::new(self.condition(depth - 1))),
+                _ => Condition::Not(Box::new(self.condition(depth - 1))),
+            }
+        }
+
+        fn rule(&mut self, n: usize) -> Rule {
+            let actions = (0..self.next(3))
+                .map(|_| Action::Assign {
+                    field: ["X.a", "X.c", "Order.Discount"][self.next(3) as usize].to_string(),
+                    expr: self.expr(2),
+                })
+                .collect();
+            let mut rule = Rule::new(format!("R{}", n), self.condition(3), actions);
+            if self.next(2) == 0 {
+                rule.description = Some(format!("rule number {}", n));
+            }
+            if self.next(2) == 0 {
+                rule.comments = vec!["// leading".into(), "/* block\n   comment */".into()];
+            }
+            if self.next(2) == 0 {
+                let place = [CommentPlace::When, CommentPlace::Action(rule.actions.len())][self.next(2) as usize];
+                rule.placed_comments.push((place, "// inside".into()));
+            }
+            rule.salience = self.next(20) as i64 - 10;
+            rule.no_loop = self.next(2) == 0;
+            rule.lock_on_active = self.next(2) == 0;
+            if self.next(3) == 0 {
+                rule.activation_group = Some(ActivationGroup { name: "Group".into(), unique: self.next(2) == 0 });
+            }
+            rule
+        }
+    }
+
+    #[test]
+    fn test_print_parse_round_trip() {
+        let mut gen = Gen(0x2545_f491_4f6c_dd1d);
+        for n in 0..500 {
+            let rules = vec![gen.rule(n), gen.rule(n + 1)];
+            let printed = rules.iter().map(|r| r.to_string()).collect::<Vec<_>>().join("\n\n");
+            let parsed = parse(printed.clone()).unwrap_or_else(|e| panic!("{}\n{}", e, printed));
+            assert_eq!(parsed, rules, "{}", printed);
+        }
+    }
+
+    #[test]
+    fn test_print_minimal_parentheses() {
+        let input = "rule R { when (X.a == 1 && X.b == 2) || !(X.c == 3 || X.d == 4) then X.e = (1 + 2) + (3 + 4); }";
+        let rules = parse(input.to_string()).unwrap();
+        assert_eq!(rules[0].condition.to_string(), "X.a == 1 && X.b == 2 || !(X.c == 3 || X.d == 4)");
+        assert_eq!(rules[0].actions[0].to_string(), "X.e = 1 + 2 + (3 + 4);");
+    }
 }
@@ -0,0 +1,302 @@
+//! Per-rule timings, collected by the engine when profiling is on (see
+//! [`crate::engine::RuleEngine::set_profiling`]).
+//!
+//! Each rule records how often its condition was evaluated and how often it
+//! fired, with the total and worst time spent in each. Within a condition
+//! every comparison is timed too, to find the expensive sub-expressions.
+
+use std::time::{Duration, Instant};
+
+use crate::{
+    ast::{Condition, Rule},
+    context::{DataContext, FactId},
+};
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Timing {
+    pub count: usize,
+    pub total: Duration,
+    pub max: Duration,
+}
+
+impl Timing {
+    fn add(&mut self, elapsed: Duration) {
+        self.count += 1;
+        self.total += elapsed;
+        self.max = self.max.max(elapsed);
+    }
+}
+
+/// One comparison in a rule's condition.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExprProfile {
+    pub expr: String,
+    pub timing: Timing,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RuleProfile {
+    pub name: String,
+    pub evaluate: Timing,
+    pub execute: Timing,
+    /// The condition's comparisons, patterns and `exists` tests, in source order.
+    pub exprs: Vec<ExprProfile>,
+}
+
+impl RuleProfile {
+    pub fn total(&self) -> Duration {
+        self.evaluate.total + self.execute.total
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Profile {
+    /// By rule index, for the rules the engine has seen.
+    pub rules: Vec<RuleProfile>,
+}
+
+fn compares<'a>(cond: &'a Condition, out: &mut Vec<&'a Condition>) {
+    match cond {
+        Condition::Compare { .. } => out.push(cond),
+        Condition::And(a, b) | Condition::Or(a, b) => {
+            compares(a, out);
+            compares(b, out);
+        }
+        Condition::Not(c) => compares(c, out),
+        Condition::Pattern { .. } | Condition::Exists(_) => out.push(cond),
+    }
+}
+
+// Evaluates like `Condition::evaluate`, timing each comparison; `next`
+// numbers them in source order.
+fn timed(cond: &Condition, ctx: &DataContext, next: &mut usize, exprs: &mut [ExprProfile]) -> Result<bool, String> {
+    match cond {
+        Condition::Compare { .. } | Condition::Pattern { .. } | Condition::Exists(_) => {
+            let id = *next;
+            *next += 1;
+            let start = Instant::now();
+            let result = cond.evaluate(ctx);
+            exprs[id].timing.add(start.elapsed());
+            result
+        }
+        Condition::And(a, b) => {
+            let a = timed(a, ctx, next, exprs)?;
+            if a {
+                timed(b, ctx, next, exprs)
+            } else {
+                Ok(false)
+            }
+        }
+        Condition::Or(a, b) => {
+            let a = timed(a, ctx, next, exprs)?;
+            if a {
+                Ok(true)
+            } else {
+                timed(b, ctx, next, exprs)
+            }
+        }
+        Condition::Not(c) => Ok(!timed(c, ctx, next, exprs)?),
+    }
+}
+
+// microseconds, as folded stacks want integer weights
+fn micros(d: Duration) -> u128 {
+    d.as_micros()
+}
+
+// folded stacks separate frames with ';' and end with a space and weight
+fn frame(name: &str) -> String {
+    name.replace(';', ",").replace('\n', " ")
+}
+
+impl Profile {
+    fn rule(&mut self, i: usize, rule: &Rule) -> &mut RuleProfile {
+        while self.rules.len() <= i {
+            self.rules.push(RuleProfile {
+                name: String::new(),
+                evaluate: Timing::default(),
+                execute: Timing::default(),
+                exprs: Vec::new(),
+            });
+        }
+        let profile = &mut self.rules[i];
+        if profile.name != rule.name {
+            let mut conds = Vec::new();
+            compares(&rule.condition, &mut conds);
+            *profile = RuleProfile {
+                name: rule.name.clone(),
+                evaluate: Timing::default(),
+                execute: Timing::default(),
+                exprs: conds
+                    .into_iter()
+                    .map(|c| ExprProfile {
+                        expr: c.to_string(),
+                        timing: Timing::default(),
+                    })
+                    .collect(),
+            };
+        }
+        profile
+    }
+
+    /// Evaluates rule `i`'s condition, timing it.
+    pub fn evaluate(&mut self, i: usize, rule: &Rule, ctx: &DataContext) -> Result<bool, String> {
+        let profile = self.rule(i, rule);
+        let start = Instant::now();
+        let result = timed(&rule.condition, ctx, &mut 0, &mut profile.exprs);
+        profile.evaluate.add(start.elapsed());
+        result
+    }
+
+    /// Finds rule `i`'s matches, as [`Rule::matches`] does, timing it. The
+    /// comparisons are timed one by one only in rules without patterns.
+    pub fn matches(&mut self, i: usize, rule: &Rule, ctx: &DataContext) -> Result<Vec<Vec<FactId>>, String> {
+        if rule.patterns().is_empty() {
+            return Ok(if self.evaluate(i, rule, ctx)? { vec![Vec::new()] } else { Vec::new() });
+        }
+        let profile = self.rule(i, rule);
+        let start = Instant::now();
+        let result = rule.matches(ctx);
+        profile.evaluate.add(start.elapsed());
+        result
+    }
+
+    /// Runs rule `i`'s actions on `facts`, as [`Rule::fire`] does, timing
+    /// them.
+    pub fn execute(&mut self, i: usize, rule: &Rule, ctx: &mut DataContext, facts: &[FactId]) -> Result<(), String> {
+        let profile = self.rule(i, rule);
+        let start = Instant::now();
+        let result = rule.fire(ctx, facts);
+        profile.execute.add(start.elapsed());
+        result
+    }
+
+    /// The rules that were evaluated, most expensive first.
+    pub fn sorted(&self) -> Vec<&RuleProfile> {
+        let mut rules: Vec<&RuleProfile> = self.rules.iter().filter(|r| r.evaluate.count > 0).collect();
+        rules.sort_by(|a, b| b.total().cmp(&a.total()).then_with(|| a.name.cmp(&b.name)));
+        rules
+    }
+
+    /// The `n` comparisons with the most total time, with their rule.
+    pub fn hottest_exprs(&self, n: usize) -> Vec<(&str, &ExprProfile)> {
+        let mut exprs: Vec<(&str, &ExprProfile)> = self
+            .rules
+            .iter()
+            .flat_map(|r| r.exprs.iter().map(move |e| (r.name.as_str(), e)))
+            .filter(|(_, e)| e.timing.count > 0)
+            .collect();
+        exprs.sort_by_key(|(_, e)| std::cmp::Reverse(e.timing.total));
+        exprs.truncate(n);
+        exprs
+    }
+
+    /// A table of the rules, most expensive first, then the ten most
+    /// expensive comparisons.
+    pub fn table(&self) -> String {
+        let rules = self.sorted();
+        let width = rules.iter().map(|r| r.name.len()).max().unwrap_or(0).max(4);
+        let mut out = format!(
+            "{:<width$}  {:>6}  {:>6}  {:>12}  {:>12}  {:>12}  {:>12}\n",
+            "rule", "evals", "fires", "eval total", "eval max", "exec total", "exec max"
+        );
+        for r in rules {
+            out.push_str(&format!(
+                "{:<width$}  {:>6}  {:>6}  {:>12}  {:>12}  {:>12}  {:>12}\n",
+                r.name,
+                r.evaluate.count,
+                r.execute.count,
+                format!("{:?}", r.evaluate.total),
+                format!("{:?}", r.evaluate.max),
+                format!("{:?}", r.execute.total),
+                format!("{:?}", r.execute.max),
+            ));
+        }
+
+        let exprs = self.hottest_exprs(10);
+        if !exprs.is_empty() {
+            out.push_str("\nslowest comparisons:\n");
+            for (rule, e) in exprs {
+                out.push_str(&format!(
+                    "{:>12}  {:>6}  {}: {}\n",
+                    format!("{:?}", e.timing.total),
+                    e.timing.count,
+                    rule,
+                    e.expr
+                ));
+            }
+        }
+        out
+    }
+
+    /// Folded stacks, one `frame;frame weight` line per leaf with the weight
+    /// in microseconds, for `flamegraph.pl` and compatible tools.
+    pub fn folded(&self) -> String {
+        let mut out = String::new();
+        for r in self.rules.iter().filter(|r| r.evaluate.count > 0) {
+            let name = frame(&r.name);
+            let children: Duration = r.exprs.iter().map(|e| e.timing.total).sum();
+            let own = r.evaluate.total.saturating_sub(children);
+            out.push_str(&format!("{};evaluate {}\n", name, micros(own)));
+            for e in r.exprs.iter().filter(|e| e.timing.count > 0) {
+                out.push_str(&format!("{};evaluate;{} {}\n", name, frame(&e.expr), micros(e.timing.total)));
+            }
+            if r.execute.count > 0 {
+                out.push_str(&format!("{};execute {}\n", name, micros(r.execute.total)));
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{engine::RuleEngine, parser::parse, value::Value};
+
+    #[test]
+    fn test_profile_counts() {
+        let rules = parse(
+            r#"
+    rule Discount { when Order.Total > 100 && Order.Vip == true then Order.Discount = 10; }
+    rule Never { when Order.Total < 0 || Order.Total == 7 then Order.Discount = 0; }
+    "#
+            .to_string(),
+        )
+        .unwrap();
+        let mut ctx = DataContext::new();
+        ctx.set("Order.Total".into(), Value::Int(150));
+        ctx.set("Order.Vip".into(), Value::Bool(true));
+
+        let mut engine = RuleEngine::new();
+        assert!(engine.profile().is_none());
+        engine.set_profiling(true);
+        engine.execute(&rules, &mut ctx).unwrap();
+
+        let profile = engine.profile().unwrap();
+        let discount = &profile.rules[0];
+        // evaluated before firing; afterwards refraction skips it
+        assert_eq!((discount.evaluate.count, discount.execute.count), (1, 1));
+        // evaluated once; Discount's firing changes nothing it reads
+        let never = &profile.rules[1];
+        assert_eq!((never.evaluate.count, never.execute.count), (1, 0));
+        let counts: Vec<(&str, usize)> = never.exprs.iter().map(|e| (e.expr.as_str(), e.timing.count)).collect();
+        assert_eq!(counts, vec![("Order.Total < 0", 1), ("Order.Total == 7", 1)]);
+
+        assert!(profile.table().starts_with("rule       evals   fires"));
+        let folded = profile.folded();
+        let stacks: Vec<&str> = folded.lines().map(|l| l.rsplit_once(' ').unwrap().0).collect();
+        assert_eq!(
+            stacks,
+            vec![
+                "Discount;evaluate",
+                "Discount;evaluate;Order.Total > 100",
+                "Discount;evaluate;Order.Vip == true",
+                "Discount;execute",
+                "Never;evaluate",
+                "Never;evaluate;Order.Total < 0",
+                "Never;evaluate;Order.Total == 7",
+            ]
+        );
+    }
+}
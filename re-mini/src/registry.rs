@@ -0,0 +1,335 @@
+//! Named knowledge bases that a running service can replace without
+//! stopping.
+//!
+//! Each name holds one active version. [`KnowledgeBaseRegistry::get`]
+//! hands out the active version behind an `Arc`, so a run that started on
+//! it keeps it until the run ends, whatever is swapped in meanwhile. A
+//! reload that fails to parse leaves the active version in place.
+//!
+//! With the `watch` feature, [`Watcher`] polls rule files and reloads
+//! the ones that change.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    sync::{Arc, RwLock},
+};
+
+use crate::{
+    knowledge_base::KnowledgeBase,
+    lexer::{line_col, Diagnostic},
+    limits::Limits,
+    parser::parse_recovering_with_limits,
+};
+
+/// One version of a knowledge base, as handed out by the registry.
+#[derive(Debug)]
+pub struct KnowledgeBaseVersion {
+    /// Starts at 1 and goes up by one with every swap under the same name.
+    pub version: u64,
+    pub kb: KnowledgeBase,
+}
+
+#[derive(Default)]
+pub struct KnowledgeBaseRegistry {
+    /// The parse-time limits rule sources are loaded with.
+    pub limits: Limits,
+    active: RwLock<HashMap<String, Arc<KnowledgeBaseVersion>>>,
+}
+
+/// Formats load errors as `path:line:col: message` lines.
+pub fn diagnostics_to_string(path: &str, source: &str, diagnostics: &[Diagnostic]) -> String {
+    diagnostics
+        .iter()
+        .map(|d| {
+            let (line, col) = line_col(source, d.span.start);
+            format!("{}:{}:{}: {}", path, line + 1, col + 1, d.message)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+impl KnowledgeBaseRegistry {
+    pub fn new() -> KnowledgeBaseRegistry {
+        KnowledgeBaseRegistry::default()
+    }
+
+    /// The active version of `name`.
+    pub fn get(&self, name: &str) -> Option<Arc<KnowledgeBaseVersion>> {
+        self.active.read().unwrap().get(name).cloned()
+    }
+
+    /// The names with an active version, sorted.
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.active.read().unwrap().keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Makes `kb` the active version under its name, returning its version
+    /// number. Runs holding the previous version are unaffected.
+    pub fn swap(&self, kb: KnowledgeBase) -> u64 {
+        let mut active = self.active.write().unwrap();
+        let version = active.get(&kb.name).map_or(1, |current| current.version + 1);
+        active.insert(kb.name.clone(), Arc::new(KnowledgeBaseVersion { version, kb }));
+        version
+    }
+
+    /// Parses `source` within the registry's limits and swaps it in as
+    /// `name`. On any error the active version stays, and every error found
+    /// is returned.
+    pub fn load(&self, name: &str, source: &str) -> Result<u64, Vec<Diagnostic>> {
+        let (rules, mut diagnostics) = parse_recovering_with_limits(source.to_string(), &self.limits);
+        let mut seen = HashSet::new();
+        for (rule, span) in &rules {
+            if !seen.insert(rule.name.as_str()) {
+                diagnostics.push(Diagnostic::new(format!("duplicate rule name {}", rule.name), *span));
+            }
+        }
+        if !diagnostics.is_empty() {
+            diagnostics.sort_by_key(|d| d.span.start);
+            return Err(diagnostics);
+        }
+        Ok(self.swap(KnowledgeBase::new(name, rules.into_iter().map(|(rule, _)| rule).collect())))
+    }
+
+    /// Loads a rule file, GRL or compiled, as `name`, holding either to the
+    /// registry's limits. Errors in GRL are reported as
+    /// `path:line:col: message` lines, and in a compiled file as
+    /// `path: message` lines.
+    pub fn load_file(&self, name: &str, path: &str) -> Result<u64, String> {
+        let bytes = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        if KnowledgeBase::is_compiled(&bytes) {
+            let mut kb = KnowledgeBase::from_bytes_with_limits(&bytes, &self.limits).map_err(|e| format!("{}: {}", path, e))?;
+            let mut seen = HashSet::new();
+            let duplicates: Vec<String> = kb
+                .rules
+                .iter()
+                .filter(|rule| !seen.insert(rule.name.as_str()))
+                .map(|rule| format!("{}: duplicate rule name {}", path, rule.name))
+                .collect();
+            if !duplicates.is_empty() {
+                return Err(duplicates.join("\n"));
+            }
+            kb.name = name.to_string();
+            return Ok(self.swap(kb));
+        }
+        let source = String::from_utf8(bytes).map_err(|_| format!("{}: not a rule file", path))?;
+        self.load(name, &source).map_err(|d| diagnostics_to_string(path, &source, &d))
+    }
+
+    /// Drops `name`; runs holding a version of it keep it.
+    pub fn remove(&self, name: &str) -> bool {
+        self.active.write().unwrap().remove(name).is_some()
+    }
+}
+
+#[cfg(feature = "watch")]
+pub use watch::{WatchHandle, Watcher};
+
+#[cfg(feature = "watch")]
+mod watch {
+    use std::{
+        fs,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread,
+        time::{Duration, SystemTime},
+    };
+
+    use super::KnowledgeBaseRegistry;
+
+    struct WatchedFile {
+        name: String,
+        path: String,
+        // what the file looked like when last loaded
+        seen: Option<(SystemTime, u64)>,
+    }
+
+    /// Polls rule files and reloads each into the registry when its
+    /// modification time or size changes.
+    #[derive(Default)]
+    pub struct Watcher {
+        files: Vec<WatchedFile>,
+    }
+
+    /// Stops the polling thread when dropped.
+    pub struct WatchHandle {
+        stop: Arc<AtomicBool>,
+        thread: Option<thread::JoinHandle<()>>,
+    }
+
+    impl Drop for WatchHandle {
+        fn drop(&mut self) {
+            self.stop.store(true, Ordering::Relaxed);
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    impl Watcher {
+        pub fn new() -> Watcher {
+            Watcher::default()
+        }
+
+        /// Watches `path`, loading it as `name` on the next poll.
+        pub fn watch(&mut self, name: &str, path: &str) {
+            self.files.push(WatchedFile {
+                name: name.to_string(),
+                path: path.to_string(),
+                seen: None,
+            });
+        }
+
+        /// Reloads the files that changed since the last poll, returning
+        /// each one's name with its new version or its errors. A file that
+        /// fails to load is retried only once it changes again.
+        pub fn poll(&mut self, registry: &KnowledgeBaseRegistry) -> Vec<(String, Result<u64, String>)> {
+            let mut reloaded = Vec::new();
+            for file in &mut self.files {
+                let stamp = match fs::metadata(&file.path) {
+                    Ok(meta) => (meta.modified().unwrap_or(SystemTime::UNIX_EPOCH), meta.len()),
+                    // a file being replaced may be briefly missing
+                    Err(_) => continue,
+                };
+                if file.seen == Some(stamp) {
+                    continue;
+                }
+                file.seen = Some(stamp);
+                reloaded.push((file.name.clone(), registry.load_file(&file.name, &file.path)));
+            }
+            reloaded
+        }
+
+        /// Polls every `interval` on a background thread, passing each
+        /// reload's outcome to `report`.
+        pub fn spawn(
+            mut self,
+            registry: Arc<KnowledgeBaseRegistry>,
+            interval: Duration,
+            report: impl Fn(&str, Result<u64, String>) + Send + 'static,
+        ) -> WatchHandle {
+            let stop = Arc::new(AtomicBool::new(false));
+            let stopped = stop.clone();
+            let thread = thread::spawn(move || {
+                while !stopped.load(Ordering::Relaxed) {
+                    for (name, result) in self.poll(&registry) {
+                        report(&name, result);
+                    }
+                    thread::sleep(interval);
+                }
+            });
+            WatchHandle {
+                stop,
+                thread: Some(thread),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_swap_keeps_old_versions_alive() {
+        let registry = KnowledgeBaseRegistry::new();
+        assert!(registry.get("orders").is_none());
+
+        assert_eq!(registry.load("orders", "rule A { when X.a == 1 then X.b = 1; }"), Ok(1));
+        let in_flight = registry.get("orders").unwrap();
+
+        assert_eq!(registry.load("orders", "rule B { when X.a == 1 then X.b = 2; }"), Ok(2));
+        assert_eq!(in_flight.version, 1);
+        assert_eq!(in_flight.kb.rules[0].name, "A");
+        let current = registry.get("orders").unwrap();
+        assert_eq!((current.version, current.kb.rules[0].name.as_str()), (2, "B"));
+        assert_eq!(registry.names(), vec!["orders"]);
+    }
+
+    #[test]
+    fn test_failed_load_keeps_active_version() {
+        let registry = KnowledgeBaseRegistry::new();
+        registry.load("orders", "rule A { when X.a == 1 then X.b = 1; }").unwrap();
+
+        let source = "rule A { when X.a == then X.b = 1; }\nrule B { when X.a == 1 then X.b = 1 }";
+        let errors = registry.load("orders", source).unwrap_err();
+        assert_eq!(
+            diagnostics_to_string("orders.grl", source, &errors),
+            "orders.grl:1:22: unexpected token in atom Some(Then)\norders.grl:2:37: expected ';'"
+        );
+
+        let dup = "rule A { when X.a == 1 then X.b = 1; }\nrule A { when X.a == 2 then X.b = 2; }";
+        let errors = registry.load("orders", dup).unwrap_err();
+        assert_eq!(diagnostics_to_string("orders.grl", dup, &errors), "orders.grl:2:6: duplicate rule name A");
+
+        let current = registry.get("orders").unwrap();
+        assert_eq!(current.version, 1);
+    }
+
+    #[test]
+    fn test_load_within_limits() {
+        let mut registry = KnowledgeBaseRegistry::new();
+        registry.limits.max_depth = 8;
+        let deep = format!("rule A {{ when {}X.a{} == 1 then X.b = 1; }}", "(".repeat(20), ")".repeat(20));
+        let errors = registry.load("orders", &deep).unwrap_err();
+        assert_eq!(errors[0].message, "expression nested deeper than 8 levels");
+        assert!(registry.get("orders").is_none());
+        assert_eq!(registry.load("orders", "rule A { when (X.a) == 1 then X.b = 1; }"), Ok(1));
+    }
+
+    #[test]
+    fn test_load_compiled_file() {
+        let dir = std::env::temp_dir().join(format!("re-mini-compiled-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("orders.kb");
+        let path_str = path.to_str().unwrap();
+        let source = "rule A { when year(year(X.a)) == 1 then X.b = 1; }\nrule A { when X.a == 2 then X.b = 2; }";
+        let rules = crate::parser::parse(source.to_string()).unwrap();
+        KnowledgeBase::new("orders", rules).save(path_str).unwrap();
+
+        let mut registry = KnowledgeBaseRegistry::new();
+        assert_eq!(registry.load_file("orders", path_str).unwrap_err(), format!("{}: duplicate rule name A", path_str));
+        registry.limits.max_depth = 3;
+        assert_eq!(
+            registry.load_file("orders", path_str).unwrap_err(),
+            format!("{}: expression nested deeper than 3 levels", path_str)
+        );
+        assert!(registry.get("orders").is_none());
+
+        let rules = crate::parser::parse("rule A { when X.a == 1 then X.b = 1; }".to_string()).unwrap();
+        KnowledgeBase::new("other", rules).save(path_str).unwrap();
+        assert_eq!(registry.load_file("orders", path_str), Ok(1));
+        assert_eq!(registry.get("orders").unwrap().kb.name, "orders");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "watch")]
+    #[test]
+    fn test_watcher_reloads_changed_files() {
+        let dir = std::env::temp_dir().join(format!("re-mini-watch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("orders.grl");
+        let path_str = path.to_str().unwrap();
+        fs::write(&path, "rule A { when X.a == 1 then X.b = 1; }").unwrap();
+
+        let registry = KnowledgeBaseRegistry::new();
+        let mut watcher = Watcher::new();
+        watcher.watch("orders", path_str);
+        assert_eq!(watcher.poll(&registry), vec![("orders".to_string(), Ok(1))]);
+        assert!(watcher.poll(&registry).is_empty());
+
+        fs::write(&path, "rule A { when X.a == 1 then X.b = }").unwrap();
+        let reloaded = watcher.poll(&registry);
+        assert!(reloaded[0].1.as_ref().unwrap_err().contains("orders.grl:1:"));
+        assert_eq!(registry.get("orders").unwrap().version, 1);
+
+        fs::write(&path, "rule Better { when X.a == 1 then X.b = 2; }").unwrap();
+        assert_eq!(watcher.poll(&registry), vec![("orders".to_string(), Ok(2))]);
+        assert_eq!(registry.get("orders").unwrap().kb.rules[0].name, "Better");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
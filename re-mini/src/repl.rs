@@ -0,0 +1,190 @@
+//! Line-oriented session behind the `re-mini-repl` binary.
+
+use std::fs;
+
+use crate::{
+    ast::Rule,
+    context::DataContext,
+    engine::RuleEngine,
+    lexer::is_ident_continue,
+    parser::{parse, parse_condition, parse_expr},
+};
+
+pub const HELP: &str = "\
+commands:
+    load <file.grl>       load rules, replacing the current ones
+    rules                 list loaded rules
+    set <field> = <expr>  set a fact
+    unset <field>         remove a fact
+    facts                 list facts
+    agenda                list rules that would fire next
+    step                  fire one rule
+    run                   fire rules until the agenda is empty
+    reset                 forget which rules fired
+    quit                  leave
+anything else is evaluated as a condition or an expression";
+
+pub struct Repl {
+    rules: Vec<Rule>,
+    ctx: DataContext,
+    engine: RuleEngine,
+    pub done: bool,
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Repl {
+    pub fn new() -> Repl {
+        Repl {
+            rules: Vec::new(),
+            ctx: DataContext::new(),
+            engine: RuleEngine::new(),
+            done: false,
+        }
+    }
+
+    pub fn load_source(&mut self, source: &str) -> Result<String, String> {
+        self.rules = parse(source.to_string())?;
+        self.engine.reset();
+        Ok(format!("loaded {} rules", self.rules.len()))
+    }
+
+    /// Handles one input line, returning the text to show.
+    pub fn eval_line(&mut self, line: &str) -> Result<String, String> {
+        let line = line.trim();
+        let (command, rest) = match line.split_once(char::is_whitespace) {
+            Some((c, r)) => (c, r.trim()),
+            None => (line, ""),
+        };
+
+        match command {
+            "" => Ok(String::new()),
+            "help" => Ok(HELP.into()),
+            "quit" | "exit" => {
+                self.done = true;
+                Ok(String::new())
+            }
+            "load" => {
+                let source = fs::read_to_string(rest).map_err(|e| format!("{}: {}", rest, e))?;
+                self.load_source(&source)
+            }
+            "rules" => Ok(self
+                .rules
+                .iter()
+                .map(|r| format!("{} (salience {})", r.name, r.salience))
+                .collect::<Vec<_>>()
+                .join("\n")),
+            "set" => {
+                let (field, expr) = split_assignment(rest)?;
+                let value = parse_expr(expr.to_string())?.evaluate(&self.ctx)?;
+                let out = format!("{} = {}", field, value);
+                self.ctx.set(field.to_string(), value);
+                Ok(out)
+            }
+            "unset" => match self.ctx.remove(rest.to_string()) {
+                Some(_) => Ok(String::new()),
+                None => Err(format!("field {} not found", rest)),
+            },
+            "facts" => Ok(self
+                .ctx
+                .sorted_facts()
+                .iter()
+                .map(|(k, v)| format!("{} = {}", k, v))
+                .collect::<Vec<_>>()
+                .join("\n")),
+            "agenda" => {
+                let agenda = self.engine.agenda(&self.rules, &self.ctx)?;
+                Ok(agenda
+                    .iter()
+                    .map(|a| a.describe(&self.rules, &self.ctx))
+                    .collect::<Vec<_>>()
+                    .join("\n"))
+            }
+            "step" => match self.engine.step(&self.rules, &mut self.ctx)? {
+                Some(a) => Ok(format!("fired {}", a.describe(&self.rules, &self.ctx))),
+                None => Ok("agenda is empty".into()),
+            },
+            "run" => {
+                let mut fired = Vec::new();
+                while let Some(a) = self.engine.step(&self.rules, &mut self.ctx)? {
+                    fired.push(a.describe(&self.rules, &self.ctx));
+                }
+                if fired.is_empty() {
+                    Ok("agenda is empty".into())
+                } else {
+                    Ok(format!("fired {}", fired.join(", ")))
+                }
+            }
+            "reset" => {
+                self.engine.reset();
+                Ok(String::new())
+            }
+            _ => self.evaluate(line),
+        }
+    }
+
+    fn evaluate(&self, input: &str) -> Result<String, String> {
+        match parse_condition(input.to_string()) {
+            Ok(cond) => Ok(cond.evaluate(&self.ctx)?.to_string()),
+            Err(cond_err) => match parse_expr(input.to_string()) {
+                Ok(expr) => Ok(expr.evaluate(&self.ctx)?.to_string()),
+                Err(_) => Err(cond_err),
+            },
+        }
+    }
+}
+
+// splits `Order.Total = 150` at the first `=` that isn't part of `==`
+fn split_assignment(input: &str) -> Result<(&str, &str), String> {
+    let bytes = input.as_bytes();
+    for (i, &b) in bytes.iter().enumerate() {
+        if b == b'=' && bytes.get(i + 1) != Some(&b'=') {
+            let field = input[..i].trim();
+            if field.is_empty() || !field.chars().all(|c| is_ident_continue(c) || c == '.') {
+                break;
+            }
+            return Ok((field, input[i + 1..].trim()));
+        }
+    }
+    Err("expected <field> = <expr>".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session() {
+        let mut repl = Repl::new();
+        repl.load_source(
+            r#"
+    rule Discount salience 5 { when Order.Total > 100 then Order.Discount = 10; }
+    rule Shipping { when Order.Total > 50 then Order.Shipping = 0; }
+    "#,
+        )
+        .unwrap();
+
+        assert_eq!(repl.eval_line("set Order.Total = 100 + 50").unwrap(), "Order.Total = 150");
+        assert_eq!(repl.eval_line("Order.Total > 100 && Order.Total < 200").unwrap(), "true");
+        assert_eq!(repl.eval_line("Order.Total + 1").unwrap(), "151");
+        assert!(repl.eval_line("Order.Missing + 1").is_err());
+        assert!(repl.eval_line("set = 1").is_err());
+
+        assert_eq!(repl.eval_line("agenda").unwrap(), "Discount\nShipping");
+        assert_eq!(repl.eval_line("step").unwrap(), "fired Discount");
+        assert_eq!(repl.eval_line("agenda").unwrap(), "Shipping");
+        assert_eq!(repl.eval_line("run").unwrap(), "fired Shipping");
+        assert_eq!(repl.eval_line("step").unwrap(), "agenda is empty");
+        assert_eq!(
+            repl.eval_line("facts").unwrap(),
+            "Order.Discount = 10\nOrder.Shipping = 0\nOrder.Total = 150"
+        );
+
+        repl.eval_line("quit").unwrap();
+        assert!(repl.done);
+    }
+}
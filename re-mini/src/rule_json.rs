@@ -0,0 +1,868 @@
+//! Rules as JSON, for tools that generate rules rather than write GRL.
+//! A document holds the same rules GRL does, and converting either way
+//! loses nothing:
+//!
+//! ```json
+//! {
+//!   "rules": [
+//!     {
+//!       "name": "Discount",
+//!       "description": "Big orders",
+//!       "salience": 10,
+//!       "no_loop": true,
+//!       "when": {"all": [
+//!         {"left": {"field": "Order.Total"}, "op": ">", "right": 100},
+//!         {"not": {"left": {"field": "Order.Vip"}, "op": "==", "right": true}}
+//!       ]},
+//!       "then": [
+//!         {"set": "Order.Discount", "to": {"op": "-", "left": {"field": "Order.Total"}, "right": 90}}
+//!       ]
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! Only `name`, `when` and `then` are required; `description`, `salience`,
+//! `no_loop`, `lock_on_active`, `activation_group`, `comments` and
+//! `placed_comments` are optional. `activation_group` is `{"name": "Tier"}`,
+//! with `"unique": true` when a second match is an error. `comments` go
+//! before the rule; each of `placed_comments` is
+//! `{"at": place, "text": "// note"}`, where `place` is `"when"`, before the
+//! condition, the index of the action it goes before (the number of
+//! actions for after the last), or `"after"`, after the rule.
+//!
+//! A condition is a comparison `{"left", "op", "right"}` with `op` one of
+//! `==`, `!=`, `<`, `>`, `<=`, `>=`; `{"all": [...]}` or `{"any": [...]}`
+//! of two or more conditions, joined with `&&` or `||` from the left;
+//! `{"not": condition}`; a pattern `{"pattern": "Order", "bind": "o",
+//! "where": condition}`, where `bind` and `where` are optional and, as in
+//! GRL, bare field names in `where` are fields of the matched instance; or
+//! `{"exists": "Order", "where": condition}`, true when some instance
+//! meets the optional `where`.
+//!
+//! An action is an assignment `{"set": "Order.Discount", "to": expr}`;
+//! `{"insert": "Order", "fields": {"Total": expr, ...}}`, which adds an
+//! instance to working memory; or `{"update": "o"}` or `{"retract": "o"}`
+//! on a pattern's variable.
+//!
+//! An expression is a JSON integer, boolean or string literal;
+//! `{"date": "2024-01-31"}`, `{"datetime": "2024-01-31T10:00:00Z"}` or
+//! `{"duration": "1d12h"}`; `{"field": "Order.Total"}`;
+//! `{"op": "+" or "-", "left", "right"}`; `{"call": "year", "args": [...]}`;
+//! `{"list": [...]}` or `{"record": {"Price": 5}}` of literals;
+//! `{"facts": "Order", "where": condition}`, the instances a fact set
+//! matches; or an aggregate `{"aggregate": "sum", "of": expr, "param": "i",
+//! "value": expr}`. `count`, `any` and `all` take a `"where": condition` in
+//! place of `value`, and `count` can leave both out, along with `param`.
+//!
+//! With the `yaml` feature the same document can be written in YAML, see
+//! [`crate::yaml`].
+
+use std::collections::BTreeMap;
+
+use crate::{
+    ast::{
+        bind_fields, check_action, check_patterns, unbind_fields, Action, ActivationGroup, Aggregate, CmpOp, CommentPlace, Condition, Expr, FactSet,
+        Lambda, Op, Rule, FACT_SET_BINDING,
+    },
+    functions,
+    json::Json,
+    lexer::is_quotable,
+    limits::Limits,
+    time::{Date, DateTime, Duration},
+    value::Value,
+};
+
+fn obj(members: Vec<(&str, Json)>) -> Json {
+    Json::Object(members.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+}
+
+fn cmp_op_str(op: &CmpOp) -> &'static str {
+    match op {
+        CmpOp::Eq => "==",
+        CmpOp::NotEq => "!=",
+        CmpOp::Lt => "<",
+        CmpOp::Gt => ">",
+        CmpOp::LtEq => "<=",
+        CmpOp::GtEq => ">=",
+    }
+}
+
+pub fn rules_to_json(rules: &[Rule]) -> Json {
+    obj(vec![("rules", Json::Array(rules.iter().map(rule_to_json).collect()))])
+}
+
+pub fn rule_to_json(rule: &Rule) -> Json {
+    let mut members = vec![("name", Json::Str(rule.name.clone()))];
+    if let Some(description) = &rule.description {
+        members.push(("description", Json::Str(description.clone())));
+    }
+    if rule.salience != 0 {
+        members.push(("salience", Json::Int(rule.salience)));
+    }
+    if rule.no_loop {
+        members.push(("no_loop", Json::Bool(true)));
+    }
+    if rule.lock_on_active {
+        members.push(("lock_on_active", Json::Bool(true)));
+    }
+    if let Some(group) = &rule.activation_group {
+        let mut group_members = vec![("name", Json::Str(group.name.clone()))];
+        if group.unique {
+            group_members.push(("unique", Json::Bool(true)));
+        }
+        members.push(("activation_group", obj(group_members)));
+    }
+    if !rule.comments.is_empty() {
+        members.push(("comments", Json::Array(rule.comments.iter().map(|c| Json::Str(c.clone())).collect())));
+    }
+    if !rule.placed_comments.is_empty() {
+        let placed = rule
+            .placed_comments
+            .iter()
+            .map(|(place, text)| {
+                let at = match place {
+                    CommentPlace::When => Json::Str("when".into()),
+                    CommentPlace::Action(i) => Json::Int(*i as i64),
+                    CommentPlace::After => Json::Str("after".into()),
+                };
+                obj(vec![("at", at), ("text", Json::Str(text.clone()))])
+            })
+            .collect();
+        members.push(("placed_comments", Json::Array(placed)));
+    }
+    members.push(("when", condition_to_json(&rule.condition)));
+    let actions = rule
+        .actions
+        .iter()
+        .map(|action| match action {
+            Action::Assign { field, expr } => obj(vec![("set", Json::Str(field.clone())), ("to", expr_to_json(expr))]),
+            Action::Insert { fact_type, fields } => obj(vec![
+                ("insert", Json::Str(fact_type.clone())),
+                ("fields", Json::Object(fields.iter().map(|(f, e)| (f.clone(), expr_to_json(e))).collect())),
+            ]),
+            Action::Update(binding) => obj(vec![("update", Json::Str(binding.clone()))]),
+            Action::Retract(binding) => obj(vec![("retract", Json::Str(binding.clone()))]),
+        })
+        .collect();
+    members.push(("then", Json::Array(actions)));
+    obj(members)
+}
+
+pub fn condition_to_json(cond: &Condition) -> Json {
+    match cond {
+        Condition::Compare { left, op, right } => obj(vec![
+            ("left", expr_to_json(left)),
+            ("op", Json::Str(cmp_op_str(op).into())),
+            ("right", expr_to_json(right)),
+        ]),
+        Condition::And(..) => obj(vec![("all", Json::Array(chain(cond)))]),
+        Condition::Or(..) => obj(vec![("any", Json::Array(chain(cond)))]),
+        Condition::Not(c) => obj(vec![("not", condition_to_json(c))]),
+        Condition::Pattern { binding, fact_type, constraint } => {
+            let mut members = vec![("pattern", Json::Str(fact_type.clone()))];
+            if !binding.starts_with('$') {
+                members.push(("bind", Json::Str(binding.clone())));
+            }
+            if let Some(c) = constraint {
+                members.push(("where", condition_to_json(&unbind_fields(c, binding))));
+            }
+            obj(members)
+        }
+        Condition::Exists(set) => fact_set_to_json("exists", set),
+    }
+}
+
+fn fact_set_to_json(key: &str, set: &FactSet) -> Json {
+    let mut members = vec![(key, Json::Str(set.fact_type.clone()))];
+    if let Some(c) = &set.constraint {
+        members.push(("where", condition_to_json(&unbind_fields(c, FACT_SET_BINDING))));
+    }
+    obj(members)
+}
+
+// the operands of a chain of the same operator nested on the left, as
+// `a && b && c` parses; anything else nests in the JSON too
+fn chain(cond: &Condition) -> Vec<Json> {
+    let (Condition::And(a, b) | Condition::Or(a, b)) = cond else {
+        return vec![condition_to_json(cond)];
+    };
+    let mut items = if std::mem::discriminant(&**a) == std::mem::discriminant(cond) {
+        chain(a)
+    } else {
+        vec![condition_to_json(a)]
+    };
+    items.push(condition_to_json(b));
+    items
+}
+
+fn value_to_json(value: &Value) -> Json {
+    match value {
+        Value::Int(n) => Json::Int(*n),
+        Value::Bool(b) => Json::Bool(*b),
+        Value::Str(s) => Json::Str(s.clone()),
+        Value::Date(d) => obj(vec![("date", Json::Str(d.to_string()))]),
+        Value::DateTime(t) => obj(vec![("datetime", Json::Str(t.to_string()))]),
+        Value::Duration(d) => obj(vec![("duration", Json::Str(d.to_string()))]),
+        Value::List(items) => obj(vec![("list", Json::Array(items.iter().map(value_to_json).collect()))]),
+        Value::Record(fields) => obj(vec![(
+            "record",
+            Json::Object(fields.iter().map(|(k, v)| (k.clone(), value_to_json(v))).collect()),
+        )]),
+    }
+}
+
+pub fn expr_to_json(expr: &Expr) -> Json {
+    match expr {
+        Expr::Literal(value) => value_to_json(value),
+        Expr::FieldRef(name) => obj(vec![("field", Json::Str(name.clone()))]),
+        Expr::BinOp { left, op, right } => obj(vec![
+            ("op", Json::Str(op.to_string())),
+            ("left", expr_to_json(left)),
+            ("right", expr_to_json(right)),
+        ]),
+        Expr::Call { name, args } => obj(vec![
+            ("call", Json::Str(name.clone())),
+            ("args", Json::Array(args.iter().map(expr_to_json).collect())),
+        ]),
+        Expr::Aggregate { func, list, lambda } => {
+            let mut members = vec![("aggregate", Json::Str(func.name().into())), ("of", expr_to_json(list))];
+            match lambda {
+                Some(Lambda::Map(param, body)) => {
+                    members.push(("param", Json::Str(param.clone())));
+                    members.push(("value", expr_to_json(body)));
+                }
+                Some(Lambda::Filter(param, cond)) => {
+                    members.push(("param", Json::Str(param.clone())));
+                    members.push(("where", condition_to_json(cond)));
+                }
+                None => {}
+            }
+            obj(members)
+        }
+        Expr::Facts(set) => fact_set_to_json("facts", set),
+    }
+}
+
+/// Reads the rules of a JSON rules document. Errors name the path of the
+/// offending value, like `rules[0].when.all[1]`. Each rule is held to the
+/// nesting depth and node count `limits` hold GRL rules to.
+pub fn rules_from_json(json: &Json, limits: &Limits) -> Result<Vec<Rule>, String> {
+    let items = json.get("rules").and_then(|r| r.as_array()).ok_or("expected an object with a rules array")?;
+    items.iter().enumerate().map(|(i, item)| rule_from_json(item, &format!("rules[{}]", i), limits)).collect()
+}
+
+/// Parses a JSON rules document.
+pub fn parse_json_rules(input: &str) -> Result<Vec<Rule>, String> {
+    parse_json_rules_with_limits(input, &Limits::default())
+}
+
+/// Parses a JSON rules document, rejecting a document or rule nested
+/// deeper, or a rule with more nodes, than `limits` allows.
+pub fn parse_json_rules_with_limits(input: &str, limits: &Limits) -> Result<Vec<Rule>, String> {
+    rules_from_json(&Json::parse_with_limits(input, limits)?, limits)
+}
+
+/// Parses a YAML rules document, the same schema as JSON.
+#[cfg(feature = "yaml")]
+pub fn parse_yaml_rules(input: &str) -> Result<Vec<Rule>, String> {
+    parse_yaml_rules_with_limits(input, &Limits::default())
+}
+
+/// [`parse_json_rules_with_limits`] for YAML.
+#[cfg(feature = "yaml")]
+pub fn parse_yaml_rules_with_limits(input: &str, limits: &Limits) -> Result<Vec<Rule>, String> {
+    rules_from_json(&crate::yaml::parse_with_limits(input, limits)?, limits)
+}
+
+#[cfg(feature = "yaml")]
+pub fn rules_to_yaml(rules: &[Rule]) -> String {
+    crate::yaml::to_yaml(&rules_to_json(rules))
+}
+
+// the depth and size of the rule being read, held to the same limits the
+// GRL parser enforces
+struct Nesting {
+    depth: usize,
+    nodes: usize,
+    max_depth: usize,
+    max_nodes: usize,
+}
+
+impl Nesting {
+    fn new(limits: &Limits) -> Self {
+        Nesting {
+            depth: 0,
+            nodes: 0,
+            max_depth: limits.max_depth,
+            max_nodes: limits.max_nodes,
+        }
+    }
+
+    fn nested<T>(&mut self, path: &str, read: impl FnOnce(&mut Nesting) -> Result<T, String>) -> Result<T, String> {
+        if self.depth >= self.max_depth {
+            return Err(format!("{}: expression nested deeper than {} levels", path, self.max_depth));
+        }
+        self.nodes += 1;
+        if self.nodes > self.max_nodes {
+            return Err(format!("{}: rule has more than {} expression nodes", path, self.max_nodes));
+        }
+        self.depth += 1;
+        let result = read(self);
+        self.depth -= 1;
+        result
+    }
+}
+
+fn keys(json: &Json, path: &str, allowed: &[&str]) -> Result<(), String> {
+    let Json::Object(members) = json else {
+        return Err(format!("{}: expected an object", path));
+    };
+    match members.iter().find(|(k, _)| !allowed.contains(&k.as_str())) {
+        Some((k, _)) => Err(format!("{}: unknown key '{}'", path, k)),
+        None => Ok(()),
+    }
+}
+
+fn field<'a>(json: &'a Json, path: &str, key: &str) -> Result<&'a Json, String> {
+    json.get(key).ok_or_else(|| format!("{}: missing '{}'", path, key))
+}
+
+fn string<'a>(json: &'a Json, path: &str) -> Result<&'a str, String> {
+    json.as_str().ok_or_else(|| format!("{}: expected a string", path))
+}
+
+fn flag(json: &Json, path: &str, key: &str) -> Result<bool, String> {
+    match json.get(key) {
+        None => Ok(false),
+        Some(Json::Bool(b)) => Ok(*b),
+        Some(_) => Err(format!("{}.{}: expected true or false", path, key)),
+    }
+}
+
+// a dotted path of names GRL can write
+fn check_path(name: &str, path: &str, segments: &[usize]) -> Result<(), String> {
+    let parts: Vec<&str> = name.split('.').collect();
+    if !segments.contains(&parts.len()) || !parts.iter().all(|p| is_quotable(p)) {
+        return Err(format!("{}: invalid field name '{}'", path, name));
+    }
+    Ok(())
+}
+
+// names the patterns written without a variable `$1`, `$2`, ... in order
+fn number_patterns(cond: Condition, n: &mut usize) -> Condition {
+    match cond {
+        Condition::And(a, b) => {
+            let a = number_patterns(*a, n);
+            Condition::And(Box::new(a), Box::new(number_patterns(*b, n)))
+        }
+        Condition::Pattern { binding, fact_type, constraint } if binding == "$" => {
+            *n += 1;
+            let binding = format!("${}", n);
+            let constraint = constraint.map(|c| Box::new(bind_fields(&unbind_fields(&c, "$"), &binding, &[])));
+            Condition::Pattern { binding, fact_type, constraint }
+        }
+        other => other,
+    }
+}
+
+pub fn rule_from_json(json: &Json, path: &str, limits: &Limits) -> Result<Rule, String> {
+    let mut n = Nesting::new(limits);
+    keys(
+        json,
+        path,
+        &["name", "description", "salience", "no_loop", "lock_on_active", "activation_group", "comments", "placed_comments", "when", "then"],
+    )?;
+    let name = string(field(json, path, "name")?, &format!("{}.name", path))?;
+    if !is_quotable(name) {
+        return Err(format!("{}.name: invalid rule name '{}'", path, name));
+    }
+    let condition = number_patterns(condition_in(field(json, path, "when")?, &format!("{}.when", path), &[], &mut n)?, &mut 0);
+    check_patterns(&condition).map_err(|e| format!("{}.when: {}", path, e))?;
+    let actions = field(json, path, "then")?
+        .as_array()
+        .ok_or_else(|| format!("{}.then: expected an array", path))?
+        .iter()
+        .enumerate()
+        .map(|(i, action)| action_from_json(action, &format!("{}.then[{}]", path, i), &mut n))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut rule = Rule::new(name.to_string(), condition, actions);
+    let variables: Vec<&str> = rule.patterns().into_iter().map(|(v, _)| v).collect();
+    for (i, action) in rule.actions.iter().enumerate() {
+        check_action(action, &variables).map_err(|e| format!("{}.then[{}]: {}", path, i, e))?;
+    }
+    rule.description = match json.get("description") {
+        Some(d) => Some(string(d, &format!("{}.description", path))?.to_string()),
+        None => None,
+    };
+    rule.salience = match json.get("salience") {
+        Some(s) => s.as_i64().ok_or_else(|| format!("{}.salience: expected an integer", path))?,
+        None => 0,
+    };
+    rule.no_loop = flag(json, path, "no_loop")?;
+    rule.lock_on_active = flag(json, path, "lock_on_active")?;
+    if let Some(group) = json.get("activation_group") {
+        let at = format!("{}.activation_group", path);
+        keys(group, &at, &["name", "unique"])?;
+        let name = string(field(group, &at, "name")?, &format!("{}.name", at))?.to_string();
+        rule.activation_group = Some(ActivationGroup { name, unique: flag(group, &at, "unique")? });
+    }
+    if let Some(comments) = json.get("comments") {
+        let comments = comments.as_array().ok_or_else(|| format!("{}.comments: expected an array", path))?;
+        for (i, c) in comments.iter().enumerate() {
+            rule.comments.push(string(c, &format!("{}.comments[{}]", path, i))?.to_string());
+        }
+    }
+    if let Some(placed) = json.get("placed_comments") {
+        let placed = placed.as_array().ok_or_else(|| format!("{}.placed_comments: expected an array", path))?;
+        for (i, c) in placed.iter().enumerate() {
+            let path = format!("{}.placed_comments[{}]", path, i);
+            keys(c, &path, &["at", "text"])?;
+            let place = match field(c, &path, "at")? {
+                Json::Str(s) if s == "when" => CommentPlace::When,
+                Json::Str(s) if s == "after" => CommentPlace::After,
+                Json::Int(n) if (0..=rule.actions.len() as i64).contains(n) => CommentPlace::Action(*n as usize),
+                _ => return Err(format!("{}.at: expected \"when\", \"after\" or an action index", path)),
+            };
+            let text = string(field(c, &path, "text")?, &format!("{}.text", path))?;
+            rule.placed_comments.push((place, text.to_string()));
+        }
+    }
+    Ok(rule)
+}
+
+fn action_from_json(json: &Json, path: &str, n: &mut Nesting) -> Result<Action, String> {
+    if let Some(fact_type) = json.get("insert") {
+        keys(json, path, &["insert", "fields"])?;
+        let fact_type = string(fact_type, &format!("{}.insert", path))?;
+        check_path(fact_type, &format!("{}.insert", path), &[1])?;
+        let fields_path = format!("{}.fields", path);
+        let Json::Object(members) = field(json, path, "fields")? else {
+            return Err(format!("{}: expected an object", fields_path));
+        };
+        let mut fields = Vec::new();
+        for (name, expr) in members {
+            let path = format!("{}.{}", fields_path, name);
+            check_path(name, &path, &[1])?;
+            fields.push((name.clone(), expr_in(expr, &path, &[], n)?));
+        }
+        return Ok(Action::Insert {
+            fact_type: fact_type.to_string(),
+            fields,
+        });
+    }
+    for key in ["update", "retract"] {
+        if let Some(binding) = json.get(key) {
+            keys(json, path, &[key])?;
+            let binding = string(binding, &format!("{}.{}", path, key))?.to_string();
+            return Ok(if key == "update" { Action::Update(binding) } else { Action::Retract(binding) });
+        }
+    }
+    keys(json, path, &["set", "to"])?;
+    let field_name = string(field(json, path, "set")?, &format!("{}.set", path))?;
+    check_path(field_name, &format!("{}.set", path), &[2])?;
+    let expr = expr_in(field(json, path, "to")?, &format!("{}.to", path), &[], n)?;
+    Ok(Action::Assign {
+        field: field_name.to_string(),
+        expr,
+    })
+}
+
+pub fn condition_from_json(json: &Json, path: &str, limits: &Limits) -> Result<Condition, String> {
+    condition_in(json, path, &[], &mut Nesting::new(limits))
+}
+
+// `params` are the parameters of the lambdas the condition is inside,
+// which are never fields of a pattern or fact set
+fn condition_in(json: &Json, path: &str, params: &[String], n: &mut Nesting) -> Result<Condition, String> {
+    n.nested(path, |n| condition_node(json, path, params, n))
+}
+
+fn condition_node(json: &Json, path: &str, params: &[String], n: &mut Nesting) -> Result<Condition, String> {
+    for (key, and) in [("all", true), ("any", false)] {
+        if let Some(items) = json.get(key) {
+            keys(json, path, &[key])?;
+            let items = items.as_array().ok_or_else(|| format!("{}.{}: expected an array", path, key))?;
+            if items.len() < 2 {
+                return Err(format!("{}.{}: needs at least two conditions", path, key));
+            }
+            let mut conds = items
+                .iter()
+                .enumerate()
+                .map(|(i, c)| condition_in(c, &format!("{}.{}[{}]", path, key, i), params, n));
+            let mut cond = conds.next().unwrap()?;
+            for next in conds {
+                let (a, b) = (Box::new(cond), Box::new(next?));
+                cond = if and { Condition::And(a, b) } else { Condition::Or(a, b) };
+            }
+            return Ok(cond);
+        }
+    }
+    if let Some(fact_type) = json.get("pattern") {
+        keys(json, path, &["pattern", "bind", "where"])?;
+        let fact_type = string(fact_type, &format!("{}.pattern", path))?.to_string();
+        check_path(&fact_type, &format!("{}.pattern", path), &[1])?;
+        // numbered by `rule_from_json`, as the parser numbers them
+        let binding = match json.get("bind") {
+            Some(b) => string(b, &format!("{}.bind", path))?.to_string(),
+            None => "$".to_string(),
+        };
+        check_path(&binding, &format!("{}.bind", path), &[1])?;
+        let constraint = match json.get("where") {
+            Some(c) => Some(Box::new(bind_fields(&condition_in(c, &format!("{}.where", path), params, n)?, &binding, params))),
+            None => None,
+        };
+        return Ok(Condition::Pattern { binding, fact_type, constraint });
+    }
+    if json.get("exists").is_some() {
+        return Ok(Condition::Exists(fact_set_from_json(json, path, "exists", params, n)?));
+    }
+    if let Some(inner) = json.get("not") {
+        keys(json, path, &["not"])?;
+        return Ok(Condition::Not(Box::new(condition_in(inner, &format!("{}.not", path), params, n)?)));
+    }
+
+    keys(json, path, &["left", "op", "right"])?;
+    let op = match string(field(json, path, "op")?, &format!("{}.op", path))? {
+        "==" => CmpOp::Eq,
+        "!=" => CmpOp::NotEq,
+        "<" => CmpOp::Lt,
+        ">" => CmpOp::Gt,
+        "<=" => CmpOp::LtEq,
+        ">=" => CmpOp::GtEq,
+        other => return Err(format!("{}.op: unknown comparison operator '{}'", path, other)),
+    };
+    Ok(Condition::Compare {
+        left: expr_in(field(json, path, "left")?, &format!("{}.left", path), params, n)?,
+        op,
+        right: expr_in(field(json, path, "right")?, &format!("{}.right", path), params, n)?,
+    })
+}
+
+fn fact_set_from_json(json: &Json, path: &str, key: &str, params: &[String], n: &mut Nesting) -> Result<FactSet, String> {
+    keys(json, path, &[key, "where"])?;
+    let fact_type = string(field(json, path, key)?, &format!("{}.{}", path, key))?.to_string();
+    check_path(&fact_type, &format!("{}.{}", path, key), &[1])?;
+    let constraint = match json.get("where") {
+        Some(c) => {
+            let c = condition_in(c, &format!("{}.where", path), params, n)?;
+            Some(Box::new(bind_fields(&c, FACT_SET_BINDING, params)))
+        }
+        None => None,
+    };
+    Ok(FactSet { fact_type, constraint })
+}
+
+fn value_from_json(json: &Json, path: &str, n: &mut Nesting) -> Result<Value, String> {
+    match expr_in(json, path, &[], n)? {
+        Expr::Literal(value) => Ok(value),
+        _ => Err(format!("{}: expected a literal", path)),
+    }
+}
+
+pub fn expr_from_json(json: &Json, path: &str, limits: &Limits) -> Result<Expr, String> {
+    expr_in(json, path, &[], &mut Nesting::new(limits))
+}
+
+fn expr_in(json: &Json, path: &str, params: &[String], n: &mut Nesting) -> Result<Expr, String> {
+    n.nested(path, |n| expr_node(json, path, params, n))
+}
+
+fn expr_node(json: &Json, path: &str, params: &[String], n: &mut Nesting) -> Result<Expr, String> {
+    let literal = |key: &str| -> Result<Option<String>, String> {
+        match json.get(key) {
+            Some(v) => {
+                keys(json, path, &[key])?;
+                Ok(Some(string(v, &format!("{}.{}", path, key))?.to_string()))
+            }
+            None => Ok(None),
+        }
+    };
+    let at = |e: String| format!("{}: {}", path, e);
+
+    match json {
+        Json::Int(n) => return Ok(Expr::Literal(Value::Int(*n))),
+        Json::Bool(b) => return Ok(Expr::Literal(Value::Bool(*b))),
+        Json::Str(s) => return Ok(Expr::Literal(Value::Str(s.clone()))),
+        Json::Object(_) => {}
+        _ => return Err(format!("{}: expected an expression", path)),
+    }
+    if let Some(d) = literal("date")? {
+        return Ok(Expr::Literal(Value::Date(Date::parse(&d).map_err(at)?)));
+    }
+    if let Some(t) = literal("datetime")? {
+        return Ok(Expr::Literal(Value::DateTime(DateTime::parse(&t).map_err(at)?)));
+    }
+    if let Some(d) = literal("duration")? {
+        return Ok(Expr::Literal(Value::Duration(Duration::parse(&d).map_err(at)?)));
+    }
+    if let Some(name) = literal("field")? {
+        check_path(&name, path, &[1, 2])?;
+        return Ok(Expr::FieldRef(name));
+    }
+    if let Some(name) = json.get("call") {
+        keys(json, path, &["call", "args"])?;
+        let name = string(name, &format!("{}.call", path))?.to_string();
+        let args = match json.get("args") {
+            Some(args) => args
+                .as_array()
+                .ok_or_else(|| format!("{}.args: expected an array", path))?
+                .iter()
+                .enumerate()
+                .map(|(i, a)| expr_in(a, &format!("{}.args[{}]", path, i), params, n))
+                .collect::<Result<Vec<_>, _>>()?,
+            None => Vec::new(),
+        };
+        functions::check_call(&name, args.len()).map_err(at)?;
+        return Ok(Expr::Call { name, args });
+    }
+    if let Some(items) = json.get("list") {
+        keys(json, path, &["list"])?;
+        let items = items.as_array().ok_or_else(|| format!("{}.list: expected an array", path))?;
+        let items = items
+            .iter()
+            .enumerate()
+            .map(|(i, item)| value_from_json(item, &format!("{}.list[{}]", path, i), n))
+            .collect::<Result<Vec<_>, _>>()?;
+        return Ok(Expr::Literal(Value::List(items)));
+    }
+    if let Some(fields) = json.get("record") {
+        keys(json, path, &["record"])?;
+        let Json::Object(members) = fields else {
+            return Err(format!("{}.record: expected an object", path));
+        };
+        let mut record = BTreeMap::new();
+        for (name, value) in members {
+            let path = format!("{}.record.{}", path, name);
+            check_path(name, &path, &[1])?;
+            record.insert(name.clone(), value_from_json(value, &path, n)?);
+        }
+        return Ok(Expr::Literal(Value::Record(record)));
+    }
+    if json.get("facts").is_some() {
+        return Ok(Expr::Facts(fact_set_from_json(json, path, "facts", params, n)?));
+    }
+    if let Some(func) = json.get("aggregate") {
+        keys(json, path, &["aggregate", "of", "param", "value", "where"])?;
+        let name = string(func, &format!("{}.aggregate", path))?;
+        let func = Aggregate::from_name(name)
+            .ok_or_else(|| format!("{}.aggregate: unknown aggregate '{}'", path, name))?;
+        let list = Box::new(expr_in(field(json, path, "of")?, &format!("{}.of", path), params, n)?);
+        // the parameter, and the parameters in scope inside the lambda
+        let param = || -> Result<(String, Vec<String>), String> {
+            let param = string(field(json, path, "param")?, &format!("{}.param", path))?.to_string();
+            check_path(&param, &format!("{}.param", path), &[1])?;
+            Ok((param.clone(), params.iter().cloned().chain([param]).collect()))
+        };
+        let lambda = match (json.get("value"), json.get("where")) {
+            (None, None) => None,
+            (Some(value), None) => {
+                let (param, inner) = param()?;
+                Some(Lambda::Map(param, Box::new(expr_in(value, &format!("{}.value", path), &inner, n)?)))
+            }
+            (None, Some(cond)) => {
+                let (param, inner) = param()?;
+                Some(Lambda::Filter(param, Box::new(condition_in(cond, &format!("{}.where", path), &inner, n)?)))
+            }
+            (Some(_), Some(_)) => return Err(format!("{}: expected 'value' or 'where', not both", path)),
+        };
+        if lambda.is_none() && json.get("param").is_some() {
+            return Err(format!("{}.param: needs a 'value' or 'where'", path));
+        }
+        func.check(lambda.as_ref()).map_err(at)?;
+        return Ok(Expr::Aggregate { func, list, lambda });
+    }
+
+    keys(json, path, &["op", "left", "right"])?;
+    let op = match string(field(json, path, "op")?, &format!("{}.op", path))? {
+        "+" => Op::Add,
+        "-" => Op::Sub,
+        other => return Err(format!("{}.op: unknown operator '{}'", path, other)),
+    };
+    Ok(Expr::BinOp {
+        left: Box::new(expr_in(field(json, path, "left")?, &format!("{}.left", path), params, n)?),
+        op,
+        right: Box::new(expr_in(field(json, path, "right")?, &format!("{}.right", path), params, n)?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    const RULES: &str = r#"
+    // discounts
+    rule Discount "Big orders" salience 10 no-loop {
+        when
+            Order.Total > 100 && Order.Items >= 3 && !(Order.Placed < d"2024-01-01") || (Order.A == 1 || Order.B == 2) && Order.C == 3
+        then
+            Order.Discount = Order.Total - (100 + -5);
+            Order.Due = datetime("2024-06-01T10:00:00+02:00") + 1d12h;
+            Order.Note = "say \"hi\"";
+            Order.W = now() + -3d;
+    }
+    rule Simple lock-on-active activation-group "Flags" unique { when Flag == false then Order.Flag = true; }
+    rule Owner { when o: Order(Total > 100) && Customer() && c: Customer(Id == o.CustomerId) then o.Owner = c.Name; update(o); insert(Audit { Order: o.Id, Seen: true }); }
+    rule Fraud { when not exists Alert() && Order.Limits.any(l => Transaction(Amount > l).count() > 0) /* any */ then Order.Sum = Order.Items.sum(i => i.Price); // sum
+    }
+    // end
+    "#;
+
+    #[test]
+    fn test_round_trip() {
+        let rules = parse(RULES.to_string()).unwrap();
+        let json = rules_to_json(&rules);
+        assert_eq!(parse_json_rules(&json.pretty()).unwrap(), rules);
+
+        // `&&` and `||` bind left to right: ((a && b && c) || (e || f)) && g
+        let when = json.get("rules").unwrap().as_array().unwrap()[0].get("when").unwrap();
+        let all = when.get("all").unwrap().as_array().unwrap();
+        assert_eq!(all.len(), 2);
+        let any = all[0].get("any").unwrap().as_array().unwrap();
+        assert_eq!(any.len(), 2);
+        assert_eq!(any[0].get("all").unwrap().as_array().unwrap().len(), 3);
+        assert_eq!(any[1].get("any").unwrap().as_array().unwrap().len(), 2);
+
+        // pattern constraints name their own fields without the variable
+        let when = json.get("rules").unwrap().as_array().unwrap()[2].get("when").unwrap();
+        assert_eq!(
+            when.to_string(),
+            r#"{"all":[{"pattern":"Order","bind":"o","where":{"left":{"field":"Total"},"op":">","right":100}},{"pattern":"Customer"},{"pattern":"Customer","bind":"c","where":{"left":{"field":"Id"},"op":"==","right":{"field":"o.CustomerId"}}}]}"#
+        );
+        let then = json.get("rules").unwrap().as_array().unwrap()[2].get("then").unwrap();
+        assert_eq!(
+            then.to_string(),
+            r#"[{"set":"o.Owner","to":{"field":"c.Name"}},{"update":"o"},{"insert":"Audit","fields":{"Order":{"field":"o.Id"},"Seen":true}}]"#
+        );
+
+        // a lambda's parameter is not a field of the fact set inside it
+        let when = json.get("rules").unwrap().as_array().unwrap()[3].get("when").unwrap();
+        assert_eq!(
+            when.to_string(),
+            r#"{"all":[{"not":{"exists":"Alert"}},{"left":{"aggregate":"any","of":{"field":"Order.Limits"},"param":"l","where":{"left":{"aggregate":"count","of":{"facts":"Transaction","where":{"left":{"field":"Amount"},"op":">","right":{"field":"l"}}}},"op":">","right":0}},"op":"==","right":true}]}"#
+        );
+    }
+
+    #[test]
+    fn test_documented_example() {
+        let rules = parse_json_rules(
+            r#"{"rules": [{
+                "name": "Discount", "description": "Big orders", "salience": 10, "no_loop": true,
+                "when": {"all": [
+                    {"left": {"field": "Order.Total"}, "op": ">", "right": 100},
+                    {"not": {"left": {"field": "Order.Vip"}, "op": "==", "right": true}}
+                ]},
+                "then": [{"set": "Order.Discount", "to": {"op": "-", "left": {"field": "Order.Total"}, "right": 90}}]
+            }]}"#,
+        )
+        .unwrap();
+        let grl = r#"rule Discount "Big orders" salience 10 no-loop {
+            when Order.Total > 100 && !(Order.Vip == true)
+            then Order.Discount = Order.Total - 90;
+        }"#;
+        assert_eq!(rules, parse(grl.to_string()).unwrap());
+    }
+
+    #[test]
+    fn test_errors_name_the_path() {
+        let err = |input: &str| parse_json_rules(input).unwrap_err();
+        assert_eq!(err("[]"), "expected an object with a rules array");
+        assert_eq!(
+            err(r#"{"rules": [{"name": "R", "when": {"all": [{"left": 1, "op": "=~", "right": 2}, {"not": 1}]}, "then": []}]}"#),
+            "rules[0].when.all[0].op: unknown comparison operator '=~'"
+        );
+        assert_eq!(
+            err(r#"{"rules": [{"name": "R", "when": {"left": {"call": "year", "args": []}, "op": "==", "right": 1}, "then": []}]}"#),
+            "rules[0].when.left: function year takes 1 argument, got 0"
+        );
+        assert_eq!(
+            err(r#"{"rules": [{"name": "R", "when": {"left": 1, "op": "==", "right": 1}, "then": [{"set": "Discount", "to": 1}]}]}"#),
+            "rules[0].then[0].set: invalid field name 'Discount'"
+        );
+        assert_eq!(
+            err(r#"{"rules": [{"name": "R", "salience": "high", "when": {"left": 1, "op": "==", "right": 1}, "then": []}]}"#),
+            "rules[0].salience: expected an integer"
+        );
+        assert_eq!(err(r#"{"rules": [{"name": "R", "when": {"left": 1}, "then": [], "x": 1}]}"#), "rules[0]: unknown key 'x'");
+        assert_eq!(
+            err(r#"{"rules": [{"name": "R", "when": {"any": [{"pattern": "Order"}, {"pattern": "Order"}]}, "then": []}]}"#),
+            "rules[0].when: pattern Order() can only be joined to the condition with a top-level &&"
+        );
+        assert_eq!(
+            err(r#"{"rules": [{"name": "R", "when": {"pattern": "Order"}, "then": [{"set": "X.a", "to": 1}, {"retract": "o"}]}]}"#),
+            "rules[0].then[1]: variable o is not bound by a pattern"
+        );
+        assert_eq!(
+            err(r#"{"rules": [{"name": "R", "when": {"pattern": "Order"}, "then": [{"insert": "A", "fields": {"b.c": 1}}]}]}"#),
+            "rules[0].then[0].fields.b.c: invalid field name 'b.c'"
+        );
+        // names GRL can't quote
+        assert_eq!(
+            err(r#"{"rules": [{"name": "", "when": {"left": 1, "op": "==", "right": 1}, "then": []}]}"#),
+            "rules[0].name: invalid rule name ''"
+        );
+        assert_eq!(
+            err(r#"{"rules": [{"name": "R", "when": {"left": {"field": "X.a`b"}, "op": "==", "right": 1}, "then": []}]}"#),
+            "rules[0].when.left: invalid field name 'X.a`b'"
+        );
+        assert_eq!(
+            err(r#"{"rules": [{"name": "R", "when": {"pattern": "Or\nder"}, "then": []}]}"#),
+            "rules[0].when.pattern: invalid field name 'Or\nder'"
+        );
+        assert_eq!(
+            err(r#"{"rules": [{"name": "R", "when": {"left": {"aggregate": "median", "of": {"field": "X.a"}}, "op": "==", "right": 1}, "then": []}]}"#),
+            "rules[0].when.left.aggregate: unknown aggregate 'median'"
+        );
+        assert_eq!(
+            err(r#"{"rules": [{"name": "R", "when": {"left": {"aggregate": "sum", "of": {"field": "X.a"}, "param": "i", "where": {"left": 1, "op": "==", "right": 1}}, "op": "==", "right": 1}, "then": []}]}"#),
+            "rules[0].when.left: sum() takes a value, like sum(i => i.Price)"
+        );
+        assert_eq!(
+            err(r#"{"rules": [{"name": "R", "when": {"left": {"list": [{"field": "X.a"}]}, "op": "==", "right": 1}, "then": []}]}"#),
+            "rules[0].when.left.list[0]: expected a literal"
+        );
+        assert_eq!(
+            err(r#"{"rules": [{"name": "R", "when": {"left": 1, "op": "==", "right": 1}, "then": [], "placed_comments": [{"at": 1, "text": "// x"}]}]}"#),
+            "rules[0].placed_comments[0].at: expected \"when\", \"after\" or an action index"
+        );
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut when = obj(vec![("left", Json::Int(1)), ("op", Json::Str("==".into())), ("right", Json::Int(1))]);
+        for _ in 0..1000 {
+            when = obj(vec![("not", when)]);
+        }
+        let rule = obj(vec![("name", Json::Str("R".into())), ("when", when), ("then", Json::Array(Vec::new()))]);
+        let err = rules_from_json(&obj(vec![("rules", Json::Array(vec![rule]))]), &Limits::default()).unwrap_err();
+        assert!(err.starts_with("rules[0].when.not.not."));
+        assert!(err.ends_with(".not: expression nested deeper than 128 levels"));
+
+        let deep = format!("{{\"rules\": [{{\"name\": \"R\", \"when\": {}1", "{\"not\": ".repeat(100_000));
+        assert_eq!(parse_json_rules(&deep).unwrap_err(), "JSON nested deeper than 128 levels at pos 1033");
+
+        let limits = Limits {
+            max_nodes: 10,
+            ..Limits::default()
+        };
+        let compare = r#"{"left": {"field": "X.a"}, "op": "==", "right": 1}"#;
+        let wide = format!(r#"{{"rules": [{{"name": "R", "when": {{"all": [{}]}}, "then": []}}]}}"#, [compare; 4].join(", "));
+        assert_eq!(
+            parse_json_rules_with_limits(&wide, &limits).unwrap_err(),
+            "rules[0].when.all[3]: rule has more than 10 expression nodes"
+        );
+        let small = format!(r#"{{"rules": [{{"name": "R", "when": {{"all": [{}]}}, "then": []}}]}}"#, [compare; 3].join(", "));
+        assert!(parse_json_rules_with_limits(&small, &limits).is_ok());
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn test_yaml_round_trip() {
+        let rules = parse(RULES.to_string()).unwrap();
+        let yaml = rules_to_yaml(&rules);
+        assert!(yaml.starts_with("rules:\n  - name: \"Discount\"\n    description: \"Big orders\"\n"));
+        assert_eq!(parse_yaml_rules(&yaml).unwrap(), rules);
+    }
+}
@@ -0,0 +1,135 @@
+//! An immutable set of rules that threads share through an `Arc`, with a
+//! batch API that runs it over many fact contexts in parallel.
+//!
+//! Parallelism uses scoped std threads, one chunk of contexts per core;
+//! with the `rayon` feature it uses rayon's global pool instead.
+
+use std::sync::Arc;
+
+use crate::{
+    ast::Rule,
+    context::DataContext,
+    engine::RuleEngine,
+    knowledge_base::KnowledgeBase,
+    limits::Limits,
+    time::Clock,
+};
+
+pub struct RuleSet {
+    rules: Vec<Rule>,
+    pub limits: Limits,
+    pub clock: Clock,
+    pub rollback_on_error: bool,
+}
+
+impl From<KnowledgeBase> for RuleSet {
+    fn from(kb: KnowledgeBase) -> RuleSet {
+        RuleSet::new(kb.rules)
+    }
+}
+
+impl RuleSet {
+    pub fn new(rules: Vec<Rule>) -> RuleSet {
+        RuleSet {
+            rules,
+            limits: Limits::default(),
+            clock: Clock::System,
+            rollback_on_error: false,
+        }
+    }
+
+    /// Wraps the set for sharing between threads.
+    pub fn shared(self) -> Arc<RuleSet> {
+        Arc::new(self)
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    fn engine(&self) -> RuleEngine {
+        let mut engine = RuleEngine::new();
+        engine.limits = self.limits.clone();
+        engine.clock = self.clock.clone();
+        engine.rollback_on_error = self.rollback_on_error;
+        engine
+    }
+
+    /// Runs the rules on one context, as [`RuleEngine::execute`] does.
+    pub fn execute(&self, ctx: &mut DataContext) -> Result<Vec<String>, String> {
+        self.engine().execute(&self.rules, ctx)
+    }
+
+    /// Runs the rules on every context independently, in parallel. The
+    /// results are in the order of `contexts`, and one failing doesn't
+    /// stop the others.
+    pub fn execute_all(&self, contexts: &mut [DataContext]) -> Vec<Result<Vec<String>, String>> {
+        #[cfg(feature = "rayon")]
+        {
+            use rayon::prelude::*;
+            contexts.par_iter_mut().map(|ctx| self.execute(ctx)).collect()
+        }
+
+        #[cfg(not(feature = "rayon"))]
+        {
+            let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+            let chunk = contexts.len().div_ceil(threads).max(1);
+            std::thread::scope(|scope| {
+                let handles: Vec<_> = contexts
+                    .chunks_mut(chunk)
+                    .map(|chunk| scope.spawn(move || chunk.iter_mut().map(|ctx| self.execute(ctx)).collect::<Vec<_>>()))
+                    .collect();
+                handles.into_iter().flat_map(|h| h.join().unwrap()).collect()
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parser::parse, value::Value};
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_execute_all_in_order() {
+        assert_send_sync::<RuleSet>();
+        assert_send_sync::<Arc<RuleSet>>();
+
+        let rules = parse(
+            r#"
+    rule Discount { when Order.Total > 100 then Order.Discount = Order.Total - 100; }
+    rule Broken { when Order.Total == 7 then Order.Discount = Order.Missing; }
+    "#
+            .to_string(),
+        )
+        .unwrap();
+        let set = RuleSet::new(rules).shared();
+
+        let mut contexts: Vec<DataContext> = (0..1000)
+            .map(|n| {
+                let mut ctx = DataContext::new();
+                ctx.set("Order.Total".into(), Value::Int(n));
+                ctx
+            })
+            .collect();
+
+        let other = set.clone();
+        let results = std::thread::spawn(move || other.execute_all(&mut contexts).into_iter().zip(contexts).collect::<Vec<_>>())
+            .join()
+            .unwrap();
+
+        assert_eq!(results.len(), 1000);
+        assert_eq!(results[7].0, Err("rule Broken: field Order.Missing not found".into()));
+        for (n, (fired, ctx)) in results.iter().enumerate() {
+            assert_eq!(ctx.get("Order.Total".into()), Some(&Value::Int(n as i64)));
+            if n > 100 {
+                assert_eq!(fired, &Ok(vec!["Discount".to_string()]));
+                assert_eq!(ctx.get("Order.Discount".into()), Some(&Value::Int(n as i64 - 100)));
+            } else if n != 7 {
+                assert_eq!(fired, &Ok(vec![]));
+            }
+        }
+    }
+}
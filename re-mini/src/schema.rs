@@ -0,0 +1,308 @@
+use std::{collections::BTreeMap, fmt};
+
+use crate::{
+    ast::{Action, Aggregate, Condition, Expr, FactSet, Lambda, Op, Rule, FACT_SET_BINDING},
+    functions,
+    json::Json,
+    value::Value,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ValueType {
+    Int,
+    Bool,
+    Str,
+    Date,
+    DateTime,
+    Duration,
+    List,
+    Record,
+}
+
+impl ValueType {
+    pub fn of(value: &Value) -> ValueType {
+        match value {
+            Value::Int(_) => ValueType::Int,
+            Value::Bool(_) => ValueType::Bool,
+            Value::Str(_) => ValueType::Str,
+            Value::Date(_) => ValueType::Date,
+            Value::DateTime(_) => ValueType::DateTime,
+            Value::Duration(_) => ValueType::Duration,
+            Value::List(_) => ValueType::List,
+            Value::Record(_) => ValueType::Record,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<ValueType, String> {
+        match name {
+            "int" => Ok(ValueType::Int),
+            "bool" => Ok(ValueType::Bool),
+            "str" | "string" => Ok(ValueType::Str),
+            "date" => Ok(ValueType::Date),
+            "datetime" => Ok(ValueType::DateTime),
+            "duration" => Ok(ValueType::Duration),
+            "list" => Ok(ValueType::List),
+            "record" => Ok(ValueType::Record),
+            other => Err(format!("unknown type '{}'", other)),
+        }
+    }
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            ValueType::Int => "int",
+            ValueType::Bool => "bool",
+            ValueType::Str => "str",
+            ValueType::Date => "date",
+            ValueType::DateTime => "datetime",
+            ValueType::Duration => "duration",
+            ValueType::List => "list",
+            ValueType::Record => "record",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Declared types of fact fields, used to type-check rules before they run.
+#[derive(Clone, Debug, Default)]
+pub struct Schema {
+    fields: BTreeMap<String, ValueType>,
+}
+
+impl Schema {
+    pub fn new() -> Schema {
+        Schema::default()
+    }
+
+    /// Reads `{"Order": {"Total": "int"}}` or `{"Order.Total": "int"}`.
+    pub fn from_json(json: &Json) -> Result<Schema, String> {
+        let mut schema = Schema::new();
+        let Json::Object(members) = json else {
+            return Err("schema must be a JSON object".into());
+        };
+        schema.add_members("", members)?;
+        Ok(schema)
+    }
+
+    fn add_members(&mut self, prefix: &str, members: &[(String, Json)]) -> Result<(), String> {
+        for (k, v) in members {
+            let path = if prefix.is_empty() {
+                k.clone()
+            } else {
+                format!("{}.{}", prefix, k)
+            };
+            match v {
+                Json::Object(inner) => self.add_members(&path, inner)?,
+                Json::Str(name) => {
+                    let ty = ValueType::from_name(name).map_err(|e| format!("{}: {}", path, e))?;
+                    self.fields.insert(path, ty);
+                }
+                other => return Err(format!("{}: expected a type name, got {}", path, other)),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn insert(&mut self, field: String, ty: ValueType) {
+        self.fields.insert(field, ty);
+    }
+
+    pub fn get(&self, field: &str) -> Option<ValueType> {
+        self.fields.get(field).copied()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    pub fn fields(&self) -> impl Iterator<Item = (&String, &ValueType)> {
+        self.fields.iter()
+    }
+
+    /// Type errors in a rule. Fields missing from the schema are not
+    /// reported here, and expressions using them are not checked.
+    pub fn check_rule(&self, rule: &Rule) -> Vec<String> {
+        let mut errors = Vec::new();
+        let vars = rule.patterns();
+        self.check_condition(&rule.condition, &vars, &mut errors);
+        for action in &rule.actions {
+            match action {
+                Action::Assign { field, expr } => {
+                    let ty = self.expr_type(expr, &vars, &mut errors);
+                    if let (Some(want), Some(got)) = (self.field_type(field, &vars), ty) {
+                        if want != got {
+                            errors.push(format!("cannot assign {} to {} ({})", got, field, want));
+                        }
+                    }
+                }
+                Action::Insert { fact_type, fields } => {
+                    for (field, expr) in fields {
+                        let path = format!("{}.{}", fact_type, field);
+                        let ty = self.expr_type(expr, &vars, &mut errors);
+                        if let (Some(want), Some(got)) = (self.get(&path), ty) {
+                            if want != got {
+                                errors.push(format!("cannot assign {} to {} ({})", got, path, want));
+                            }
+                        }
+                    }
+                }
+                Action::Update(_) | Action::Retract(_) => {}
+            }
+        }
+        errors
+    }
+
+    // a pattern variable's fields are declared under its fact type
+    fn field_type(&self, name: &str, vars: &[(&str, &str)]) -> Option<ValueType> {
+        if let Some((var, field)) = name.split_once('.') {
+            if let Some((_, fact_type)) = vars.iter().find(|(v, _)| *v == var) {
+                return self.get(&format!("{}.{}", fact_type, field));
+            }
+        }
+        self.get(name)
+    }
+
+    fn check_condition(&self, cond: &Condition, vars: &[(&str, &str)], errors: &mut Vec<String>) {
+        match cond {
+            Condition::Compare { left, right, .. } => {
+                let l = self.expr_type(left, vars, errors);
+                let r = self.expr_type(right, vars, errors);
+                if let (Some(l), Some(r)) = (l, r) {
+                    // a date compares with a datetime as midnight UTC
+                    let dates = [ValueType::Date, ValueType::DateTime];
+                    if l != r && !(dates.contains(&l) && dates.contains(&r)) {
+                        errors.push(format!("cannot compare {} with {} in `{}`", l, r, cond));
+                    }
+                }
+            }
+            Condition::And(a, b) | Condition::Or(a, b) => {
+                self.check_condition(a, vars, errors);
+                self.check_condition(b, vars, errors);
+            }
+            Condition::Not(c) => self.check_condition(c, vars, errors),
+            Condition::Pattern { constraint, .. } => {
+                if let Some(c) = constraint {
+                    self.check_condition(c, vars, errors);
+                }
+            }
+            Condition::Exists(set) => self.check_fact_set(set, vars, errors),
+        }
+    }
+
+    // the fields of a fact set's instances are declared under its type
+    fn check_fact_set(&self, set: &FactSet, vars: &[(&str, &str)], errors: &mut Vec<String>) {
+        if let Some(c) = &set.constraint {
+            let mut vars = vars.to_vec();
+            vars.push((FACT_SET_BINDING, &set.fact_type));
+            self.check_condition(c, &vars, errors);
+        }
+    }
+
+    fn expr_type(&self, expr: &Expr, vars: &[(&str, &str)], errors: &mut Vec<String>) -> Option<ValueType> {
+        match expr {
+            Expr::Literal(v) => Some(ValueType::of(v)),
+            Expr::FieldRef(name) => self.field_type(name, vars),
+            Expr::BinOp { left, op, right } => {
+                let l = self.expr_type(left, vars, errors)?;
+                let r = self.expr_type(right, vars, errors)?;
+                use ValueType::*;
+                match (op, l, r) {
+                    (Op::Add | Op::Sub, Int, Int) => Some(Int),
+                    (Op::Add, Str, Str) => Some(Str),
+                    (Op::Add, Date, Duration) | (Op::Add, Duration, Date) | (Op::Sub, Date, Duration) => Some(Date),
+                    (Op::Add, DateTime, Duration) | (Op::Add, Duration, DateTime) | (Op::Sub, DateTime, Duration) => {
+                        Some(DateTime)
+                    }
+                    (Op::Add | Op::Sub, Duration, Duration) | (Op::Sub, Date, Date) | (Op::Sub, DateTime, DateTime) => {
+                        Some(Duration)
+                    }
+                    _ => {
+                        errors.push(format!(
+                            "cannot apply {} to {} and {} in `{}`",
+                            op, l, r, expr
+                        ));
+                        None
+                    }
+                }
+            }
+            Expr::Call { name, args } => {
+                for arg in args {
+                    self.expr_type(arg, vars, errors);
+                }
+                functions::signature(name).map(|(_, ty)| ty)
+            }
+            Expr::Aggregate { func, list, lambda } => {
+                if let Some(ty) = self.expr_type(list, vars, errors) {
+                    if ty != ValueType::List {
+                        errors.push(format!("{}() needs a list, not {} in `{}`", func, ty, expr));
+                    }
+                }
+                // the items' types aren't declared, so only what the
+                // lambda reads besides them is checked
+                let item = match lambda {
+                    Some(Lambda::Map(_, body)) => self.expr_type(body, vars, errors),
+                    Some(Lambda::Filter(_, cond)) => {
+                        self.check_condition(cond, vars, errors);
+                        None
+                    }
+                    None => None,
+                };
+                match func {
+                    Aggregate::Count | Aggregate::Avg => Some(ValueType::Int),
+                    Aggregate::Any | Aggregate::All => Some(ValueType::Bool),
+                    Aggregate::Sum | Aggregate::Min | Aggregate::Max => item,
+                }
+            }
+            Expr::Facts(set) => {
+                self.check_fact_set(set, vars, errors);
+                Some(ValueType::List)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_check_rule() {
+        let schema = Schema::from_json(
+            &Json::parse(r#"{"Order": {"Total": "int", "Code": "str", "Items": "list"}, "Order.Vip": "bool"}"#)
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(schema.get("Order.Vip"), Some(ValueType::Bool));
+
+        let rules = parse(
+            r#"
+    rule Ok { when Order.Total > 1 && Order.Code == "x" then Order.Total = Order.Total + 1; }
+    rule Bad { when Order.Total == "x" || Order.Unknown == 1 then Order.Vip = 1; Order.Code = Order.Code + 1; }
+    rule Lists { when Order.Items.count() == "x" && Order.Total.sum(t => t) > 1 then Order.Vip = Order.Items.any(i => i.Ok == true); }
+    "#
+            .to_string(),
+        )
+        .unwrap();
+
+        assert!(schema.check_rule(&rules[0]).is_empty());
+        assert_eq!(
+            schema.check_rule(&rules[1]),
+            vec![
+                "cannot compare int with str in `Order.Total == \"x\"`",
+                "cannot assign int to Order.Vip (bool)",
+                "cannot apply + to str and int in `Order.Code + 1`",
+            ]
+        );
+        assert_eq!(
+            schema.check_rule(&rules[2]),
+            vec![
+                "cannot compare int with str in `Order.Items.count() == \"x\"`",
+                "sum() needs a list, not int in `Order.Total.sum(t => t)`",
+            ]
+        );
+
+        assert!(Schema::from_json(&Json::parse(r#"{"A": "float"}"#).unwrap()).is_err());
+    }
+}
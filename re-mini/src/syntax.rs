@@ -0,0 +1,324 @@
+//! Lossless syntax tree: every byte of the source, whitespace and comments
+//! included, belongs to exactly one token, so tools can edit a rule file
+//! and leave everything they didn't touch byte-identical.
+
+use std::ops::Range;
+
+use crate::{
+    ast::{Aggregate, Rule},
+    lexer::{lex, quote_path, Diagnostic, Span, Token},
+    parser::parse_with_ranges,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyntaxKind {
+    Keyword,
+    Ident,
+    Int,
+    Bool,
+    Str,
+    /// A date, datetime or duration literal.
+    Time,
+    Punct,
+    /// Zero-width token at the end of input, holding trailing trivia.
+    Eof,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TriviaKind {
+    Whitespace,
+    Comment,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Trivia {
+    pub kind: TriviaKind,
+    pub text: String,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SyntaxToken {
+    pub kind: SyntaxKind,
+    pub text: String,
+    pub span: Span,
+    /// Whitespace and comments between the previous token and this one.
+    pub leading: Vec<Trivia>,
+}
+
+/// A rule together with the tokens it was parsed from.
+pub struct RuleNode {
+    pub rule: Rule,
+    pub tokens: Range<usize>,
+    /// Index of the `{` token that ends the rule header.
+    pub body_start: usize,
+}
+
+/// An occurrence of a field name, like `Order.Total`, in a rule body.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FieldRef {
+    pub name: String,
+    pub span: Span,
+    pub rule: usize,
+    /// The field is assigned to, rather than read.
+    pub write: bool,
+}
+
+/// Replace the text at `span` with `text`; an empty span inserts.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextEdit {
+    pub span: Span,
+    pub text: String,
+}
+
+pub struct SyntaxTree {
+    tokens: Vec<SyntaxToken>,
+    rules: Vec<RuleNode>,
+}
+
+impl SyntaxTree {
+    pub fn parse(source: &str) -> Result<SyntaxTree, Diagnostic> {
+        let mut tokens = Vec::new();
+        let mut leading = Vec::new();
+        for (token, span) in lex(source)? {
+            let text = source[span.start..span.end].to_string();
+            let kind = match token {
+                Token::Whitespace => {
+                    leading.push(Trivia { kind: TriviaKind::Whitespace, text, span });
+                    continue;
+                }
+                Token::Comment(_) => {
+                    leading.push(Trivia { kind: TriviaKind::Comment, text, span });
+                    continue;
+                }
+                Token::Rule | Token::When | Token::Then => SyntaxKind::Keyword,
+                Token::Ident(_) => SyntaxKind::Ident,
+                Token::Int(_) => SyntaxKind::Int,
+                Token::Bool(_) => SyntaxKind::Bool,
+                Token::StringLit(_) => SyntaxKind::Str,
+                Token::Date(_) | Token::DateTime(_) | Token::Duration(_) => SyntaxKind::Time,
+                _ => SyntaxKind::Punct,
+            };
+            tokens.push(SyntaxToken {
+                kind,
+                text,
+                span,
+                leading: std::mem::take(&mut leading),
+            });
+        }
+        tokens.push(SyntaxToken {
+            kind: SyntaxKind::Eof,
+            text: String::new(),
+            span: Span::new(source.len(), source.len()),
+            leading,
+        });
+
+        // the parser skips trivia, so its token indices line up with ours
+        let rules = parse_with_ranges(source.to_string())?
+            .into_iter()
+            .map(|(rule, range)| {
+                let body_start = range
+                    .clone()
+                    .find(|&i| tokens[i].text == "{")
+                    .unwrap_or(range.start);
+                RuleNode {
+                    rule,
+                    tokens: range,
+                    body_start,
+                }
+            })
+            .collect();
+
+        Ok(SyntaxTree { tokens, rules })
+    }
+
+    pub fn tokens(&self) -> &[SyntaxToken] {
+        &self.tokens
+    }
+
+    pub fn rules(&self) -> &[RuleNode] {
+        &self.rules
+    }
+
+    /// Rebuilds the source text, identical to the parsed input.
+    pub fn to_source(&self) -> String {
+        let mut out = String::new();
+        for token in &self.tokens {
+            for trivia in &token.leading {
+                out.push_str(&trivia.text);
+            }
+            out.push_str(&token.text);
+        }
+        out
+    }
+
+    pub fn rule_named(&self, name: &str) -> Option<&RuleNode> {
+        self.rules.iter().find(|r| r.rule.name == name)
+    }
+
+    /// Span of the rule's name in its header.
+    pub fn rule_name_span(&self, rule: usize) -> Span {
+        self.tokens[self.rules[rule].tokens.start + 1].span
+    }
+
+    /// Every field name written in a rule body, in source order.
+    pub fn field_refs(&self) -> Vec<FieldRef> {
+        let mut refs = Vec::new();
+        for (r, node) in self.rules.iter().enumerate() {
+            let mut i = node.body_start;
+            // lambda parameters seen so far, `i` in `i => i.Price`
+            let mut params = Vec::new();
+            while i < node.tokens.end {
+                if self.tokens[i].kind != SyntaxKind::Ident {
+                    i += 1;
+                    continue;
+                }
+
+                let mut end = i;
+                let aggregate = Aggregate::from_name(self.text(i + 2)).is_some() && self.text(i + 3) == "(";
+                if self.text(i + 1) == "." && self.kind(i + 2) == Some(SyntaxKind::Ident) && !aggregate {
+                    end = i + 2;
+                }
+                if self.text(i + 1) == "=>" {
+                    params.push(self.text(i));
+                }
+                // `name(` is a function call or pattern, `name:` a pattern
+                // variable, `insert(name {` a fact type and `update(name)`
+                // a variable, none of them fields; nor are lambda
+                // parameters and the `exists` keywords
+                let action = i >= 2 && self.text(i - 1) == "(" && matches!(self.text(i - 2), "insert" | "update" | "retract");
+                let keyword = match self.text(i) {
+                    "exists" => self.kind(i + 1) == Some(SyntaxKind::Ident),
+                    "not" => self.text(i + 1) == "exists",
+                    _ => false,
+                };
+                let local = params.contains(&self.text(i));
+                if !matches!(self.text(end + 1), "(" | ":" | "{" | "=>") && !action && !keyword && !local {
+                    let name = (i..=end).map(|j| self.tokens[j].text.trim_matches('`')).collect();
+                    refs.push(FieldRef {
+                        name,
+                        span: Span::new(self.tokens[i].span.start, self.tokens[end].span.end),
+                        rule: r,
+                        write: self.text(end + 1) == "=",
+                    });
+                }
+                i = end + 1;
+            }
+        }
+        refs
+    }
+
+    /// Edits renaming every reference to field `old`.
+    pub fn rename_field(&self, old: &str, new: &str) -> Vec<TextEdit> {
+        self.field_refs()
+            .into_iter()
+            .filter(|f| f.name == old)
+            .map(|f| TextEdit {
+                span: f.span,
+                text: quote_path(new),
+            })
+            .collect()
+    }
+
+    /// Edit setting a rule's salience, replacing the existing value or
+    /// adding the attribute at the end of the header.
+    pub fn set_salience(&self, rule: &str, salience: i64) -> Result<TextEdit, String> {
+        let node = self.rule_named(rule).ok_or_else(|| format!("rule {} not found", rule))?;
+        let header = node.tokens.start..node.body_start;
+
+        if let Some(i) = header.clone().find(|&i| self.tokens[i].text == "salience") {
+            let mut end = i + 1;
+            if self.text(end) == "-" {
+                end += 1;
+            }
+            return Ok(TextEdit {
+                span: Span::new(self.tokens[i + 1].span.start, self.tokens[end].span.end),
+                text: salience.to_string(),
+            });
+        }
+
+        let at = self.tokens[node.body_start - 1].span.end;
+        Ok(TextEdit {
+            span: Span::new(at, at),
+            text: format!(" salience {}", salience),
+        })
+    }
+
+    fn text(&self, i: usize) -> &str {
+        self.tokens.get(i).map(|t| t.text.as_str()).unwrap_or("")
+    }
+
+    fn kind(&self, i: usize) -> Option<SyntaxKind> {
+        self.tokens.get(i).map(|t| t.kind)
+    }
+}
+
+/// Applies non-overlapping edits to `source`.
+pub fn apply_edits(source: &str, edits: &[TextEdit]) -> String {
+    let mut edits: Vec<&TextEdit> = edits.iter().collect();
+    edits.sort_by_key(|e| std::cmp::Reverse(e.span.start));
+
+    let mut out = source.to_string();
+    for edit in edits {
+        out.replace_range(edit.span.start..edit.span.end, &edit.text);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "// pricing rules\r\n\
+rule Discount \"big orders\" {\n\
+    when\n\
+        Order.Total > 100 /* inclusive? */ && Order.Discount == 0\n\
+    then\n\
+        Order.Discount   = Order.Total + 10; // flat\n\
+}\n\
+\n\
+rule Shipping salience -3 no-loop {when Order.Total>50 then Order.Shipping=0;}\n\
+/* trailing */\n";
+
+    #[test]
+    fn test_lossless() {
+        let tree = SyntaxTree::parse(SOURCE).unwrap();
+        assert_eq!(tree.to_source(), SOURCE);
+        assert_eq!(tree.rules().len(), 2);
+        assert_eq!(tree.rules()[1].rule.salience, -3);
+
+        let eof = tree.tokens().last().unwrap();
+        assert_eq!(eof.kind, SyntaxKind::Eof);
+        assert_eq!(eof.leading[1].text, "/* trailing */");
+    }
+
+    #[test]
+    fn test_rename_field() {
+        let tree = SyntaxTree::parse(SOURCE).unwrap();
+        let edits = tree.rename_field("Order.Total", "Order.Amount");
+        assert_eq!(edits.len(), 3);
+
+        let out = apply_edits(SOURCE, &edits);
+        assert_eq!(out, SOURCE.replace("Order.Total", "Order.Amount"));
+
+        // neither aggregates, lambda parameters nor `exists` are fields
+        let source = "rule R { when not exists Alert() && Order.Items.any(i => i.Price > Order.Min) then X.a = 1; }";
+        let names: Vec<String> = SyntaxTree::parse(source).unwrap().field_refs().into_iter().map(|f| f.name).collect();
+        assert_eq!(names, vec!["Order.Items", "Order.Min", "X.a"]);
+    }
+
+    #[test]
+    fn test_set_salience() {
+        let tree = SyntaxTree::parse(SOURCE).unwrap();
+
+        let edit = tree.set_salience("Discount", 10).unwrap();
+        let out = apply_edits(SOURCE, &[edit]);
+        assert_eq!(out, SOURCE.replace("\"big orders\" {", "\"big orders\" salience 10 {"));
+
+        let edit = tree.set_salience("Shipping", 7).unwrap();
+        let out = apply_edits(SOURCE, &[edit]);
+        assert_eq!(out, SOURCE.replace("salience -3", "salience 7"));
+
+        assert!(tree.set_salience("Nope", 1).is_err());
+    }
+}
@@ -0,0 +1,222 @@
+//! Declarative rule tests: a JSON file of named scenarios, each giving
+//! input facts and what the rules should do with them.
+//!
+//! ```json
+//! {
+//!   "now": "2024-06-01T10:00:00Z",
+//!   "scenarios": [
+//!     {
+//!       "name": "big order gets a discount",
+//!       "facts": {"Order": {"Total": 150}},
+//!       "fired": ["Discount"],
+//!       "expect": {"Order": {"Discount": 10}}
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! `fired` lists the rules in firing order and `expect` the facts that
+//! must hold afterwards, with `null` for a fact that must be unset; both
+//! are optional. A scenario may give `error`, text the engine's error must
+//! contain, instead. `now` fixes the clock for every scenario.
+
+use crate::{
+    ast::Rule,
+    engine::RuleEngine,
+    json::{context_from_json, value_to_json, Json},
+    time::{Clock, DateTime},
+};
+
+pub struct TestSuite {
+    pub now: Option<DateTime>,
+    pub scenarios: Vec<Scenario>,
+}
+
+pub struct Scenario {
+    pub name: String,
+    pub facts: Json,
+    pub fired: Option<Vec<String>>,
+    /// Expected facts by dotted path; `Json::Null` means unset.
+    pub expect: Vec<(String, Json)>,
+    pub error: Option<String>,
+}
+
+/// The outcome of one scenario: it passed if nothing failed.
+pub struct ScenarioResult {
+    pub name: String,
+    pub failures: Vec<String>,
+}
+
+impl ScenarioResult {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+// dotted paths of the leaves of a nested object
+fn flatten(prefix: &str, json: &Json, out: &mut Vec<(String, Json)>) {
+    match json {
+        Json::Object(members) => {
+            for (k, v) in members {
+                let path = if prefix.is_empty() {
+                    k.clone()
+                } else {
+                    format!("{}.{}", prefix, k)
+                };
+                flatten(&path, v, out);
+            }
+        }
+        leaf => out.push((prefix.to_string(), leaf.clone())),
+    }
+}
+
+impl TestSuite {
+    pub fn from_json(input: &str) -> Result<TestSuite, String> {
+        let json = Json::parse(input)?;
+        let now = match json.get("now") {
+            Some(now) => Some(DateTime::parse(now.as_str().ok_or("now must be a string")?)?),
+            None => None,
+        };
+
+        let mut scenarios = Vec::new();
+        let items = json.get("scenarios").and_then(|s| s.as_array()).ok_or("missing scenarios array")?;
+        for (i, item) in items.iter().enumerate() {
+            let name = match item.get("name").and_then(|n| n.as_str()) {
+                Some(name) => name.to_string(),
+                None => format!("scenario {}", i + 1),
+            };
+            let facts = item.get("facts").cloned().unwrap_or(Json::Object(vec![]));
+
+            let fired = match item.get("fired") {
+                Some(fired) => {
+                    let names = fired.as_array().ok_or_else(|| format!("{}: fired must be an array", name))?;
+                    let names = names
+                        .iter()
+                        .map(|n| n.as_str().map(|n| n.to_string()))
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or_else(|| format!("{}: fired must list rule names", name))?;
+                    Some(names)
+                }
+                None => None,
+            };
+
+            let mut expect = Vec::new();
+            if let Some(facts) = item.get("expect") {
+                if !matches!(facts, Json::Object(_)) {
+                    return Err(format!("{}: expect must be an object", name));
+                }
+                flatten("", facts, &mut expect);
+            }
+
+            let error = item.get("error").and_then(|e| e.as_str()).map(|e| e.to_string());
+            scenarios.push(Scenario { name, facts, fired, expect, error });
+        }
+
+        Ok(TestSuite { now, scenarios })
+    }
+
+    pub fn run(&self, rules: &[Rule]) -> Vec<ScenarioResult> {
+        self.scenarios.iter().map(|s| self.run_scenario(rules, s)).collect()
+    }
+
+    fn run_scenario(&self, rules: &[Rule], scenario: &Scenario) -> ScenarioResult {
+        let mut failures = Vec::new();
+        let result = context_from_json(&scenario.facts).and_then(|mut ctx| {
+            let mut engine = RuleEngine::new();
+            if let Some(now) = self.now {
+                engine.clock = Clock::Fixed(now);
+            }
+            engine.execute(rules, &mut ctx).map(|fired| (fired, ctx))
+        });
+
+        match (result, &scenario.error) {
+            (Err(e), Some(expected)) if e.contains(expected.as_str()) => {}
+            (Err(e), _) => failures.push(format!("error: {}", e)),
+            (Ok(_), Some(expected)) => failures.push(format!("expected an error containing '{}'", expected)),
+            (Ok((fired, ctx)), None) => {
+                if let Some(expected) = &scenario.fired {
+                    if *expected != fired {
+                        failures.push(format!("fired: expected [{}], got [{}]", expected.join(", "), fired.join(", ")));
+                    }
+                }
+                for (path, expected) in &scenario.expect {
+                    let actual = ctx.get(path.clone()).map(value_to_json).unwrap_or(Json::Null);
+                    if actual != *expected {
+                        let show = |j: &Json| match j {
+                            Json::Null => "(unset)".to_string(),
+                            j => j.to_string(),
+                        };
+                        failures.push(format!("{}: expected {}, got {}", path, show(expected), show(&actual)));
+                    }
+                }
+            }
+        }
+
+        ScenarioResult {
+            name: scenario.name.clone(),
+            failures,
+        }
+    }
+}
+
+/// One line per scenario, failures indented below it, then a summary.
+pub fn report(results: &[ScenarioResult]) -> String {
+    let mut out = String::new();
+    for result in results {
+        let status = if result.passed() { "PASS" } else { "FAIL" };
+        out.push_str(&format!("{} {}\n", status, result.name));
+        for failure in &result.failures {
+            out.push_str(&format!("    {}\n", failure));
+        }
+    }
+    let passed = results.iter().filter(|r| r.passed()).count();
+    out.push_str(&format!("{} passed, {} failed\n", passed, results.len() - passed));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    const RULES: &str = r#"
+    rule Discount { when Order.Total > 100 then Order.Discount = 10; }
+    rule Weekend { when weekday(today()) >= 6 then Order.Shipping = 0; }
+    rule Broken { when Order.Total == 0 then Order.Discount = Order.Missing; }
+    "#;
+
+    #[test]
+    fn test_run_suite() {
+        let suite = TestSuite::from_json(
+            r#"{
+            "now": "2024-06-01T10:00:00Z",
+            "scenarios": [
+                {"name": "big", "facts": {"Order": {"Total": 150}}, "fired": ["Discount", "Weekend"],
+                 "expect": {"Order": {"Discount": 10, "Shipping": 0}}},
+                {"name": "small", "facts": {"Order": {"Total": 50}}, "fired": ["Discount"],
+                 "expect": {"Order.Discount": 10, "Order.Total": 50, "Order.Note": null}},
+                {"name": "broken", "facts": {"Order": {"Total": 0}}, "error": "field Order.Missing not found"}
+            ]
+        }"#,
+        )
+        .unwrap();
+        let rules = parse(RULES.to_string()).unwrap();
+
+        let results = suite.run(&rules);
+        assert_eq!(
+            report(&results),
+            "PASS big\n\
+             FAIL small\n    fired: expected [Discount], got [Weekend]\n    Order.Discount: expected 10, got (unset)\n\
+             PASS broken\n\
+             2 passed, 1 failed\n"
+        );
+        assert!(!results[1].passed());
+    }
+
+    #[test]
+    fn test_bad_suite() {
+        assert_eq!(TestSuite::from_json("{}").err().unwrap(), "missing scenarios array");
+        let err = TestSuite::from_json(r#"{"scenarios": [{"name": "x", "fired": "Discount"}]}"#).err().unwrap();
+        assert_eq!(err, "x: fired must be an array");
+    }
+}
@@ -0,0 +1,418 @@
+//! Calendar dates, instants with a UTC offset, and durations.
+//!
+//! Dates use the proleptic Gregorian calendar; the conversions follow
+//! Howard Hinnant's `days_from_civil` algorithms.
+
+use std::{
+    cmp::Ordering,
+    fmt,
+    hash::{Hash, Hasher},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const SECS_PER_DAY: i64 = 86_400;
+
+/// A calendar date, stored as days since 1970-01-01.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Date {
+    days: i64,
+}
+
+/// An instant, along with the UTC offset it was written in. Two values
+/// are equal when they name the same instant, whatever their offsets.
+#[derive(Clone, Copy, Debug)]
+pub struct DateTime {
+    /// Seconds since 1970-01-01T00:00:00Z.
+    secs: i64,
+    /// Seconds east of UTC.
+    offset: i32,
+}
+
+/// A signed length of time, in seconds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Duration {
+    secs: i64,
+}
+
+/// Where `now()` gets the time from.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Clock {
+    #[default]
+    System,
+    /// Always the same instant, for deterministic tests.
+    Fixed(DateTime),
+}
+
+impl Clock {
+    pub fn now(&self) -> DateTime {
+        match self {
+            Clock::System => {
+                let secs = match SystemTime::now().duration_since(UNIX_EPOCH) {
+                    Ok(d) => d.as_secs() as i64,
+                    Err(e) => -(e.duration().as_secs() as i64),
+                };
+                DateTime { secs, offset: 0 }
+            }
+            Clock::Fixed(t) => *t,
+        }
+    }
+}
+
+fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = (m as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y, m, d)
+}
+
+fn days_in_month(y: i64, m: u32) -> u32 {
+    match m {
+        2 if y % 4 == 0 && (y % 100 != 0 || y % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// a fixed-width run of ASCII digits
+fn digits(s: &str, width: usize) -> Option<u32> {
+    if s.len() == width && s.bytes().all(|b| b.is_ascii_digit()) {
+        s.parse().ok()
+    } else {
+        None
+    }
+}
+
+impl Date {
+    pub fn from_ymd(year: i64, month: u32, day: u32) -> Option<Date> {
+        if !(1..=12).contains(&month) || day == 0 || day > days_in_month(year, month) {
+            return None;
+        }
+        Some(Date {
+            days: days_from_civil(year, month, day),
+        })
+    }
+
+    pub fn from_epoch_days(days: i64) -> Date {
+        Date { days }
+    }
+
+    /// Days since 1970-01-01.
+    pub fn epoch_days(&self) -> i64 {
+        self.days
+    }
+
+    /// Parses `YYYY-MM-DD`.
+    pub fn parse(s: &str) -> Result<Date, String> {
+        let invalid = || format!("invalid date '{}', expected YYYY-MM-DD", s);
+        let mut parts = s.split('-');
+        let (Some(y), Some(m), Some(d), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+            return Err(invalid());
+        };
+        let (y, m, d) = (digits(y, 4).ok_or_else(invalid)?, digits(m, 2).ok_or_else(invalid)?, digits(d, 2).ok_or_else(invalid)?);
+        Date::from_ymd(y as i64, m, d).ok_or_else(|| format!("date '{}' does not exist", s))
+    }
+
+    pub fn year(&self) -> i64 {
+        civil_from_days(self.days).0
+    }
+
+    pub fn month(&self) -> u32 {
+        civil_from_days(self.days).1
+    }
+
+    pub fn day(&self) -> u32 {
+        civil_from_days(self.days).2
+    }
+
+    /// ISO weekday: 1 is Monday, 7 is Sunday.
+    pub fn weekday(&self) -> u32 {
+        // 1970-01-01 was a Thursday
+        ((self.days + 3).rem_euclid(7) + 1) as u32
+    }
+
+    /// The date the given number of days later, or earlier if negative.
+    pub fn add_days(&self, days: i64) -> Option<Date> {
+        self.days.checked_add(days).map(|days| Date { days })
+    }
+
+    pub fn days_since(&self, other: &Date) -> i64 {
+        self.days - other.days
+    }
+
+    /// Midnight at the start of this date, at the given offset; an error
+    /// for a date too far out for a datetime.
+    pub fn at_midnight(&self, offset: i32) -> Result<DateTime, String> {
+        let secs = self
+            .days
+            .checked_mul(SECS_PER_DAY)
+            .and_then(|secs| secs.checked_sub(offset as i64))
+            .ok_or_else(|| format!("date {} out of range for a datetime", self))?;
+        Ok(DateTime { secs, offset })
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (y, m, d) = civil_from_days(self.days);
+        write!(f, "{:04}-{:02}-{:02}", y, m, d)
+    }
+}
+
+impl DateTime {
+    pub fn from_unix(secs: i64, offset: i32) -> DateTime {
+        DateTime { secs, offset }
+    }
+
+    pub fn unix_secs(&self) -> i64 {
+        self.secs
+    }
+
+    /// Seconds east of UTC.
+    pub fn offset(&self) -> i32 {
+        self.offset
+    }
+
+    /// Parses `YYYY-MM-DDTHH:MM[:SS]` followed by `Z` or `+HH:MM`/`-HH:MM`.
+    pub fn parse(s: &str) -> Result<DateTime, String> {
+        let invalid = || format!("invalid datetime '{}', expected YYYY-MM-DDTHH:MM:SS with Z or a UTC offset", s);
+        let (date, rest) = s.split_once(['T', 't']).ok_or_else(invalid)?;
+        let date = Date::parse(date)?;
+
+        let (time, offset) = if let Some(time) = rest.strip_suffix(['Z', 'z']) {
+            (time, 0)
+        } else {
+            let at = rest.rfind(['+', '-']).ok_or_else(invalid)?;
+            let (time, offset) = rest.split_at(at);
+            let sign = if offset.starts_with('-') { -1 } else { 1 };
+            let (h, m) = offset[1..].split_once(':').ok_or_else(invalid)?;
+            let (h, m) = (digits(h, 2).ok_or_else(invalid)?, digits(m, 2).ok_or_else(invalid)?);
+            if h > 23 || m > 59 {
+                return Err(invalid());
+            }
+            (time, sign * (h * 3600 + m * 60) as i32)
+        };
+
+        let mut parts = time.split(':');
+        let h = parts.next().and_then(|h| digits(h, 2)).ok_or_else(invalid)?;
+        let m = parts.next().and_then(|m| digits(m, 2)).ok_or_else(invalid)?;
+        let sec = match parts.next() {
+            Some(sec) => digits(sec, 2).ok_or_else(invalid)?,
+            None => 0,
+        };
+        if parts.next().is_some() || h > 23 || m > 59 || sec > 59 {
+            return Err(invalid());
+        }
+
+        let local = date.days * SECS_PER_DAY + (h * 3600 + m * 60 + sec) as i64;
+        Ok(DateTime {
+            secs: local - offset as i64,
+            offset,
+        })
+    }
+
+    // seconds since the epoch in local time
+    fn local_secs(&self) -> i64 {
+        self.secs.saturating_add(self.offset as i64)
+    }
+
+    /// The calendar date at this instant, in its own offset.
+    pub fn date(&self) -> Date {
+        Date {
+            days: self.local_secs().div_euclid(SECS_PER_DAY),
+        }
+    }
+
+    pub fn hour(&self) -> u32 {
+        (self.local_secs().rem_euclid(SECS_PER_DAY) / 3600) as u32
+    }
+
+    pub fn checked_add(&self, d: Duration) -> Option<DateTime> {
+        self.secs.checked_add(d.secs).map(|secs| DateTime { secs, offset: self.offset })
+    }
+
+    pub fn since(&self, other: &DateTime) -> Option<Duration> {
+        self.secs.checked_sub(other.secs).map(Duration::from_secs)
+    }
+}
+
+impl PartialEq for DateTime {
+    fn eq(&self, other: &Self) -> bool {
+        self.secs == other.secs
+    }
+}
+
+// hashes the instant alone, as equality compares it
+impl Hash for DateTime {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.secs.hash(state);
+    }
+}
+
+impl PartialOrd for DateTime {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.secs.partial_cmp(&other.secs)
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sod = self.local_secs().rem_euclid(SECS_PER_DAY);
+        write!(f, "{}T{:02}:{:02}:{:02}", self.date(), sod / 3600, sod / 60 % 60, sod % 60)?;
+        if self.offset == 0 {
+            return write!(f, "Z");
+        }
+        let sign = if self.offset < 0 { '-' } else { '+' };
+        let offset = self.offset.abs();
+        write!(f, "{}{:02}:{:02}", sign, offset / 3600, offset / 60 % 60)
+    }
+}
+
+const UNITS: [(char, i64); 5] = [('w', 7 * SECS_PER_DAY), ('d', SECS_PER_DAY), ('h', 3600), ('m', 60), ('s', 1)];
+
+impl Duration {
+    pub fn from_secs(secs: i64) -> Duration {
+        Duration { secs }
+    }
+
+    pub fn from_days(days: i64) -> Option<Duration> {
+        days.checked_mul(SECS_PER_DAY).map(Duration::from_secs)
+    }
+
+    pub fn secs(&self) -> i64 {
+        self.secs
+    }
+
+    /// Whole days, if the duration is a whole number of days.
+    pub fn whole_days(&self) -> Option<i64> {
+        (self.secs % SECS_PER_DAY == 0).then_some(self.secs / SECS_PER_DAY)
+    }
+
+    /// Parses an unsigned duration written as numbers with units, largest
+    /// first, e.g. `30d` or `1d12h`. The units are `w`, `d`, `h`, `m`, `s`.
+    pub fn parse(s: &str) -> Result<Duration, String> {
+        let invalid = || format!("invalid duration '{}'", s);
+        let mut secs: i64 = 0;
+        // a leading `-`, as a negative duration displays
+        let (sign, mut rest) = match s.strip_prefix('-') {
+            Some(rest) => (-1, rest),
+            None => (1, s),
+        };
+        let mut units = &UNITS[..];
+        while !rest.is_empty() {
+            let n_end = rest.find(|c: char| !c.is_ascii_digit() && c != '_').ok_or_else(invalid)?;
+            let n: i64 = rest[..n_end].replace('_', "").parse().map_err(|_| invalid())?;
+            let unit = rest[n_end..].chars().next().ok_or_else(invalid)?;
+            let at = units.iter().position(|(u, _)| *u == unit).ok_or_else(invalid)?;
+            secs = n
+                .checked_mul(units[at].1 * sign)
+                .and_then(|n| secs.checked_add(n))
+                .ok_or_else(|| format!("duration '{}' out of range", s))?;
+            units = &units[at + 1..];
+            rest = &rest[n_end + 1..];
+        }
+        if s.is_empty() || s == "-" {
+            return Err(invalid());
+        }
+        Ok(Duration { secs })
+    }
+
+    pub fn checked_add(&self, other: Duration) -> Option<Duration> {
+        self.secs.checked_add(other.secs).map(Duration::from_secs)
+    }
+
+    pub fn checked_neg(&self) -> Option<Duration> {
+        self.secs.checked_neg().map(Duration::from_secs)
+    }
+}
+
+// largest units first and no weeks, e.g. `1d12h`; `-` for negative
+impl fmt::Display for Duration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.secs == 0 {
+            return write!(f, "0s");
+        }
+        if self.secs < 0 {
+            write!(f, "-")?;
+        }
+        let mut rest = self.secs.unsigned_abs();
+        for (unit, size) in &UNITS[1..] {
+            let n = rest / *size as u64;
+            if n > 0 {
+                write!(f, "{}{}", n, unit)?;
+            }
+            rest %= *size as u64;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dates() {
+        let d = Date::parse("2024-02-29").unwrap();
+        assert_eq!((d.year(), d.month(), d.day(), d.weekday()), (2024, 2, 29, 4));
+        assert_eq!(d.add_days(1).unwrap().to_string(), "2024-03-01");
+        assert_eq!(Date::parse("1969-12-31").unwrap().days_since(&Date::parse("2000-01-01").unwrap()), -10958);
+        assert_eq!(Date::parse("2023-02-29").unwrap_err(), "date '2023-02-29' does not exist");
+        assert!(Date::parse("2024-2-1").is_err());
+        assert_eq!(d.at_midnight(3600).unwrap().to_string(), "2024-02-29T00:00:00+01:00");
+        assert!(Date { days: 1 << 50 }.at_midnight(0).is_err());
+
+        // round trip across four centuries, leap days included
+        for days in (-146_097..146_097).step_by(7) {
+            let d = Date { days };
+            assert_eq!(Date::parse(&d.to_string()), Ok(d));
+        }
+    }
+
+    #[test]
+    fn test_datetimes() {
+        let a = DateTime::parse("2024-01-01T10:00:00+02:00").unwrap();
+        let b = DateTime::parse("2024-01-01T08:00Z").unwrap();
+        assert_eq!(a, b);
+        assert_eq!(a.to_string(), "2024-01-01T10:00:00+02:00");
+        assert_eq!(b.to_string(), "2024-01-01T08:00:00Z");
+
+        let late = DateTime::parse("2023-12-31T23:30:00-05:00").unwrap();
+        assert_eq!((late.date().to_string(), late.hour()), ("2023-12-31".to_string(), 23));
+        // 04:30 UTC, the same day as `a`
+        assert!(late < a);
+        assert_eq!(late.since(&a).unwrap().to_string(), "-3h30m");
+        assert!(DateTime::parse("2024-01-01T10:00:00").is_err());
+    }
+
+    #[test]
+    fn test_durations() {
+        assert_eq!(Duration::parse("1d12h").unwrap().secs(), 129_600);
+        assert_eq!(Duration::parse("2w").unwrap().to_string(), "14d");
+        assert_eq!(Duration::parse("90m").unwrap().to_string(), "1h30m");
+        assert!(Duration::parse("12h1d").is_err());
+        assert!(Duration::parse("5x").is_err());
+
+        assert_eq!(Duration::parse("-1d12h").unwrap().secs(), -129_600);
+        let min = Duration::from_secs(i64::MIN);
+        assert_eq!(Duration::parse(&min.to_string()).unwrap(), min);
+        assert!(Duration::parse("-").is_err());
+        assert!(Duration::parse("--3d").is_err());
+    }
+}
@@ -1,14 +1,120 @@
+use std::{cmp::Ordering, collections::BTreeMap, fmt};
 
-#[derive(Clone, Debug, PartialEq, PartialOrd)]
+use crate::{
+    lexer::{quote_ident, quote_str},
+    time::{Date, DateTime, Duration},
+};
+
+#[derive(Clone, Debug, PartialEq, Hash)]
 pub enum Value {
-    Int(i64)
+    Int(i64),
+    Bool(bool),
+    Str(String),
+    Date(Date),
+    DateTime(DateTime),
+    Duration(Duration),
+    /// A list, like an order's items, as read from the facts.
+    List(Vec<Value>),
+    /// An object inside a list, or the fields of an instance.
+    Record(BTreeMap<String, Value>),
 }
 
 impl Value {
     pub fn add(&self, other: &Value) -> Result<Value, String> {
         match (self, other) {
-            (Value::Int(a), Value::Int(b)) => Ok(Value::Int(a + b)),
+            (Value::Int(a), Value::Int(b)) => a
+                .checked_add(*b)
+                .map(Value::Int)
+                .ok_or_else(|| format!("integer overflow in {} + {}", a, b)),
+            (Value::Str(a), Value::Str(b)) => Ok(Value::Str(format!("{}{}", a, b))),
+            (Value::Date(date), Value::Duration(d)) | (Value::Duration(d), Value::Date(date)) => {
+                let days = d
+                    .whole_days()
+                    .ok_or_else(|| format!("cannot add {} to date {}: not a whole number of days", d, date))?;
+                date.add_days(days).map(Value::Date).ok_or_else(|| format!("date overflow in {} + {}", date, d))
+            }
+            (Value::DateTime(t), Value::Duration(d)) | (Value::Duration(d), Value::DateTime(t)) => t
+                .checked_add(*d)
+                .map(Value::DateTime)
+                .ok_or_else(|| format!("datetime overflow in {} + {}", t, d)),
+            (Value::Duration(a), Value::Duration(b)) => a
+                .checked_add(*b)
+                .map(Value::Duration)
+                .ok_or_else(|| format!("duration overflow in {} + {}", a, b)),
             _ => Err(format!("cannot add {:?} and {:?}", self, other)),
         }
     }
-}
\ No newline at end of file
+
+    pub fn sub(&self, other: &Value) -> Result<Value, String> {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) => a
+                .checked_sub(*b)
+                .map(Value::Int)
+                .ok_or_else(|| format!("integer overflow in {} - {}", a, b)),
+            (Value::Date(a), Value::Date(b)) => Duration::from_days(a.days_since(b))
+                .map(Value::Duration)
+                .ok_or_else(|| format!("duration overflow in {} - {}", a, b)),
+            (Value::DateTime(a), Value::DateTime(b)) => a
+                .since(b)
+                .map(Value::Duration)
+                .ok_or_else(|| format!("duration overflow in {} - {}", a, b)),
+            (Value::Date(_) | Value::DateTime(_) | Value::Duration(_), Value::Duration(d)) => {
+                let neg = d.checked_neg().ok_or_else(|| format!("duration overflow in -{}", d))?;
+                self.add(&Value::Duration(neg))
+            }
+            _ => Err(format!("cannot subtract {:?} from {:?}", other, self)),
+        }
+    }
+
+    /// Orders two values of the same type, as `<`, `>`, `<=` and `>=` do.
+    /// Values of different types have no order, nor do lists and records,
+    /// except that a date compares with a datetime as midnight UTC.
+    pub fn compare(&self, other: &Value) -> Result<Ordering, String> {
+        match (self, other) {
+            (Value::Date(a), Value::DateTime(b)) => Ok(a.at_midnight(0)?.unix_secs().cmp(&b.unix_secs())),
+            (Value::DateTime(a), Value::Date(b)) => Ok(a.unix_secs().cmp(&b.at_midnight(0)?.unix_secs())),
+            (Value::Int(a), Value::Int(b)) => Ok(a.cmp(b)),
+            (Value::Bool(a), Value::Bool(b)) => Ok(a.cmp(b)),
+            (Value::Str(a), Value::Str(b)) => Ok(a.cmp(b)),
+            (Value::Date(a), Value::Date(b)) => Ok(a.cmp(b)),
+            (Value::DateTime(a), Value::DateTime(b)) => Ok(a.unix_secs().cmp(&b.unix_secs())),
+            (Value::Duration(a), Value::Duration(b)) => Ok(a.cmp(b)),
+            _ => Err(format!("cannot compare {:?} and {:?}", self, other)),
+        }
+    }
+
+    /// Whether the values are equal, as `==` has it: a date equals the
+    /// datetime at midnight UTC on it, and values of other different types
+    /// are never equal.
+    pub fn equals(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Date(_), Value::DateTime(_)) | (Value::DateTime(_), Value::Date(_)) => {
+                self.compare(other).is_ok_and(Ordering::is_eq)
+            }
+            _ => self == other,
+        }
+    }
+}
+
+// prints the value as a GRL literal; lists and records have none, and
+// print as `[1, 2]` and `{Price: 5}`
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Str(s) => write!(f, "{}", quote_str(s)),
+            Value::Date(d) => write!(f, "d\"{}\"", d),
+            Value::DateTime(t) => write!(f, "dt\"{}\"", t),
+            Value::Duration(d) => write!(f, "{}", d),
+            Value::List(items) => {
+                let items: Vec<String> = items.iter().map(|v| v.to_string()).collect();
+                write!(f, "[{}]", items.join(", "))
+            }
+            Value::Record(fields) => {
+                let fields: Vec<String> = fields.iter().map(|(k, v)| format!("{}: {}", quote_ident(k), v)).collect();
+                write!(f, "{{{}}}", fields.join(", "))
+            }
+        }
+    }
+}
@@ -0,0 +1,376 @@
+//! Just enough YAML for rule documents, read into and written from
+//! [`Json`] values. Enabled by the `yaml` feature.
+//!
+//! Supported: block mappings and sequences (including `- key: value`
+//! items), `#` comments, plain, single- and double-quoted scalars, and
+//! flow collections written as JSON (`[]`, `{"a": 1}`). Plain scalars are
+//! null (`~`, `null` or nothing), booleans, integers or strings. Anchors,
+//! tags, block scalars (`|`, `>`) and multiple documents are not.
+
+use crate::{
+    json::{quote, Json},
+    limits::Limits,
+};
+
+struct Line {
+    number: usize,
+    indent: usize,
+    text: String,
+}
+
+// the line without a trailing comment; `#` starts one at the start of the
+// line or after whitespace, outside quotes
+fn strip_comment(line: &str) -> &str {
+    let mut quote: Option<char> = None;
+    let mut prev = ' ';
+    for (i, c) in line.char_indices() {
+        match quote {
+            Some('"') if c == '\\' && prev != '\\' => {}
+            Some(q) if c == q && !(q == '"' && prev == '\\') => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '#' && prev.is_whitespace() => return &line[..i],
+            None => {}
+        }
+        prev = if prev == '\\' && c == '\\' { ' ' } else { c };
+    }
+    line
+}
+
+pub fn parse(input: &str) -> Result<Json, String> {
+    parse_with_limits(input, &Limits::default())
+}
+
+/// Parses with collections, block or flow, nested at most
+/// `limits.max_depth` levels deep.
+pub fn parse_with_limits(input: &str, limits: &Limits) -> Result<Json, String> {
+    let mut lines = Vec::new();
+    for (i, raw) in input.lines().enumerate() {
+        let text = strip_comment(raw).trim_end();
+        let content = text.trim_start();
+        if content.is_empty() || (lines.is_empty() && content == "---") {
+            continue;
+        }
+        if text.starts_with('\t') {
+            return Err(format!("line {}: tabs can't indent YAML", i + 1));
+        }
+        lines.push(Line {
+            number: i + 1,
+            indent: text.len() - content.len(),
+            text: content.to_string(),
+        });
+    }
+    if lines.is_empty() {
+        return Ok(Json::Null);
+    }
+
+    let mut p = YamlParser {
+        lines,
+        pos: 0,
+        depth: 0,
+        max_depth: limits.max_depth,
+    };
+    let indent = p.lines[0].indent;
+    let value = p.node(indent)?;
+    match p.lines.get(p.pos) {
+        Some(line) => Err(format!("line {}: unexpected indentation", line.number)),
+        None => Ok(value),
+    }
+}
+
+struct YamlParser {
+    lines: Vec<Line>,
+    pos: usize,
+    depth: usize,
+    max_depth: usize,
+}
+
+fn is_item(text: &str) -> bool {
+    text == "-" || text.starts_with("- ")
+}
+
+// splits `key: value` at the colon, unquoting the key
+fn split_key(text: &str, number: usize) -> Result<Option<(String, &str)>, String> {
+    if text.starts_with('"') || text.starts_with('\'') {
+        let q = text.chars().next().unwrap();
+        let mut escaped = false;
+        for (i, c) in text.char_indices().skip(1) {
+            if q == '"' && c == '\\' && !escaped {
+                escaped = true;
+                continue;
+            }
+            if c == q && !escaped {
+                let rest = &text[i + 1..];
+                let Some(value) = rest.strip_prefix(':') else {
+                    return Ok(None);
+                };
+                if !value.is_empty() && !value.starts_with(' ') {
+                    return Ok(None);
+                }
+                // a quoted key nests nothing
+                let Json::Str(key) = scalar(&text[..=i], number, 0)? else { unreachable!() };
+                return Ok(Some((key, value.trim())));
+            }
+            escaped = false;
+        }
+        return Ok(None);
+    }
+    if text.starts_with('[') || text.starts_with('{') {
+        return Ok(None);
+    }
+    let at = match (text.find(": "), text.ends_with(':')) {
+        (Some(i), _) => i,
+        (None, true) => text.len() - 1,
+        (None, false) => return Ok(None),
+    };
+    Ok(Some((text[..at].trim_end().to_string(), text[at + 1..].trim())))
+}
+
+// `max_depth` is how much deeper a flow collection may nest
+fn scalar(text: &str, number: usize, max_depth: usize) -> Result<Json, String> {
+    let bad = |what: &str| format!("line {}: {}", number, what);
+    match text {
+        "" | "~" | "null" => return Ok(Json::Null),
+        "true" => return Ok(Json::Bool(true)),
+        "false" => return Ok(Json::Bool(false)),
+        _ => {}
+    }
+    if text.starts_with('"') || text.starts_with('[') || text.starts_with('{') {
+        let limits = Limits {
+            max_depth,
+            ..Limits::default()
+        };
+        return Json::parse_with_limits(text, &limits).map_err(|e| bad(&e));
+    }
+    if let Some(inner) = text.strip_prefix('\'') {
+        let inner = inner.strip_suffix('\'').ok_or_else(|| bad("unterminated string"))?;
+        return Ok(Json::Str(inner.replace("''", "'")));
+    }
+    if text.starts_with(['|', '>', '&', '*', '!']) {
+        return Err(bad(&format!("unsupported YAML '{}'", text)));
+    }
+    if let Ok(n) = text.parse::<i64>() {
+        return Ok(Json::Int(n));
+    }
+    Ok(Json::Str(text.to_string()))
+}
+
+impl YamlParser {
+    // the node whose first line is the current one, at `indent`
+    fn node(&mut self, indent: usize) -> Result<Json, String> {
+        let line = &self.lines[self.pos];
+        if is_item(&line.text) || split_key(&line.text, line.number)?.is_some() {
+            if self.depth == self.max_depth {
+                return Err(format!("line {}: YAML nested deeper than {} levels", line.number, self.max_depth));
+            }
+            self.depth += 1;
+            let value = if is_item(&line.text) { self.sequence(indent) } else { self.mapping(indent) };
+            self.depth -= 1;
+            value
+        } else {
+            let value = scalar(&line.text, line.number, self.max_depth - self.depth)?;
+            self.pos += 1;
+            Ok(value)
+        }
+    }
+
+    // the value after `key:` or `-` with nothing else on the line
+    fn nested(&mut self, indent: usize, in_mapping: bool) -> Result<Json, String> {
+        match self.lines.get(self.pos) {
+            Some(next) if next.indent > indent => {
+                let indent = next.indent;
+                self.node(indent)
+            }
+            // a mapping's sequence may sit at the key's own indent
+            Some(next) if in_mapping && next.indent == indent && is_item(&next.text) => self.sequence(indent),
+            _ => Ok(Json::Null),
+        }
+    }
+
+    fn sequence(&mut self, indent: usize) -> Result<Json, String> {
+        let mut items = Vec::new();
+        while let Some(line) = self.lines.get_mut(self.pos) {
+            if line.indent != indent || !is_item(&line.text) {
+                break;
+            }
+            let rest = line.text[1..].trim_start().to_string();
+            if rest.is_empty() {
+                self.pos += 1;
+                items.push(self.nested(indent, false)?);
+            } else {
+                // the rest of the line starts a node indented past the dash
+                line.indent += line.text.len() - rest.len();
+                line.text = rest;
+                let indent = line.indent;
+                items.push(self.node(indent)?);
+            }
+        }
+        Ok(Json::Array(items))
+    }
+
+    fn mapping(&mut self, indent: usize) -> Result<Json, String> {
+        let mut members: Vec<(String, Json)> = Vec::new();
+        while let Some(line) = self.lines.get(self.pos) {
+            if line.indent != indent || is_item(&line.text) {
+                break;
+            }
+            let number = line.number;
+            let Some((key, value)) = split_key(&line.text, number)? else {
+                return Err(format!("line {}: expected 'key: value'", number));
+            };
+            if members.iter().any(|(k, _)| *k == key) {
+                return Err(format!("line {}: duplicate key '{}'", number, key));
+            }
+            let value = if value.is_empty() {
+                self.pos += 1;
+                self.nested(indent, true)?
+            } else {
+                let value = scalar(value, number, self.max_depth - self.depth)?;
+                self.pos += 1;
+                value
+            };
+            members.push((key, value));
+        }
+        Ok(Json::Object(members))
+    }
+}
+
+/// Block-style YAML for `json`. Strings are always double-quoted, so none
+/// can be misread as another type.
+pub fn to_yaml(json: &Json) -> String {
+    let mut out = String::new();
+    match json {
+        Json::Array(items) if !items.is_empty() => write_items(&mut out, items, 0),
+        Json::Object(members) if !members.is_empty() => write_members(&mut out, members, 0),
+        other => {
+            out.push_str(&other.to_string());
+            out.push('\n');
+        }
+    }
+    out
+}
+
+fn plain_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '.')
+        && !key.starts_with('-')
+        && !matches!(key, "null" | "true" | "false" | "~")
+        && key.parse::<i64>().is_err()
+}
+
+fn write_members(out: &mut String, members: &[(String, Json)], indent: usize) {
+    for (i, (key, value)) in members.iter().enumerate() {
+        // the first key of a sequence item follows its dash
+        if i > 0 || !out.ends_with("- ") {
+            out.push_str(&" ".repeat(indent));
+        }
+        out.push_str(&if plain_key(key) { key.clone() } else { quote(key) });
+        out.push(':');
+        write_value(out, value, indent);
+    }
+}
+
+fn write_items(out: &mut String, items: &[Json], indent: usize) {
+    for item in items {
+        out.push_str(&" ".repeat(indent));
+        out.push_str("- ");
+        match item {
+            Json::Object(members) if !members.is_empty() => write_members(out, members, indent + 2),
+            Json::Array(items) if !items.is_empty() => {
+                out.pop();
+                out.push('\n');
+                write_items(out, items, indent + 2);
+            }
+            other => {
+                out.push_str(&other.to_string());
+                out.push('\n');
+            }
+        }
+    }
+}
+
+// after `key:`
+fn write_value(out: &mut String, value: &Json, indent: usize) {
+    match value {
+        Json::Object(members) if !members.is_empty() => {
+            out.push('\n');
+            write_members(out, members, indent + 2);
+        }
+        Json::Array(items) if !items.is_empty() => {
+            out.push('\n');
+            write_items(out, items, indent + 2);
+        }
+        other => {
+            out.push(' ');
+            out.push_str(&other.to_string());
+            out.push('\n');
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let yaml = r#"
+# a rule
+rules:
+- name: Discount   # trailing comment
+  salience: -3
+  tags: [1, "two"]
+  empty:
+  quoted: 'it''s # not a comment'
+  "odd key": "a\tb"
+  then:
+    - set: Order.Discount
+      to:
+        op: "+"
+        left: {"field": "Order.Total"}
+        right: 1
+    -
+      - nested
+"#;
+        let json = parse(yaml).unwrap();
+        assert_eq!(
+            json.to_string(),
+            r#"{"rules":[{"name":"Discount","salience":-3,"tags":[1,"two"],"empty":null,"quoted":"it's # not a comment","odd key":"a\tb","then":[{"set":"Order.Discount","to":{"op":"+","left":{"field":"Order.Total"},"right":1}},["nested"]]}]}"#
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let json = Json::parse(
+            r#"{"a": [{"b": 1, "c": [true, null, "x: y"]}, [], {}, [["deep"]]], "true": "false", "": -1}"#,
+        )
+        .unwrap();
+        let yaml = to_yaml(&json);
+        assert!(yaml.starts_with("a:\n  - b: 1\n    c:\n      - true\n      - null\n      - \"x: y\"\n  - []\n  - {}\n"));
+        assert_eq!(parse(&yaml).unwrap(), json);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(parse("a: 1\n  b: 2").unwrap_err(), "line 2: unexpected indentation");
+        assert_eq!(parse("a: 1\na: 2").unwrap_err(), "line 2: duplicate key 'a'");
+        assert_eq!(parse("a: |\n  text").unwrap_err(), "line 1: unsupported YAML '|'");
+    }
+
+    #[test]
+    fn test_depth_limit() {
+        assert_eq!(parse(&"- ".repeat(100_000)).unwrap_err(), "line 1: YAML nested deeper than 128 levels");
+        let keys: String = (0..200).map(|i| format!("{}a:\n", " ".repeat(i))).collect();
+        assert_eq!(parse(&keys).unwrap_err(), "line 129: YAML nested deeper than 128 levels");
+        // flow collections count from the depth of the block they sit in
+        let flow = format!("a:\n  b: {}", "[".repeat(200));
+        assert_eq!(parse(&flow).unwrap_err(), "line 2: JSON nested deeper than 126 levels at pos 126");
+
+        let limits = Limits {
+            max_depth: 2,
+            ..Limits::default()
+        };
+        assert!(parse_with_limits("a:\n  - 1", &limits).is_ok());
+        assert!(parse_with_limits("a:\n  - [1]", &limits).is_err());
+    }
+}